@@ -0,0 +1,675 @@
+use std::{
+    io::{BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+};
+
+use crate::{
+    auth,
+    ban::BanList,
+    config::Config,
+    control::{ActiveSession, Control},
+    datetime,
+    http::{self, Request},
+    json::{Json, ToJson},
+    quarantine::{Quarantine, QuarantinedMail},
+    queue::{Hold, QueueFlush, QueueHold},
+    quota::{MailboxQuotas, MailboxUsage},
+    server::Reloader,
+};
+
+/// Serves the operational actions on a running server over HTTP, for requests carrying
+/// `Authorization: Bearer <token>`:
+///
+/// - `GET /status` tells whether connections are accepted, the rate limit and the number
+///   of sessions
+/// - `GET /sessions` lists the sessions under way, `DELETE /sessions/{id}` kicks one
+/// - `GET /rate-limit` and `PUT /rate-limit?connections_per_minute=n` read and change the
+///   connections accepted from one address a minute, 0 for unlimited
+/// - `POST /pause` and `POST /resume` stop and start accepting connections
+/// - `POST /queue/flush` makes the queued messages due now, with `?domain=` those for one
+///   destination, as ETRN does
+/// - `GET /queue/holds` lists the holds on queued messages, `POST /queue/{queue id}/hold`
+///   and `POST /queue/senders/{domain}/hold` hold a message or a sender domain, and the
+///   same ending in `/release` release them
+/// - `POST /reload` reloads the configuration, as SIGHUP does
+/// - `GET /bans` lists the banned addresses, `DELETE /bans/{ip}` lifts a ban
+/// - `GET /mailboxes` lists what the messages of each recipient take of the local store
+///   and their quotas, `GET /mailboxes/{address}` those of one
+/// - `GET /quarantine` lists the quarantined messages without their text,
+///   `GET /quarantine/{queue id}` gives one whole, `POST /quarantine/{queue id}/release`
+///   delivers it and `DELETE /quarantine/{queue id}` purges it
+///
+/// The token is all that guards these, so the listener belongs on a loopback or management
+/// address.
+pub struct Admin {
+    token: String,
+    control: Arc<Control>,
+    reloader: Option<Arc<Reloader>>,
+    queue: Option<Arc<dyn QueueFlush>>,
+    hold: Option<Arc<dyn QueueHold>>,
+    bans: Option<Arc<BanList>>,
+    quarantine: Option<(Arc<Quarantine>, Arc<Config>)>,
+    mailbox_quotas: Option<Arc<MailboxQuotas>>,
+}
+
+impl Admin {
+    /// Controls the server `control` belongs to for clients knowing `token`.
+    pub fn new(token: &str, control: Arc<Control>) -> Admin {
+        Admin {
+            token: String::from(token),
+            control,
+            reloader: None,
+            queue: None,
+            hold: None,
+            bans: None,
+            quarantine: None,
+            mailbox_quotas: None,
+        }
+    }
+
+    /// Reloads the configuration through `reloader`, without which `/reload` is not found.
+    pub fn reloader(mut self, reloader: Arc<Reloader>) -> Admin {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Flushes `queue`, without which `/queue/flush` is not found.
+    pub fn queue(mut self, queue: Arc<dyn QueueFlush>) -> Admin {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Holds and releases queued messages through `hold`, without which `/queue/holds` and
+    /// the like are not found.
+    pub fn hold(mut self, hold: Arc<dyn QueueHold>) -> Admin {
+        self.hold = Some(hold);
+        self
+    }
+
+    /// Lists and lifts the bans of `bans`, without which `/bans` is not found.
+    pub fn bans(mut self, bans: Arc<BanList>) -> Admin {
+        self.bans = Some(bans);
+        self
+    }
+
+    /// Lists, releases to the outputs of `config` and purges the messages in `quarantine`,
+    /// without which `/quarantine` is not found.
+    pub fn quarantine(mut self, quarantine: Arc<Quarantine>, config: Arc<Config>) -> Admin {
+        self.quarantine = Some((quarantine, config));
+        self
+    }
+
+    /// Tells the usage of mailboxes from `quotas`, without which `/mailboxes` is not found.
+    pub fn mailbox_quotas(mut self, quotas: Arc<MailboxQuotas>) -> Admin {
+        self.mailbox_quotas = Some(quotas);
+        self
+    }
+
+    pub fn serve(self, listener: TcpListener) {
+        let admin = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    crate::warn!("Admin connection failed: {}", e);
+                    continue;
+                }
+            };
+            let admin = Arc::clone(&admin);
+            thread::spawn(move || admin.handle(stream));
+        }
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let (status, content_type, body) = match http::read_request(&mut reader) {
+            Ok(request) if !self.is_authorized(&request) => {
+                crate::warn!(
+                    "Unauthorized admin request {} {} from {}",
+                    request.method,
+                    request.path,
+                    stream
+                        .peer_addr()
+                        .map(|peer| peer.to_string())
+                        .unwrap_or_default()
+                );
+                (401, "text/plain", b"Unauthorized".to_vec())
+            }
+            Ok(request) => self.route(&request),
+            Err(_) => (400, "text/plain", b"Bad request".to_vec()),
+        };
+        if let Err(e) = http::write_response(&mut writer, status, content_type, &body) {
+            crate::warn!("Admin response failed: {}", e);
+        }
+    }
+
+    /// Whether `request` carries the token, compared in constant time so that the time
+    /// taken gives no hint of how much of a guess is right.
+    fn is_authorized(&self, request: &Request) -> bool {
+        let token = match request
+            .header("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+        {
+            Some(token) => token.trim().as_bytes(),
+            None => return false,
+        };
+        !self.token.is_empty() && auth::constant_time_eq(token, self.token.as_bytes())
+    }
+
+    fn route(&self, request: &Request) -> (u16, &'static str, Vec<u8>) {
+        let not_found = (404, "text/plain", b"Not found".to_vec());
+        let no_content = (204, "text/plain", Vec::new());
+        let segments: Vec<&str> = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["status"]) => json_response(Json::object(vec![
+                ("paused", Json::Bool(self.control.is_paused())),
+                (
+                    "connections_per_minute",
+                    Json::Number(self.control.connection_rate().into()),
+                ),
+                (
+                    "sessions",
+                    Json::Number(self.control.sessions().len() as i64),
+                ),
+            ])),
+            ("GET", ["sessions"]) => json_response(Json::Array(
+                self.control.sessions().iter().map(session_json).collect(),
+            )),
+            ("DELETE", ["sessions", id]) => {
+                if self.control.kick(id) {
+                    no_content
+                } else {
+                    not_found
+                }
+            }
+            ("GET", ["rate-limit"]) => json_response(Json::object(vec![(
+                "connections_per_minute",
+                Json::Number(self.control.connection_rate().into()),
+            )])),
+            ("PUT", ["rate-limit"]) => {
+                match request
+                    .query("connections_per_minute")
+                    .and_then(|rate| rate.parse().ok())
+                {
+                    Some(rate) => {
+                        self.control.set_connection_rate(rate);
+                        crate::info!("Connection rate limit set to {} a minute", rate);
+                        no_content
+                    }
+                    None => (
+                        400,
+                        "text/plain",
+                        b"Expected connections_per_minute".to_vec(),
+                    ),
+                }
+            }
+            ("POST", ["pause"]) => {
+                self.control.pause();
+                no_content
+            }
+            ("POST", ["resume"]) => {
+                self.control.resume();
+                no_content
+            }
+            ("POST", ["queue", "flush"]) => match &self.queue {
+                Some(queue) => match queue.flush(request.query("domain").unwrap_or("")) {
+                    Ok(flushed) => json_response(Json::object(vec![(
+                        "flushed",
+                        Json::Number(flushed as i64),
+                    )])),
+                    Err(e) => (500, "text/plain", e.to_string().into_bytes()),
+                },
+                None => not_found,
+            },
+            ("GET", ["queue", "holds"]) => match &self.hold {
+                Some(hold) => json_response(Json::Array(
+                    hold.holds()
+                        .iter()
+                        .map(|(hold, messages)| {
+                            let (kind, name) = match hold {
+                                Hold::Message(queue_id) => ("message", queue_id),
+                                Hold::Sender(domain) => ("sender", domain),
+                            };
+                            Json::object(vec![
+                                (kind, Json::string(name)),
+                                ("messages", Json::Number(*messages as i64)),
+                            ])
+                        })
+                        .collect(),
+                )),
+                None => not_found,
+            },
+            ("POST", ["queue", queue_id, action @ ("hold" | "release")]) => {
+                self.apply_hold(&Hold::Message(String::from(*queue_id)), action)
+            }
+            ("POST", ["queue", "senders", domain, action @ ("hold" | "release")]) => {
+                self.apply_hold(&Hold::Sender(String::from(*domain)), action)
+            }
+            ("POST", ["reload"]) => {
+                match self.reloader.as_ref().map(|reloader| reloader.reload()) {
+                    Some(Ok(())) => no_content,
+                    Some(Err(e)) => (500, "text/plain", e.to_string().into_bytes()),
+                    None => not_found,
+                }
+            }
+            ("GET", ["bans"]) => match &self.bans {
+                Some(bans) => json_response(Json::Array(
+                    bans.list()
+                        .iter()
+                        .map(|(address, until)| {
+                            Json::object(vec![
+                                ("address", Json::String(address.to_string())),
+                                ("until", Json::String(datetime::rfc3339(*until))),
+                            ])
+                        })
+                        .collect(),
+                )),
+                None => not_found,
+            },
+            ("DELETE", ["bans", address]) => {
+                let bans = match &self.bans {
+                    Some(bans) => bans,
+                    None => return not_found,
+                };
+                match address.parse() {
+                    Ok(address) if bans.unban(address) => no_content,
+                    Ok(_) => not_found,
+                    Err(_) => (400, "text/plain", b"Invalid IP address".to_vec()),
+                }
+            }
+            ("GET", ["mailboxes"]) => match &self.mailbox_quotas {
+                Some(quotas) => json_response(Json::Array(
+                    quotas.usage().iter().map(mailbox_json).collect(),
+                )),
+                None => not_found,
+            },
+            ("GET", ["mailboxes", mailbox]) => match &self.mailbox_quotas {
+                Some(quotas) => json_response(mailbox_json(&MailboxUsage {
+                    mailbox: mailbox.to_lowercase(),
+                    used: quotas.used(mailbox),
+                    limit: quotas.limit(mailbox),
+                })),
+                None => not_found,
+            },
+            ("GET", ["quarantine"]) => match &self.quarantine {
+                Some((quarantine, _)) => match quarantine.list() {
+                    Ok(list) => {
+                        json_response(Json::Array(list.iter().map(quarantined_json).collect()))
+                    }
+                    Err(e) => (500, "text/plain", e.to_string().into_bytes()),
+                },
+                None => not_found,
+            },
+            ("GET", ["quarantine", queue_id]) => match &self.quarantine {
+                Some((quarantine, _)) => match quarantine.get(queue_id) {
+                    Ok(Some(quarantined)) => json_response(quarantined.to_json()),
+                    Ok(None) => not_found,
+                    Err(e) => quarantine_error(e),
+                },
+                None => not_found,
+            },
+            ("POST", ["quarantine", queue_id, "release"]) => match &self.quarantine {
+                Some((quarantine, config)) => match quarantine.release(queue_id, config) {
+                    Ok(true) => no_content,
+                    Ok(false) => not_found,
+                    Err(e) => quarantine_error(e),
+                },
+                None => not_found,
+            },
+            ("DELETE", ["quarantine", queue_id]) => match &self.quarantine {
+                Some((quarantine, _)) => match quarantine.purge(queue_id) {
+                    Ok(true) => {
+                        crate::info!("Purged {} from quarantine", queue_id);
+                        no_content
+                    }
+                    Ok(false) => not_found,
+                    Err(e) => quarantine_error(e),
+                },
+                None => not_found,
+            },
+            (_, ["status"])
+            | (_, ["sessions"])
+            | (_, ["sessions", _])
+            | (_, ["rate-limit"])
+            | (_, ["pause"])
+            | (_, ["resume"])
+            | (_, ["queue", "flush"])
+            | (_, ["queue", "holds"])
+            | (_, ["queue", _, "hold" | "release"])
+            | (_, ["queue", "senders", _, "hold" | "release"])
+            | (_, ["reload"])
+            | (_, ["bans"])
+            | (_, ["bans", _])
+            | (_, ["mailboxes"])
+            | (_, ["mailboxes", _])
+            | (_, ["quarantine"])
+            | (_, ["quarantine", _])
+            | (_, ["quarantine", _, "release"]) => {
+                (405, "text/plain", b"Method not allowed".to_vec())
+            }
+            _ => not_found,
+        }
+    }
+
+    /// Holds or releases what `hold` names, as `action` says. Not found when there is no
+    /// such message, a sender domain may be held before any of its mail is queued.
+    fn apply_hold(&self, hold: &Hold, action: &str) -> (u16, &'static str, Vec<u8>) {
+        let queue = match &self.hold {
+            Some(queue) => queue,
+            None => return (404, "text/plain", b"Not found".to_vec()),
+        };
+        let result = if action == "hold" {
+            queue.hold(hold)
+        } else {
+            queue.release(hold)
+        };
+        match result {
+            Ok(0) if matches!(hold, Hold::Message(_)) => (404, "text/plain", b"Not found".to_vec()),
+            Ok(messages) => json_response(Json::object(vec![(
+                if action == "hold" { "held" } else { "released" },
+                Json::Number(messages as i64),
+            )])),
+            Err(e) => (500, "text/plain", e.to_string().into_bytes()),
+        }
+    }
+}
+
+fn json_response(json: Json) -> (u16, &'static str, Vec<u8>) {
+    (200, "application/json", json.to_string().into_bytes())
+}
+
+fn mailbox_json(usage: &MailboxUsage) -> Json {
+    Json::object(vec![
+        ("mailbox", Json::string(&usage.mailbox)),
+        ("used", Json::Number(usage.used as i64)),
+        (
+            "limit",
+            usage
+                .limit
+                .map_or(Json::Null, |limit| Json::Number(limit as i64)),
+        ),
+    ])
+}
+
+/// A quarantined message as listed: who it is from and to, why and when, but not its
+/// text.
+fn quarantined_json(quarantined: &QuarantinedMail) -> Json {
+    Json::object(vec![
+        ("queue_id", Json::string(&quarantined.queue_id)),
+        ("reason", Json::string(&quarantined.reason)),
+        (
+            "quarantined_at",
+            Json::String(datetime::rfc3339(quarantined.quarantined_at)),
+        ),
+        (
+            "mail_from",
+            quarantined
+                .mail
+                .mail_from
+                .as_deref()
+                .map_or(Json::Null, Json::string),
+        ),
+        (
+            "rcpt_to",
+            Json::Array(
+                quarantined
+                    .mail
+                    .rcpt_to
+                    .iter()
+                    .map(|to| Json::string(to))
+                    .collect(),
+            ),
+        ),
+        (
+            "size",
+            Json::Number(quarantined.mail.data.as_ref().map_or(0, String::len) as i64),
+        ),
+    ])
+}
+
+/// A queue ID that is not one is the client's fault, anything else the server's.
+fn quarantine_error(e: std::io::Error) -> (u16, &'static str, Vec<u8>) {
+    let status = if e.kind() == std::io::ErrorKind::InvalidInput {
+        400
+    } else {
+        500
+    };
+    (status, "text/plain", e.to_string().into_bytes())
+}
+
+fn session_json(session: &ActiveSession) -> Json {
+    Json::object(vec![
+        ("id", Json::string(&session.id)),
+        (
+            "peer",
+            session
+                .peer
+                .map_or(Json::Null, |peer| Json::String(peer.to_string())),
+        ),
+        ("started", Json::String(datetime::rfc3339(session.started))),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{self, Read, Write},
+        sync::Mutex,
+    };
+
+    fn request(method: &str, target: &str, token: &str) -> Request {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+            method, target, token
+        );
+        http::read_request(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_authorization() {
+        let admin = Admin::new("s3cret", Arc::new(Control::new()));
+        assert!(admin.is_authorized(&request("GET", "/status", "s3cret")));
+        assert!(!admin.is_authorized(&request("GET", "/status", "s3cre")));
+        assert!(!admin.is_authorized(&request("GET", "/status", "s3cret!")));
+        let raw = "GET /status HTTP/1.1\r\n\r\n";
+        assert!(!admin.is_authorized(&http::read_request(&mut raw.as_bytes()).unwrap()));
+        let open = Admin::new("", Arc::new(Control::new()));
+        assert!(!open.is_authorized(&request("GET", "/status", "")));
+    }
+
+    #[test]
+    fn test_routes() {
+        let control = Arc::new(Control::new());
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let flushed = Arc::clone(&flushed);
+            move |node: &str| -> io::Result<usize> {
+                flushed.lock().unwrap().push(String::from(node));
+                Ok(2)
+            }
+        };
+        let admin = Admin::new("t", Arc::clone(&control)).queue(Arc::new(queue));
+        let route = |method: &str, target: &str| admin.route(&request(method, target, "t"));
+
+        assert_eq!(route("POST", "/pause").0, 204);
+        assert!(control.is_paused());
+        let (status, _, body) = route("GET", "/status");
+        assert_eq!(status, 200);
+        let status = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(status.get("paused"), Some(&Json::Bool(true)));
+        assert_eq!(route("POST", "/resume").0, 204);
+        assert!(!control.is_paused());
+
+        assert_eq!(route("PUT", "/rate-limit?connections_per_minute=30").0, 204);
+        assert_eq!(control.connection_rate(), 30);
+        assert_eq!(route("PUT", "/rate-limit?connections_per_minute=x").0, 400);
+        assert_eq!(
+            route("GET", "/rate-limit").2,
+            b"{\"connections_per_minute\":30}"
+        );
+
+        let (status, _, body) = route("POST", "/queue/flush?domain=example.com");
+        assert_eq!((status, body), (200, b"{\"flushed\":2}".to_vec()));
+        assert_eq!(*flushed.lock().unwrap(), vec!["example.com"]);
+
+        assert_eq!(route("DELETE", "/sessions/nope").0, 404);
+        assert_eq!(route("GET", "/pause").0, 405);
+        assert_eq!(route("POST", "/reload").0, 404);
+        assert_eq!(route("GET", "/bans").0, 404);
+    }
+
+    #[test]
+    fn test_bans() {
+        let bans = Arc::new(BanList::new());
+        bans.record(
+            "192.0.2.1".parse().unwrap(),
+            crate::ban::Offence::Blocklisted,
+        );
+        let admin = Admin::new("t", Arc::new(Control::new())).bans(Arc::clone(&bans));
+        let route = |method: &str, target: &str| admin.route(&request(method, target, "t"));
+
+        let (_, _, body) = route("GET", "/bans");
+        let list = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(
+            list.as_array().unwrap()[0].get("address"),
+            Some(&Json::string("192.0.2.1"))
+        );
+        assert_eq!(route("DELETE", "/bans/nope").0, 400);
+        assert_eq!(route("DELETE", "/bans/192.0.2.1").0, 204);
+        assert_eq!(route("DELETE", "/bans/192.0.2.1").0, 404);
+        assert!(bans.list().is_empty());
+    }
+
+    #[test]
+    fn test_hold() {
+        use crate::{
+            delivery::DeliveryQueue,
+            output::Output,
+            retry::RetrySchedule,
+            store::{MemoryStore, MessageStore, StoreOutput},
+        };
+
+        let store = Arc::new(MemoryStore::new(10));
+        let output = Arc::new(StoreOutput(store.clone()));
+        let queue = Arc::new(DeliveryQueue::new(output, RetrySchedule::default(), 1));
+        let admin = Admin::new("t", Arc::new(Control::new())).hold(queue.clone());
+        let route = |method: &str, target: &str| admin.route(&request(method, target, "t"));
+
+        let (status, _, body) = route("POST", "/queue/senders/example.net/hold");
+        assert_eq!((status, body), (200, b"{\"held\":0}".to_vec()));
+        let mut mail = crate::email::Mail::new();
+        mail.queue_id = Some(String::from("4j5RNq31Gc8cgV"));
+        mail.mail_from = Some(String::from("<a@example.net>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        queue.deliver(&mail).unwrap();
+        assert_eq!(route("POST", "/queue/4j5RNq31Gc8cgV/hold").0, 200);
+        assert_eq!(route("POST", "/queue/nope/hold").0, 404);
+        assert_eq!(route("GET", "/queue/nope/hold").0, 405);
+        let (_, _, body) = route("GET", "/queue/holds");
+        let holds = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(holds.as_array().unwrap().len(), 2);
+
+        assert_eq!(route("POST", "/queue/senders/example.net/release").0, 200);
+        assert_eq!(route("POST", "/queue/4j5RNq31Gc8cgV/release").0, 200);
+        for _ in 0..500 {
+            if queue.is_empty() {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn test_mailboxes() {
+        use crate::{
+            quota::{MailboxLimits, MailboxQuotas},
+            store::{MemoryStore, MessageStore},
+        };
+
+        let store = Arc::new(MemoryStore::new(10));
+        let mut limits = MailboxLimits::default();
+        limits.add("1K").unwrap();
+        let quotas = Arc::new(MailboxQuotas::new(store.clone(), limits));
+        let mut mail = crate::email::Mail::new();
+        mail.rcpt_to = vec![String::from("<Bob@example.com>")];
+        mail.data = Some(String::from("hi"));
+        store.save(&mail).unwrap();
+        let admin = Admin::new("t", Arc::new(Control::new())).mailbox_quotas(quotas);
+        let route = |method: &str, target: &str| admin.route(&request(method, target, "t"));
+
+        let (_, _, body) = route("GET", "/mailboxes");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"[{"mailbox":"bob@example.com","used":2,"limit":1024}]"#
+        );
+        let (_, _, body) = route("GET", "/mailboxes/alice@example.com");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            r#"{"mailbox":"alice@example.com","used":0,"limit":1024}"#
+        );
+        assert_eq!(route("DELETE", "/mailboxes").0, 405);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let directory = std::env::temp_dir().join(format!("admin-{}", std::process::id()));
+        let quarantine = Arc::new(Quarantine::open(&directory).unwrap());
+        let mut mail = crate::email::Mail::new();
+        mail.queue_id = Some(String::from("4j5RNq31Gc8cgV"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from("Subject: cheap\r\n\r\nbuy\r\n"));
+        quarantine.add(&mail, "spam score 12.5").unwrap();
+        let admin = Admin::new("t", Arc::new(Control::new()))
+            .quarantine(Arc::clone(&quarantine), Arc::new(Config::default()));
+        let route = |method: &str, target: &str| admin.route(&request(method, target, "t"));
+
+        let (_, _, body) = route("GET", "/quarantine");
+        let list = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        let listed = &list.as_array().unwrap()[0];
+        assert_eq!(listed.get("reason"), Some(&Json::string("spam score 12.5")));
+        assert_eq!(listed.get("mail"), None);
+        assert_eq!(route("GET", "/quarantine/4j5RNq31Gc8cgV").0, 200);
+        assert_eq!(route("GET", "/quarantine/nope").0, 404);
+        assert_eq!(route("GET", "/quarantine/..").0, 400);
+        assert_eq!(route("GET", "/quarantine/4j5RNq31Gc8cgV/release").0, 405);
+        assert_eq!(route("POST", "/quarantine/4j5RNq31Gc8cgV/release").0, 204);
+        assert_eq!(route("DELETE", "/quarantine/4j5RNq31Gc8cgV").0, 404);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let admin = Admin::new("t", Arc::new(Control::new()));
+        thread::spawn(move || admin.serve(listener));
+
+        let get = |token: &str| {
+            let mut client = TcpStream::connect(address).unwrap();
+            write!(
+                client,
+                "GET /sessions HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n",
+                token
+            )
+            .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        };
+        assert!(get("wrong").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        let response = get("t");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n[]"));
+    }
+}
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, fs, io};
+
+use crate::email::{domain_of, local_part_of};
+
+/// Resolves aliases kept elsewhere, e.g. in a directory. Targets are returned as
+/// `<user@domain>`, `None` meaning that the address is no alias.
+pub trait AliasLookup: Send + Sync {
+    fn lookup(&self, address: &str) -> io::Result<Option<Vec<String>>>;
+}
+
+/// Virtual alias map rewriting recipients at RCPT TO, like Postfix `virtual_alias_maps`.
+/// Patterns are either a full address, or `@domain` for every address in the domain not
+/// matched exactly. A target of `@domain` keeps the local part of the recipient. Targets
+/// are not expanded again.
+#[derive(Clone, Debug, Default)]
+pub struct Aliases {
+    exact: HashMap<String, Vec<String>>,
+    domains: HashMap<String, Vec<String>>,
+}
+
+impl Aliases {
+    pub fn add(&mut self, pattern: &str, targets: &[&str]) {
+        let pattern = pattern.trim().to_lowercase();
+        let targets = targets.iter().map(|target| String::from(target.trim()));
+        match pattern.strip_prefix('@') {
+            Some(domain) => self
+                .domains
+                .entry(String::from(domain))
+                .or_default()
+                .extend(targets),
+            None => self.exact.entry(pattern).or_default().extend(targets),
+        }
+    }
+
+    /// Reads `pattern target, target...` lines, skipping blank lines and `#` comments.
+    pub fn load(path: &str) -> io::Result<Aliases> {
+        let mut aliases = Aliases::default();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (pattern, targets) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: alias without target", path, number + 1),
+                )
+            })?;
+            let targets: Vec<&str> = targets
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|target| !target.is_empty())
+                .collect();
+            aliases.add(pattern, &targets);
+        }
+        Ok(aliases)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.domains.is_empty()
+    }
+
+    /// The recipients `address` is rewritten to, as `<user@domain>`, `None` when no alias
+    /// matches.
+    pub fn expand(&self, address: &str) -> Option<Vec<String>> {
+        let local_part = local_part_of(address);
+        let domain = domain_of(address).unwrap_or("").to_lowercase();
+        let targets = match self
+            .exact
+            .get(&format!("{}@{}", local_part.to_lowercase(), domain))
+        {
+            Some(targets) => targets,
+            None => self.domains.get(&domain)?,
+        };
+        Some(
+            targets
+                .iter()
+                .map(|target| match target.strip_prefix('@') {
+                    Some(domain) => format!("<{}@{}>", local_part, domain),
+                    None => format!("<{}>", target),
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let mut aliases = Aliases::default();
+        aliases.add(
+            "sales@example.com",
+            &["alice@example.com", "bob@example.net"],
+        );
+        aliases.add("@example.com", &["catchall@example.com"]);
+        aliases.add("@old.example", &["@new.example"]);
+        assert_eq!(
+            aliases.expand("<Sales@Example.com>"),
+            Some(vec![
+                String::from("<alice@example.com>"),
+                String::from("<bob@example.net>")
+            ])
+        );
+        assert_eq!(
+            aliases.expand("<anyone@example.com>"),
+            Some(vec![String::from("<catchall@example.com>")])
+        );
+        assert_eq!(
+            aliases.expand("carol@old.example"),
+            Some(vec![String::from("<carol@new.example>")])
+        );
+        assert_eq!(aliases.expand("<dave@example.org>"), None);
+    }
+}
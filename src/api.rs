@@ -0,0 +1,303 @@
+use std::{
+    io::{self, BufReader, BufWriter, Write},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    events::EventFeed,
+    headers::Message,
+    http::{self, Request},
+    json::{Json, ToJson},
+    output::envelope_json,
+    store::{MessageStore, StoredMail},
+    transcript::Transcripts,
+};
+
+/// Serves the captured messages of `store` over HTTP:
+///
+/// - `GET /messages` lists summaries, filtered by the `from`, `to` and `subject` query
+///   parameters (case-insensitive substring matches)
+/// - `GET /messages/{id}` returns the message as JSON, `GET /messages/{id}.eml` the raw message
+/// - `DELETE /messages/{id}` removes it
+/// - `GET /events` streams every new message as a server-sent event carrying the envelope,
+///   or the whole message with `?payload=1`
+/// - `GET /transcripts` lists the peer IPs whose sessions are transcribed,
+///   `PUT /transcripts/{ip}` and `DELETE /transcripts/{ip}` add and remove one
+pub fn serve(
+    listener: TcpListener,
+    store: Arc<dyn MessageStore>,
+    events: Arc<EventFeed>,
+    transcripts: Arc<Transcripts>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                crate::warn!("API connection failed: {}", e);
+                continue;
+            }
+        };
+        let store = Arc::clone(&store);
+        let events = Arc::clone(&events);
+        let transcripts = Arc::clone(&transcripts);
+        thread::spawn(move || handle(stream, store.as_ref(), &events, &transcripts));
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    store: &dyn MessageStore,
+    events: &EventFeed,
+    transcripts: &Transcripts,
+) {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    let (status, content_type, body) = match http::read_request(&mut reader) {
+        Ok(request) if request.method == "GET" && request.path == "/events" => {
+            let payload = request.query("payload").is_some_and(|p| p == "1");
+            if let Err(e) = stream_events(&mut writer, events, payload) {
+                crate::debug!("Event stream closed: {}", e);
+            }
+            return;
+        }
+        Ok(request) if request.path.starts_with("/transcripts") => {
+            route_transcripts(&request, transcripts)
+        }
+        Ok(request) => route(&request, store),
+        Err(_) => (400, "text/plain", b"Bad request".to_vec()),
+    };
+    if let Err(e) = http::write_response(&mut writer, status, content_type, &body) {
+        crate::warn!("API response failed: {}", e);
+    }
+}
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+fn stream_events<W: Write>(writer: &mut W, events: &EventFeed, payload: bool) -> io::Result<()> {
+    let receiver = events.subscribe();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+          Connection: keep-alive\r\n\r\n",
+    )?;
+    writer.flush()?;
+
+    loop {
+        match receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(mail) => {
+                let event = if payload {
+                    mail.to_json()
+                } else {
+                    envelope_json(&mail)
+                };
+                write!(writer, "event: message\ndata: {}\n\n", event)?;
+            }
+            // Comments keep proxies from timing out idle streams and detect gone clients.
+            Err(RecvTimeoutError::Timeout) => writer.write_all(b": keepalive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        writer.flush()?;
+    }
+}
+
+fn route(request: &Request, store: &dyn MessageStore) -> (u16, &'static str, Vec<u8>) {
+    let not_found = (404, "text/plain", b"Not found".to_vec());
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["messages"]) => {
+            let summaries = store
+                .list()
+                .iter()
+                .filter(|stored| matches(stored, request))
+                .map(summary)
+                .collect();
+            json_response(Json::Array(summaries))
+        }
+        ("GET", ["messages", id]) => match id.strip_suffix(".eml") {
+            Some(id) => match store.get(id) {
+                Some(stored) => (
+                    200,
+                    "message/rfc822",
+                    stored.mail.data.unwrap_or_default().into_bytes(),
+                ),
+                None => not_found,
+            },
+            None => match store.get(id) {
+                Some(stored) => {
+                    let mut json = stored.mail.to_json();
+                    if let Json::Object(fields) = &mut json {
+                        fields.insert(0, (String::from("id"), Json::String(stored.id)));
+                    }
+                    json_response(json)
+                }
+                None => not_found,
+            },
+        },
+        ("DELETE", ["messages", id]) => {
+            if store.delete(id) {
+                (204, "text/plain", Vec::new())
+            } else {
+                not_found
+            }
+        }
+        (_, ["messages"]) | (_, ["messages", _]) => {
+            (405, "text/plain", b"Method not allowed".to_vec())
+        }
+        _ => not_found,
+    }
+}
+
+fn route_transcripts(request: &Request, transcripts: &Transcripts) -> (u16, &'static str, Vec<u8>) {
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["transcripts"]) => json_response(Json::Array(
+            transcripts
+                .peers()
+                .iter()
+                .map(|peer| Json::String(peer.to_string()))
+                .collect(),
+        )),
+        (method, ["transcripts", peer]) if method == "PUT" || method == "DELETE" => {
+            let peer = match peer.parse() {
+                Ok(peer) => peer,
+                Err(_) => return (400, "text/plain", b"Invalid IP address".to_vec()),
+            };
+            if method == "PUT" {
+                transcripts.enable(peer);
+            } else if !transcripts.disable(peer) {
+                return (404, "text/plain", b"Not found".to_vec());
+            }
+            (204, "text/plain", Vec::new())
+        }
+        (_, ["transcripts"]) | (_, ["transcripts", _]) => {
+            (405, "text/plain", b"Method not allowed".to_vec())
+        }
+        _ => (404, "text/plain", b"Not found".to_vec()),
+    }
+}
+
+fn json_response(json: Json) -> (u16, &'static str, Vec<u8>) {
+    (200, "application/json", json.to_string().into_bytes())
+}
+
+fn subject(stored: &StoredMail) -> String {
+    Message::parse(stored.mail.data.as_deref().unwrap_or(""))
+        .get("Subject")
+        .map(String::from)
+        .unwrap_or_default()
+}
+
+fn summary(stored: &StoredMail) -> Json {
+    Json::object(vec![
+        ("id", Json::string(&stored.id)),
+        ("queue_id", Json::optional(&stored.mail.queue_id)),
+        ("from", Json::optional(&stored.mail.mail_from)),
+        (
+            "to",
+            Json::Array(
+                stored
+                    .mail
+                    .rcpt_to
+                    .iter()
+                    .map(|rcpt| Json::string(rcpt))
+                    .collect(),
+            ),
+        ),
+        ("subject", Json::String(subject(stored))),
+        (
+            "size",
+            Json::Number(stored.mail.data.as_ref().map_or(0, |data| data.len()) as i64),
+        ),
+    ])
+}
+
+fn matches(stored: &StoredMail, request: &Request) -> bool {
+    let contains =
+        |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+    if let Some(from) = request.query("from") {
+        if !contains(stored.mail.mail_from.as_deref().unwrap_or(""), from) {
+            return false;
+        }
+    }
+    if let Some(to) = request.query("to") {
+        if !stored.mail.rcpt_to.iter().any(|rcpt| contains(rcpt, to)) {
+            return false;
+        }
+    }
+    if let Some(wanted) = request.query("subject") {
+        if !contains(&subject(stored), wanted) {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{email::Mail, store::MemoryStore};
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, target);
+        http::read_request(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_routes() {
+        let store = MemoryStore::new(10);
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<app@example.com>"));
+        mail.rcpt_to = vec![String::from("<user@example.com>")];
+        mail.data = Some(String::from("Subject: Reset password\r\n\r\nhi\r\n"));
+        store.save(&mail).unwrap();
+        mail.data = Some(String::from("Subject: Invoice\r\n\r\nhi\r\n"));
+        store.save(&mail).unwrap();
+
+        let (status, _, body) = route(&request("GET", "/messages?subject=reset"), &store);
+        assert_eq!(status, 200);
+        let list = Json::parse(std::str::from_utf8(&body).unwrap()).unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 1);
+        assert_eq!(
+            list.as_array().unwrap()[0].get("id"),
+            Some(&Json::string("1"))
+        );
+
+        let (status, content_type, body) = route(&request("GET", "/messages/2.eml"), &store);
+        assert_eq!((status, content_type), (200, "message/rfc822"));
+        assert_eq!(body, b"Subject: Invoice\r\n\r\nhi\r\n");
+
+        assert_eq!(route(&request("DELETE", "/messages/2"), &store).0, 204);
+        assert_eq!(route(&request("GET", "/messages/2"), &store).0, 404);
+        assert_eq!(route(&request("PUT", "/messages"), &store).0, 405);
+    }
+
+    #[test]
+    fn test_transcript_routes() {
+        let transcripts = Transcripts::default();
+        let put = request("PUT", "/transcripts/192.0.2.1");
+        assert_eq!(route_transcripts(&put, &transcripts).0, 204);
+        assert!(transcripts.is_enabled("192.0.2.1".parse().unwrap()));
+
+        let (_, _, body) = route_transcripts(&request("GET", "/transcripts"), &transcripts);
+        assert_eq!(body, b"[\"192.0.2.1\"]");
+
+        let delete = request("DELETE", "/transcripts/192.0.2.1");
+        assert_eq!(route_transcripts(&delete, &transcripts).0, 204);
+        assert_eq!(route_transcripts(&delete, &transcripts).0, 404);
+        let invalid = request("PUT", "/transcripts/nope");
+        assert_eq!(route_transcripts(&invalid, &transcripts).0, 400);
+    }
+}
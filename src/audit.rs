@@ -0,0 +1,159 @@
+use std::{fmt::Display, io, sync::Mutex, time::SystemTime};
+
+use crate::{
+    clock, datetime,
+    log::{LogFile, Rotation},
+};
+
+/// Environment variable with the audit log path, or `log` to send audit events to the
+/// regular log at warn level.
+pub const AUDIT_ENV: &str = "SIMPLE_SMTP_AUDIT_LOG";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditEvent {
+    CommandRejected,
+    AuthFailed,
+    RateLimited,
+    Blocklisted,
+}
+
+impl AuditEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEvent::CommandRejected => "command_rejected",
+            AuditEvent::AuthFailed => "auth_failed",
+            AuditEvent::RateLimited => "rate_limited",
+            AuditEvent::Blocklisted => "blocklisted",
+        }
+    }
+}
+
+enum AuditTarget {
+    Log,
+    File(LogFile),
+}
+
+/// Security events in a fixed format meant for fail2ban and SIEMs:
+///
+/// `<timestamp> audit event=<event> peer=<ip> session=<id> [key=value ...] reason="..."`
+///
+/// Values containing spaces or quotes are double-quoted with `\` escapes; fields only
+/// ever get added after `session`, never reordered.
+pub struct AuditLog {
+    target: Mutex<AuditTarget>,
+}
+
+impl AuditLog {
+    pub fn to_log() -> AuditLog {
+        AuditLog {
+            target: Mutex::new(AuditTarget::Log),
+        }
+    }
+
+    pub fn to_file(path: &str, rotation: Rotation, keep: usize) -> io::Result<AuditLog> {
+        Ok(AuditLog {
+            target: Mutex::new(AuditTarget::File(LogFile::open(path, rotation, keep)?)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        event: AuditEvent,
+        peer: &str,
+        session: &str,
+        fields: &[(&str, &dyn Display)],
+        reason: &str,
+    ) {
+        let line = format_line(clock::now(), event, peer, session, fields, reason);
+        match &mut *self.target.lock().unwrap_or_else(|e| e.into_inner()) {
+            AuditTarget::Log => crate::warn!("{}", line),
+            AuditTarget::File(file) => {
+                if let Err(e) = file.write_line(&line) {
+                    crate::error!("Unable to write audit log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Reads `SIMPLE_SMTP_AUDIT_LOG`, returning no audit log if it is unset or unusable.
+pub fn from_env() -> Option<AuditLog> {
+    let value = std::env::var(AUDIT_ENV).ok()?;
+    if value == "log" {
+        return Some(AuditLog::to_log());
+    }
+    match AuditLog::to_file(&value, Rotation::Never, 0) {
+        Ok(audit) => Some(audit),
+        Err(e) => {
+            eprintln!("Ignoring {}: unable to open {}: {}", AUDIT_ENV, value, e);
+            None
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    if !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != '"' && c != '\\' && c != '=')
+    {
+        return String::from(value);
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\r' | '\n' => quoted.push(' '),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_line(
+    time: SystemTime,
+    event: AuditEvent,
+    peer: &str,
+    session: &str,
+    fields: &[(&str, &dyn Display)],
+    reason: &str,
+) -> String {
+    let mut line = format!(
+        "{} audit event={} peer={} session={}",
+        datetime::rfc3339(time),
+        event.as_str(),
+        quote(peer),
+        quote(session)
+    );
+    for (key, value) in fields.iter() {
+        line.push_str(&format!(" {}={}", key, quote(&value.to_string())));
+    }
+    line.push_str(&format!(" reason={}", quote(reason.trim_end())));
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(
+            format_line(
+                UNIX_EPOCH,
+                AuditEvent::CommandRejected,
+                "192.0.2.1",
+                "0000002a",
+                &[("command", &"DATA")],
+                "554 5.4.6 Mail \"loop\" detected\n",
+            ),
+            "1970-01-01T00:00:00.000Z audit event=command_rejected peer=192.0.2.1 \
+             session=0000002a command=DATA reason=\"554 5.4.6 Mail \\\"loop\\\" detected\""
+        );
+    }
+}
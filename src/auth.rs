@@ -0,0 +1,90 @@
+use std::{collections::HashMap, fs, io};
+
+/// Checks the user name and password a client logs in with, e.g. to POP3. An error means
+/// the backend could not tell, and the client is told to try again later.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, user: &str, password: &str) -> io::Result<bool>;
+}
+
+/// A fixed set of users and their passwords, in plain text. User names are matched
+/// ignoring case, passwords exactly.
+#[derive(Clone, Debug, Default)]
+pub struct Passwords {
+    users: HashMap<String, String>,
+}
+
+impl Passwords {
+    pub fn new(users: &[(&str, &str)]) -> Passwords {
+        let mut passwords = Passwords::default();
+        for (user, password) in users.iter() {
+            passwords.add(user, password);
+        }
+        passwords
+    }
+
+    /// Reads one `user:password` per line, skipping blank lines and lines starting with
+    /// `#`. The password is what follows the first colon, spaces included.
+    pub fn load(path: &str) -> io::Result<Passwords> {
+        let mut passwords = Passwords::default();
+        for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) => passwords.add(user, password),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: expected user:password", path, number + 1),
+                    ))
+                }
+            }
+        }
+        Ok(passwords)
+    }
+
+    pub fn add(&mut self, user: &str, password: &str) {
+        self.users
+            .insert(user.trim().to_lowercase(), String::from(password));
+    }
+}
+
+impl Authenticator for Passwords {
+    fn authenticate(&self, user: &str, password: &str) -> io::Result<bool> {
+        Ok(match self.users.get(&user.to_lowercase()) {
+            Some(expected) => constant_time_eq(password.as_bytes(), expected.as_bytes()),
+            None => false,
+        })
+    }
+}
+
+/// Whether `a` and `b` are equal, taking as long however much of them matches, so that
+/// the time a guess takes gives no hint of how much of it is right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    a.len() == b.len() && difference == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passwords() {
+        let path = std::env::temp_dir().join(format!("passwords-{}", std::process::id()));
+        fs::write(&path, "# users\nalice:s3cret\n\nBob:with: colon\n").unwrap();
+        let passwords = Passwords::load(path.to_str().unwrap()).unwrap();
+        assert!(passwords.authenticate("alice", "s3cret").unwrap());
+        assert!(passwords.authenticate("bob", "with: colon").unwrap());
+        assert!(!passwords.authenticate("alice", "s3cre").unwrap());
+        assert!(!passwords.authenticate("carol", "").unwrap());
+
+        fs::write(&path, "alice\n").unwrap();
+        let e = Passwords::load(path.to_str().unwrap()).unwrap_err();
+        assert!(e.to_string().ends_with(":1: expected user:password"));
+        fs::remove_file(&path).unwrap();
+    }
+}
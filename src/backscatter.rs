@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    ban::BanList,
+    clock,
+    email::{domain_of, Mail},
+    headers::Message,
+    network::Cidr,
+};
+
+/// Why no report is sent about a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Suppressed {
+    /// Its sender is likely forged: SPF or DMARC failed.
+    Unauthenticated,
+    /// It came from a blocklisted or banned client.
+    Blocklisted,
+    /// Its sender's domain was sent too many reports lately.
+    RateLimited,
+}
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Suppressed::Unauthenticated => "sender failed authentication",
+            Suppressed::Blocklisted => "client is blocklisted",
+            Suppressed::RateLimited => "too many reports to the sender's domain",
+        })
+    }
+}
+
+/// Decides which messages failure and delay reports are sent about, so that the server
+/// cannot be made to send reports to the forged senders of spam (backscatter). Messages
+/// submitted by an authenticated client are always reported on, within `max_reports`.
+pub struct BouncePolicy {
+    /// Trusts the `Authentication-Results` headers this authserv-id added, e.g. by a
+    /// handler checking SPF and DMARC: messages they show failing either are not reported
+    /// on. Headers from anyone else are ignored, since the sender could have written them.
+    pub authserv_id: Option<String>,
+    /// Messages from clients on these networks are not reported on.
+    pub blocklist: Vec<Cidr>,
+    /// Nor are ones from clients banned at the time, see `BanList`.
+    pub bans: Option<Arc<BanList>>,
+    /// At most this many reports go to one domain within `window`, any number when unset.
+    pub max_reports: Option<u32>,
+    pub window: Duration,
+    sent: Mutex<HashMap<String, Vec<SystemTime>>>,
+}
+
+impl Default for BouncePolicy {
+    /// Suppresses nothing.
+    fn default() -> BouncePolicy {
+        BouncePolicy {
+            authserv_id: None,
+            blocklist: Vec::new(),
+            bans: None,
+            max_reports: None,
+            window: Duration::from_secs(3600),
+            sent: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl BouncePolicy {
+    /// Whether a report may be sent to the sender of `mail`, counting it if so.
+    pub fn check(&self, mail: &Mail) -> Result<(), Suppressed> {
+        if mail.auth.is_none() {
+            if self.failed_authentication(mail) {
+                return Err(Suppressed::Unauthenticated);
+            }
+            if self.is_blocklisted(mail) {
+                return Err(Suppressed::Blocklisted);
+            }
+        }
+        let max = match self.max_reports {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let domain = mail
+            .mail_from
+            .as_deref()
+            .and_then(domain_of)
+            .unwrap_or("")
+            .to_lowercase();
+        let now = clock::now();
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, times| {
+            times.retain(|at| clock::elapsed(*at) < self.window);
+            !times.is_empty()
+        });
+        let times = sent.entry(domain).or_default();
+        if times.len() >= max as usize {
+            return Err(Suppressed::RateLimited);
+        }
+        times.push(now);
+        Ok(())
+    }
+
+    fn failed_authentication(&self, mail: &Mail) -> bool {
+        let authserv_id = match &self.authserv_id {
+            Some(authserv_id) => authserv_id,
+            None => return false,
+        };
+        let message = Message::parse(mail.data.as_deref().unwrap_or(""));
+        message
+            .headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("Authentication-Results"))
+            .filter_map(|header| header.value.split_once(';'))
+            .filter(|(id, _)| {
+                let id = id.split_whitespace().next().unwrap_or("");
+                id.eq_ignore_ascii_case(authserv_id)
+            })
+            .any(|(_, results)| {
+                results.split(';').any(|result| {
+                    let result = result.trim().to_lowercase();
+                    result.starts_with("spf=fail") || result.starts_with("dmarc=fail")
+                })
+            })
+    }
+
+    fn is_blocklisted(&self, mail: &Mail) -> bool {
+        let address = match mail.origin.address {
+            Some(address) => address,
+            None => return false,
+        };
+        self.blocklist.iter().any(|cidr| cidr.contains(address))
+            || self
+                .bans
+                .as_ref()
+                .is_some_and(|bans| bans.banned_until(address).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(from: &str, data: &str) -> Mail {
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from(from));
+        mail.data = Some(String::from(data));
+        mail.origin.address = Some("192.0.2.7".parse().unwrap());
+        mail
+    }
+
+    #[test]
+    fn test_bounce_policy() {
+        let policy = BouncePolicy {
+            authserv_id: Some(String::from("mx.example.com")),
+            blocklist: vec!["198.51.100.0/24".parse().unwrap()],
+            max_reports: Some(1),
+            ..BouncePolicy::default()
+        };
+        let forged = mail(
+            "<a@example.org>",
+            "Authentication-Results: mx.example.com;\r\n dkim=none; spf=fail \
+             smtp.mailfrom=example.org\r\n\r\nhi\r\n",
+        );
+        assert_eq!(policy.check(&forged), Err(Suppressed::Unauthenticated));
+        let mut submitted = forged.clone();
+        submitted.auth = Some(String::from("a@example.org"));
+        assert_eq!(policy.check(&submitted), Ok(()));
+        assert_eq!(
+            policy.check(&mail("<b@Example.ORG>", "\r\nhi\r\n")),
+            Err(Suppressed::RateLimited)
+        );
+
+        let claimed = mail(
+            "<a@example.net>",
+            "Authentication-Results: other.example; spf=fail\r\n\r\nhi\r\n",
+        );
+        assert_eq!(policy.check(&claimed), Ok(()));
+        let mut listed = mail("<a@example.com>", "\r\nhi\r\n");
+        listed.origin.address = Some("198.51.100.9".parse().unwrap());
+        assert_eq!(policy.check(&listed), Err(Suppressed::Blocklisted));
+    }
+}
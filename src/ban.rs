@@ -0,0 +1,274 @@
+use core::net::IpAddr;
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{clock, retry::Backoff};
+
+/// Something a client did that counts towards a ban.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Offence {
+    /// Its credentials were refused.
+    AuthFailed,
+    /// It is on a block list, i.e. its connection was refused for good.
+    Blocklisted,
+    /// A command of its was refused for good, with a 5xx reply. Temporary failures, such as
+    /// greylisting or a full disk, are the server's doing and do not count.
+    Rejected,
+}
+
+impl Offence {
+    /// How much the offence counts towards `BanList::threshold`.
+    pub fn weight(&self) -> u32 {
+        match self {
+            Offence::AuthFailed => 3,
+            Offence::Blocklisted => 10,
+            Offence::Rejected => 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Record {
+    /// When each offence in the window happened, with its weight.
+    offences: Vec<(SystemTime, u32)>,
+    /// The bans so far, which the next one escalates from.
+    bans: u32,
+    banned_until: Option<SystemTime>,
+}
+
+/// Bans the addresses of clients that keep failing, e.g. guessing passwords or sending
+/// garbage, for a while: connections from them are closed as soon as they are accepted.
+/// Each ban lasts longer than the one before, as `escalation` has it.
+///
+/// Given a file, bans are kept there and survive restarts; `simple-smtp bans` lists and
+/// lifts them.
+pub struct BanList {
+    /// An address is banned once the weights of its offences within `window` reach this.
+    pub threshold: u32,
+    pub window: Duration,
+    /// How long the first, second and later bans of an address last.
+    pub escalation: Backoff,
+    records: Mutex<HashMap<IpAddr, Record>>,
+    path: Option<PathBuf>,
+}
+
+impl BanList {
+    /// Bans after 10 points within 10 minutes, for 10 minutes, then 40, then up to a day.
+    pub fn new() -> BanList {
+        BanList {
+            threshold: 10,
+            window: Duration::from_secs(600),
+            escalation: Backoff::Exponential {
+                initial: Duration::from_secs(600),
+                factor: 4,
+                max: Duration::from_secs(86400),
+            },
+            records: Mutex::new(HashMap::new()),
+            path: None,
+        }
+    }
+
+    /// A ban list kept in the file at `path`, reading the bans it holds.
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<BanList> {
+        let mut bans = BanList::new();
+        bans.path = Some(path.into());
+        bans.reload()?;
+        Ok(bans)
+    }
+
+    /// Reads the file again, e.g. after `simple-smtp bans` changed it, replacing the bans
+    /// held. Lines are `<address> <banned until, in seconds since the epoch> <bans>`.
+    pub fn reload(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut records = HashMap::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields.as_slice() {
+                [address, until, bans] => address
+                    .parse::<IpAddr>()
+                    .ok()
+                    .zip(until.parse::<u64>().ok())
+                    .zip(bans.parse::<u32>().ok()),
+                _ => None,
+            };
+            match parsed {
+                Some(((address, until), bans)) => {
+                    let record = Record {
+                        offences: Vec::new(),
+                        bans,
+                        banned_until: Some(UNIX_EPOCH + Duration::from_secs(until)),
+                    };
+                    records.insert(address, record);
+                }
+                None => crate::warn!("Skipping invalid ban {}", line),
+            }
+        }
+        *self.records.lock().unwrap() = records;
+        Ok(())
+    }
+
+    /// Counts `offence` against `address`, returning whether it got the address banned.
+    /// Addresses never banned are forgotten once their offences are out of the window.
+    pub fn record(&self, address: IpAddr, offence: Offence) -> bool {
+        let now = clock::now();
+        let window = self.window;
+        let in_window = |at: &SystemTime| now.duration_since(*at).unwrap_or_default() < window;
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, record| {
+            record.bans > 0 || record.offences.iter().any(|(at, _)| in_window(at))
+        });
+        let record = records.entry(address).or_default();
+        if record.banned_until.is_some_and(|until| until > now) {
+            return false;
+        }
+        record.offences.retain(|(at, _)| in_window(at));
+        record.offences.push((now, offence.weight()));
+        let score: u32 = record.offences.iter().map(|(_, weight)| weight).sum();
+        if score < self.threshold {
+            return false;
+        }
+        record.bans += 1;
+        let duration = self.escalation.delay(record.bans);
+        record.banned_until = Some(now + duration);
+        record.offences.clear();
+        crate::warn!(
+            "Banned {} for {}s after {:?}, ban {}",
+            address,
+            duration.as_secs(),
+            offence,
+            record.bans
+        );
+        self.save(&records);
+        true
+    }
+
+    /// Until when `address` is banned, `None` when it is not.
+    pub fn banned_until(&self, address: IpAddr) -> Option<SystemTime> {
+        let now = clock::now();
+        let records = self.records.lock().unwrap();
+        records
+            .get(&address)
+            .and_then(|record| record.banned_until)
+            .filter(|until| *until > now)
+    }
+
+    /// The addresses banned now, with until when, earliest end first.
+    pub fn list(&self) -> Vec<(IpAddr, SystemTime)> {
+        let now = clock::now();
+        let records = self.records.lock().unwrap();
+        let mut banned: Vec<(IpAddr, SystemTime)> = records
+            .iter()
+            .filter_map(|(address, record)| Some((*address, record.banned_until?)))
+            .filter(|(_, until)| *until > now)
+            .collect();
+        banned.sort_by_key(|(address, until)| (*until, *address));
+        banned
+    }
+
+    /// Lifts the ban of `address` and forgets its offences, returning whether it was
+    /// banned.
+    pub fn unban(&self, address: IpAddr) -> bool {
+        let mut records = self.records.lock().unwrap();
+        let banned = records
+            .remove(&address)
+            .and_then(|record| record.banned_until)
+            .is_some_and(|until| until > clock::now());
+        self.save(&records);
+        banned
+    }
+
+    /// Writes the bans to the file, if any, keeping past ones for their escalation.
+    fn save(&self, records: &HashMap<IpAddr, Record>) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut text = String::new();
+        for (address, record) in records {
+            if let Some(until) = record.banned_until {
+                let until = until.duration_since(UNIX_EPOCH).unwrap_or_default();
+                text.push_str(&format!(
+                    "{} {} {}\n",
+                    address,
+                    until.as_secs(),
+                    record.bans
+                ));
+            }
+        }
+        let written = path.with_extension("new");
+        if let Err(e) = fs::write(&written, text).and_then(|_| fs::rename(&written, path)) {
+            crate::error!("Unable to save bans to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl Default for BanList {
+    fn default() -> BanList {
+        BanList::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escalation() {
+        let bans = BanList::new();
+        let address: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(!bans.record(address, Offence::AuthFailed));
+        }
+        assert!(bans.banned_until(address).is_none());
+        assert!(bans.record(address, Offence::Rejected));
+        let first = bans.banned_until(address).unwrap();
+        assert!(first > clock::now() + Duration::from_secs(590));
+
+        assert!(bans.unban(address));
+        assert!(bans.list().is_empty());
+        assert!(bans.record(address, Offence::Blocklisted));
+        assert_eq!(bans.list().len(), 1);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("bans-{}", std::process::id()));
+        let address: IpAddr = "2001:db8::1".parse().unwrap();
+        let bans = BanList::open(&path).unwrap();
+        assert!(bans.record(address, Offence::Blocklisted));
+        assert!(bans.record("192.0.2.7".parse().unwrap(), Offence::Blocklisted));
+        drop(bans);
+
+        let bans = BanList::open(&path).unwrap();
+        assert!(bans.banned_until(address).is_some());
+        bans.unban("192.0.2.7".parse().unwrap());
+        let reopened = BanList::open(&path).unwrap();
+        assert_eq!(reopened.list().len(), 1);
+        let record = reopened.records.lock().unwrap()[&address].clone();
+        assert_eq!(record.bans, 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_forget() {
+        let mut bans = BanList::new();
+        bans.window = Duration::ZERO;
+        for host in 1..=100u8 {
+            assert!(!bans.record(IpAddr::from([192, 0, 2, host]), Offence::Rejected));
+        }
+        assert_eq!(bans.records.lock().unwrap().len(), 1);
+    }
+}
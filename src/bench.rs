@@ -0,0 +1,326 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// A load test: `connections` clients sending `messages` messages each to `target`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bench {
+    /// The server to test, as `host:port`.
+    pub target: String,
+    pub connections: usize,
+    /// Sent by each client over its one connection.
+    pub messages: usize,
+    /// The size in bytes of each message.
+    pub size: usize,
+    /// Messages per second across all clients, as fast as possible when `None`.
+    pub rate: Option<f64>,
+    pub from: String,
+    pub to: String,
+}
+
+impl Bench {
+    pub fn new(target: &str) -> Bench {
+        Bench {
+            target: String::from(target),
+            connections: 10,
+            messages: 100,
+            size: 1024,
+            rate: None,
+            from: String::from("bench@example.com"),
+            to: String::from("sink@example.com"),
+        }
+    }
+
+    /// Reads the options given after `bench` on the command line, e.g.
+    /// `--target 127.0.0.1:25 --connections 50 --messages 20 --size 10000 --rate 500`.
+    pub fn from_args(args: &[String]) -> Result<Bench, String> {
+        let mut bench = Bench::new("127.0.0.1:7878");
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", option))?;
+            let number = || -> Result<usize, String> {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid {} {}", option, value))
+            };
+            match option.as_str() {
+                "--target" => bench.target = value.clone(),
+                "--connections" => bench.connections = number()?.max(1),
+                "--messages" => bench.messages = number()?,
+                "--size" => bench.size = number()?,
+                "--rate" => {
+                    bench.rate = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|rate: &f64| *rate > 0.0)
+                            .ok_or_else(|| format!("invalid --rate {}", value))?,
+                    )
+                }
+                "--from" => bench.from = value.clone(),
+                "--to" => bench.to = value.clone(),
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        Ok(bench)
+    }
+
+    /// Runs the clients until they are all done and sums up how it went.
+    pub fn run(&self) -> Report {
+        let message = self.message();
+        // Each client paces itself to its share of the rate.
+        let interval = self
+            .rate
+            .map(|rate| Duration::from_secs_f64(self.connections as f64 / rate));
+        let (results, received) = mpsc::channel();
+        let started = Instant::now();
+        let clients: Vec<_> = (0..self.connections)
+            .map(|_| {
+                let bench = self.clone();
+                let message = message.clone();
+                let results = results.clone();
+                thread::spawn(move || bench.client(&message, interval, &results))
+            })
+            .collect();
+        drop(results);
+
+        let mut report = Report::default();
+        for result in received {
+            match result {
+                Ok(latency) => {
+                    report.sent += 1;
+                    report.bytes += message.len() as u64;
+                    report.latencies.push(latency);
+                }
+                Err(e) => {
+                    report.errors += 1;
+                    crate::debug!("Bench error: {}", e);
+                }
+            }
+        }
+        for client in clients {
+            let _ = client.join();
+        }
+        report.elapsed = started.elapsed();
+        report.latencies.sort();
+        report
+    }
+
+    /// A message of `size` bytes followed by the final dot, no line of it starting with one.
+    fn message(&self) -> Vec<u8> {
+        let mut message = format!(
+            "From: <{}>\r\nTo: <{}>\r\nSubject: bench\r\n\r\n",
+            self.from, self.to
+        )
+        .into_bytes();
+        while message.len() < self.size {
+            let line = (self.size - message.len()).clamp(3, 78);
+            message.extend(std::iter::repeat_n(b'x', line - 2));
+            message.extend_from_slice(b"\r\n");
+        }
+        message.extend_from_slice(b".\r\n");
+        message
+    }
+
+    /// Sends the messages of one client over one connection, reporting the time each took
+    /// or why it failed. A connection that fails fails the messages not yet sent.
+    fn client(
+        &self,
+        message: &[u8],
+        interval: Option<Duration>,
+        results: &mpsc::Sender<io::Result<Duration>>,
+    ) {
+        let mut session = match Session::open(&self.target) {
+            Ok(session) => session,
+            Err(e) => {
+                for _ in 0..self.messages {
+                    let _ = results.send(Err(io::Error::new(e.kind(), e.to_string())));
+                }
+                return;
+            }
+        };
+        let started = Instant::now();
+        for sent in 0..self.messages {
+            if let Some(interval) = interval {
+                let due = started + interval * sent as u32;
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+            let attempt = Instant::now();
+            let result = session
+                .transaction(&self.from, &self.to, message)
+                .map(|_| attempt.elapsed());
+            let broken = result.is_err();
+            let _ = results.send(result);
+            if broken {
+                for _ in sent + 1..self.messages {
+                    let _ = results.send(Err(io::Error::other("connection failed")));
+                }
+                return;
+            }
+        }
+        let _ = session.command("QUIT", 221);
+    }
+}
+
+/// What a `Bench` run measured.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Messages the server accepted.
+    pub sent: u64,
+    /// Messages refused or not sent because of a failed connection.
+    pub errors: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// The time each accepted message took, from MAIL FROM to the reply to the final dot,
+    /// shortest first.
+    pub latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Accepted messages per second.
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.sent as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// The latency `percentile` percent of the messages did not exceed.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "sent {} messages ({} bytes) in {:.2}s, {} errors",
+            self.sent,
+            self.bytes,
+            self.elapsed.as_secs_f64(),
+            self.errors
+        )?;
+        writeln!(f, "throughput {:.1} messages/s", self.throughput())?;
+        write!(
+            f,
+            "latency p50 {:.1}ms p90 {:.1}ms p99 {:.1}ms max {:.1}ms",
+            millis(self.percentile(50.0)),
+            millis(self.percentile(90.0)),
+            millis(self.percentile(99.0)),
+            millis(self.percentile(100.0))
+        )
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// The client side of a session, just enough for the bench.
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Session {
+    fn open(target: &str) -> io::Result<Session> {
+        let stream = TcpStream::connect(target)?;
+        stream.set_nodelay(true)?;
+        let mut session = Session {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        session.expect(220)?;
+        session.command("EHLO bench.localhost", 250)?;
+        Ok(session)
+    }
+
+    fn transaction(&mut self, from: &str, to: &str, message: &[u8]) -> io::Result<()> {
+        self.command(&format!("MAIL FROM:<{}>", from), 250)?;
+        self.command(&format!("RCPT TO:<{}>", to), 250)?;
+        self.command("DATA", 354)?;
+        self.writer.write_all(message)?;
+        self.expect(250)
+    }
+
+    fn command(&mut self, command: &str, code: u16) -> io::Result<()> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        self.expect(code)
+    }
+
+    /// Reads a possibly multi-line reply, failing unless it has `code`.
+    fn expect(&mut self, code: u16) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "server closed the connection",
+                ));
+            }
+            if line.as_bytes().get(3) != Some(&b'-') {
+                break;
+            }
+        }
+        match line.get(..3).and_then(|reply| reply.parse::<u16>().ok()) {
+            Some(reply) if reply == code => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "server replied {}",
+                line.trim_end()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, server::Server};
+
+    #[test]
+    fn test_from_args() {
+        let args: Vec<String> = ["--target", "mx:25", "--connections", "4", "--rate", "50"]
+            .iter()
+            .map(|arg| String::from(*arg))
+            .collect();
+        let bench = Bench::from_args(&args).unwrap();
+        assert_eq!(bench.target, "mx:25");
+        assert_eq!(bench.connections, 4);
+        assert_eq!(bench.rate, Some(50.0));
+        assert!(Bench::from_args(&[String::from("--size")]).is_err());
+        assert!(Bench::from_args(&[String::from("--rate"), String::from("0")]).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut bench = Bench::new(&address.to_string());
+        bench.connections = 2;
+        bench.messages = 3;
+        bench.size = 300;
+        assert!(bench.message().len() >= 300);
+        let report = bench.run();
+        assert_eq!(report.sent, 6);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.latencies.len(), 6);
+        assert!(report.percentile(50.0) <= report.percentile(100.0));
+        assert!(report.to_string().contains("sent 6 messages"));
+    }
+}
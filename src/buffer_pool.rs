@@ -0,0 +1,130 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+use crate::metrics;
+
+/// How a `BufferPool` has fared, to tune its limits by.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    /// Buffers waiting in the pool.
+    pub idle: usize,
+    /// Buffers handed out that the pool had to allocate.
+    pub allocated: u64,
+    /// Buffers handed out that were given back before.
+    pub reused: u64,
+    /// Buffers given back that were dropped, the pool being full or them too large.
+    pub discarded: u64,
+}
+
+/// Buffers given back by finished sessions for the next ones to read lines, batch replies
+/// and spool DATA into, so that connection churn does not go through the allocator.
+pub struct BufferPool {
+    /// The most buffers kept waiting.
+    pub max_idle: usize,
+    /// Buffers that grew larger than this, e.g. for a big message, are not kept.
+    pub max_buffer_size: usize,
+    idle: Mutex<Vec<Vec<u8>>>,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(max_idle: usize) -> BufferPool {
+        BufferPool {
+            max_idle,
+            max_buffer_size: 1 << 20,
+            idle: Mutex::new(Vec::new()),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes.
+    pub fn take(&self, capacity: usize) -> Vec<u8> {
+        let buffer = self.idle.lock().unwrap().pop();
+        match buffer {
+            Some(mut buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                metrics::increment(metrics::BUFFERS_REUSED);
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                metrics::increment(metrics::BUFFERS_ALLOCATED);
+                Vec::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Like `take`, for text.
+    pub fn take_string(&self, capacity: usize) -> String {
+        String::from_utf8(self.take(capacity)).unwrap_or_default()
+    }
+
+    /// Keeps `buffer` for a later `take`, unless the pool is full or it is too large.
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() > 0 && buffer.capacity() <= self.max_buffer_size {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < self.max_idle {
+                buffer.clear();
+                idle.push(buffer);
+                return;
+            }
+        }
+        self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn give_string(&self, text: String) {
+        self.give(text.into_bytes());
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            idle: self.idle.lock().unwrap().len(),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for BufferPool {
+    /// Enough for the buffers of a few dozen sessions.
+    fn default() -> BufferPool {
+        BufferPool::new(256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut pool = BufferPool::new(1);
+        pool.max_buffer_size = 4096;
+        let mut buffer = pool.take(100);
+        buffer.extend_from_slice(b"EHLO");
+        pool.give(buffer);
+        let buffer = pool.take(10);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 100);
+        pool.give(Vec::with_capacity(8192));
+        pool.give(buffer);
+        pool.give(Vec::with_capacity(10));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                idle: 1,
+                allocated: 1,
+                reused: 1,
+                discarded: 2,
+            }
+        );
+    }
+}
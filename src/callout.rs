@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{clock, dsn, output::relay::Relay};
+
+/// The most answers kept; once reached, expired ones are dropped before another is added.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Verifies the recipients of relayed domains by asking the server they are relayed to,
+/// see `Relay::callout`, so that unknown ones are refused at RCPT TO instead of bounced
+/// once that server refuses the message. Answers are kept for a while, so a recipient is
+/// not asked about on every message; failures to ask are not kept.
+pub struct Callout {
+    /// How long a recipient found to exist is not asked about again.
+    pub positive_ttl: Duration,
+    /// How long a recipient found not to exist is not asked about again.
+    pub negative_ttl: Duration,
+    answers: Mutex<HashMap<String, (bool, SystemTime)>>,
+}
+
+impl Default for Callout {
+    fn default() -> Callout {
+        Callout::new(Duration::from_secs(3600), Duration::from_secs(600))
+    }
+}
+
+impl Callout {
+    pub fn new(positive_ttl: Duration, negative_ttl: Duration) -> Callout {
+        Callout {
+            positive_ttl,
+            negative_ttl,
+            answers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `relay` takes mail for `address`, from a recent answer if there is one.
+    pub fn verify(&self, relay: &Relay, address: &str) -> io::Result<bool> {
+        let key = dsn::split_parameters(address)
+            .0
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_lowercase();
+        if let Some(exists) = self.cached(&key) {
+            return Ok(exists);
+        }
+        let exists = relay.callout(address)?;
+        let mut answers = self.answers.lock().unwrap();
+        if answers.len() >= MAX_ENTRIES {
+            answers.retain(|_, (exists, at)| clock::elapsed(*at) <= self.ttl(*exists));
+        }
+        answers.insert(key, (exists, clock::now()));
+        Ok(exists)
+    }
+
+    /// Drops every answer, for recipients to be asked about again.
+    pub fn clear(&self) {
+        self.answers.lock().unwrap().clear();
+    }
+
+    fn cached(&self, key: &str) -> Option<bool> {
+        let answers = self.answers.lock().unwrap();
+        let (exists, at) = answers.get(key)?;
+        Some(*exists).filter(|_| clock::elapsed(*at) <= self.ttl(*exists))
+    }
+
+    fn ttl(&self, exists: bool) -> Duration {
+        if exists {
+            self.positive_ttl
+        } else {
+            self.negative_ttl
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    /// A server refusing every RCPT TO, counting the connections it accepted.
+    fn refusing_server() -> (u16, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        listener.set_nonblocking(true).unwrap();
+        let handle = thread::spawn(move || {
+            let mut connections = 0;
+            let deadline = clock::now() + Duration::from_millis(500);
+            while clock::now() < deadline {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => {
+                        thread::sleep(Duration::from_millis(10));
+                        continue;
+                    }
+                };
+                connections += 1;
+                stream.set_nonblocking(false).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                writer.write_all(b"220 relay.example.net\r\n").unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let reply = match line.get(..4).unwrap_or("") {
+                        "RCPT" => "550 5.1.1 No such user\r\n",
+                        "QUIT" => "221 Bye\r\n",
+                        _ => "250 Ok\r\n",
+                    };
+                    writer.write_all(reply.as_bytes()).unwrap();
+                    line.clear();
+                }
+            }
+            connections
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_callout() {
+        let (port, server) = refusing_server();
+        let relay = Relay::new("127.0.0.1", port, "mx.example.com");
+        let callout = Callout::default();
+        assert!(!callout.verify(&relay, "<nobody@example.com>").unwrap());
+        assert!(!callout.verify(&relay, "<Nobody@Example.com>").unwrap());
+        assert_eq!(server.join().unwrap(), 1);
+
+        let unreachable = Relay::new("127.0.0.1", port, "mx.example.com");
+        assert!(!callout
+            .verify(&unreachable, "<nobody@example.com>")
+            .unwrap());
+        callout.clear();
+        assert!(callout
+            .verify(&unreachable, "<nobody@example.com>")
+            .is_err());
+    }
+}
@@ -0,0 +1,141 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::connect;
+
+/// How much of a message is sent to clamd at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The ClamAV daemon, which each accepted message is streamed to through its `INSTREAM`
+/// command before it is queued: infected ones are refused, or quarantined.
+pub struct Clamd {
+    /// `host:port`, or `unix:` and the path of a socket.
+    pub address: String,
+    pub timeout: Duration,
+    /// Whether messages are accepted unscanned when clamd cannot be reached, rather than
+    /// deferred.
+    pub fail_open: bool,
+    /// Whether infected messages are quarantined rather than refused.
+    pub quarantine: bool,
+}
+
+impl Clamd {
+    pub fn new(address: &str) -> Clamd {
+        Clamd {
+            address: String::from(address),
+            timeout: Duration::from_secs(60),
+            fail_open: false,
+            quarantine: false,
+        }
+    }
+
+    /// The name of the signature `data` matches, `None` if it is clean.
+    pub fn scan(&self, data: &[u8]) -> io::Result<Option<String>> {
+        #[cfg(unix)]
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            let stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            return instream(&stream, &stream, data);
+        }
+        let (host, port) = self
+            .address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid clamd address {}", self.address),
+                )
+            })?;
+        let stream = connect::connect(host.trim_matches(&['[', ']'][..]), port, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        instream(&stream, &stream, data)
+    }
+}
+
+/// Sends `data` in length-prefixed chunks, ended by an empty one, and reads the verdict.
+fn instream(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    data: &[u8],
+) -> io::Result<Option<String>> {
+    writer.write_all(b"zINSTREAM\0")?;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        writer.write_all(&(chunk.len() as u32).to_be_bytes())?;
+        writer.write_all(chunk)?;
+    }
+    writer.write_all(&[0; 4])?;
+    writer.flush()?;
+    let mut reply = Vec::new();
+    reader.read_to_end(&mut reply)?;
+    parse_reply(String::from_utf8_lossy(&reply).trim_end_matches(['\0', '\n']))
+}
+
+/// The signature in a reply of clamd, e.g. `stream: Eicar-Signature FOUND`.
+fn parse_reply(reply: &str) -> io::Result<Option<String>> {
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        return Ok(None);
+    }
+    match result.strip_suffix(" FOUND") {
+        Some(signature) => Ok(Some(String::from(signature.trim()))),
+        None => Err(io::Error::other(format!("clamd replied {}", reply))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_parse_reply() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), None);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            Some(String::from("Win.Test.EICAR_HDB-1"))
+        );
+        assert!(parse_reply("INSTREAM size limit exceeded. ERROR").is_err());
+    }
+
+    #[test]
+    fn test_scan() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let mut length = [0; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length = u32::from_be_bytes(length) as usize;
+                if length == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; length];
+                stream.read_exact(&mut chunk).unwrap();
+                data.extend(chunk);
+            }
+            stream
+                .write_all(b"stream: Eicar-Signature FOUND\0")
+                .unwrap();
+            data
+        });
+        let clamd = Clamd::new(&address.to_string());
+        let data = vec![b'x'; CHUNK_SIZE + 10];
+        assert_eq!(
+            clamd.scan(&data).unwrap(),
+            Some(String::from("Eicar-Signature"))
+        );
+        assert_eq!(server.join().unwrap(), data);
+    }
+}
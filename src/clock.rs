@@ -0,0 +1,39 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+/// Source of the current time for sessions, logs and audit lines. The system clock is used
+/// unless another one is installed, which targets such as `wasm32-unknown-unknown` need as
+/// `SystemTime::now` panics there.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> SystemTime + Send + Sync,
+{
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// Reads the time from `clock` from now on. Only the first call has an effect.
+pub fn set(clock: Box<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+pub fn now() -> SystemTime {
+    match CLOCK.get() {
+        Some(clock) => clock.now(),
+        None => SystemTime::now(),
+    }
+}
+
+/// Time passed since `earlier`, zero if the clock went backwards.
+pub fn elapsed(earlier: SystemTime) -> Duration {
+    now().duration_since(earlier).unwrap_or_default()
+}
@@ -0,0 +1,215 @@
+#[cfg(feature = "server")]
+use std::sync::mpsc::Sender;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    alias::{AliasLookup, Aliases},
+    audit::AuditLog,
+    auth::Authenticator,
+    ban::BanList,
+    buffer_pool::BufferPool,
+    disk::DiskCheck,
+    domain::Domains,
+    extension::Verbs,
+    footer::Footer,
+    handler::{DefaultHandler, SmtpHandler},
+    headers::HeaderRule,
+    journal::Journal,
+    list::MailingLists,
+    memory::MemoryBudget,
+    output::Output,
+    policy::Policy,
+    priority::PriorityPolicy,
+    quarantine::Quarantine,
+    queue::QueueFlush,
+    quota::{MailboxQuotas, SenderQuotas},
+    recipient::RecipientVerifier,
+    retry::RetrySchedule,
+    script::PolicyScript,
+    session::SessionContext,
+    sieve::Sieve,
+    transcript::Transcripts,
+    transport::Transports,
+};
+#[cfg(feature = "server")]
+use crate::{
+    callout::Callout, clamav::Clamd, control::Control, milter::Milter,
+    policy_service::PolicyService, server::ReceivedMail, spam::SpamFilter,
+};
+
+pub struct Config {
+    pub server_name: String,
+    /// Messages carrying more `Received` headers than this are rejected as looping.
+    pub max_hops: usize,
+    /// The longest line read at once, its ending included. Longer commands are refused,
+    /// longer data lines are read in parts.
+    pub max_line_length: usize,
+    /// How long a client on a socket may take to send its next command or data line before
+    /// it is told 421 and disconnected, 5 minutes as RFC 5321 section 4.5.3.2 suggests.
+    /// Never when unset.
+    pub command_timeout: Option<Duration>,
+    /// Applied in order to every accepted message before it is handed on.
+    pub header_rules: Vec<HeaderRule>,
+    /// Appended to the body of every accepted message, after the header rules ran.
+    pub footer: Option<Footer>,
+    /// Every accepted message is handed to each of these in turn, for its recipients with
+    /// the local transport.
+    pub outputs: Vec<Box<dyn Output>>,
+    /// Selects the sessions whose protocol exchange is recorded.
+    pub transcripts: Arc<Transcripts>,
+    /// Receives rejected commands and other security events when set.
+    pub audit: Option<Arc<AuditLog>>,
+    /// Clients that keep failing are banned for a while when set.
+    pub bans: Option<Arc<BanList>>,
+    /// Consulted at each step of every session.
+    pub handler: Arc<dyn SmtpHandler>,
+    /// Every accepted message is also sent to each of these, see `Server::incoming`.
+    #[cfg(feature = "server")]
+    pub incoming: Vec<Sender<ReceivedMail>>,
+    /// Pauses, throttles and lists the sessions of the server while it runs.
+    #[cfg(feature = "server")]
+    pub control: Arc<Control>,
+    /// Commands understood in addition to the built-in ones.
+    pub verbs: Verbs,
+    /// The recipient domains mail is accepted for, any when empty. Others need a client
+    /// allowed to relay, see `Policy::trust`.
+    pub domains: Domains,
+    /// Rewrites recipients at RCPT TO, in an accepted domain.
+    pub aliases: Aliases,
+    /// Consulted for the recipients `aliases` does not rewrite.
+    pub alias_lookup: Option<Arc<dyn AliasLookup>>,
+    /// Consulted for every RCPT TO in an accepted domain that no alias matched; any
+    /// recipient exists when unset.
+    pub recipients: Option<Arc<dyn RecipientVerifier>>,
+    /// Checks the credentials clients send with AUTH PLAIN, which is offered only when set,
+    /// and then only over TLS, e.g. terminated by a proxy as `Policy::tls` has it, unless
+    /// `Policy::insecure_auth` lets them travel in the clear.
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Asks the server a domain is relayed to whether RCPT TO a recipient there that no
+    /// alias matched exists, when set, see `Transport::Relay`.
+    #[cfg(feature = "server")]
+    pub callout: Option<Arc<Callout>>,
+    /// Asked about every MAIL FROM, RCPT TO and DATA once the handler accepted it, when
+    /// set.
+    #[cfg(feature = "server")]
+    pub policy_service: Option<Arc<PolicyService>>,
+    /// Shown every session and message in turn, and may change or refuse them, see
+    /// `milter::MilterSession`.
+    #[cfg(feature = "server")]
+    pub milters: Vec<Arc<Milter>>,
+    /// Scans each message for viruses once the handler and milters accepted it, when set.
+    #[cfg(feature = "server")]
+    pub clamd: Option<Arc<Clamd>>,
+    /// Scans each message once the handler and milters accepted it, when set.
+    #[cfg(feature = "server")]
+    pub spam_filter: Option<Arc<SpamFilter>>,
+    /// Run at connect, MAIL FROM, RCPT TO and the end of DATA once the handler accepted
+    /// them, when set.
+    pub script: Option<Arc<PolicyScript>>,
+    /// Decides what happens to the recipients of each domain, local delivery by default.
+    pub transports: Transports,
+    /// Runs the Sieve scripts of recipients delivered to locally, when set.
+    pub sieve: Option<Arc<Sieve>>,
+    /// Recipients that are lists are replaced by the members once a message is accepted.
+    pub lists: MailingLists,
+    /// What sessions allow, see `MailFSM::policy` to change it for a single session.
+    pub policy: Policy,
+    /// The policy of sessions on some local ports instead of `policy`, e.g. MX on 25 and
+    /// submission on 587 served by the same process.
+    pub profiles: HashMap<u16, Policy>,
+    /// Asked to deliver the mail held for a domain when a client on a trusted network
+    /// sends ETRN, which is not offered while this is unset.
+    pub queue: Option<Arc<dyn QueueFlush>>,
+    /// The shortest by-time in seconds a client may ask for with DELIVERBY, advertised
+    /// in the EHLO reply when not 0.
+    pub min_by_time: u64,
+    /// MT-PRIORITY is offered, with this policy, when set.
+    pub priority_policy: Option<PriorityPolicy>,
+    /// How messages queued for delivery are retried after a temporary failure, and how
+    /// long they may wait.
+    pub retry: RetrySchedule,
+    /// Lends sessions the buffers they read, reply and spool messages into.
+    pub buffers: Arc<BufferPool>,
+    /// Bounds the memory sessions hold for commands and spooled messages.
+    pub memory: Arc<MemoryBudget>,
+    /// Limits what authenticated clients send, share it for the counts to survive reloads.
+    pub sender_quotas: Arc<SenderQuotas>,
+    /// Limits what the messages of each recipient take of a local store, when set.
+    pub mailbox_quotas: Option<Arc<MailboxQuotas>>,
+    /// DATA is deferred while the storage volume is short of space, when set.
+    pub disk: Option<DiskCheck>,
+    /// Accepted messages are journaled before they are acknowledged, when set, see
+    /// `Server::run` for their recovery.
+    pub journal: Option<Arc<Journal>>,
+    /// Keeps the messages a handler quarantines, which are refused while this is unset.
+    pub quarantine: Option<Arc<Quarantine>>,
+}
+
+impl Config {
+    pub fn new(server_name: String) -> Config {
+        Config {
+            server_name,
+            max_hops: 30,
+            max_line_length: 1000,
+            command_timeout: Some(Duration::from_secs(5 * 60)),
+            header_rules: Vec::new(),
+            footer: None,
+            outputs: Vec::new(),
+            transcripts: Arc::new(Transcripts::default()),
+            audit: None,
+            bans: None,
+            handler: Arc::new(DefaultHandler),
+            #[cfg(feature = "server")]
+            incoming: Vec::new(),
+            #[cfg(feature = "server")]
+            control: Arc::new(Control::new()),
+            verbs: Verbs::default(),
+            domains: Domains::default(),
+            aliases: Aliases::default(),
+            alias_lookup: None,
+            recipients: None,
+            authenticator: None,
+            #[cfg(feature = "server")]
+            callout: None,
+            #[cfg(feature = "server")]
+            policy_service: None,
+            #[cfg(feature = "server")]
+            milters: Vec::new(),
+            #[cfg(feature = "server")]
+            clamd: None,
+            #[cfg(feature = "server")]
+            spam_filter: None,
+            script: None,
+            transports: Transports::default(),
+            sieve: None,
+            lists: MailingLists::default(),
+            policy: Policy::default(),
+            profiles: HashMap::new(),
+            queue: None,
+            min_by_time: 0,
+            priority_policy: None,
+            retry: RetrySchedule::default(),
+            buffers: Arc::new(BufferPool::default()),
+            memory: Arc::new(MemoryBudget::default()),
+            sender_quotas: Arc::new(SenderQuotas::default()),
+            mailbox_quotas: None,
+            disk: None,
+            journal: None,
+            quarantine: None,
+        }
+    }
+
+    /// The policy of a session according to the port it came in on.
+    pub fn policy_for(&self, context: &SessionContext) -> &Policy {
+        context
+            .local_port()
+            .and_then(|port| self.profiles.get(&port))
+            .unwrap_or(&self.policy)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new(String::from("my.server"))
+    }
+}
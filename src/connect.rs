@@ -0,0 +1,407 @@
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+/// How long one connection attempt has before the next address is tried alongside it, the
+/// Connection Attempt Delay of RFC 8305.
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to `host` and `port` as Happy Eyeballs (RFC 8305) has it: the addresses are
+/// tried IPv6 first, alternating between the families, each attempt getting
+/// `ATTEMPT_DELAY` before the next one starts, so that a broken IPv6 path costs a quarter
+/// of a second rather than a whole `timeout`. The first connection made is returned.
+pub fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    connect_from(host, port, &[], timeout)
+}
+
+/// Like `connect`, from the address of `source` in the family of each attempt, e.g. one
+/// IPv4 and one IPv6 address a multi-homed host sends mail from. Attempts in a family
+/// without one use whichever address the system picks.
+pub fn connect_from(
+    host: &str,
+    port: u16,
+    source: &[IpAddr],
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let addresses = interleave((host, port).to_socket_addrs()?.collect());
+    connect_any_from(&addresses, source, timeout)
+}
+
+/// Like `connect`, for addresses already in the order to try them.
+pub fn connect_any(addresses: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    connect_any_from(addresses, &[], timeout)
+}
+
+/// Like `connect_from`, for addresses already in the order to try them.
+pub fn connect_any_from(
+    addresses: &[SocketAddr],
+    source: &[IpAddr],
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let (sender, receiver) = mpsc::channel();
+    let mut started = 0;
+    let mut pending = 0;
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host not found");
+    loop {
+        if started < addresses.len() {
+            let address = addresses[started];
+            let source = source
+                .iter()
+                .find(|source| source.is_ipv6() == address.is_ipv6())
+                .copied();
+            let sender = sender.clone();
+            // A connection made after another one won is dropped with the failed send.
+            thread::spawn(move || {
+                let _ = sender.send(match source {
+                    Some(source) => sys::connect_from(source, address, timeout),
+                    None => TcpStream::connect_timeout(&address, timeout),
+                });
+            });
+            started += 1;
+            pending += 1;
+        } else if pending == 0 {
+            return Err(last_error);
+        }
+        let result = if started < addresses.len() {
+            match receiver.recv_timeout(ATTEMPT_DELAY) {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(result) => result,
+                Err(_) => return Err(last_error),
+            }
+        };
+        pending -= 1;
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+}
+
+/// `addresses` alternating between IPv6 and IPv4, starting with IPv6 when there is any.
+pub(crate) fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut interleaved = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// Binding a TCP socket before connecting it, which the standard library does not offer.
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io,
+        net::{IpAddr, SocketAddr, TcpStream},
+        os::{
+            raw::{c_int, c_long, c_void},
+            unix::io::{AsRawFd, FromRawFd},
+        },
+        time::Duration,
+    };
+
+    const AF_INET: u16 = 2;
+    const AF_INET6: u16 = 10;
+    const SOCK_STREAM: c_int = 1;
+    const SOCK_CLOEXEC: c_int = 0o2000000;
+    const SOL_SOCKET: c_int = 1;
+    const SO_SNDTIMEO: c_int = 21;
+
+    extern "C" {
+        fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+        fn bind(fd: c_int, address: *const c_void, length: u32) -> c_int;
+        fn connect(fd: c_int, address: *const c_void, length: u32) -> c_int;
+        fn setsockopt(
+            fd: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            length: u32,
+        ) -> c_int;
+    }
+
+    /// `address` as a `sockaddr_in` or `sockaddr_in6`.
+    fn sockaddr(address: SocketAddr) -> Vec<u8> {
+        let mut sockaddr = Vec::with_capacity(28);
+        match address.ip() {
+            IpAddr::V4(ip) => {
+                sockaddr.extend_from_slice(&AF_INET.to_ne_bytes());
+                sockaddr.extend_from_slice(&address.port().to_be_bytes());
+                sockaddr.extend_from_slice(&ip.octets());
+                sockaddr.extend_from_slice(&[0; 8]);
+            }
+            IpAddr::V6(ip) => {
+                sockaddr.extend_from_slice(&AF_INET6.to_ne_bytes());
+                sockaddr.extend_from_slice(&address.port().to_be_bytes());
+                sockaddr.extend_from_slice(&[0; 4]);
+                sockaddr.extend_from_slice(&ip.octets());
+                sockaddr.extend_from_slice(&[0; 4]);
+            }
+        }
+        sockaddr
+    }
+
+    fn check(result: c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn connect_from(
+        source: IpAddr,
+        address: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        let family = if address.is_ipv6() { AF_INET6 } else { AF_INET };
+        // SAFETY: socket has no preconditions; the descriptor it returns is owned by the
+        // stream from here on, which closes it on every path.
+        let stream = unsafe {
+            let fd = socket(c_int::from(family), SOCK_STREAM | SOCK_CLOEXEC, 0);
+            check(fd)?;
+            TcpStream::from_raw_fd(fd)
+        };
+        let fd = stream.as_raw_fd();
+        let local = sockaddr(SocketAddr::new(source, 0));
+        let remote = sockaddr(address);
+        // Linux gives up a blocking connect after the send timeout.
+        let timeval: [c_long; 2] = [
+            timeout.as_secs().min(c_long::MAX as u64) as c_long,
+            c_long::from(timeout.subsec_micros() as i32),
+        ];
+        // SAFETY: the pointers and lengths describe live buffers of the sizes given, which
+        // the calls only read.
+        unsafe {
+            check(setsockopt(
+                fd,
+                SOL_SOCKET,
+                SO_SNDTIMEO,
+                timeval.as_ptr() as *const c_void,
+                std::mem::size_of_val(&timeval) as u32,
+            ))?;
+            check(bind(
+                fd,
+                local.as_ptr() as *const c_void,
+                local.len() as u32,
+            ))?;
+            check(connect(
+                fd,
+                remote.as_ptr() as *const c_void,
+                remote.len() as u32,
+            ))
+            .map_err(|e| match e.raw_os_error() {
+                // EINPROGRESS, what a timed out connect fails with.
+                Some(115) => io::Error::new(io::ErrorKind::TimedOut, "connection timed out"),
+                _ => e,
+            })?;
+        }
+        Ok(stream)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{
+        io,
+        net::{IpAddr, SocketAddr, TcpStream},
+        time::Duration,
+    };
+
+    pub fn connect_from(_: IpAddr, _: SocketAddr, _: Duration) -> io::Result<TcpStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "source addresses are only supported on Linux",
+        ))
+    }
+}
+
+/// A SOCKS5 proxy (RFC 1928) outbound connections go through, e.g. where only the proxy
+/// may reach the internet. The proxy resolves the names it is asked to connect to.
+#[derive(Clone, Debug)]
+pub struct Socks5 {
+    pub host: String,
+    pub port: u16,
+    /// The user name and password the proxy asks for (RFC 1929), if any.
+    pub credentials: Option<(String, String)>,
+}
+
+impl Socks5 {
+    pub fn new(host: &str, port: u16) -> Socks5 {
+        Socks5 {
+            host: String::from(host),
+            port,
+            credentials: None,
+        }
+    }
+
+    /// A connection to `host` and `port` through the proxy.
+    pub fn connect(&self, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+        let mut stream = connect(&self.host, self.port, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        const NO_AUTHENTICATION: u8 = 0x00;
+        const USERNAME_PASSWORD: u8 = 0x02;
+        let method = match self.credentials {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTHENTICATION,
+        };
+        stream.write_all(&[5, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [5, method] {
+            return Err(socks_error("proxy refused the authentication method"));
+        }
+        if let Some((user, password)) = &self.credentials {
+            let mut request = vec![1, user.len().min(255) as u8];
+            request.extend_from_slice(&user.as_bytes()[..user.len().min(255)]);
+            request.push(password.len().min(255) as u8);
+            request.extend_from_slice(&password.as_bytes()[..password.len().min(255)]);
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0 {
+                return Err(socks_error("proxy refused the credentials"));
+            }
+        }
+
+        const CONNECT: u8 = 1;
+        let mut request = vec![5, CONNECT, 0];
+        match host.trim_matches(|c| c == '[' || c == ']').parse() {
+            Ok(IpAddr::V4(address)) => {
+                request.push(1);
+                request.extend_from_slice(&address.octets());
+            }
+            Ok(IpAddr::V6(address)) => {
+                request.push(4);
+                request.extend_from_slice(&address.octets());
+            }
+            Err(_) => {
+                request.extend_from_slice(&[3, host.len().min(255) as u8]);
+                request.extend_from_slice(&host.as_bytes()[..host.len().min(255)]);
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return Err(socks_error(match reply[1] {
+                2 => "connection not allowed by the proxy",
+                3 => "network unreachable from the proxy",
+                4 => "host unreachable from the proxy",
+                5 => "connection refused through the proxy",
+                6 => "TTL expired at the proxy",
+                _ => "proxy failed to connect",
+            }));
+        }
+        // The address the proxy bound follows, which is of no use here.
+        let length = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut length = [0];
+                stream.read_exact(&mut length)?;
+                usize::from(length[0])
+            }
+            _ => return Err(socks_error("malformed reply from the proxy")),
+        };
+        let mut bound = vec![0; length + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(stream)
+    }
+}
+
+fn socks_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_interleave() {
+        let addresses: Vec<SocketAddr> = ["192.0.2.1:25", "192.0.2.2:25", "[2001:db8::1]:25"]
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect();
+        let interleaved = interleave(addresses);
+        assert_eq!(interleaved[0].to_string(), "[2001:db8::1]:25");
+        assert_eq!(interleaved[1].to_string(), "192.0.2.1:25");
+        assert_eq!(interleaved[2].to_string(), "192.0.2.2:25");
+    }
+
+    #[test]
+    fn test_socks5() {
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = proxy.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut client, _) = proxy.accept().unwrap();
+            let mut greeting = [0; 3];
+            client.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 2]);
+            client.write_all(&[5, 2]).unwrap();
+            let mut auth = [0; 10];
+            client.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x03bob\x04pass");
+            client.write_all(&[1, 0]).unwrap();
+            let mut request = [0; 21];
+            client.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..5], &[5, 1, 0, 3, 14]);
+            assert_eq!(&request[5..19], b"mx.example.com");
+            assert_eq!(&request[19..], &[0, 25]);
+            client
+                .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 25])
+                .unwrap();
+            client.write_all(b"220 mx.example.com\r\n").unwrap();
+        });
+
+        let mut socks = Socks5::new("127.0.0.1", port);
+        socks.credentials = Some((String::from("bob"), String::from("pass")));
+        let mut stream = socks
+            .connect("mx.example.com", 25, Duration::from_secs(5))
+            .unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).unwrap();
+        assert_eq!(greeting, "220 mx.example.com\r\n");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_connect_from() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let stream = connect_from("127.0.0.1", port, &[source], Duration::from_secs(5)).unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), source);
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer.ip(), source);
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = {
+            let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+            closed.local_addr().unwrap()
+        };
+        let timeout = Duration::from_secs(5);
+        let stream = connect_any(&[closed, listener.local_addr().unwrap()], timeout).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert!(connect_any(&[closed], timeout).is_err());
+        assert!(connect_any(&[], timeout).is_err());
+    }
+}
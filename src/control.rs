@@ -0,0 +1,191 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{clock, reply::Reply};
+
+/// A session being served, as listed by `Control::sessions`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveSession {
+    pub id: String,
+    pub peer: Option<SocketAddr>,
+    pub started: SystemTime,
+}
+
+struct Registered {
+    session: ActiveSession,
+    stream: TcpStream,
+}
+
+/// What operators change while the server runs, e.g. through `admin`: whether new
+/// connections are accepted, how fast, and the sessions under way. Kept across
+/// configuration reloads.
+#[derive(Default)]
+pub struct Control {
+    paused: AtomicBool,
+    connection_rate: AtomicU32,
+    sessions: Mutex<HashMap<String, Registered>>,
+    /// When the current minute of each address started, and its connections since.
+    connections: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control::default()
+    }
+
+    /// Turns new connections away with 421 until `resume`, leaving the sessions under
+    /// way alone.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        crate::info!("Paused accepting connections");
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        crate::info!("Resumed accepting connections");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Connections accepted from one address a minute, the others turned away with
+    /// 421. Unlimited when 0, the default.
+    pub fn set_connection_rate(&self, per_minute: u32) {
+        self.connection_rate.store(per_minute, Ordering::Relaxed);
+    }
+
+    pub fn connection_rate(&self) -> u32 {
+        self.connection_rate.load(Ordering::Relaxed)
+    }
+
+    /// The reply turning away a new connection from `peer`, `None` when it is welcome.
+    pub fn refusal(&self, peer: Option<IpAddr>) -> Option<Reply> {
+        if self.is_paused() {
+            return Some(Reply::new(
+                421,
+                "4.3.2 Not accepting mail now, try again later",
+            ));
+        }
+        let (limit, peer) = match (self.connection_rate(), peer) {
+            (0, _) | (_, None) => return None,
+            (limit, Some(peer)) => (limit, peer),
+        };
+        let now = Instant::now();
+        let mut connections = self.connections.lock().unwrap();
+        connections.retain(|_, (since, _)| now.duration_since(*since) < Duration::from_secs(60));
+        let (_, count) = connections.entry(peer).or_insert((now, 0));
+        *count += 1;
+        if *count > limit {
+            return Some(Reply::new(
+                421,
+                "4.7.0 Too many connections, try again later",
+            ));
+        }
+        None
+    }
+
+    /// Lists the session `id` on `stream` until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, id: &str, stream: &TcpStream) -> Registration {
+        match stream.try_clone() {
+            Ok(stream) => {
+                let session = ActiveSession {
+                    id: String::from(id),
+                    peer: stream.peer_addr().ok(),
+                    started: clock::now(),
+                };
+                let mut sessions = self.sessions.lock().unwrap();
+                sessions.insert(String::from(id), Registered { session, stream });
+            }
+            Err(e) => crate::warn!("Unable to register session {}: {}", id, e),
+        }
+        Registration {
+            control: Arc::clone(self),
+            id: String::from(id),
+        }
+    }
+
+    /// The sessions under way, oldest first.
+    pub fn sessions(&self) -> Vec<ActiveSession> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut active: Vec<ActiveSession> = sessions
+            .values()
+            .map(|registered| registered.session.clone())
+            .collect();
+        active.sort_by(|a, b| (a.started, &a.id).cmp(&(b.started, &b.id)));
+        active
+    }
+
+    /// Closes the connection of the session `id`, returning whether there is one.
+    pub fn kick(&self, id: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        match sessions.get(id) {
+            Some(registered) => {
+                crate::info!("Kicking session {}", id);
+                let _ = registered.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a session listed in `Control` while it lasts.
+pub struct Registration {
+    control: Arc<Control>,
+    id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.control.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener};
+
+    #[test]
+    fn test_refusal() {
+        let control = Control::new();
+        let peer = Some("192.0.2.1".parse().unwrap());
+        assert!(control.refusal(peer).is_none());
+        control.pause();
+        assert_eq!(control.refusal(peer).unwrap().code, 421);
+        control.resume();
+
+        control.set_connection_rate(2);
+        assert!(control.refusal(peer).is_none());
+        assert!(control.refusal(peer).is_none());
+        assert!(control.refusal(peer).is_some());
+        assert!(control
+            .refusal(Some("192.0.2.2".parse().unwrap()))
+            .is_none());
+    }
+
+    #[test]
+    fn test_kick() {
+        let control = Arc::new(Control::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let registration = control.register("s1", &server);
+        assert_eq!(control.sessions().len(), 1);
+        assert_eq!(control.sessions()[0].peer, client.local_addr().ok());
+        assert!(control.kick("s1"));
+        assert!(!control.kick("s2"));
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        drop(registration);
+        assert!(control.sessions().is_empty());
+    }
+}
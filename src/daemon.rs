@@ -0,0 +1,334 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Detaches the process from its terminal to run in the background, as init scripts
+/// expect: the parent returns to the shell, and standard input and output go to
+/// `/dev/null`, so that logging has to go to a file or syslog, see `log`.
+///
+/// Only the calling thread carries on in the background, so this has to be called before
+/// any other thread is started.
+pub fn daemonize() -> io::Result<()> {
+    sys::daemonize()
+}
+
+/// A file holding the ID of the running process, for init scripts to signal it, removed
+/// when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the ID of this process to `path`. Fails when the file names another process
+    /// still running, e.g. the server started twice; a file left by a crash is replaced.
+    pub fn create<P: Into<PathBuf>>(path: P) -> io::Result<PidFile> {
+        let path = path.into();
+        if let Some(pid) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && sys::is_running(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("already running as process {}", pid),
+                ));
+            }
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(PidFile { path })
+    }
+}
+
+/// A file in a directory the server may no longer write to, having dropped privileges,
+/// is emptied instead, which `create` takes for a file left by a crash.
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let result = match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(&self.path)
+                .map(drop),
+            result => result,
+        };
+        if let Err(e) = result {
+            crate::warn!("Unable to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Gives up root for `user`, and its primary group or `group`, for good: meant to be
+/// called once the privileged ports are bound, before any client is served. Does nothing
+/// when already running as `user`; fails when the change cannot be made, e.g. without
+/// root, or could be undone.
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<()> {
+    let (uid, gid) = owner(user, group)?;
+    sys::switch_user(uid, gid)?;
+    crate::info!("Running as {} ({}:{})", user, uid, gid);
+    Ok(())
+}
+
+/// The user and group IDs `drop_privileges` switches to.
+pub fn owner(user: &str, group: Option<&str>) -> io::Result<(u32, u32)> {
+    let (uid, primary) = lookup_user(user)?;
+    match group {
+        Some(group) => Ok((uid, lookup_group(group)?)),
+        None => Ok((uid, primary)),
+    }
+}
+
+/// Hands `path`, with everything below it when it is a directory, over to `uid` and `gid`:
+/// for the files created as root that are still written once privileges are dropped, such
+/// as the journal, the quarantine and the PID file.
+pub fn chown_all<P: AsRef<Path>>(path: P, uid: u32, gid: u32) -> io::Result<()> {
+    let path = path.as_ref();
+    sys::chown(path, uid, gid)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            chown_all(entry?.path(), uid, gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// The user and primary group IDs of the account `user`.
+pub fn lookup_user(user: &str) -> io::Result<(u32, u32)> {
+    sys::lookup_user(user)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no user {}", user)))
+}
+
+/// The ID of the group `group`.
+pub fn lookup_group(group: &str) -> io::Result<u32> {
+    sys::lookup_group(group)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no group {}", group)))
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        convert::TryFrom,
+        ffi::CString,
+        fs::OpenOptions,
+        io,
+        os::{
+            raw::{c_char, c_int},
+            unix::io::AsRawFd,
+        },
+        path::Path,
+    };
+
+    extern "C" {
+        fn fork() -> c_int;
+        fn setsid() -> c_int;
+        fn dup2(old: c_int, new: c_int) -> c_int;
+        fn kill(pid: c_int, signal: c_int) -> c_int;
+        fn _exit(status: c_int) -> !;
+        fn getpwnam(name: *const c_char) -> *const Passwd;
+        fn getgrnam(name: *const c_char) -> *const Group;
+        fn getuid() -> u32;
+        fn getgid() -> u32;
+        fn setgroups(size: usize, list: *const u32) -> c_int;
+        fn setgid(gid: u32) -> c_int;
+        fn setuid(uid: u32) -> c_int;
+    }
+
+    /// The leading fields of `struct passwd`, the only ones read.
+    #[repr(C)]
+    struct Passwd {
+        name: *const c_char,
+        password: *const c_char,
+        uid: u32,
+        gid: u32,
+    }
+
+    /// The leading fields of `struct group`.
+    #[repr(C)]
+    struct Group {
+        name: *const c_char,
+        password: *const c_char,
+        gid: u32,
+    }
+
+    fn c_string(name: &str) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub fn lookup_user(user: &str) -> io::Result<Option<(u32, u32)>> {
+        let name = c_string(user)?;
+        // SAFETY: `name` is NUL terminated; the entry is read before any other lookup
+        // may overwrite it.
+        let entry = unsafe { getpwnam(name.as_ptr()).as_ref() };
+        Ok(entry.map(|entry| (entry.uid, entry.gid)))
+    }
+
+    pub fn lookup_group(group: &str) -> io::Result<Option<u32>> {
+        let name = c_string(group)?;
+        // SAFETY: as in `lookup_user`.
+        let entry = unsafe { getgrnam(name.as_ptr()).as_ref() };
+        Ok(entry.map(|entry| entry.gid))
+    }
+
+    pub fn chown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        std::os::unix::fs::lchown(path, Some(uid), Some(gid))
+    }
+
+    pub fn switch_user(uid: u32, gid: u32) -> io::Result<()> {
+        // SAFETY: none of these calls takes a pointer but `setgroups`, given one group.
+        unsafe {
+            if getuid() == uid && getgid() == gid {
+                return Ok(());
+            }
+            // The supplementary groups first, then the group, while still allowed to.
+            if setgroups(1, &gid) == -1 || setgid(gid) == -1 || setuid(uid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if uid != 0 && setuid(0) == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "root privileges could be regained",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Forks and lets the parent exit, so that the child carries on in the background.
+    fn detach() -> io::Result<()> {
+        // SAFETY: the caller guarantees no other thread runs, so the child has a
+        // consistent copy of the process.
+        match unsafe { fork() } {
+            -1 => Err(io::Error::last_os_error()),
+            0 => Ok(()),
+            // SAFETY: `_exit` skips the destructors and buffers the child still owns.
+            _ => unsafe { _exit(0) },
+        }
+    }
+
+    pub fn daemonize() -> io::Result<()> {
+        detach()?;
+        // SAFETY: no arguments; fails only when already a process group leader, which
+        // the child of a fork is not.
+        if unsafe { setsid() } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // A second fork makes sure the daemon, no longer a session leader, never gets a
+        // controlling terminal again.
+        detach()?;
+        std::env::set_current_dir("/")?;
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..3 {
+            // SAFETY: both descriptors are open; `null` stays open until after the call.
+            if unsafe { dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_running(pid: u32) -> bool {
+        match c_int::try_from(pid) {
+            // SAFETY: signal 0 only checks that the process exists.
+            Ok(pid) if pid > 0 => unsafe { kill(pid, 0) == 0 },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    pub fn daemonize() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "daemon mode is only supported on Unix",
+        ))
+    }
+
+    pub fn is_running(_: u32) -> bool {
+        false
+    }
+
+    pub fn lookup_user(_: &str) -> io::Result<Option<(u32, u32)>> {
+        Err(unsupported())
+    }
+
+    pub fn lookup_group(_: &str) -> io::Result<Option<u32>> {
+        Err(unsupported())
+    }
+
+    pub fn switch_user(_: u32, _: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn chown(_: &std::path::Path, _: u32, _: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "users are only supported on Unix",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("simple-smtp-{}.pid", std::process::id()));
+        // Left by a process long gone.
+        fs::write(&path, "2147483647\n").unwrap();
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_chown_all() {
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = std::env::temp_dir().join(format!("chown-{}", std::process::id()));
+        fs::create_dir_all(directory.join("spool")).unwrap();
+        fs::write(directory.join("spool").join("A1"), "hi").unwrap();
+        let metadata = fs::metadata(&directory).unwrap();
+        // Only root may give files away; anyone may to themselves.
+        let (uid, gid) = match metadata.uid() {
+            0 => (65534, 65534),
+            uid => (uid, metadata.gid()),
+        };
+        chown_all(&directory, uid, gid).unwrap();
+        for path in [directory.clone(), directory.join("spool").join("A1")] {
+            let metadata = fs::metadata(path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        let e = lookup_user("no-such-user-here").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,271 @@
+use std::{io, str::FromStr, sync::Arc};
+
+use crate::digest;
+
+/// A TLSA record (RFC 6698) of `_<port>._tcp.<host>`, e.g. `3 1 1 <sha-256 in hex>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tlsa {
+    pub usage: u8,
+    pub selector: u8,
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+const DANE_TA: u8 = 2;
+const DANE_EE: u8 = 3;
+
+impl Tlsa {
+    /// Whether the record can be used for SMTP, which only honours DANE-TA and DANE-EE
+    /// (RFC 7672) and where SHA-512 is not supported here.
+    pub fn is_usable(&self) -> bool {
+        (self.usage == DANE_TA || self.usage == DANE_EE) && self.selector <= 1 && self.matching <= 1
+    }
+
+    /// Whether the DER encoded `certificate` is the one the record describes.
+    pub fn matches(&self, certificate: &[u8]) -> bool {
+        let selected = match self.selector {
+            0 => Some(certificate),
+            1 => subject_public_key_info(certificate),
+            _ => None,
+        };
+        match (selected, self.matching) {
+            (Some(selected), 0) => selected == self.data.as_slice(),
+            (Some(selected), 1) => digest::sha256(selected)[..] == self.data[..],
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Tlsa {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Tlsa, String> {
+        let invalid = || format!("invalid TLSA record {}", value);
+        let mut fields = value.split_whitespace();
+        let mut number = || -> Result<u8, String> {
+            fields
+                .next()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(invalid)
+        };
+        let (usage, selector, matching) = (number()?, number()?, number()?);
+        let hex: String = fields.collect();
+        if hex.is_empty() || !hex.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        Ok(Tlsa {
+            usage,
+            selector,
+            matching,
+            data,
+        })
+    }
+}
+
+/// Looks up the TLSA records of `host` and `port`. Only answers a DNSSEC validating
+/// resolver found secure may be returned, an insecure or missing answer is an empty list;
+/// an error leaves it unknown whether the host has any.
+pub trait TlsaLookup: Send + Sync {
+    fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>>;
+}
+
+impl<F> TlsaLookup for F
+where
+    F: Fn(&str, u16) -> io::Result<Vec<Tlsa>> + Send + Sync,
+{
+    fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>> {
+        self(host, port)
+    }
+}
+
+/// What to do with a host that has no usable TLSA records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fallback {
+    /// Deliver without DANE, as RFC 7672 has it.
+    Deliver,
+    /// Keep the message until the host publishes records.
+    Defer,
+}
+
+/// DANE for outbound connections: a host with usable TLSA records is only sent mail over
+/// TLS with a certificate matching one of them. Only DANE-EE records are matched, against
+/// the host's own certificate; DANE-TA takes checking the signatures along the chain,
+/// which this crate cannot do, so a host with only DANE-TA records is deferred.
+pub struct Dane {
+    pub lookup: Arc<dyn TlsaLookup>,
+    pub fallback: Fallback,
+}
+
+impl Dane {
+    pub fn new(lookup: Arc<dyn TlsaLookup>) -> Dane {
+        Dane {
+            lookup,
+            fallback: Fallback::Deliver,
+        }
+    }
+
+    /// Checks a connection to `host` and `port`, `chain` being the certificates the host
+    /// presented, its own first, or `None` without TLS. Any usable TLSA record fails a
+    /// connection without TLS. Failures are temporary.
+    pub fn verify(&self, host: &str, port: u16, chain: Option<&[Vec<u8>]>) -> io::Result<()> {
+        let records: Vec<Tlsa> = self
+            .lookup
+            .tlsa(host.trim_end_matches('.'), port)?
+            .into_iter()
+            .filter(Tlsa::is_usable)
+            .collect();
+        if records.is_empty() {
+            return match self.fallback {
+                Fallback::Deliver => Ok(()),
+                Fallback::Defer => Err(io::Error::other(format!(
+                    "{} has no usable TLSA records",
+                    host
+                ))),
+            };
+        }
+        let chain = chain.ok_or_else(|| {
+            io::Error::other(format!("{} has TLSA records and requires TLS", host))
+        })?;
+        let matched = records.iter().any(|record| {
+            record.usage == DANE_EE && chain.first().is_some_and(|leaf| record.matches(leaf))
+        });
+        if matched {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "certificate of {} matches none of its TLSA records",
+                host
+            )))
+        }
+    }
+}
+
+/// The `SubjectPublicKeyInfo` of a DER encoded X.509 certificate, with its header.
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    // The version is optional and tagged [0].
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.2;
+    }
+    // Serial number, signature algorithm, issuer, validity and subject come first.
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (element, _, _) = der_element(tbs)?;
+    Some(element)
+}
+
+/// Splits the first DER element off `input`: the whole element, its content and the rest.
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *input.get(1)?;
+    let (length, header) = if first < 0x80 {
+        (usize::from(first), 2)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let length = input
+            .get(2..2 + count)?
+            .iter()
+            .fold(0, |length, byte| length << 8 | usize::from(*byte));
+        (length, 2 + count)
+    };
+    let end = header.checked_add(length)?;
+    let element = input.get(..end)?;
+    Some((element, &element[header..], &input[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        if content.len() < 0x80 {
+            element.push(content.len() as u8);
+        } else {
+            element.extend_from_slice(&[0x81, content.len() as u8]);
+        }
+        element.extend_from_slice(content);
+        element
+    }
+
+    fn certificate(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let spki = der(0x30, &der(0x03, key));
+        let tbs = [
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(0x30, &[]),
+            der(0x30, &[]),
+            der(0x30, &[]),
+            der(0x30, &[]),
+            spki.clone(),
+        ]
+        .concat();
+        let certificate = [der(0x30, &tbs), der(0x30, &[]), der(0x03, &[0])].concat();
+        (der(0x30, &certificate), spki)
+    }
+
+    #[test]
+    fn test_parse() {
+        let record: Tlsa = "3 1 1 0A0b 0c".parse().unwrap();
+        assert_eq!(record.data, vec![0x0a, 0x0b, 0x0c]);
+        assert!(record.is_usable());
+        assert!(!"1 0 1 0a".parse::<Tlsa>().unwrap().is_usable());
+        assert!("3 1 1 0".parse::<Tlsa>().is_err());
+        assert!("3 1".parse::<Tlsa>().is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (leaf, spki) = certificate(&[7; 200]);
+        assert_eq!(subject_public_key_info(&leaf), Some(spki.as_slice()));
+        let record = Tlsa {
+            usage: DANE_EE,
+            selector: 1,
+            matching: 1,
+            data: digest::sha256(&spki).to_vec(),
+        };
+        let lookup = move |host: &str, _: u16| -> io::Result<Vec<Tlsa>> {
+            Ok(if host == "mx.example.com" {
+                vec![record.clone()]
+            } else {
+                Vec::new()
+            })
+        };
+        let mut dane = Dane::new(Arc::new(lookup));
+        dane.verify("mx.example.com", 25, Some(std::slice::from_ref(&leaf)))
+            .unwrap();
+        assert!(dane.verify("mx.example.com", 25, None).is_err());
+        let (other, _) = certificate(&[8; 16]);
+        assert!(dane.verify("mx.example.com", 25, Some(&[other])).is_err());
+
+        // The issuer is not checked to have signed the leaf, so it vouches for nothing.
+        let (issuer, issuer_spki) = certificate(&[9; 64]);
+        let anchor = Tlsa {
+            usage: DANE_TA,
+            selector: 1,
+            matching: 1,
+            data: digest::sha256(&issuer_spki).to_vec(),
+        };
+        let dane_ta = Dane::new(Arc::new(move |_: &str, _: u16| Ok(vec![anchor.clone()])));
+        let (other, _) = certificate(&[8; 16]);
+        assert!(dane_ta
+            .verify("mx.example.net", 25, Some(&[other, issuer]))
+            .is_err());
+
+        dane.verify("mx.example.org", 25, None).unwrap();
+        dane.fallback = Fallback::Defer;
+        assert!(dane.verify("mx.example.org", 25, None).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    convert::TryFrom,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::json::{FromJson, Json, ToJson};
+
+/// What to do with a message that cannot be delivered in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Return it to the sender as failed.
+    Return,
+    /// Deliver it anyway and tell the sender it was late.
+    Notify,
+}
+
+/// The `BY` parameter of MAIL FROM (RFC 2852), e.g. `BY=3600;R`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeliverBy {
+    /// When the message has to be delivered by, from the time of MAIL FROM.
+    pub deadline: SystemTime,
+    pub mode: Mode,
+    /// Whether each hop is asked to report relaying the message.
+    pub trace: bool,
+}
+
+impl DeliverBy {
+    /// Reads `value` as given on MAIL FROM at `now`, refusing a by-time under `minimum`
+    /// seconds or one that is already over in return mode.
+    pub fn parse(value: &str, now: SystemTime, minimum: u64) -> Result<DeliverBy, String> {
+        let invalid = || format!("Invalid BY {}", value);
+        let (time, modifiers) = value.split_once(';').ok_or_else(invalid)?;
+        let time: i64 = time.parse().map_err(|_| invalid())?;
+        let modifiers = modifiers.to_uppercase();
+        let (mode, trace) = match modifiers.as_str() {
+            "R" => (Mode::Return, false),
+            "RT" => (Mode::Return, true),
+            "N" => (Mode::Notify, false),
+            "NT" => (Mode::Notify, true),
+            _ => return Err(invalid()),
+        };
+        if mode == Mode::Return && time <= 0 {
+            return Err(format!("BY time {} is not positive", time));
+        }
+        if time >= 0 && (time as u64) < minimum {
+            return Err(format!("BY time {} is less than {}", time, minimum));
+        }
+        let offset = Duration::from_secs(time.unsigned_abs());
+        let deadline = if time >= 0 {
+            now + offset
+        } else {
+            now.checked_sub(offset).unwrap_or(now)
+        };
+        Ok(DeliverBy {
+            deadline,
+            mode,
+            trace,
+        })
+    }
+
+    /// Seconds left until the deadline, negative once it passed.
+    pub fn remaining(&self, now: SystemTime) -> i64 {
+        match self.deadline.duration_since(now) {
+            Ok(left) => left.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now > self.deadline
+    }
+
+    /// The parameter to pass on to the next hop at `now`, with the time left.
+    pub fn to_parameter(&self, now: SystemTime) -> String {
+        let mode = match self.mode {
+            Mode::Return => "R",
+            Mode::Notify => "N",
+        };
+        let trace = if self.trace { "T" } else { "" };
+        format!("BY={};{}{}", self.remaining(now), mode, trace)
+    }
+}
+
+/// The deadline in milliseconds since the epoch, so that it survives a restart.
+impl ToJson for DeliverBy {
+    fn to_json(&self) -> Json {
+        let deadline = self
+            .deadline
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as i64);
+        let mode = match self.mode {
+            Mode::Return => "R",
+            Mode::Notify => "N",
+        };
+        Json::object(vec![
+            ("deadline", Json::Number(deadline)),
+            ("mode", Json::string(mode)),
+            ("trace", Json::Bool(self.trace)),
+        ])
+    }
+}
+
+impl FromJson for DeliverBy {
+    fn from_json(json: &Json) -> Option<DeliverBy> {
+        let deadline = u64::try_from(json.get("deadline")?.as_i64()?).ok()?;
+        let mode = match json.get("mode")?.as_str()? {
+            "R" => Mode::Return,
+            "N" => Mode::Notify,
+            _ => return None,
+        };
+        Some(DeliverBy {
+            deadline: UNIX_EPOCH + Duration::from_millis(deadline),
+            mode,
+            trace: json.get("trace")?.as_bool()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_parse() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let by = DeliverBy::parse("120;rt", now, 0).unwrap();
+        assert_eq!(by.mode, Mode::Return);
+        assert!(by.trace);
+        assert_eq!(by.to_parameter(now + Duration::from_secs(20)), "BY=100;RT");
+        assert!(!by.is_expired(now + Duration::from_secs(120)));
+        assert!(by.is_expired(now + Duration::from_secs(121)));
+
+        let late = DeliverBy::parse("-30;N", now, 60).unwrap();
+        assert_eq!(late.remaining(now), -30);
+        assert!(late.is_expired(now));
+
+        assert!(DeliverBy::parse("0;R", now, 0).is_err());
+        assert!(DeliverBy::parse("30;R", now, 60).is_err());
+        assert!(DeliverBy::parse("30;X", now, 0).is_err());
+        assert!(DeliverBy::parse("30", now, 0).is_err());
+    }
+}
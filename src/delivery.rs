@@ -0,0 +1,660 @@
+use std::{
+    collections::HashSet,
+    io,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    backscatter::BouncePolicy,
+    clock,
+    dsn::{self, Action},
+    email::{self, Mail},
+    metrics,
+    output::{self, Output},
+    queue::{Hold, QueueFlush, QueueHold},
+    reply::Reply,
+    retry::{Failure, RetrySchedule},
+};
+
+/// The most messages to one destination a worker takes at once.
+const BATCH_SIZE: usize = 50;
+
+struct Queued {
+    mail: Mail,
+    /// The domain of the first recipient, lowercased.
+    destination: String,
+    /// The domain of the sender, lowercased, empty for bounces.
+    sender: String,
+    /// Whether it was held by its queue ID.
+    held: bool,
+    queued_at: SystemTime,
+    due: SystemTime,
+    failures: u32,
+    /// Whether its sender was told it is late.
+    warned: bool,
+}
+
+#[derive(Default)]
+struct State {
+    queued: Vec<Queued>,
+    /// The destinations a worker is delivering to.
+    busy: HashSet<String>,
+    /// The messages the workers took.
+    in_flight: usize,
+    /// The sender domains held.
+    held_senders: HashSet<String>,
+    stopping: bool,
+}
+
+impl State {
+    fn is_held(&self, queued: &Queued) -> bool {
+        queued.held || self.held_senders.contains(&queued.sender)
+    }
+}
+
+struct Shared {
+    output: Arc<dyn Output>,
+    retry: RetrySchedule,
+    bounces: OnceLock<(String, Arc<dyn Output>)>,
+    bounce_policy: OnceLock<Arc<BouncePolicy>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Takes messages for a slow output, e.g. a `Relay`, and hands them on from a pool of
+/// worker threads, so that the client is answered without waiting for it. Each worker
+/// takes the messages due for one destination at a time, which no other worker delivers
+/// to meanwhile; given an `idle_timeout`, a relay sends them over one connection. Messages
+/// that fail temporarily are tried again as `retry` has it, the others and the expired
+/// ones are returned to their sender through the output given to `bounce`, which also
+/// tells the senders of messages late past `RetrySchedule::delay_warning`. Messages can be
+/// held, see `QueueHold`; their lifetime runs on meanwhile, so that one released after it
+/// expired is returned as soon as an attempt fails.
+///
+/// The queue is held in memory: messages still in it when the process ends are lost,
+/// unless they were journaled, see `journal`.
+pub struct DeliveryQueue {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl DeliveryQueue {
+    /// Starts `workers` threads delivering to `output`.
+    pub fn new(output: Arc<dyn Output>, retry: RetrySchedule, workers: usize) -> DeliveryQueue {
+        let shared = Arc::new(Shared {
+            output,
+            retry,
+            bounces: OnceLock::new(),
+            bounce_policy: OnceLock::new(),
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        DeliveryQueue { shared, workers }
+    }
+
+    /// Sends failure reports, from `server_name`, through `output` from now on. Only the
+    /// first call has an effect.
+    pub fn bounce(&self, server_name: &str, output: Arc<dyn Output>) {
+        let _ = self.shared.bounces.set((String::from(server_name), output));
+    }
+
+    /// Sends reports only about the messages `policy` allows, see `backscatter`. Only the
+    /// first call has an effect.
+    pub fn bounce_policy(&self, policy: Arc<BouncePolicy>) {
+        let _ = self.shared.bounce_policy.set(policy);
+    }
+
+    /// The messages waiting, including those being delivered.
+    pub fn len(&self) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.queued.len() + state.in_flight
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Output for DeliveryQueue {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let destination = mail
+            .rcpt_to
+            .first()
+            .and_then(|rcpt| email::domain_of(rcpt))
+            .unwrap_or("")
+            .to_lowercase();
+        let sender = mail
+            .mail_from
+            .as_deref()
+            .and_then(email::domain_of)
+            .unwrap_or("")
+            .to_lowercase();
+        let now = clock::now();
+        let mut state = self.shared.state.lock().unwrap();
+        state.queued.push(Queued {
+            mail: mail.clone(),
+            destination,
+            sender,
+            held: false,
+            queued_at: now,
+            due: now,
+            failures: 0,
+            warned: false,
+        });
+        self.shared.changed.notify_one();
+        Ok(())
+    }
+}
+
+impl QueueFlush for DeliveryQueue {
+    /// Makes the messages for `node`, or all of them when empty, due now, e.g. for ETRN
+    /// or an operator who knows the destination is back. There are no named queues.
+    fn flush(&self, node: &str) -> io::Result<usize> {
+        let node = node.to_lowercase();
+        let matches = |destination: &str| match node.strip_prefix('@') {
+            Some(domain) => destination == domain || destination.ends_with(&format!(".{}", domain)),
+            None => node.is_empty() || destination == node,
+        };
+        let now = clock::now();
+        let mut state = self.shared.state.lock().unwrap();
+        let mut flushed = 0;
+        for queued in &mut state.queued {
+            if matches(&queued.destination) {
+                queued.due = now;
+                flushed += 1;
+            }
+        }
+        self.shared.changed.notify_all();
+        Ok(flushed)
+    }
+}
+
+impl QueueHold for DeliveryQueue {
+    fn hold(&self, hold: &Hold) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let held = match hold {
+            Hold::Message(queue_id) => {
+                let mut held = 0;
+                for queued in &mut state.queued {
+                    if queued.mail.queue_id.as_deref() == Some(queue_id.as_str()) {
+                        queued.held = true;
+                        held += 1;
+                    }
+                }
+                held
+            }
+            Hold::Sender(domain) => {
+                let domain = domain.to_lowercase();
+                let held = state
+                    .queued
+                    .iter()
+                    .filter(|queued| queued.sender == domain)
+                    .count();
+                state.held_senders.insert(domain);
+                held
+            }
+        };
+        crate::info!("Holding {}: {} messages", hold, held);
+        Ok(held)
+    }
+
+    fn release(&self, hold: &Hold) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let released = match hold {
+            Hold::Message(queue_id) => {
+                let mut released = 0;
+                for queued in &mut state.queued {
+                    if queued.held && queued.mail.queue_id.as_deref() == Some(queue_id.as_str()) {
+                        queued.held = false;
+                        released += 1;
+                    }
+                }
+                released
+            }
+            Hold::Sender(domain) => {
+                let domain = domain.to_lowercase();
+                if !state.held_senders.remove(&domain) {
+                    return Ok(0);
+                }
+                state
+                    .queued
+                    .iter()
+                    .filter(|queued| queued.sender == domain)
+                    .count()
+            }
+        };
+        crate::info!("Released {}: {} messages", hold, released);
+        self.shared.changed.notify_all();
+        Ok(released)
+    }
+
+    fn holds(&self) -> Vec<(Hold, usize)> {
+        let state = self.shared.state.lock().unwrap();
+        let mut holds: Vec<(Hold, usize)> = state
+            .queued
+            .iter()
+            .filter(|queued| queued.held)
+            .map(|queued| {
+                let queue_id = queued.mail.queue_id.clone().unwrap_or_default();
+                (Hold::Message(queue_id), 1)
+            })
+            .collect();
+        for domain in &state.held_senders {
+            let count = state
+                .queued
+                .iter()
+                .filter(|queued| &queued.sender == domain)
+                .count();
+            holds.push((Hold::Sender(domain.clone()), count));
+        }
+        holds.sort();
+        holds
+    }
+}
+
+impl Drop for DeliveryQueue {
+    /// Stops the workers once they are done with the messages they took.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopping = true;
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn work(&self) {
+        while let Some((destination, batch)) = self.next_batch() {
+            let taken = batch.len();
+            let mut retries = Vec::new();
+            for queued in batch {
+                if let Some(queued) = self.attempt(queued) {
+                    retries.push(queued);
+                }
+            }
+            let mut state = self.state.lock().unwrap();
+            state.busy.remove(&destination);
+            state.in_flight -= taken;
+            state.queued.extend(retries);
+            self.changed.notify_all();
+        }
+    }
+
+    /// Waits for messages due for a destination no other worker has, and takes them.
+    /// `None` once the queue stops.
+    fn next_batch(&self) -> Option<(String, Vec<Queued>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.stopping {
+                return None;
+            }
+            let now = clock::now();
+            let ready = state.queued.iter().position(|queued| {
+                queued.due <= now
+                    && !state.busy.contains(&queued.destination)
+                    && !state.is_held(queued)
+            });
+            if let Some(index) = ready {
+                let destination = state.queued[index].destination.clone();
+                let mut batch = Vec::new();
+                let mut index = 0;
+                while index < state.queued.len() && batch.len() < BATCH_SIZE {
+                    let queued = &state.queued[index];
+                    if queued.destination == destination
+                        && queued.due <= now
+                        && !state.is_held(queued)
+                    {
+                        batch.push(state.queued.remove(index));
+                    } else {
+                        index += 1;
+                    }
+                }
+                state.busy.insert(destination.clone());
+                state.in_flight += batch.len();
+                return Some((destination, batch));
+            }
+            // Until the next message neither held nor held up by another worker is due, or
+            // anything changes.
+            let wait = state
+                .queued
+                .iter()
+                .filter(|queued| {
+                    !state.busy.contains(&queued.destination) && !state.is_held(queued)
+                })
+                .map(|queued| queued.due.duration_since(now).unwrap_or_default())
+                .min()
+                .unwrap_or(Duration::from_secs(60));
+            state = self.changed.wait_timeout(state, wait).unwrap().0;
+        }
+    }
+
+    /// Delivers `queued`, giving it back when it is to be tried again.
+    fn attempt(&self, mut queued: Queued) -> Option<Queued> {
+        let queue_id = queued.mail.queue_id.clone().unwrap_or_default();
+        let error = match email::deliver_to(&*self.output, &queued.mail, &queue_id) {
+            Ok(()) => return None,
+            Err(e) => e,
+        };
+        // Recipients accepted before the failure are neither tried again nor bounced.
+        let delivered = output::delivered(&error);
+        queued.mail.rcpt_to.retain(|rcpt| !delivered.contains(rcpt));
+        queued.failures += 1;
+        let now = clock::now();
+        let next = if output::is_permanent(&error) {
+            None
+        } else {
+            self.retry
+                .next_attempt(Failure::of(&error), queued.failures, queued.queued_at, now)
+        };
+        match next {
+            Some(due) => {
+                crate::info!("Deferred {}: {}", queue_id, error);
+                if !queued.warned && self.retry.is_late(queued.queued_at, now) {
+                    let reply = Reply::new(451, &format!("4.4.7 {}", error));
+                    self.report(&queued.mail, Action::Delayed, &reply);
+                    queued.warned = true;
+                }
+                queued.due = due;
+                Some(queued)
+            }
+            None => {
+                crate::warn!("Giving up on {}: {}", queue_id, error);
+                let reply = if now >= self.retry.expires(queued.queued_at) {
+                    Reply::new(554, &format!("5.4.7 Delivery time expired: {}", error))
+                } else {
+                    Reply::new(554, &format!("5.4.0 {}", error))
+                };
+                self.report(&queued.mail, Action::Failed, &reply);
+                None
+            }
+        }
+    }
+
+    /// Tells the sender of `mail` that every recipient failed, or is late, with `reply`.
+    fn report(&self, mail: &Mail, action: Action, reply: &Reply) {
+        let (server_name, output) = match self.bounces.get() {
+            Some(bounces) => bounces,
+            None => return,
+        };
+        if let Some(policy) = self.bounce_policy.get() {
+            if let Err(reason) = policy.check(mail) {
+                let queue_id = mail.queue_id.as_deref().unwrap_or_default();
+                crate::info!("No {} report for {}: {}", action.as_str(), queue_id, reason);
+                metrics::increment(metrics::BOUNCES_SUPPRESSED);
+                return;
+            }
+        }
+        let recipients: Vec<(String, Reply)> = mail
+            .rcpt_to
+            .iter()
+            .map(|rcpt| (rcpt.clone(), reply.clone()))
+            .collect();
+        let report = match action {
+            Action::Failed => dsn::failure_report(server_name, mail, &recipients),
+            Action::Delayed => dsn::delay_report(server_name, mail, &recipients),
+        };
+        if let Some(report) = report {
+            if let Err(e) = output.deliver(&report) {
+                crate::error!("Unable to send {} report: {}", action.as_str(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Refuses every other delivery, temporarily or for good, and records the others.
+    struct Flaky {
+        attempts: AtomicUsize,
+        permanent: bool,
+        delivered: Mutex<Vec<String>>,
+    }
+
+    impl Output for Flaky {
+        fn deliver(&self, mail: &Mail) -> io::Result<()> {
+            if self
+                .attempts
+                .fetch_add(1, Ordering::SeqCst)
+                .is_multiple_of(2)
+            {
+                return Err(if self.permanent {
+                    output::permanent_failure(String::from("no such user"))
+                } else {
+                    io::Error::other("try again")
+                });
+            }
+            let mut delivered = self.delivered.lock().unwrap();
+            delivered.push(mail.queue_id.clone().unwrap_or_default());
+            Ok(())
+        }
+    }
+
+    fn mail(queue_id: &str, rcpt: &str) -> Mail {
+        let mut mail = Mail::new();
+        mail.queue_id = Some(String::from(queue_id));
+        mail.mail_from = Some(String::from("<a@example.net>"));
+        mail.rcpt_to = vec![String::from(rcpt)];
+        mail.data = Some(String::from("Subject: hi\r\n\r\nhi\r\n"));
+        mail
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn test_retry() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(0),
+            permanent: false,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let retry = RetrySchedule::from_backoff("0s".parse().unwrap());
+        let queue = DeliveryQueue::new(flaky.clone(), retry, 2);
+        queue.deliver(&mail("A", "<b@example.com>")).unwrap();
+        wait_until(|| queue.is_empty());
+        assert_eq!(*flaky.delivered.lock().unwrap(), vec!["A"]);
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 2);
+    }
+
+    /// Accepts the first recipient and fails the rest, once.
+    #[derive(Default)]
+    struct Partial(Mutex<Vec<Vec<String>>>);
+
+    impl Output for Partial {
+        fn deliver(&self, mail: &Mail) -> io::Result<()> {
+            let mut attempts = self.0.lock().unwrap();
+            attempts.push(mail.rcpt_to.clone());
+            if attempts.len() > 1 {
+                return Ok(());
+            }
+            let delivered = mail.rcpt_to[..1].to_vec();
+            Err(output::partial_delivery(
+                delivered,
+                io::Error::other("relay replied 451"),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_partial() {
+        let partial = Arc::new(Partial::default());
+        let retry = RetrySchedule::from_backoff("0s".parse().unwrap());
+        let queue = DeliveryQueue::new(partial.clone(), retry, 1);
+        let mut mail = mail("A", "<b@example.com>");
+        mail.rcpt_to.push(String::from("<c@example.com>"));
+        queue.deliver(&mail).unwrap();
+        wait_until(|| queue.is_empty());
+        assert_eq!(
+            *partial.0.lock().unwrap(),
+            vec![
+                vec![
+                    String::from("<b@example.com>"),
+                    String::from("<c@example.com>")
+                ],
+                vec![String::from("<c@example.com>")],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bounce() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(0),
+            permanent: true,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let bounces = Arc::new(Flaky {
+            attempts: AtomicUsize::new(1),
+            permanent: true,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let queue = DeliveryQueue::new(flaky.clone(), RetrySchedule::default(), 1);
+        queue.bounce("mx.example.com", bounces.clone());
+        queue.deliver(&mail("A", "<b@example.com>")).unwrap();
+        wait_until(|| !bounces.delivered.lock().unwrap().is_empty());
+        assert!(flaky.delivered.lock().unwrap().is_empty());
+    }
+
+    #[derive(Default)]
+    struct Reports(Mutex<Vec<Mail>>);
+
+    impl Output for Reports {
+        fn deliver(&self, mail: &Mail) -> io::Result<()> {
+            self.0.lock().unwrap().push(mail.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(0),
+            permanent: false,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let reports = Arc::new(Reports::default());
+        let retry = RetrySchedule {
+            delay_warning: Some(Duration::ZERO),
+            ..RetrySchedule::from_backoff("0s".parse().unwrap())
+        };
+        let queue = DeliveryQueue::new(flaky.clone(), retry, 1);
+        queue.bounce("mx.example.com", reports.clone());
+        queue.deliver(&mail("A", "<b@example.com>")).unwrap();
+        wait_until(|| queue.is_empty());
+        assert_eq!(*flaky.delivered.lock().unwrap(), vec!["A"]);
+        {
+            let reports = reports.0.lock().unwrap();
+            assert_eq!(reports.len(), 1);
+            let report = reports[0].data.as_deref().unwrap();
+            assert!(report.contains("Action: delayed\r\nStatus: 4.4.7\r\n"));
+        }
+
+        let retry = RetrySchedule {
+            max_lifetime: Duration::ZERO,
+            ..RetrySchedule::from_backoff("0s".parse().unwrap())
+        };
+        let queue = DeliveryQueue::new(flaky.clone(), retry, 1);
+        queue.bounce("mx.example.com", reports.clone());
+        queue.deliver(&mail("B", "<b@example.com>")).unwrap();
+        wait_until(|| reports.0.lock().unwrap().len() == 2);
+        let reports = reports.0.lock().unwrap();
+        let report = reports[1].data.as_deref().unwrap();
+        assert!(report.contains("Action: failed\r\nStatus: 5.4.7\r\n"));
+        assert!(report.contains("Delivery time expired: try again"));
+    }
+
+    #[test]
+    fn test_bounce_policy() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(0),
+            permanent: true,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let reports = Arc::new(Reports::default());
+        let queue = DeliveryQueue::new(flaky.clone(), RetrySchedule::default(), 1);
+        queue.bounce("mx.example.com", reports.clone());
+        let mut policy = BouncePolicy::default();
+        policy.max_reports = Some(1);
+        queue.bounce_policy(Arc::new(policy));
+        for queue_id in ["A", "B"].iter() {
+            flaky.attempts.store(0, Ordering::SeqCst);
+            queue.deliver(&mail(queue_id, "<b@example.com>")).unwrap();
+            wait_until(|| queue.is_empty());
+        }
+        // The second report to example.net is over the limit.
+        assert_eq!(flaky.attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(reports.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_flush() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(0),
+            permanent: false,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let retry = RetrySchedule::from_backoff("1h".parse().unwrap());
+        let queue = DeliveryQueue::new(flaky.clone(), retry, 1);
+        queue.deliver(&mail("A", "<b@example.com>")).unwrap();
+        assert_eq!(queue.flush("example.net").unwrap(), 0);
+        // Once the first attempt failed and the message waits an hour for the next.
+        wait_until(|| flaky.attempts.load(Ordering::SeqCst) == 1);
+        assert_eq!(queue.flush("mx.example.com").unwrap(), 0);
+        wait_until(|| queue.flush("@EXAMPLE.com").unwrap() == 1);
+        wait_until(|| queue.is_empty());
+        assert_eq!(*flaky.delivered.lock().unwrap(), vec!["A"]);
+    }
+
+    #[test]
+    fn test_hold() {
+        let flaky = Arc::new(Flaky {
+            attempts: AtomicUsize::new(1),
+            permanent: false,
+            delivered: Mutex::new(Vec::new()),
+        });
+        let queue = DeliveryQueue::new(flaky.clone(), RetrySchedule::default(), 1);
+        let sender = Hold::Sender(String::from("Example.NET"));
+        assert_eq!(queue.hold(&sender).unwrap(), 0);
+        queue.deliver(&mail("A", "<b@example.com>")).unwrap();
+        queue.deliver(&mail("B", "<b@example.com>")).unwrap();
+        assert_eq!(queue.hold(&Hold::Message(String::from("B"))).unwrap(), 1);
+        assert_eq!(
+            queue.holds(),
+            vec![
+                (Hold::Message(String::from("B")), 1),
+                (Hold::Sender(String::from("example.net")), 2)
+            ]
+        );
+        thread::sleep(Duration::from_millis(50));
+        assert!(flaky.delivered.lock().unwrap().is_empty());
+
+        assert_eq!(queue.release(&sender).unwrap(), 2);
+        assert_eq!(queue.release(&sender).unwrap(), 0);
+        wait_until(|| queue.len() == 1);
+        assert_eq!(*flaky.delivered.lock().unwrap(), vec!["A"]);
+        // So that the next attempt succeeds.
+        flaky.attempts.store(1, Ordering::SeqCst);
+        assert_eq!(queue.release(&Hold::Message(String::from("B"))).unwrap(), 1);
+        wait_until(|| queue.is_empty());
+        assert_eq!(*flaky.delivered.lock().unwrap(), vec!["A", "B"]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{io, sync::Arc};
+
+/// Tells how many bytes are free on the volume messages are stored on.
+pub trait FreeSpace: Send + Sync {
+    fn free_bytes(&self) -> io::Result<u64>;
+}
+
+impl<F> FreeSpace for F
+where
+    F: Fn() -> io::Result<u64> + Send + Sync,
+{
+    fn free_bytes(&self) -> io::Result<u64> {
+        self()
+    }
+}
+
+/// Defers DATA while the storage volume is close to full, so that no message is accepted
+/// that cannot be written out.
+pub struct DiskCheck {
+    pub volume: Arc<dyn FreeSpace>,
+    /// DATA is deferred unless more than this many bytes are free.
+    pub min_free: u64,
+}
+
+impl DiskCheck {
+    pub fn new(volume: Arc<dyn FreeSpace>, min_free: u64) -> DiskCheck {
+        DiskCheck { volume, min_free }
+    }
+
+    /// Whether there is room for another message. A volume that cannot be checked is
+    /// taken to have room, so that a failing check does not stop all mail.
+    pub fn has_room(&self) -> bool {
+        match self.volume.free_bytes() {
+            Ok(free) => free > self.min_free,
+            Err(e) => {
+                crate::warn!("Unable to check free disk space: {}", e);
+                true
+            }
+        }
+    }
+}
+
+/// The volume holding `path`, as far as unprivileged users may fill it.
+#[cfg(feature = "server")]
+pub struct Volume {
+    pub path: std::path::PathBuf,
+}
+
+#[cfg(feature = "server")]
+impl Volume {
+    pub fn new<P: Into<std::path::PathBuf>>(path: P) -> Volume {
+        Volume { path: path.into() }
+    }
+}
+
+#[cfg(feature = "server")]
+impl FreeSpace for Volume {
+    fn free_bytes(&self) -> io::Result<u64> {
+        sys::free_bytes(&self.path)
+    }
+}
+
+#[cfg(all(feature = "server", target_os = "linux", target_pointer_width = "64"))]
+mod sys {
+    use std::{
+        ffi::CString,
+        io,
+        os::{raw::c_char, unix::ffi::OsStrExt},
+        path::Path,
+    };
+
+    extern "C" {
+        fn statvfs(path: *const c_char, buf: *mut u64) -> i32;
+    }
+
+    pub fn free_bytes(path: &Path) -> io::Result<u64> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // `struct statvfs` is 112 bytes on 64-bit Linux; `f_frsize` and `f_bavail` are its
+        // second and fifth fields.
+        let mut buf = [0u64; 16];
+        // SAFETY: `path` is NUL terminated and `buf` is larger than `struct statvfs`.
+        if unsafe { statvfs(path.as_ptr(), buf.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buf[1].saturating_mul(buf[4]))
+    }
+}
+
+#[cfg(all(
+    feature = "server",
+    not(all(target_os = "linux", target_pointer_width = "64"))
+))]
+mod sys {
+    use std::{io, path::Path};
+
+    pub fn free_bytes(_: &Path) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "free disk space is only checked on 64-bit Linux",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_room() {
+        let volume = |free: u64| Arc::new(move || -> io::Result<u64> { Ok(free) });
+        assert!(DiskCheck::new(volume(1001), 1000).has_room());
+        assert!(!DiskCheck::new(volume(1000), 1000).has_room());
+        let broken = Arc::new(|| -> io::Result<u64> { Err(io::Error::other("statvfs")) });
+        assert!(DiskCheck::new(broken, 1000).has_room());
+    }
+
+    #[cfg(all(feature = "server", target_os = "linux", target_pointer_width = "64"))]
+    #[test]
+    fn test_volume() {
+        assert!(Volume::new("/").free_bytes().unwrap() > 0);
+        assert!(Volume::new("/nonexistent/path").free_bytes().is_err());
+    }
+}
@@ -0,0 +1,575 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    clock,
+    dane::{Tlsa, TlsaLookup},
+    metrics,
+    tls_rpt::ReportAddressLookup,
+    trace::random_u64,
+};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Mx,
+    Txt,
+    Tlsa,
+}
+
+impl RecordType {
+    fn code(&self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Aaaa => 28,
+            RecordType::Mx => 15,
+            RecordType::Txt => 16,
+            RecordType::Tlsa => 52,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Mx { preference: u16, exchange: String },
+    Txt(String),
+    Tlsa(Tlsa),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Answer {
+    pub records: Vec<Record>,
+    /// Whether the name server validated the answer with DNSSEC, i.e. set the AD bit.
+    /// Only worth anything when the path to that server is trusted, e.g. on the host.
+    pub authenticated: bool,
+}
+
+struct Cached {
+    answer: Answer,
+    expires: SystemTime,
+}
+
+/// A stub resolver asking recursive name servers over UDP, and over TCP for answers too
+/// long for a datagram. Answers, including the absence of records, are cached for their
+/// TTL. Queries, cache hits and failures are counted in `metrics`.
+pub struct Resolver {
+    /// Asked in order, the next one when one fails or does not answer in time.
+    pub servers: Vec<SocketAddr>,
+    /// How long each server has to answer.
+    pub timeout: Duration,
+    /// How many times the list of servers is gone through before giving up.
+    pub attempts: usize,
+    /// The longest an answer is cached in seconds, whatever its TTL.
+    pub max_ttl: u32,
+    /// How long in seconds a name without records is cached when the answer does not
+    /// say, i.e. carries no SOA record.
+    pub negative_ttl: u32,
+    cache: Mutex<HashMap<(String, RecordType), Cached>>,
+}
+
+impl Resolver {
+    pub fn new(servers: Vec<SocketAddr>) -> Resolver {
+        Resolver {
+            servers,
+            timeout: Duration::from_secs(5),
+            attempts: 2,
+            max_ttl: 86400,
+            negative_ttl: 300,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Asks the name servers in `/etc/resolv.conf`, or one on the host if it lists none.
+    pub fn system() -> Resolver {
+        let servers: Vec<SocketAddr> = fs::read_to_string(RESOLV_CONF)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+            .map(|address| SocketAddr::new(address, 53))
+            .collect();
+        if servers.is_empty() {
+            Resolver::new(vec![SocketAddr::from(([127, 0, 0, 1], 53))])
+        } else {
+            Resolver::new(servers)
+        }
+    }
+
+    /// The records of `kind` for `name`, from the cache while they are fresh. An empty
+    /// answer means the name does not exist or has no such records; an error that no
+    /// server gave an answer.
+    pub fn query(&self, name: &str, kind: RecordType) -> io::Result<Answer> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        let key = (name, kind);
+        let now = clock::now();
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.retain(|_, cached| cached.expires > now);
+            if let Some(cached) = cache.get(&key) {
+                metrics::increment(metrics::DNS_CACHE_HITS);
+                return Ok(cached.answer.clone());
+            }
+        }
+        metrics::increment(metrics::DNS_QUERIES);
+        let started = clock::now();
+        let result = self.resolve(&key.0, kind);
+        metrics::timing(metrics::DNS_DURATION, clock::elapsed(started));
+        let (answer, ttl) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                metrics::increment(metrics::DNS_FAILURES);
+                return Err(e);
+            }
+        };
+        let ttl = ttl.unwrap_or(self.negative_ttl).min(self.max_ttl);
+        if ttl > 0 {
+            self.cache.lock().unwrap().insert(
+                key,
+                Cached {
+                    answer: answer.clone(),
+                    expires: now + Duration::from_secs(u64::from(ttl)),
+                },
+            );
+        }
+        Ok(answer)
+    }
+
+    /// The addresses of `host`, IPv6 first.
+    pub fn ip_addrs(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Ok(address) = host.trim_matches(|c| c == '[' || c == ']').parse() {
+            return Ok(vec![address]);
+        }
+        let mut addresses = Vec::new();
+        for kind in [RecordType::Aaaa, RecordType::A].iter() {
+            for record in self.query(host, *kind)?.records {
+                match record {
+                    Record::A(address) => addresses.push(IpAddr::V4(address)),
+                    Record::Aaaa(address) => addresses.push(IpAddr::V6(address)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// The mail exchangers of `domain` by preference, the domain itself when it has none
+    /// (RFC 5321, section 5.1).
+    pub fn mx(&self, domain: &str) -> io::Result<Vec<String>> {
+        let mut exchanges: Vec<(u16, String)> = self
+            .query(domain, RecordType::Mx)?
+            .records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Mx {
+                    preference,
+                    exchange,
+                } => Some((preference, exchange)),
+                _ => None,
+            })
+            .collect();
+        if exchanges.is_empty() {
+            return Ok(vec![String::from(domain.trim_end_matches('.'))]);
+        }
+        exchanges.sort_by_key(|(preference, _)| *preference);
+        Ok(exchanges
+            .into_iter()
+            .map(|(_, exchange)| exchange)
+            .collect())
+    }
+
+    pub fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .query(name, RecordType::Txt)?
+            .records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Txt(text) => Some(text),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Goes through the servers until one answers, with the TTL to cache the answer for,
+    /// `None` for an empty answer without SOA record.
+    fn resolve(&self, name: &str, kind: RecordType) -> io::Result<(Answer, Option<u32>)> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no name servers");
+        for _ in 0..self.attempts.max(1) {
+            for server in self.servers.iter() {
+                // Unpredictable, as is the source port, so that answers are hard to forge.
+                let id = random_u64() as u16;
+                let query = encode_query(id, name, kind);
+                match self
+                    .exchange(*server, &query)
+                    .and_then(|response| parse_response(id, kind, &response))
+                {
+                    Ok(answer) => return Ok(answer),
+                    Err(e) => last_error = e,
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn exchange(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let bind: SocketAddr = if server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(server)?;
+        socket.send(query)?;
+        let mut buffer = [0; 512];
+        loop {
+            let length = socket.recv(&mut buffer)?;
+            let response = &buffer[..length];
+            // Datagrams answering other queries, or forged, are ignored.
+            if !answers(query, response) {
+                continue;
+            }
+            const TRUNCATED: u8 = 0x02;
+            if response.get(2).is_some_and(|flags| flags & TRUNCATED != 0) {
+                return self.exchange_tcp(server, query);
+            }
+            return Ok(response.to_vec());
+        }
+    }
+
+    fn exchange_tcp(&self, server: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+        stream.write_all(&message)?;
+        let mut length = [0; 2];
+        stream.read_exact(&mut length)?;
+        let mut response = vec![0; usize::from(u16::from_be_bytes(length))];
+        stream.read_exact(&mut response)?;
+        if !answers(query, &response) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "DNS response does not answer the query",
+            ));
+        }
+        Ok(response)
+    }
+}
+
+/// Whether `response` is a response to `query`: the same ID, the QR bit set, a standard
+/// query, and the question echoed byte for byte.
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    const RESPONSE: u8 = 0x80;
+    const OPCODE: u8 = 0x78;
+    // The query has one question and nothing after it.
+    let question = query.get(12..);
+    response.get(..2) == query.get(..2)
+        && response
+            .get(2)
+            .is_some_and(|flags| flags & RESPONSE != 0 && flags & OPCODE == 0)
+        && response.get(4..6) == query.get(4..6)
+        && response.get(12..query.len()) == question
+}
+
+/// Only DNSSEC validated records count for DANE, see `Answer::authenticated`.
+impl TlsaLookup for Resolver {
+    fn tlsa(&self, host: &str, port: u16) -> io::Result<Vec<Tlsa>> {
+        let answer = self.query(&format!("_{}._tcp.{}", port, host), RecordType::Tlsa)?;
+        if !answer.authenticated {
+            return Ok(Vec::new());
+        }
+        Ok(answer
+            .records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Tlsa(tlsa) => Some(tlsa),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+impl ReportAddressLookup for Resolver {
+    fn txt_record(&self, domain: &str) -> io::Result<Option<String>> {
+        Ok(self
+            .txt(&format!("_smtp._tls.{}", domain))?
+            .into_iter()
+            .find(|txt| txt.starts_with("v=TLSRPTv1")))
+    }
+}
+
+fn encode_query(id: u16, name: &str, kind: RecordType) -> Vec<u8> {
+    const RECURSION_DESIRED: u8 = 0x01;
+    // Asks a validating resolver to say whether the answer is authentic (RFC 6840).
+    const AUTHENTIC_DATA: u8 = 0x20;
+    let mut query = id.to_be_bytes().to_vec();
+    query.extend_from_slice(&[RECURSION_DESIRED, AUTHENTIC_DATA, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        query.push(label.len().min(63) as u8);
+        query.extend_from_slice(&label.as_bytes()[..label.len().min(63)]);
+    }
+    query.push(0);
+    query.extend_from_slice(&kind.code().to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query
+}
+
+fn parse_response(id: u16, kind: RecordType, message: &[u8]) -> io::Result<(Answer, Option<u32>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS response");
+    let u16_at = |offset: usize| -> Option<u16> {
+        message
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    if u16_at(0) != Some(id) {
+        return Err(malformed());
+    }
+    let flags = u16_at(2).ok_or_else(malformed)?;
+    const NXDOMAIN: u16 = 3;
+    match flags & 0x000f {
+        0 | NXDOMAIN => {}
+        rcode => {
+            return Err(io::Error::other(format!(
+                "name server answered rcode {}",
+                rcode
+            )))
+        }
+    }
+    let authenticated = flags & 0x0020 != 0;
+    let questions = u16_at(4).ok_or_else(malformed)?;
+    let answers = u16_at(6).ok_or_else(malformed)?;
+    let authorities = u16_at(8).ok_or_else(malformed)?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(message, offset).ok_or_else(malformed)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    let mut ttl: Option<u32> = None;
+    let mut negative_ttl = None;
+    for index in 0..u32::from(answers) + u32::from(authorities) {
+        let (_, end) = read_name(message, offset).ok_or_else(malformed)?;
+        let header = message.get(end..end + 10).ok_or_else(malformed)?;
+        let code = u16::from_be_bytes([header[0], header[1]]);
+        let record_ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let length = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let start = end + 10;
+        let data = message.get(start..start + length).ok_or_else(malformed)?;
+        offset = start + length;
+        if index >= u32::from(answers) {
+            const SOA: u16 = 6;
+            if code == SOA {
+                let (_, end) = read_name(message, start).ok_or_else(malformed)?;
+                let (_, end) = read_name(message, end).ok_or_else(malformed)?;
+                let minimum = message.get(end + 16..end + 20).ok_or_else(malformed)?;
+                let minimum = u32::from_be_bytes([minimum[0], minimum[1], minimum[2], minimum[3]]);
+                negative_ttl = Some(record_ttl.min(minimum));
+            }
+            continue;
+        }
+        if code != kind.code() {
+            continue;
+        }
+        let record = match kind {
+            RecordType::A if length == 4 => {
+                Record::A(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+            }
+            RecordType::Aaaa if length == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                Record::Aaaa(Ipv6Addr::from(octets))
+            }
+            RecordType::Mx if length > 2 => Record::Mx {
+                preference: u16::from_be_bytes([data[0], data[1]]),
+                exchange: read_name(message, start + 2).ok_or_else(malformed)?.0,
+            },
+            RecordType::Txt => {
+                let mut text = Vec::new();
+                let mut rest = data;
+                while let Some((&length, tail)) = rest.split_first() {
+                    let length = usize::from(length).min(tail.len());
+                    text.extend_from_slice(&tail[..length]);
+                    rest = &tail[length..];
+                }
+                Record::Txt(String::from_utf8_lossy(&text).into_owned())
+            }
+            RecordType::Tlsa if length > 3 => Record::Tlsa(Tlsa {
+                usage: data[0],
+                selector: data[1],
+                matching: data[2],
+                data: data[3..].to_vec(),
+            }),
+            _ => return Err(malformed()),
+        };
+        ttl = Some(ttl.map_or(record_ttl, |ttl| ttl.min(record_ttl)));
+        records.push(record);
+    }
+    let ttl = if records.is_empty() {
+        negative_ttl
+    } else {
+        ttl
+    };
+    Ok((
+        Answer {
+            records,
+            authenticated,
+        },
+        ttl,
+    ))
+}
+
+/// The possibly compressed name at `offset` of `message`, and where the data after it
+/// starts.
+fn read_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Pointers only lead backwards in a well formed message, this bounds a malformed one.
+    for _ in 0..message.len() {
+        let length = *message.get(offset)?;
+        match length {
+            0 => {
+                return Some((labels.join("."), end.unwrap_or(offset + 1)));
+            }
+            length if length & 0xc0 == 0xc0 => {
+                let pointer =
+                    usize::from(length & 0x3f) << 8 | usize::from(*message.get(offset + 1)?);
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            length => {
+                let label = message.get(offset + 1..offset + 1 + usize::from(length))?;
+                labels.push(String::from_utf8_lossy(label).to_lowercase());
+                offset += 1 + usize::from(length);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    /// A response to `query` with `answers` as (type, TTL, data) and, when there are none,
+    /// an SOA record with a minimum TTL of 60.
+    fn response(query: &[u8], answers: &[(u16, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut message = query.to_vec();
+        message[2] = 0x81;
+        message[3] = 0xa0;
+        message[6..8].copy_from_slice(&(answers.len() as u16).to_be_bytes());
+        if answers.is_empty() {
+            message[9] = 1;
+        }
+        for (code, ttl, data) in answers.iter() {
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(&code.to_be_bytes());
+            message.extend_from_slice(&[0, 1]);
+            message.extend_from_slice(&ttl.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        if answers.is_empty() {
+            let mut soa = vec![2, b'n', b's', 0xc0, 12, 0];
+            for value in [1u32, 2, 3, 4, 60].iter() {
+                soa.extend_from_slice(&value.to_be_bytes());
+            }
+            message.extend_from_slice(&[0xc0, 12, 0, 6, 0, 1, 0, 0, 14, 16]);
+            message.extend_from_slice(&(soa.len() as u16).to_be_bytes());
+            message.extend_from_slice(&soa);
+        }
+        message
+    }
+
+    #[test]
+    fn test_query() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let queries = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&queries);
+        thread::spawn(move || {
+            let mut buffer = [0; 512];
+            while let Ok((length, peer)) = server.recv_from(&mut buffer) {
+                *counted.lock().unwrap() += 1;
+                let query = &buffer[..length];
+                let (name, end) = read_name(query, 12).unwrap();
+                let code = u16::from_be_bytes([query[end], query[end + 1]]);
+                let answers = match (name.as_str(), code) {
+                    ("example.com", 15) => vec![
+                        (15, 300, vec![0, 20, 3, b'm', b'x', b'2', 0xc0, 12]),
+                        (15, 600, vec![0, 10, 3, b'm', b'x', b'1', 0xc0, 12]),
+                    ],
+                    ("mx1.example.com", 1) => vec![(1, 300, vec![192, 0, 2, 1])],
+                    ("example.com", 16) => vec![(16, 300, b"\x05hello\x06 world".to_vec())],
+                    _ => Vec::new(),
+                };
+                server.send_to(&response(query, &answers), peer).unwrap();
+            }
+        });
+
+        let resolver = Resolver::new(vec![address]);
+        assert_eq!(
+            resolver.mx("Example.com.").unwrap(),
+            vec!["mx1.example.com", "mx2.example.com"]
+        );
+        assert_eq!(
+            resolver.ip_addrs("mx1.example.com").unwrap(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(resolver.txt("example.com").unwrap(), vec!["hello world"]);
+        assert_eq!(resolver.mx("example.org").unwrap(), vec!["example.org"]);
+        let asked = *queries.lock().unwrap();
+        resolver.mx("example.com").unwrap();
+        resolver.mx("example.org").unwrap();
+        assert_eq!(*queries.lock().unwrap(), asked);
+        assert!(
+            resolver
+                .query("example.com", RecordType::Mx)
+                .unwrap()
+                .authenticated
+        );
+    }
+
+    #[test]
+    fn test_answers() {
+        let query = encode_query(0x1234, "example.com", RecordType::A);
+        let answer = response(&query, &[(1, 300, vec![192, 0, 2, 1])]);
+        assert!(answers(&query, &answer));
+        // The query itself, reflected.
+        assert!(!answers(&query, &query));
+        let mut other_id = answer.clone();
+        other_id[1] ^= 1;
+        assert!(!answers(&query, &other_id));
+        let mut other_opcode = answer.clone();
+        other_opcode[2] |= 0x10;
+        assert!(!answers(&query, &other_opcode));
+        let forged = response(
+            &encode_query(0x1234, "example.org", RecordType::A),
+            &[(1, 300, vec![192, 0, 2, 1])],
+        );
+        assert!(!answers(&query, &forged));
+        let other_type = response(&encode_query(0x1234, "example.com", RecordType::Aaaa), &[]);
+        assert!(!answers(&query, &other_type));
+        assert!(!answers(&query, &answer[..20]));
+    }
+
+    #[test]
+    fn test_unanswered() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut resolver = Resolver::new(vec![server.local_addr().unwrap()]);
+        resolver.timeout = Duration::from_millis(50);
+        resolver.attempts = 1;
+        assert!(resolver.query("example.com", RecordType::A).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{
+    email::{domain_of, Mail},
+    output::Output,
+};
+
+/// A recipient domain the server accepts mail for.
+pub struct Domain {
+    pub name: String,
+    /// Messages with recipients in this domain are also handed to each of these, with only
+    /// those recipients, e.g. to store every domain separately.
+    pub outputs: Vec<Box<dyn Output>>,
+}
+
+impl Domain {
+    pub fn new(name: &str) -> Domain {
+        Domain {
+            name: String::from(name),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// The message as delivered to this domain, `None` when none of its recipients is here.
+    pub fn route(&self, mail: &Mail) -> Option<Mail> {
+        let rcpt_to: Vec<String> = mail
+            .rcpt_to
+            .iter()
+            .filter(|rcpt| domain_of(rcpt).is_some_and(|domain| self.matches(domain)))
+            .cloned()
+            .collect();
+        if rcpt_to.is_empty() {
+            return None;
+        }
+        Some(Mail {
+            rcpt_to,
+            ..mail.clone()
+        })
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        self.name.eq_ignore_ascii_case(domain.trim_end_matches('.'))
+    }
+}
+
+/// The domains the server accepts mail for. When empty, any recipient is accepted.
+#[derive(Default)]
+pub struct Domains {
+    domains: Vec<Domain>,
+}
+
+impl Domains {
+    pub fn add(&mut self, domain: Domain) {
+        self.domains.push(domain);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+
+    /// Whether RCPT TO `address` may be accepted. `<postmaster>` always is, as RFC 5321
+    /// requires.
+    pub fn accepts(&self, address: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        match domain_of(address) {
+            Some(domain) => self.domains.iter().any(|known| known.matches(domain)),
+            None => address
+                .trim()
+                .trim_matches(|c| c == '<' || c == '>')
+                .eq_ignore_ascii_case("postmaster"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Domain> {
+        self.domains.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domains() {
+        let mut domains = Domains::default();
+        assert!(domains.accepts("<anyone@anywhere.org>"));
+        domains.add(Domain::new("example.com"));
+        assert!(domains.accepts("<user@Example.COM>"));
+        assert!(!domains.accepts("<user@example.org>"));
+        assert!(domains.accepts("<Postmaster>"));
+
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![
+            String::from("<a@example.com>"),
+            String::from("<b@example.org>"),
+        ];
+        let domain = domains.iter().next().unwrap();
+        assert_eq!(
+            domain.route(&mail).unwrap().rcpt_to,
+            vec!["<a@example.com>"]
+        );
+        mail.rcpt_to.remove(0);
+        assert!(domain.route(&mail).is_none());
+    }
+}
@@ -0,0 +1,588 @@
+use std::time::UNIX_EPOCH;
+
+use crate::{
+    clock, datetime,
+    email::Mail,
+    headers::Message,
+    json::{FromJson, Json, ToJson},
+    reply::Reply,
+};
+
+/// How much of the message a failure report returns, from the `RET` parameter of MAIL FROM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ret {
+    Full,
+    Headers,
+}
+
+impl Ret {
+    fn parse(value: &str) -> Option<Ret> {
+        match value.to_uppercase().as_str() {
+            "FULL" => Some(Ret::Full),
+            "HDRS" => Some(Ret::Headers),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Ret::Full => "FULL",
+            Ret::Headers => "HDRS",
+        }
+    }
+}
+
+/// The `NOTIFY` parameter of RCPT TO; `NEVER` is all false.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl Notify {
+    pub const NEVER: Notify = Notify {
+        success: false,
+        failure: false,
+        delay: false,
+    };
+
+    fn parse(value: &str) -> Option<Notify> {
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Some(Notify::NEVER);
+        }
+        let mut notify = Notify::NEVER;
+        for keyword in value.split(',') {
+            match keyword.to_uppercase().as_str() {
+                "SUCCESS" => notify.success = true,
+                "FAILURE" => notify.failure = true,
+                "DELAY" => notify.delay = true,
+                _ => return None,
+            }
+        }
+        Some(notify)
+    }
+
+    fn to_parameter(self) -> String {
+        let mut keywords = Vec::new();
+        if self.success {
+            keywords.push("SUCCESS");
+        }
+        if self.failure {
+            keywords.push("FAILURE");
+        }
+        if self.delay {
+            keywords.push("DELAY");
+        }
+        if keywords.is_empty() {
+            keywords.push("NEVER");
+        }
+        keywords.join(",")
+    }
+}
+
+/// Failures and delays are reported when the client does not say, as RFC 3461 suggests.
+impl Default for Notify {
+    fn default() -> Notify {
+        Notify {
+            success: false,
+            failure: true,
+            delay: true,
+        }
+    }
+}
+
+/// The DSN parameters given with one recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientDsn {
+    /// The recipient without angle brackets, as accepted.
+    pub address: String,
+    pub notify: Option<Notify>,
+    /// The original recipient as given, e.g. `rfc822;user+2Bx@example.com` in xtext.
+    pub orcpt: Option<String>,
+}
+
+/// The DSN parameters of a transaction (RFC 3461).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dsn {
+    pub ret: Option<Ret>,
+    /// The envelope id chosen by the client, in xtext.
+    pub envid: Option<String>,
+    pub recipients: Vec<RecipientDsn>,
+}
+
+impl ToJson for Dsn {
+    fn to_json(&self) -> Json {
+        let recipients = self
+            .recipients
+            .iter()
+            .map(|recipient| {
+                Json::object(vec![
+                    ("address", Json::string(&recipient.address)),
+                    (
+                        "notify",
+                        Json::optional(&recipient.notify.map(Notify::to_parameter)),
+                    ),
+                    ("orcpt", Json::optional(&recipient.orcpt)),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            (
+                "ret",
+                Json::optional(&self.ret.map(|ret| String::from(ret.as_str()))),
+            ),
+            ("envid", Json::optional(&self.envid)),
+            ("recipients", Json::Array(recipients)),
+        ])
+    }
+}
+
+impl FromJson for Dsn {
+    fn from_json(json: &Json) -> Option<Dsn> {
+        let optional = |json: &Json, name: &str| match json.get(name) {
+            Some(value) => value.as_optional_string(),
+            None => Some(None),
+        };
+        let ret = match optional(json, "ret")? {
+            Some(ret) => Some(Ret::parse(&ret)?),
+            None => None,
+        };
+        let mut recipients = Vec::new();
+        for recipient in json
+            .get("recipients")
+            .map_or(Some(&Vec::new()), Json::as_array)?
+        {
+            let notify = match optional(recipient, "notify")? {
+                Some(notify) => Some(Notify::parse(&notify)?),
+                None => None,
+            };
+            recipients.push(RecipientDsn {
+                address: String::from(recipient.get("address")?.as_str()?),
+                notify,
+                orcpt: optional(recipient, "orcpt")?,
+            });
+        }
+        Some(Dsn {
+            ret,
+            envid: optional(json, "envid")?,
+            recipients,
+        })
+    }
+}
+
+impl Dsn {
+    pub fn recipient(&self, rcpt: &str) -> Option<&RecipientDsn> {
+        let (address, _) = split_parameters(rcpt);
+        let address = bare(address);
+        self.recipients
+            .iter()
+            .find(|recipient| recipient.address.eq_ignore_ascii_case(address))
+    }
+
+    /// What the client wants to hear about `rcpt`.
+    pub fn notify(&self, rcpt: &str) -> Notify {
+        self.recipient(rcpt)
+            .and_then(|recipient| recipient.notify)
+            .unwrap_or_default()
+    }
+
+    /// The parameters to pass on with MAIL FROM, each preceded by a space.
+    pub fn mail_parameters(&self) -> String {
+        let mut parameters = String::new();
+        if let Some(ret) = self.ret {
+            parameters.push_str(&format!(" RET={}", ret.as_str()));
+        }
+        if let Some(envid) = &self.envid {
+            parameters.push_str(&format!(" ENVID={}", envid));
+        }
+        parameters
+    }
+
+    /// The parameters to pass on with RCPT TO for `rcpt`, each preceded by a space.
+    pub fn rcpt_parameters(&self, rcpt: &str) -> String {
+        let mut parameters = String::new();
+        if let Some(recipient) = self.recipient(rcpt) {
+            if let Some(notify) = recipient.notify {
+                parameters.push_str(&format!(" NOTIFY={}", notify.to_parameter()));
+            }
+            if let Some(orcpt) = &recipient.orcpt {
+                parameters.push_str(&format!(" ORCPT={}", orcpt));
+            }
+        }
+        parameters
+    }
+
+    /// Takes `RET` and `ENVID` from the parameters of MAIL FROM.
+    pub(crate) fn parse_mail(&mut self, parameters: &[(String, &str)]) -> Result<(), String> {
+        for (keyword, value) in parameters.iter() {
+            match keyword.as_str() {
+                "RET" => {
+                    self.ret =
+                        Some(Ret::parse(value).ok_or_else(|| format!("Invalid RET {}", value))?)
+                }
+                "ENVID" if is_xtext(value) && value.len() <= 100 => {
+                    self.envid = Some(String::from(*value))
+                }
+                "ENVID" => return Err(format!("Invalid ENVID {}", value)),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes `NOTIFY` and `ORCPT` from the parameters of RCPT TO for each of `addresses`,
+    /// which are the recipient itself or what it expands to.
+    pub(crate) fn parse_rcpt(
+        &mut self,
+        addresses: &[&str],
+        parameters: &[(String, &str)],
+    ) -> Result<(), String> {
+        let mut notify = None;
+        let mut orcpt = None;
+        for (keyword, value) in parameters.iter() {
+            match keyword.as_str() {
+                "NOTIFY" => {
+                    notify = Some(
+                        Notify::parse(value).ok_or_else(|| format!("Invalid NOTIFY {}", value))?,
+                    )
+                }
+                "ORCPT" => match value.split_once(';') {
+                    Some((kind, address)) if !kind.is_empty() && is_xtext(address) => {
+                        orcpt = Some(String::from(*value))
+                    }
+                    _ => return Err(format!("Invalid ORCPT {}", value)),
+                },
+                _ => {}
+            }
+        }
+        if notify.is_none() && orcpt.is_none() {
+            return Ok(());
+        }
+        for address in addresses.iter() {
+            let (address, _) = split_parameters(address);
+            self.recipients.push(RecipientDsn {
+                address: String::from(bare(address)),
+                notify,
+                orcpt: orcpt.clone(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Splits the argument of MAIL FROM or RCPT TO into the address and its ESMTP parameters,
+/// keywords uppercased, e.g. `<a@example.com> RET=HDRS`.
+pub fn split_parameters(argument: &str) -> (&str, Vec<(String, &str)>) {
+    let argument = argument.trim();
+    let end = match argument.starts_with('<') {
+        true => argument.find('>').map_or(argument.len(), |end| end + 1),
+        false => argument.find(char::is_whitespace).unwrap_or(argument.len()),
+    };
+    let (address, rest) = argument.split_at(end);
+    let parameters = rest
+        .split_whitespace()
+        .map(|parameter| {
+            let (keyword, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            (keyword.to_uppercase(), value)
+        })
+        .collect();
+    (address, parameters)
+}
+
+fn bare(address: &str) -> &str {
+    address.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+fn is_xtext(value: &str) -> bool {
+    value
+        .bytes()
+        .all(|byte| (b'!'..=b'~').contains(&byte) && byte != b'=')
+}
+
+/// Decodes the `+XX` escapes of RFC 3461 xtext.
+pub fn xtext_decode(value: &str) -> String {
+    let mut decoded = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = value
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'+')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encodes `value` as xtext, escaping `+`, `=` and anything outside printable ASCII.
+pub fn xtext_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'+' | b'=' => format!("+{:02X}", byte),
+            b'!'..=b'~' => String::from(byte as char),
+            _ => format!("+{:02X}", byte),
+        })
+        .collect()
+}
+
+/// What a report says happened to the recipients it lists.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Failed,
+    Delayed,
+}
+
+impl Action {
+    /// The `Action` field of the report.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Failed => "failed",
+            Action::Delayed => "delayed",
+        }
+    }
+}
+
+/// The failure report (RFC 3464) to send to the sender of `mail` for the recipients that
+/// could not be delivered, or `None` when nobody is to be told: the sender is null, or
+/// every failed recipient asked for no failure notice. The returned message has the null
+/// sender so that it cannot bounce in turn, and holds only the headers of the original
+/// when the client gave `RET=HDRS`.
+pub fn failure_report(
+    server_name: &str,
+    mail: &Mail,
+    failures: &[(String, Reply)],
+) -> Option<Mail> {
+    report(server_name, mail, Action::Failed, failures)
+}
+
+/// Like `failure_report`, for recipients that are still being tried but late, e.g. past
+/// their deliver-by time, and asked to hear about delays.
+pub fn delay_report(server_name: &str, mail: &Mail, delays: &[(String, Reply)]) -> Option<Mail> {
+    report(server_name, mail, Action::Delayed, delays)
+}
+
+fn report(
+    server_name: &str,
+    mail: &Mail,
+    action: Action,
+    recipients: &[(String, Reply)],
+) -> Option<Mail> {
+    let sender = mail.mail_from.as_deref().map(split_parameters)?.0;
+    if bare(sender).is_empty() {
+        return None;
+    }
+    let failures: Vec<&(String, Reply)> = recipients
+        .iter()
+        .filter(|(rcpt, _)| match action {
+            Action::Failed => mail.dsn.notify(rcpt).failure,
+            Action::Delayed => mail.dsn.notify(rcpt).delay,
+        })
+        .collect();
+    if failures.is_empty() {
+        return None;
+    }
+    let now = clock::now();
+    let boundary = format!(
+        "{}/{}",
+        now.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+        server_name
+    );
+    let mut data = String::new();
+    data.push_str(&format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{}>\r\n",
+        server_name
+    ));
+    data.push_str(&format!("To: <{}>\r\n", bare(sender)));
+    let (subject, summary) = match action {
+        Action::Failed => (
+            "Undelivered Mail Returned to Sender",
+            "could not be delivered",
+        ),
+        Action::Delayed => ("Delayed Mail (still being retried)", "is late for"),
+    };
+    data.push_str(&format!("Subject: {}\r\n", subject));
+    data.push_str(&format!("Date: {}\r\n", datetime::rfc5322(now)));
+    data.push_str("Auto-Submitted: auto-replied\r\n");
+    data.push_str("MIME-Version: 1.0\r\n");
+    data.push_str(&format!(
+        "Content-Type: multipart/report; report-type=delivery-status; boundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+
+    data.push_str(&format!(
+        "--{}\r\nContent-Type: text/plain; charset=us-ascii\r\n\r\n",
+        boundary
+    ));
+    data.push_str(&format!(
+        "This is the mail system at {}. Your message {}:\r\n\r\n",
+        server_name, summary
+    ));
+    for (rcpt, reply) in failures.iter() {
+        let address = bare(split_parameters(rcpt).0);
+        data.push_str(&format!(
+            "<{}>: {} {}\r\n",
+            address,
+            reply.code,
+            reply.text()
+        ));
+    }
+
+    data.push_str(&format!(
+        "\r\n--{}\r\nContent-Type: message/delivery-status\r\n\r\n",
+        boundary
+    ));
+    data.push_str(&format!("Reporting-MTA: dns; {}\r\n", server_name));
+    if let Some(envid) = &mail.dsn.envid {
+        data.push_str(&format!(
+            "Original-Envelope-Id: {}\r\n",
+            xtext_decode(envid)
+        ));
+    }
+    for (rcpt, reply) in failures.iter() {
+        let address = bare(split_parameters(rcpt).0);
+        data.push_str(&format!("\r\nFinal-Recipient: rfc822; {}\r\n", address));
+        if let Some(orcpt) = mail.dsn.recipient(rcpt).and_then(|r| r.orcpt.as_ref()) {
+            let (kind, original) = orcpt.split_once(';').unwrap_or(("rfc822", orcpt));
+            data.push_str(&format!(
+                "Original-Recipient: {}; {}\r\n",
+                kind,
+                xtext_decode(original)
+            ));
+        }
+        let status = reply.enhanced.map_or_else(
+            || format!("{}.0.0", reply.code / 100),
+            |code| code.to_string(),
+        );
+        data.push_str(&format!("Action: {}\r\n", action.as_str()));
+        data.push_str(&format!("Status: {}\r\n", status));
+        data.push_str(&format!(
+            "Diagnostic-Code: smtp; {} {}\r\n",
+            reply.code,
+            reply.text()
+        ));
+    }
+
+    let original = mail.data.as_deref().unwrap_or("");
+    match mail.dsn.ret {
+        Some(Ret::Headers) => {
+            let mut message = Message::parse(original);
+            message.body = String::new();
+            data.push_str(&format!(
+                "\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n{}",
+                boundary, message
+            ));
+        }
+        _ => data.push_str(&format!(
+            "\r\n--{}\r\nContent-Type: message/rfc822\r\n\r\n{}",
+            boundary, original
+        )),
+    }
+    data.push_str(&format!("\r\n--{}--\r\n", boundary));
+
+    let mut report = Mail::new();
+    report.helo = Some(String::from(server_name));
+    report.mail_from = Some(String::from("<>"));
+    report.rcpt_to = vec![format!("<{}>", bare(sender))];
+    report.data = Some(data);
+    Some(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_parameters() {
+        let (address, parameters) = split_parameters(" <a@example.com> ret=hdrs ENVID=x+2By");
+        assert_eq!(address, "<a@example.com>");
+        assert_eq!(
+            parameters,
+            vec![
+                (String::from("RET"), "hdrs"),
+                (String::from("ENVID"), "x+2By")
+            ]
+        );
+        assert_eq!(split_parameters("a@example.com").0, "a@example.com");
+        assert_eq!(xtext_decode("x+2By"), "x+y");
+        assert_eq!(xtext_encode("x+y=z"), "x+2By+3Dz");
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut dsn = Dsn::default();
+        let (_, parameters) = split_parameters("<a@example.com> RET=HDRS ENVID=QQ314159");
+        dsn.parse_mail(&parameters).unwrap();
+        assert_eq!(dsn.mail_parameters(), " RET=HDRS ENVID=QQ314159");
+        let (_, parameters) =
+            split_parameters("<b@example.com> NOTIFY=failure,delay ORCPT=rfc822;b@example.com");
+        dsn.parse_rcpt(&["<b@example.com>"], &parameters).unwrap();
+        assert_eq!(
+            dsn.rcpt_parameters("<B@example.com>"),
+            " NOTIFY=FAILURE,DELAY ORCPT=rfc822;b@example.com"
+        );
+        assert_eq!(dsn.rcpt_parameters("<c@example.com>"), "");
+        assert_eq!(dsn.notify("<c@example.com>"), Notify::default());
+
+        let (_, parameters) = split_parameters("<a@example.com> RET=ALL");
+        assert!(dsn.parse_mail(&parameters).is_err());
+        let (_, parameters) = split_parameters("<b@example.com> NOTIFY=NEVER,SUCCESS");
+        assert!(dsn.parse_rcpt(&["<b@example.com>"], &parameters).is_err());
+    }
+
+    #[test]
+    fn test_failure_report() {
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.com> RET=HDRS"));
+        mail.rcpt_to = vec![
+            String::from("<b@example.com>"),
+            String::from("<c@example.com> NOTIFY=NEVER"),
+        ];
+        mail.data = Some(String::from("Subject: hi\r\n\r\nsecret\r\n"));
+        mail.dsn.ret = Some(Ret::Headers);
+        mail.dsn.envid = Some(String::from("id+2B1"));
+        mail.dsn.recipients.push(RecipientDsn {
+            address: String::from("c@example.com"),
+            notify: Some(Notify::NEVER),
+            orcpt: None,
+        });
+        let failures = vec![
+            (
+                String::from("<b@example.com>"),
+                Reply::new(550, "5.1.1 No such user"),
+            ),
+            (
+                String::from("<c@example.com> NOTIFY=NEVER"),
+                Reply::new(550, "5.1.1 No such user"),
+            ),
+        ];
+        let report = failure_report("mx.example.com", &mail, &failures).unwrap();
+        assert_eq!(report.mail_from.as_deref(), Some("<>"));
+        assert_eq!(report.rcpt_to, vec!["<a@example.com>"]);
+        let data = report.data.unwrap();
+        assert!(data.contains("Final-Recipient: rfc822; b@example.com\r\n"));
+        assert!(!data.contains("c@example.com"));
+        assert!(data.contains("Status: 5.1.1\r\n"));
+        assert!(data.contains("Original-Envelope-Id: id+1\r\n"));
+        assert!(data.contains("Subject: hi\r\n"));
+        assert!(!data.contains("secret"));
+
+        assert!(failure_report("mx.example.com", &mail, &failures[1..]).is_none());
+        let delay = delay_report("mx.example.com", &mail, &failures).unwrap();
+        assert!(delay.data.unwrap().contains("Action: delayed\r\n"));
+        mail.mail_from = Some(String::from("<>"));
+        assert!(failure_report("mx.example.com", &mail, &failures).is_none());
+    }
+}
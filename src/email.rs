@@ -1,7 +1,54 @@
-use std::fmt::Display;
+use core::net::{IpAddr, SocketAddr};
+use std::{convert::TryFrom, fmt::Display, io, sync::Arc, time::UNIX_EPOCH};
 
-#[derive(PartialEq)]
-enum State {
+use crate::{
+    clock,
+    config::Config,
+    datetime,
+    deliver_by::{self, DeliverBy},
+    dsn::{self, Dsn},
+    encoding,
+    handler::Verdict,
+    headers::{self, Message},
+    json::{FromJson, Json, ToJson},
+    log,
+    memory::Allocation,
+    metrics,
+    output::{self, Output},
+    policy::Policy,
+    queue,
+    reply::Reply,
+    script::{Hook, Scope},
+    session::SessionContext,
+    trace,
+    transport::Transport,
+};
+#[cfg(feature = "server")]
+use crate::{
+    milter::{self, Milter, MilterSession, Modification, Response},
+    policy_service::Stage,
+    spam,
+};
+
+/// Returns the domain of an address as given in MAIL FROM/RCPT TO, e.g. `<user@domain>`.
+pub fn domain_of(address: &str) -> Option<&str> {
+    let address = address.trim().trim_start_matches('<');
+    let address = address.split('>').next().unwrap_or("");
+    address.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// Returns the local part of an address, the whole address when it has no domain.
+pub fn local_part_of(address: &str) -> &str {
+    let address = address.trim().trim_start_matches('<');
+    let address = address.split('>').next().unwrap_or("");
+    address
+        .rsplit_once('@')
+        .map_or(address, |(local_part, _)| local_part)
+}
+
+/// Where a session is in the protocol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
     New,
     Hello,
     MailFrom,
@@ -10,20 +57,124 @@ enum State {
     Quit,
 }
 
+/// The kind of reply a command gets, from the first digit of its code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyClass {
+    /// 2xx, done.
+    Completed,
+    /// 3xx, go on, e.g. with the message after DATA.
+    Intermediate,
+    /// 4xx, refused for now.
+    TransientFailure,
+    /// 5xx, refused.
+    PermanentFailure,
+}
+
+impl ReplyClass {
+    pub fn of(code: u16) -> Option<ReplyClass> {
+        match code / 100 {
+            2 => Some(ReplyClass::Completed),
+            3 => Some(ReplyClass::Intermediate),
+            4 => Some(ReplyClass::TransientFailure),
+            5 => Some(ReplyClass::PermanentFailure),
+            _ => None,
+        }
+    }
+}
+
+/// A command the session accepts in a state, see `MailFSM::transition_table`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transition {
+    pub from: State,
+    /// The start of the command in upper case, e.g. `MAIL FROM:`, or `.` ending DATA.
+    pub verb: &'static str,
+    /// Where the session is once the command succeeded.
+    pub to: State,
+    /// The reply when it succeeded; a command refused with 4xx or 5xx leaves the state
+    /// as it was.
+    pub reply: ReplyClass,
+}
+
+/// Where a message came from, as the session saw it, for outputs that pass it on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origin {
+    pub address: Option<IpAddr>,
+    pub name: Option<String>,
+    pub protocol: Option<String>,
+}
+
+impl ToJson for Origin {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            (
+                "address",
+                Json::optional(&self.address.map(|address| address.to_string())),
+            ),
+            ("name", Json::optional(&self.name)),
+            ("protocol", Json::optional(&self.protocol)),
+        ])
+    }
+}
+
+impl FromJson for Origin {
+    fn from_json(json: &Json) -> Option<Origin> {
+        let optional = |name: &str| match json.get(name) {
+            Some(value) => value.as_optional_string(),
+            None => Some(None),
+        };
+        let address = match optional("address")? {
+            Some(address) => Some(address.parse().ok()?),
+            None => None,
+        };
+        Some(Origin {
+            address,
+            name: optional("name")?,
+            protocol: optional("protocol")?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct Mail {
+    /// Assigned once the message is accepted at the end of DATA.
+    pub queue_id: Option<String>,
     pub helo: Option<String>,
     pub mail_from: Option<String>,
     pub rcpt_to: Vec<String>,
     pub data: Option<String>,
+    /// The delivery status notification parameters given with MAIL FROM and RCPT TO.
+    pub dsn: Dsn,
+    /// The deadline set with the `BY` parameter of MAIL FROM.
+    pub deliver_by: Option<DeliverBy>,
+    /// Whether the sender asked with REQUIRETLS (RFC 8689) that the message only travels
+    /// over validated TLS.
+    pub require_tls: bool,
+    /// From the `MT-PRIORITY` parameter (RFC 6710), -9 lowest to 9 highest, 0 when absent.
+    pub priority: i8,
+    /// Who submitted the message, from the `AUTH` parameter of MAIL FROM (RFC 4954) when
+    /// the client may assert it, or the identity the client authenticated as.
+    pub auth: Option<String>,
+    pub origin: Origin,
+    /// The folder local delivery files the message into, the inbox when unset, as a Sieve
+    /// script decided, see `sieve::Sieve`.
+    pub folder: Option<String>,
 }
 
 impl Mail {
     pub fn new() -> Mail {
         Mail {
+            queue_id: None,
             helo: None,
             mail_from: None,
             rcpt_to: Vec::new(),
             data: None,
+            dsn: Dsn::default(),
+            deliver_by: None,
+            require_tls: false,
+            priority: 0,
+            auth: None,
+            origin: Origin::default(),
+            folder: None,
         }
     }
 
@@ -40,11 +191,103 @@ impl Mail {
     }
 
     fn add_data_chunk(&mut self, data_chunk: &str) {
-        if let None = self.data {
-            self.data = Some(String::from(data_chunk));
-        } else {
-            self.data.as_mut().map(|s| s.push_str(data_chunk));
+        match self.data.as_mut() {
+            Some(data) => data.push_str(data_chunk),
+            None => self.data = Some(String::from(data_chunk)),
+        }
+    }
+}
+
+impl Default for Mail {
+    fn default() -> Mail {
+        Mail::new()
+    }
+}
+
+/// Every field, so that a message survives the journal, quarantine or an output intact;
+/// one added to `Mail` needs a mapping here and in `from_json`.
+impl ToJson for Mail {
+    fn to_json(&self) -> Json {
+        let mut fields = vec![
+            ("queue_id", Json::optional(&self.queue_id)),
+            ("helo", Json::optional(&self.helo)),
+            ("mail_from", Json::optional(&self.mail_from)),
+            (
+                "rcpt_to",
+                Json::Array(self.rcpt_to.iter().map(|rcpt| Json::string(rcpt)).collect()),
+            ),
+            ("data", Json::optional(&self.data)),
+        ];
+        // The parameters of MAIL FROM and RCPT TO only when given, as clients rarely do.
+        if self.dsn != Dsn::default() {
+            fields.push(("dsn", self.dsn.to_json()));
+        }
+        if let Some(deliver_by) = &self.deliver_by {
+            fields.push(("deliver_by", deliver_by.to_json()));
+        }
+        if self.require_tls {
+            fields.push(("require_tls", Json::Bool(true)));
+        }
+        if self.priority != 0 {
+            fields.push(("priority", Json::Number(self.priority.into())));
         }
+        if let Some(auth) = &self.auth {
+            fields.push(("auth", Json::string(auth)));
+        }
+        if self.origin != Origin::default() {
+            fields.push(("origin", self.origin.to_json()));
+        }
+        if let Some(folder) = &self.folder {
+            fields.push(("folder", Json::string(folder)));
+        }
+        Json::object(fields)
+    }
+}
+
+impl FromJson for Mail {
+    fn from_json(json: &Json) -> Option<Mail> {
+        let optional = |name: &str| match json.get(name) {
+            Some(value) => value.as_optional_string(),
+            None => Some(None),
+        };
+        let rcpt_to = match json.get("rcpt_to") {
+            Some(rcpt_to) => rcpt_to
+                .as_array()?
+                .iter()
+                .map(|rcpt| rcpt.as_str().map(String::from))
+                .collect::<Option<Vec<String>>>()?,
+            None => Vec::new(),
+        };
+        let priority = match json.get("priority") {
+            Some(priority) => i8::try_from(priority.as_i64()?).ok()?,
+            None => 0,
+        };
+        Some(Mail {
+            queue_id: optional("queue_id")?,
+            helo: optional("helo")?,
+            mail_from: optional("mail_from")?,
+            rcpt_to,
+            data: optional("data")?,
+            dsn: match json.get("dsn") {
+                Some(dsn) => Dsn::from_json(dsn)?,
+                None => Dsn::default(),
+            },
+            deliver_by: match json.get("deliver_by") {
+                Some(deliver_by) => Some(DeliverBy::from_json(deliver_by)?),
+                None => None,
+            },
+            require_tls: match json.get("require_tls") {
+                Some(require_tls) => require_tls.as_bool()?,
+                None => false,
+            },
+            priority,
+            auth: optional("auth")?,
+            origin: match json.get("origin") {
+                Some(origin) => Origin::from_json(origin)?,
+                None => Origin::default(),
+            },
+            folder: optional("folder")?,
+        })
     }
 }
 
@@ -68,136 +311,2996 @@ impl Display for Mail {
             output.push_str(&format!("DATA \n{}\n", data));
         }
 
-        write!(f, "{}\n", output)
+        writeln!(f, "{}", output)
     }
 }
 
 pub struct MailFSM {
     current_state: State,
-    server_name: String,
+    config: Arc<Config>,
+    transaction: Option<log::SpanGuard>,
+    transaction_trace: Option<trace::Span>,
+    /// The first refusal of a data chunk, answered at the end of DATA.
+    data_verdict: Option<Verdict>,
+    /// What the spooled message holds of the memory budget.
+    spool: Allocation,
+    /// Set when the message went over the memory budget, and is no longer spooled.
+    spool_exceeded: bool,
+    /// Copied from the configuration, and possibly replaced for this session only.
+    policy: Policy,
+    context: SessionContext,
+    /// Replies owed after the one `process_line` returned, see `pending_replies`.
+    pending: Vec<Reply>,
+    /// Whether the last message went to the quarantine, see `is_quarantined`.
+    quarantined: bool,
+    /// Set after AUTH without an initial response, until the client sent its credentials.
+    authenticating: bool,
+    /// The transactions started so far, which number them for a policy service.
+    transactions: u32,
+    /// The session with each of `Config::milters`, `None` while there is none.
+    #[cfg(feature = "server")]
+    milters: Vec<Option<MilterSession>>,
+    /// Whether a milter asked for the current message to be dropped once accepted.
+    discarded: bool,
     pub mail: Mail,
 }
 
 const HELO: &str = "HELO";
 const EHLO: &str = "EHLO";
+const LHLO: &str = "LHLO";
 const MAIL_FROM: &str = "MAIL FROM:";
 const RCPT_TO: &str = "RCPT TO:";
 const DATA: &str = "DATA";
 const QUIT: &str = "QUIT";
+const RSET: &str = "RSET";
+const ETRN: &str = "ETRN";
+const XCLIENT: &str = "XCLIENT";
+const AUTH: &str = "AUTH";
 const DOT: &str = ".";
 
 impl MailFSM {
     pub fn new(server_name: String) -> MailFSM {
+        MailFSM::with_config(Arc::new(Config::new(server_name)))
+    }
+
+    pub fn with_config(config: Arc<Config>) -> MailFSM {
+        MailFSM::with_context(config, SessionContext::new(None, None))
+    }
+
+    pub fn with_context(config: Arc<Config>, mut context: SessionContext) -> MailFSM {
+        #[cfg(feature = "server")]
+        let milters = config.milters.iter().map(|_| None).collect();
+        let policy = config.policy_for(&context).clone();
+        context.tls |= policy.tls;
         MailFSM {
             current_state: State::New,
-            server_name,
+            policy,
+            spool: config.memory.allocation(),
+            config,
+            transaction: None,
+            transaction_trace: None,
+            data_verdict: None,
+            spool_exceeded: false,
+            context,
+            pending: Vec::new(),
+            quarantined: false,
+            authenticating: false,
+            transactions: 0,
+            #[cfg(feature = "server")]
+            milters,
+            discarded: false,
             mail: Mail::new(),
         }
     }
 
-    pub fn process_line(&mut self, line: &str) -> Option<String> {
+    /// Replaces the policy of the configuration for this session.
+    pub fn policy(mut self, policy: Policy) -> MailFSM {
+        self.context.tls |= policy.tls;
+        self.policy = policy;
+        self
+    }
+
+    pub fn process_line(&mut self, line: &str) -> Option<Reply> {
+        // Message lines are most of what a session reads; they are not commands.
+        if self.current_state == State::Data {
+            return self.process_data_line(line);
+        }
+        // Credentials, not a command.
+        if self.authenticating {
+            self.authenticating = false;
+            return Some(self.authenticate(line.trim()));
+        }
         let curated_line = line.trim().to_uppercase();
-        match &self.current_state {
-            State::New if curated_line.starts_with(HELO) || curated_line.starts_with(EHLO) => {
-                self.mail.add_hello(&line.trim()[HELO.len()..]);
+        let handler = Arc::clone(&self.config.handler);
+        let verb = curated_line.split_whitespace().next().unwrap_or("");
+        if !self.policy.allows(verb) {
+            return Some(self.reply(502, "5.5.1 Command not implemented"));
+        }
+        match self.current_state {
+            State::New if self.is_greeting(&curated_line) => {
+                let domain = &line.trim()[HELO.len()..];
+                if let Some(reply) = handler.on_helo(&self.context, domain.trim()).reply(451) {
+                    return Some(reply);
+                }
+                self.mail.add_hello(domain);
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.milter_step(true, |_| Ok(Response::Continue)) {
+                    self.mail.helo = None;
+                    return Some(reply);
+                }
+                if self.context.protocol.is_none() {
+                    let protocol = match &curated_line[..HELO.len()] {
+                        EHLO => "ESMTP",
+                        LHLO => "LMTP",
+                        _ => "SMTP",
+                    };
+                    self.context.protocol = Some(String::from(protocol));
+                }
                 self.current_state = State::Hello;
-                Some(format!("250 {}\n", self.server_name))
+                if curated_line.starts_with(HELO) {
+                    return Some(self.reply(250, &self.config.server_name));
+                }
+                let mut lines = self.reply(250, &self.config.server_name).lines;
+                lines.push(String::from("DSN"));
+                lines.push(match self.config.min_by_time {
+                    0 => String::from("DELIVERBY"),
+                    min => format!("DELIVERBY {}", min),
+                });
+                if self.context.tls {
+                    lines.push(String::from("REQUIRETLS"));
+                }
+                if let Some(policy) = self.config.priority_policy {
+                    lines.push(format!("MT-PRIORITY {}", policy.as_str()));
+                }
+                if self.offers_auth() {
+                    lines.push(String::from("AUTH PLAIN"));
+                }
+                if self.config.queue.is_some() {
+                    lines.push(String::from(ETRN));
+                }
+                if self.may_use_xclient() {
+                    lines.push(String::from("XCLIENT NAME ADDR PORT PROTO HELO LOGIN"));
+                }
+                lines.extend(self.config.verbs.capabilities().map(String::from));
+                Some(Reply::multiline(250, None, lines))
             }
-            State::Hello if curated_line.starts_with(MAIL_FROM) => {
-                self.mail.add_mail_from(&line.trim()[MAIL_FROM.len()..]);
+            State::New | State::Hello
+                if curated_line.starts_with(MAIL_FROM)
+                    && (self.current_state == State::Hello || !self.policy.require_helo) =>
+            {
+                if self.policy.require_auth && self.context.authenticated.is_none() {
+                    return Some(self.reply(530, "5.7.0 Authentication required"));
+                }
+                if let Some(reply) = self.quota_refusal(1) {
+                    return Some(reply);
+                }
+                let from = &line.trim()[MAIL_FROM.len()..];
+                let mut mail = match self.envelope(from) {
+                    Ok(mail) => mail,
+                    Err(reply) => return Some(reply),
+                };
+                if let Some(reply) = handler.on_mail_from(&self.context, from.trim()).reply(451) {
+                    return Some(reply);
+                }
+                if let Some(reply) = self.script_refusal(Hook::Mail, from, "") {
+                    return Some(reply);
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.policy_service_refusal(Stage::Mail, from, "") {
+                    return Some(reply);
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.milter_mail(from) {
+                    return Some(reply);
+                }
+                // A new transaction on the same connection starts from an empty envelope.
+                mail.helo = self.mail.helo.take();
+                self.replace_mail(mail);
+                self.mail.add_mail_from(from);
+                self.transactions += 1;
+                self.context.transaction_started_at = Some(clock::now());
+                let from = self.mail.mail_from.clone().unwrap_or_default();
+                self.transaction = None;
+                self.transaction = Some(log::span("transaction", &[("from", &from)]));
+                self.transaction_trace = Some(trace::start(
+                    "transaction",
+                    trace::Kind::Internal,
+                    &[("mail.from", &from)],
+                ));
                 self.current_state = State::MailFrom;
-                Some(String::from("250 Ok\n"))
+                Some(self.reply(250, "Ok"))
             }
-            State::MailFrom if curated_line.starts_with(RCPT_TO) => {
-                self.mail.add_rcpt_to(&line.trim()[RCPT_TO.len()..]);
+            State::MailFrom | State::RcptTo if curated_line.starts_with(RCPT_TO) => {
+                if let Some(max) = self.policy.max_recipients {
+                    if self.mail.rcpt_to.len() >= max {
+                        return Some(self.reply(452, "4.5.3 Too many recipients"));
+                    }
+                }
+                let recipients = self.mail.rcpt_to.len() as u32 + 1;
+                if let Some(reply) = self.quota_refusal(recipients) {
+                    return Some(reply);
+                }
+                let rcpt = &line.trim()[RCPT_TO.len()..];
+                let local = self.config.domains.accepts(rcpt);
+                if !local && !self.may_relay() {
+                    return Some(self.reply(554, "5.7.1 Relay access denied"));
+                }
+                let aliased = match self.expand_aliases(rcpt) {
+                    Ok(aliased) => aliased.filter(|_| local),
+                    Err(e) => {
+                        crate::warn!("Unable to look up aliases of {}: {}", rcpt.trim(), e);
+                        return Some(self.reply(451, "4.3.0 Temporary failure, try again later"));
+                    }
+                };
+                if let (true, None, Some(verifier)) = (local, &aliased, &self.config.recipients) {
+                    match verifier.verify(local_part_of(rcpt), domain_of(rcpt)) {
+                        Ok(true) => {}
+                        Ok(false) => return Some(self.reply(550, "5.1.1 No such user")),
+                        Err(e) => {
+                            crate::warn!("Unable to verify {}: {}", rcpt.trim(), e);
+                            return Some(
+                                self.reply(451, "4.3.0 Temporary failure, try again later"),
+                            );
+                        }
+                    }
+                }
+                #[cfg(feature = "server")]
+                if let (true, None) = (local, &aliased) {
+                    if let Some(reply) = self.callout_refusal(rcpt) {
+                        return Some(reply);
+                    }
+                }
+                if let Some(reply) = handler.on_rcpt_to(&self.context, rcpt.trim()).reply(451) {
+                    return Some(reply);
+                }
+                if let Some(reply) = self.script_refusal(Hook::Rcpt, "", rcpt) {
+                    return Some(reply);
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.policy_service_refusal(Stage::Rcpt, "", rcpt) {
+                    return Some(reply);
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.milter_step(false, |session| {
+                    session.rcpt(&rcpt.split_whitespace().collect::<Vec<&str>>())
+                }) {
+                    return Some(reply);
+                }
+                let (_, parameters) = dsn::split_parameters(rcpt);
+                let addresses: Vec<&str> = match &aliased {
+                    Some(targets) => targets.iter().map(String::as_str).collect(),
+                    None => vec![rcpt],
+                };
+                if addresses
+                    .iter()
+                    .any(|address| self.is_over_quota(address, 0))
+                {
+                    return Some(self.reply(552, "5.2.2 Mailbox full"));
+                }
+                if let Err(e) = self.mail.dsn.parse_rcpt(&addresses, &parameters) {
+                    return Some(self.reply(501, &format!("5.5.4 {}", e)));
+                }
+                match aliased {
+                    Some(targets) => {
+                        for target in targets.iter() {
+                            self.mail.add_rcpt_to(target);
+                        }
+                    }
+                    None => self.mail.add_rcpt_to(rcpt),
+                }
                 self.current_state = State::RcptTo;
-                Some(String::from("250 Ok\n"))
-            }
-            State::RcptTo if curated_line.starts_with(RCPT_TO) => {
-                self.mail.add_rcpt_to(&line.trim()[RCPT_TO.len()..]);
-                Some(String::from("250 Ok\n"))
+                Some(self.reply(250, "Ok"))
             }
             State::RcptTo if curated_line.starts_with(DATA) => {
+                if self
+                    .config
+                    .disk
+                    .as_ref()
+                    .is_some_and(|disk| !disk.has_room())
+                {
+                    return Some(self.reply(452, "4.3.1 Insufficient system storage"));
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.policy_service_refusal(Stage::Data, "", "") {
+                    return Some(reply);
+                }
+                #[cfg(feature = "server")]
+                if let Some(reply) = self.milter_step(false, MilterSession::data) {
+                    return Some(reply);
+                }
                 self.mail.add_rcpt_to(&line.trim()[DATA.len()..]);
                 self.current_state = State::Data;
-                Some(String::from("354 End data with <CR><LF>.<CR><LF>\n"))
+                Some(self.reply(354, "End data with <CR><LF>.<CR><LF>"))
+            }
+            State::New | State::Hello
+                if curated_line.starts_with(XCLIENT)
+                    && !self.policy.xclient_networks.is_empty() =>
+            {
+                Some(self.xclient(line.trim()[XCLIENT.len()..].trim()))
+            }
+            State::Hello if curated_line.starts_with(AUTH) && self.offers_auth() => {
+                Some(self.auth(line.trim()[AUTH.len()..].trim()))
+            }
+            State::New | State::MailFrom | State::RcptTo
+                if curated_line.starts_with(AUTH) && self.offers_auth() =>
+            {
+                Some(self.reply(503, "5.5.1 Bad sequence of commands"))
+            }
+            State::Hello if curated_line.starts_with(ETRN) && self.config.queue.is_some() => {
+                Some(self.etrn(line.trim()[ETRN.len()..].trim()))
+            }
+            State::Hello | State::MailFrom | State::RcptTo if curated_line == RSET => {
+                #[cfg(feature = "server")]
+                self.milter_abort();
+                let helo = self.mail.helo.take();
+                self.replace_mail(Mail::new());
+                self.mail.helo = helo;
+                self.transaction = None;
+                self.transaction_trace = None;
+                self.current_state = State::Hello;
+                Some(self.reply(250, "2.0.0 Ok"))
+            }
+            _ if curated_line.starts_with(QUIT) => Some(self.quit()),
+            state => Some(
+                self.config
+                    .verbs
+                    .dispatch(state, &self.context, line)
+                    .unwrap_or_else(|| self.reply(500, "5.5.1 Unknown command")),
+            ),
+        }
+    }
+
+    fn quit(&mut self) -> Reply {
+        self.config.handler.on_quit(&self.context);
+        #[cfg(feature = "server")]
+        for session in self.milters.iter_mut().filter_map(Option::take) {
+            let _ = session.quit();
+        }
+        self.current_state = State::Quit;
+        self.reply(221, "Bye")
+    }
+
+    fn process_data_line(&mut self, line: &str) -> Option<Reply> {
+        if line.trim() == DOT {
+            return Some(self.complete_message());
+        }
+        self.process_data_fragment(line);
+        None
+    }
+
+    /// Adds the rest of a data line too long to be read at once, which neither ends the
+    /// message nor can be a command.
+    pub fn process_data_fragment(&mut self, fragment: &str) {
+        if self.spool_exceeded {
+            return;
+        }
+        if !self.spool.try_grow(fragment.len()) {
+            crate::warn!("Message over the memory budget, dropping it");
+            self.spool_exceeded = true;
+            if let Some(data) = self.mail.data.take() {
+                self.config.buffers.give_string(data);
+            }
+            self.spool.clear();
+            return;
+        }
+        if self.data_verdict.is_none() {
+            let verdict = self.config.handler.on_data_chunk(&self.context, fragment);
+            if verdict != Verdict::Accept {
+                self.data_verdict = Some(verdict);
+            }
+        }
+        if self.mail.data.is_none() {
+            self.mail.data = Some(self.config.buffers.take_string(fragment.len()));
+        }
+        self.mail.add_data_chunk(fragment);
+    }
+
+    /// Starts over with `mail`, giving the spool of the last message back to the pool.
+    fn replace_mail(&mut self, mail: Mail) {
+        let previous = std::mem::replace(&mut self.mail, mail);
+        if let Some(data) = previous.data {
+            self.config.buffers.give_string(data);
+        }
+        self.spool.clear();
+    }
+
+    fn complete_message(&mut self) -> Reply {
+        let recipients: Vec<String> = self
+            .mail
+            .rcpt_to
+            .iter()
+            .filter(|rcpt| !rcpt.is_empty())
+            .cloned()
+            .collect();
+        let accepted = self.accept_message();
+        self.quarantined = matches!(accepted, Ok((_, Some(_))));
+        let mut replies = match &accepted {
+            Ok((queue_id, Some(reason))) => {
+                let reply = self.quarantine(queue_id, reason);
+                vec![
+                    reply;
+                    if self.policy.lmtp {
+                        recipients.len()
+                    } else {
+                        1
+                    }
+                ]
+            }
+            Ok((queue_id, None)) if self.discarded => {
+                crate::info!("Message {} discarded by a milter", queue_id);
+                let reply = self.reply(250, &format!("Ok: queued as {}", queue_id));
+                vec![
+                    reply;
+                    if self.policy.lmtp {
+                        recipients.len()
+                    } else {
+                        1
+                    }
+                ]
+            }
+            Ok((queue_id, None)) if self.policy.lmtp => recipients
+                .iter()
+                .map(|rcpt| {
+                    if self.is_over_quota(rcpt, self.message_size()) {
+                        return self.reply(552, "5.2.2 Mailbox full");
+                    }
+                    let mail = Mail {
+                        rcpt_to: vec![rcpt.clone()],
+                        ..self.mail.clone()
+                    };
+                    self.deliver(&mail, queue_id)
+                })
+                .collect(),
+            Ok((queue_id, None)) => vec![self.deliver(&self.mail, queue_id)],
+            Err(reply) if self.policy.lmtp => vec![reply.clone(); recipients.len().max(1)],
+            Err(reply) => vec![reply.clone()],
+        };
+        #[cfg(feature = "server")]
+        if accepted.is_err() {
+            self.milter_abort();
+        }
+        self.discarded = false;
+        // Whether or not the outputs took it, the client has its answer now.
+        if let (Ok((queue_id, _)), Some(journal)) = (&accepted, &self.config.journal) {
+            if let Err(e) = journal.complete(queue_id) {
+                crate::error!("Unable to journal {}: {}", queue_id, e);
+            }
+        }
+        if replies.iter().any(|reply| reply.is_positive()) {
+            if let Some(identity) = &self.context.authenticated {
+                self.config
+                    .sender_quotas
+                    .record(identity, recipients.len() as u32);
+            }
+            metrics::increment(metrics::MESSAGES_ACCEPTED);
+            metrics::count(
+                metrics::RECEIVED_BYTES,
+                self.mail.data.as_ref().map_or(0, |data| data.len()) as u64,
+            );
+            crate::info!(
+                rcpt_count = recipients.len(),
+                size = self.mail.data.as_ref().map_or(0, |data| data.len());
+                "Message accepted"
+            );
+        }
+        if let Some(span) = &mut self.transaction_trace {
+            if let Some(reply) = replies.iter().find(|reply| !reply.is_positive()) {
+                span.set_error(&reply.text());
+            }
+        }
+        self.transaction_trace = None;
+        self.current_state = State::Hello;
+        let reply = replies.remove(0);
+        self.pending = replies;
+        reply
+    }
+
+    /// Runs the checks and rewrites of a complete message, returning its queue id and the
+    /// reason to quarantine it if any, or the reply refusing it.
+    fn accept_message(&mut self) -> Result<(String, Option<String>), Reply> {
+        if self.spool_exceeded {
+            self.spool_exceeded = false;
+            self.data_verdict = None;
+            metrics::increment(metrics::MESSAGES_REJECTED);
+            return Err(self.reply(452, "4.3.1 Insufficient system resources"));
+        }
+        let data_verdict = self.data_verdict.take();
+        if let Some(reply) = data_verdict.as_ref().and_then(|verdict| verdict.reply(451)) {
+            self.mail.data = None;
+            crate::info!("Message refused by handler: {}", reply.text());
+            metrics::increment(metrics::MESSAGES_REJECTED);
+            return Err(reply);
+        }
+        if let Some(data) = &self.mail.data {
+            if let Some(reason) = self.detect_loop(&Message::parse(data)) {
+                self.mail.data = None;
+                crate::warn!("Rejected message: {}", reason);
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(self.reply(554, &format!("5.4.6 {}", reason)));
+            }
+        }
+        let queue_id = queue::new_queue_id();
+        if let Some(data) = self.mail.data.take() {
+            let mut stamped = self.received_header(&data, &queue_id);
+            stamped.push_str(&data);
+            self.config.buffers.give_string(data);
+            self.mail.data = Some(stamped);
+        }
+        if !self.config.header_rules.is_empty() {
+            if let Some(data) = headers::rewrite(&self.mail, &self.config.header_rules) {
+                self.mail.data = Some(data);
+            }
+        }
+        if self.policy.fix_headers {
+            if let Some(data) = &self.mail.data {
+                self.mail.data = Some(self.fix_headers(data));
+            }
+        }
+        if let (Some(footer), Some(data)) = (&self.config.footer, &self.mail.data) {
+            self.mail.data = Some(footer.apply(data));
+        }
+        let verdict = self
+            .config
+            .handler
+            .on_message_complete(&self.context, &self.mail);
+        if let Some(reply) = verdict.reply(451) {
+            self.mail.data = None;
+            crate::info!("Message refused by handler: {}", reply.text());
+            metrics::increment(metrics::MESSAGES_REJECTED);
+            return Err(reply);
+        }
+        let script_quarantine = match self.script_end_of_data() {
+            Ok(reason) => reason,
+            Err(reply) => {
+                self.mail.data = None;
+                crate::info!("Message refused by script: {}", reply.text());
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(reply);
+            }
+        };
+        #[cfg(feature = "server")]
+        let milter_quarantine = match self.milter_end_of_message(&queue_id) {
+            Ok(reason) => reason,
+            Err(reply) => {
+                self.mail.data = None;
+                crate::info!("Message refused by milter: {}", reply.text());
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(reply);
+            }
+        };
+        #[cfg(feature = "server")]
+        let virus_quarantine = match self.virus_check(&queue_id) {
+            Ok(reason) => reason,
+            Err(reply) => {
+                self.mail.data = None;
+                crate::info!("Message refused by virus scan: {}", reply.text());
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(reply);
+            }
+        };
+        #[cfg(feature = "server")]
+        let spam_quarantine = match self.spam_check(&queue_id) {
+            Ok(reason) => reason,
+            Err(reply) => {
+                self.mail.data = None;
+                crate::info!("Message refused by spam filter: {}", reply.text());
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(reply);
+            }
+        };
+        let quarantine = match (verdict, data_verdict) {
+            (Verdict::Quarantine(reason), _) | (_, Some(Verdict::Quarantine(reason))) => {
+                Some(reason)
+            }
+            _ => None,
+        };
+        let quarantine = quarantine.or(script_quarantine);
+        #[cfg(feature = "server")]
+        let quarantine = quarantine
+            .or(milter_quarantine)
+            .or(virus_quarantine)
+            .or(spam_quarantine);
+        if let (Some(reason), None) = (&quarantine, &self.config.quarantine) {
+            self.mail.data = None;
+            crate::info!("Message refused, no quarantine for it: {}", reason);
+            metrics::increment(metrics::MESSAGES_REJECTED);
+            return Err(self.reply(554, "5.7.1 Message refused by policy"));
+        }
+        // In LMTP, each recipient is answered for on its own.
+        let size = self.message_size();
+        let recipients = self.mail.rcpt_to.iter().filter(|rcpt| !rcpt.is_empty());
+        if quarantine.is_none()
+            && !self.policy.lmtp
+            && recipients
+                .clone()
+                .any(|rcpt| self.is_over_quota(rcpt, size))
+        {
+            self.mail.data = None;
+            crate::info!("Rejected message: mailbox full");
+            metrics::increment(metrics::MESSAGES_REJECTED);
+            return Err(self.reply(552, "5.2.2 Mailbox full"));
+        }
+        if let Some(deliver_by) = &self.mail.deliver_by {
+            if deliver_by.mode == deliver_by::Mode::Return && deliver_by.is_expired(clock::now()) {
+                self.mail.data = None;
+                crate::info!("Rejected message: delivery time expired");
+                metrics::increment(metrics::MESSAGES_REJECTED);
+                return Err(self.reply(554, "5.4.7 Delivery time expired"));
+            }
+        }
+        log::record("queue_id", &queue_id);
+        if let Some(span) = &mut self.transaction_trace {
+            span.set_attribute("queue_id", &queue_id);
+        }
+        self.mail.queue_id = Some(queue_id.clone());
+        if let Some(journal) = &self.config.journal {
+            if let Err(e) = journal.record(&self.mail) {
+                crate::error!("Unable to journal {}: {}", queue_id, e);
+                return Err(self.reply(451, "4.3.0 Temporary failure, try again later"));
+            }
+        }
+        Ok((queue_id, quarantine))
+    }
+
+    /// Whether a message of `size` bytes would not fit in the mailbox of `rcpt`, see
+    /// `MailboxQuotas::fits`.
+    fn is_over_quota(&self, rcpt: &str, size: u64) -> bool {
+        self.config
+            .mailbox_quotas
+            .as_ref()
+            .is_some_and(|quotas| !quotas.fits(rcpt, size))
+    }
+
+    fn message_size(&self) -> u64 {
+        self.mail.data.as_ref().map_or(0, String::len) as u64
+    }
+
+    /// Runs `step` with the session of each milter in turn, up to the first that refuses,
+    /// replying as it did. With `open`, a milter without a session is connected first and
+    /// told about the client and its HELO; otherwise it defers the command, unless it
+    /// fails open, since it missed the start of the transaction.
+    #[cfg(feature = "server")]
+    fn milter_step(
+        &mut self,
+        open: bool,
+        mut step: impl FnMut(&mut MilterSession) -> io::Result<Response>,
+    ) -> Option<Reply> {
+        let config = Arc::clone(&self.config);
+        for (index, milter) in config.milters.iter().enumerate() {
+            let result = match self.milters[index].take() {
+                Some(mut session) => step(&mut session).map(|response| (Some(session), response)),
+                None if open => self
+                    .open_milter(milter)
+                    .and_then(|(mut session, response)| {
+                        if response != Response::Continue {
+                            // Refused before the session began, it is opened again next time.
+                            return Ok((None, response));
+                        }
+                        step(&mut session).map(|response| (Some(session), response))
+                    }),
+                None if milter.fail_open => continue,
+                None => return Some(self.reply(451, "4.3.0 Temporary failure, try again later")),
+            };
+            let response = match result {
+                Ok((session, response)) => {
+                    self.milters[index] = session;
+                    response
+                }
+                Err(e) => {
+                    crate::warn!("Unable to talk to milter {}: {}", milter.address, e);
+                    if milter.fail_open {
+                        continue;
+                    }
+                    return Some(self.reply(451, "4.3.0 Temporary failure, try again later"));
+                }
+            };
+            match response {
+                Response::Continue | Response::Accept => {}
+                Response::Discard => self.discarded = true,
+                Response::Reject => return Some(self.reply(550, "5.7.1 Command rejected")),
+                Response::TempFail => {
+                    return Some(self.reply(451, "4.7.1 Service unavailable - try again later"))
+                }
+                Response::Reply(reply) => return Some(reply),
+            }
+        }
+        None
+    }
+
+    /// Connects to `milter`, telling it about the client and its HELO if it greeted.
+    #[cfg(feature = "server")]
+    fn open_milter(&self, milter: &Milter) -> io::Result<(MilterSession, Response)> {
+        let mut session = milter.open()?;
+        let client = self
+            .context
+            .client_name
+            .clone()
+            .or_else(|| self.context.peer.map(|peer| format!("[{}]", peer.ip())))
+            .unwrap_or_else(|| String::from("localhost"));
+        session.macros(
+            b'C',
+            &[
+                ("j", &self.config.server_name),
+                ("{daemon_name}", "simple-smtp"),
+                ("_", &client),
+            ],
+        )?;
+        let response = session.connect(&client, self.context.peer)?;
+        if response != Response::Continue {
+            return Ok((session, response));
+        }
+        let response = match &self.mail.helo {
+            Some(helo) => session.helo(helo)?,
+            None => Response::Continue,
+        };
+        Ok((session, response))
+    }
+
+    /// The reply the script gives at `hook`, see `Config::script`. The sender is `from` at
+    /// MAIL FROM, where it is not in the envelope yet.
+    fn script_refusal(&self, hook: Hook, from: &str, rcpt: &str) -> Option<Reply> {
+        let script = self.config.script.as_ref()?;
+        let scope = Scope {
+            context: &self.context,
+            helo: self.mail.helo.as_deref().unwrap_or(""),
+            from: match hook {
+                Hook::Mail => from,
+                _ => self.mail.mail_from.as_deref().unwrap_or(""),
+            },
+            rcpt,
+            recipients: match hook {
+                Hook::Mail => &[],
+                _ => &self.mail.rcpt_to,
+            },
+            data: None,
+        };
+        script.run(hook, &scope).0.reply(451)
+    }
+
+    /// Runs the end of DATA section of the script, making the header changes it asks for,
+    /// and returns the reason to quarantine the message if it decided so.
+    fn script_end_of_data(&mut self) -> Result<Option<String>, Reply> {
+        let script = match &self.config.script {
+            Some(script) => Arc::clone(script),
+            None => return Ok(None),
+        };
+        let scope = Scope {
+            context: &self.context,
+            helo: self.mail.helo.as_deref().unwrap_or(""),
+            from: self.mail.mail_from.as_deref().unwrap_or(""),
+            rcpt: "",
+            recipients: &self.mail.rcpt_to,
+            data: self.mail.data.as_deref(),
+        };
+        let (verdict, rules) = script.run(Hook::Data, &scope);
+        if let Some(reply) = verdict.reply(451) {
+            return Err(reply);
+        }
+        if let Some(data) = headers::rewrite(&self.mail, &rules).filter(|_| !rules.is_empty()) {
+            self.mail.data = Some(data);
+        }
+        Ok(match verdict {
+            Verdict::Quarantine(reason) => Some(reason),
+            _ => None,
+        })
+    }
+
+    /// Tells the milters about MAIL FROM `from`, connecting to those without a session.
+    #[cfg(feature = "server")]
+    fn milter_mail(&mut self, from: &str) -> Option<Reply> {
+        self.discarded = false;
+        let identity = self.context.authenticated.clone();
+        self.milter_step(true, |session| {
+            if let Some(identity) = &identity {
+                session.macros(b'M', &[("{auth_authen}", identity)])?;
+            }
+            session.mail(&from.split_whitespace().collect::<Vec<&str>>())
+        })
+    }
+
+    /// Shows the milters the message accepted as `queue_id`, and makes the changes they
+    /// ask for, returning the reason to quarantine it if one asked to.
+    #[cfg(feature = "server")]
+    fn milter_end_of_message(&mut self, queue_id: &str) -> Result<Option<String>, Reply> {
+        if self.config.milters.is_empty() {
+            return Ok(None);
+        }
+        let data = self.mail.data.clone().unwrap_or_default();
+        let mut modifications = Vec::new();
+        let refusal = self.milter_step(false, |session| {
+            session.macros(b'E', &[("i", queue_id)])?;
+            let (response, changes) = session.end_of_message(&data)?;
+            modifications.extend(changes);
+            Ok(response)
+        });
+        if let Some(reply) = refusal {
+            return Err(reply);
+        }
+        milter::apply(&modifications, &mut self.mail);
+        Ok(modifications
+            .into_iter()
+            .find_map(|modification| match modification {
+                Modification::Quarantine(reason) => Some(reason),
+                _ => None,
+            }))
+    }
+
+    /// Has clamd scan the message accepted as `queue_id`, see `Config::clamd`, returning
+    /// the reason to quarantine it if it is infected and infected messages are quarantined.
+    #[cfg(feature = "server")]
+    fn virus_check(&mut self, queue_id: &str) -> Result<Option<String>, Reply> {
+        let clamd = match &self.config.clamd {
+            Some(clamd) => Arc::clone(clamd),
+            None => return Ok(None),
+        };
+        let data = self.mail.data.as_deref().unwrap_or("");
+        match clamd.scan(data.as_bytes()) {
+            Ok(None) => Ok(None),
+            Ok(Some(signature)) => {
+                crate::warn!("Message {} is infected: {}", queue_id, signature);
+                if clamd.quarantine {
+                    Ok(Some(format!("virus {}", signature)))
+                } else {
+                    Err(self.reply(554, &format!("5.7.1 Virus found: {}", signature)))
+                }
+            }
+            Err(e) => {
+                crate::warn!("Unable to scan message {} for viruses: {}", queue_id, e);
+                if clamd.fail_open {
+                    Ok(None)
+                } else {
+                    Err(self.reply(451, "4.3.0 Temporary failure, try again later"))
+                }
+            }
+        }
+    }
+
+    /// Scans the message accepted as `queue_id`, tagging it, see `Config::spam_filter`,
+    /// and returns the reason to quarantine it if its score calls for it.
+    #[cfg(feature = "server")]
+    fn spam_check(&mut self, queue_id: &str) -> Result<Option<String>, Reply> {
+        let filter = match &self.config.spam_filter {
+            Some(filter) => Arc::clone(filter),
+            None => return Ok(None),
+        };
+        let scan = match filter.scan(&self.mail, &self.context, queue_id) {
+            Ok(scan) => scan,
+            Err(e) => {
+                crate::warn!("Unable to scan message {}: {}", queue_id, e);
+                if filter.fail_open {
+                    return Ok(None);
+                }
+                return Err(self.reply(451, "4.3.0 Temporary failure, try again later"));
+            }
+        };
+        crate::info!(
+            "Message {} scored {:.1}/{:.1}",
+            queue_id,
+            scan.score,
+            scan.required
+        );
+        match filter.decide(&scan) {
+            Verdict::Quarantine(reason) => Ok(Some(reason)),
+            verdict => match verdict.reply(451) {
+                Some(reply) => Err(reply),
+                None => {
+                    spam::tag(&scan, &mut self.mail);
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    /// Tells the milters the current message is abandoned, closing the sessions of those
+    /// that cannot be told.
+    #[cfg(feature = "server")]
+    fn milter_abort(&mut self) {
+        for slot in self.milters.iter_mut() {
+            if slot
+                .as_mut()
+                .is_some_and(|session| session.abort().is_err())
+            {
+                *slot = None;
+            }
+        }
+    }
+
+    /// The reply the policy service gives at `stage`, see `Config::policy_service`. The
+    /// sender is `from` at MAIL FROM, where it is not in the envelope yet.
+    #[cfg(feature = "server")]
+    fn policy_service_refusal(&self, stage: Stage, from: &str, rcpt: &str) -> Option<Reply> {
+        let service = self.config.policy_service.as_ref()?;
+        let bare = |address: &str| {
+            let address = dsn::split_parameters(address).0;
+            String::from(address.trim().trim_start_matches('<').trim_end_matches('>'))
+        };
+        let sender = match stage {
+            Stage::Mail => bare(from),
+            _ => bare(self.mail.mail_from.as_deref().unwrap_or("")),
+        };
+        let recipient = bare(rcpt);
+        let recipient_count = match stage {
+            Stage::Data => self.mail.rcpt_to.len().to_string(),
+            _ => String::from("0"),
+        };
+        let transaction = match stage {
+            Stage::Mail => self.transactions + 1,
+            _ => self.transactions,
+        };
+        let instance = format!("{}.{}", self.context.session_id, transaction);
+        let client_address = self
+            .context
+            .peer
+            .map(|peer| peer.ip().to_string())
+            .unwrap_or_default();
+        let client_name = self.context.client_name.as_deref().unwrap_or("unknown");
+        let server_address = self
+            .context
+            .local
+            .map(|local| local.ip().to_string())
+            .unwrap_or_default();
+        let server_port = self
+            .context
+            .local_port()
+            .map(|port| port.to_string())
+            .unwrap_or_default();
+        let attributes = [
+            (
+                "protocol_name",
+                self.context.protocol.as_deref().unwrap_or("SMTP"),
+            ),
+            ("helo_name", self.mail.helo.as_deref().unwrap_or("")),
+            ("queue_id", ""),
+            ("sender", &sender),
+            ("recipient", &recipient),
+            ("recipient_count", &recipient_count),
+            ("client_address", &client_address),
+            ("client_name", client_name),
+            ("reverse_client_name", client_name),
+            ("instance", &instance),
+            (
+                "sasl_username",
+                self.context.authenticated.as_deref().unwrap_or(""),
+            ),
+            ("server_address", &server_address),
+            ("server_port", &server_port),
+        ];
+        service.check(stage, &attributes).reply(451)
+    }
+
+    /// The reply refusing or deferring RCPT TO `rcpt` when it is in a domain relayed to
+    /// another server and that server does not take it, see `Config::callout`.
+    #[cfg(feature = "server")]
+    fn callout_refusal(&self, rcpt: &str) -> Option<Reply> {
+        let callout = self.config.callout.as_ref()?;
+        let relay = match self.config.transports.get(domain_of(rcpt)) {
+            Transport::Relay(relay) => relay,
+            _ => return None,
+        };
+        match callout.verify(relay, rcpt) {
+            Ok(true) => None,
+            Ok(false) => Some(self.reply(550, "5.1.1 No such user")),
+            Err(e) => {
+                crate::warn!("Unable to verify {} with a callout: {}", rcpt.trim(), e);
+                Some(self.reply(451, "4.3.0 Temporary failure, try again later"))
             }
-            State::Data if line.trim() == DOT => Some(format!(
-                "250 Ok: queued as {}\n",
-                self.mail.data.as_ref().unwrap_or(&String::from("")).len()
-            )),
-            State::Data if curated_line.starts_with(QUIT) => {
-                self.current_state = State::Quit;
-                Some(String::from("221 Bye\n"))
+        }
+    }
+
+    /// The reply deferring a message to `recipients` recipients from an authenticated
+    /// client that would go over its quota, `None` when it may send it.
+    fn quota_refusal(&self, recipients: u32) -> Option<Reply> {
+        let identity = self.context.authenticated.as_ref()?;
+        let quota = self.config.sender_quotas.exceeded(identity, recipients)?;
+        crate::info!("Quota of {} exceeded: {}", identity, quota);
+        metrics::increment(metrics::QUOTA_EXCEEDED);
+        Some(self.reply(452, "4.7.1 Sending quota exceeded, try again later"))
+    }
+
+    /// Keeps the message in the quarantine rather than delivering it, replying how that
+    /// went.
+    fn quarantine(&self, queue_id: &str, reason: &str) -> Reply {
+        let quarantine = match &self.config.quarantine {
+            Some(quarantine) => quarantine,
+            None => return self.reply(451, "4.3.0 Temporary failure, try again later"),
+        };
+        match quarantine.add(&self.mail, reason) {
+            Ok(()) => {
+                crate::info!("Quarantined {}: {}", queue_id, reason);
+                metrics::increment(metrics::MESSAGES_QUARANTINED);
+                self.reply(250, &format!("Ok: queued as {}", queue_id))
+            }
+            Err(e) => {
+                crate::error!("Unable to quarantine {}: {}", queue_id, e);
+                self.reply(451, "4.3.0 Temporary failure, try again later")
             }
-            State::Data => {
-                self.mail.add_data_chunk(line);
-                None
+        }
+    }
+
+    /// Hands `mail` to the outputs its recipients route to, replying how that went.
+    fn deliver(&self, mail: &Mail, queue_id: &str) -> Reply {
+        match deliver(&self.config, mail, queue_id) {
+            Ok(()) => self.reply(250, &format!("Ok: queued as {}", queue_id)),
+            Err(e) if output::is_permanent(&e) => {
+                self.reply(554, &format!("5.3.0 Delivery failed: {}", e))
             }
-            _ => Some(String::from("Unknown command")),
+            Err(_) => self.reply(451, "4.3.0 Temporary failure, try again later"),
+        }
+    }
+
+    /// The `Received` header of RFC 5321 for a message with `data` queued as `queue_id`,
+    /// with the line endings `data` uses.
+    fn received_header(&self, data: &str, queue_id: &str) -> String {
+        let newline = match data.find('\n') {
+            Some(end) if data[..end].ends_with('\r') => "\r\n",
+            Some(_) => "\n",
+            None => "\r\n",
+        };
+        let helo = self.mail.helo.as_deref().unwrap_or("unknown");
+        let client = self
+            .context
+            .client_name
+            .clone()
+            .or_else(|| self.context.peer.map(|peer| format!("[{}]", peer.ip())))
+            .unwrap_or_else(|| String::from("unknown"));
+        let protocol = match (&self.context.protocol, self.policy.lmtp) {
+            (_, true) => "LMTP",
+            (Some(protocol), _) => protocol.as_str(),
+            (None, _) => "SMTP",
+        };
+        // DATA leaves an empty entry behind the recipients.
+        let mut recipients = self.mail.rcpt_to.iter().filter(|rcpt| !rcpt.is_empty());
+        let recipient = match (recipients.next(), recipients.next()) {
+            (Some(rcpt), None) => format!("{}\tfor {}", newline, rcpt),
+            _ => String::new(),
+        };
+        format!(
+            "Received: from {} ({}){}\tby {} with {} id {}{};{}\t{}{}",
+            helo,
+            client,
+            newline,
+            self.config.server_name,
+            protocol,
+            queue_id,
+            recipient,
+            newline,
+            datetime::rfc5322(clock::now()),
+            newline
+        )
+    }
+
+    /// Adds the `Date` and `Message-ID` headers a submitted message lacks.
+    fn fix_headers(&self, data: &str) -> String {
+        let mut message = Message::parse(data);
+        let now = clock::now();
+        if message.get("Date").is_none() {
+            message.add("Date", &datetime::rfc5322(now));
+        }
+        if message.get("Message-ID").is_none() {
+            let nanos = now
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            let id = format!(
+                "<{}.{}@{}>",
+                nanos, self.context.session_id, self.config.server_name
+            );
+            message.add("Message-ID", &id);
+        }
+        message.to_string()
+    }
+
+    fn detect_loop(&self, message: &Message) -> Option<String> {
+        let hops = message.count("Received");
+        if hops > self.config.max_hops {
+            return Some(format!(
+                "Too many hops ({} > {})",
+                hops, self.config.max_hops
+            ));
         }
+        let by_us = format!("by {}", self.config.server_name.to_lowercase());
+        let looped = message.headers.iter().any(|h| {
+            h.name.eq_ignore_ascii_case("Received")
+                && h.value
+                    .to_lowercase()
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .windows(2)
+                    .any(|pair| pair.join(" ") == by_us)
+        });
+        if looped {
+            return Some(String::from("Mail loop detected"));
+        }
+        None
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config)
+    }
+
+    pub fn context(&self) -> &SessionContext {
+        &self.context
+    }
+
+    /// For updates made outside the state machine, e.g. after a TLS handshake or AUTH.
+    pub fn context_mut(&mut self) -> &mut SessionContext {
+        &mut self.context
     }
 
     pub fn is_finished(&self) -> bool {
         self.current_state == State::Quit
     }
 
-    pub fn greeting(&self) -> String {
-        String::from(format!("220 {} simple-smtp\n", self.server_name))
+    pub fn is_receiving_data(&self) -> bool {
+        self.current_state == State::Data
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Whether the next line is expected to carry the credentials of AUTH, which are not to
+    /// be logged.
+    pub fn is_authenticating(&self) -> bool {
+        self.authenticating
+    }
 
-    #[test]
-    fn test_mail() {
+    pub fn state(&self) -> State {
+        self.current_state
+    }
+
+    /// The protocol this session implements, as data for property tests and conformance
+    /// checkers: each command accepted in each state, the state it leads to and the class
+    /// of its reply, given the configuration and policy of the session. Commands missing
+    /// from it are refused with 5xx and change nothing, message lines in DATA get no reply.
+    /// The verbs of `Config::verbs` are not listed.
+    pub fn transition_table(&self) -> Vec<Transition> {
+        use ReplyClass::{Completed, Intermediate};
+        use State::*;
+
+        let transition = |from, verb, to, reply| Transition {
+            from,
+            verb,
+            to,
+            reply,
+        };
+        let mut table = Vec::new();
+        let greetings: &[&'static str] = if self.policy.lmtp {
+            &[LHLO]
+        } else {
+            &[HELO, EHLO]
+        };
+        for greeting in greetings {
+            table.push(transition(New, greeting, Hello, Completed));
+        }
+        if !self.policy.require_helo {
+            table.push(transition(New, MAIL_FROM, MailFrom, Completed));
+        }
+        table.push(transition(Hello, MAIL_FROM, MailFrom, Completed));
+        table.push(transition(MailFrom, RCPT_TO, RcptTo, Completed));
+        table.push(transition(RcptTo, RCPT_TO, RcptTo, Completed));
+        table.push(transition(RcptTo, DATA, Data, Intermediate));
+        table.push(transition(Data, DOT, Hello, Completed));
+        if !self.policy.xclient_networks.is_empty() {
+            table.push(transition(New, XCLIENT, New, Completed));
+            table.push(transition(Hello, XCLIENT, New, Completed));
+        }
+        if self.config.queue.is_some() {
+            table.push(transition(Hello, ETRN, Hello, Completed));
+        }
+        for from in [Hello, MailFrom, RcptTo] {
+            table.push(transition(from, RSET, Hello, Completed));
+        }
+        for from in [New, Hello, MailFrom, RcptTo] {
+            table.push(transition(from, QUIT, Quit, Completed));
+        }
+        // The policy does not apply to the lines of a message.
+        table.retain(|transition| {
+            let verb = transition.verb.split([' ', ':']).next();
+            transition.from == Data || self.policy.allows(verb.unwrap_or(""))
+        });
+        table
+    }
+
+    /// The static alias map first, then the alias lookup if any.
+    fn expand_aliases(&self, rcpt: &str) -> io::Result<Option<Vec<String>>> {
+        if let Some(targets) = self.config.aliases.expand(rcpt) {
+            return Ok(Some(targets));
+        }
+        match &self.config.alias_lookup {
+            Some(lookup) => lookup.lookup(rcpt),
+            None => Ok(None),
+        }
+    }
+
+    /// A new envelope with what the parameters of MAIL FROM ask for, or the reply refusing
+    /// them.
+    fn envelope(&self, from: &str) -> Result<Mail, Reply> {
+        let (_, parameters) = dsn::split_parameters(from);
+        let invalid = |e: String| self.reply(501, &format!("5.5.4 {}", e));
         let mut mail = Mail::new();
-        mail.add_hello("server");
-        assert_eq!(mail.helo, Some(String::from("server")));
-        mail.add_mail_from("some@email");
-        assert_eq!(mail.mail_from, Some(String::from("some@email")));
-        mail.add_rcpt_to("email@some");
-        mail.add_rcpt_to("email1@some");
-        assert_eq!(mail.rcpt_to, vec!["email@some", "email1@some"]);
-        mail.add_data_chunk("abc");
-        mail.add_data_chunk("def");
-        assert_eq!(mail.data, Some(String::from("abcdef")));
+        mail.dsn.parse_mail(&parameters).map_err(invalid)?;
+        for (keyword, value) in parameters.iter() {
+            match keyword.as_str() {
+                "BY" => {
+                    mail.deliver_by = Some(
+                        DeliverBy::parse(value, clock::now(), self.config.min_by_time)
+                            .map_err(invalid)?,
+                    )
+                }
+                "MT-PRIORITY" if self.config.priority_policy.is_some() => {
+                    mail.priority = crate::priority::parse(value)
+                        .ok_or_else(|| invalid(format!("Invalid MT-PRIORITY {}", value)))?
+                }
+                "REQUIRETLS" if !self.context.tls => {
+                    return Err(self.reply(555, "5.7.10 REQUIRETLS needs a TLS connection"))
+                }
+                "REQUIRETLS" => mail.require_tls = true,
+                "AUTH" => mail.auth = self.asserted_submitter(&dsn::xtext_decode(value)),
+                _ => {}
+            }
+        }
+        if !parameters.iter().any(|(keyword, _)| keyword == "AUTH") {
+            mail.auth = self.context.authenticated.clone();
+        }
+        mail.origin = Origin {
+            address: self.context.peer.map(|peer| peer.ip()),
+            name: self.context.client_name.clone(),
+            protocol: self.context.protocol.clone(),
+        };
+        Ok(mail)
     }
 
-    #[test]
-    fn test_mail_fsm() {
-        let mut mail_fsm = MailFSM::new(String::from("test.server"));
-        assert_eq!(
-            mail_fsm.process_line("HELO server\n"),
-            Some(String::from("250 test.server\n"))
-        );
-        assert_eq!(
-            mail_fsm.process_line("MAIL FROM: sender@email\n"),
-            Some(String::from("250 Ok\n"))
-        );
-        assert_eq!(
-            mail_fsm.process_line("RCPT TO: rcpt1@email\n"),
-            Some(String::from("250 Ok\n"))
-        );
-        assert_eq!(
-            mail_fsm.process_line("RCPT TO: rcpt2@email\n"),
-            Some(String::from("250 Ok\n"))
-        );
-        assert_eq!(
-            mail_fsm.process_line("DATA\n"),
-            Some(String::from("354 End data with <CR><LF>.<CR><LF>\n"))
-        );
-        assert_eq!(mail_fsm.process_line("qwert\n"), None);
-        assert_eq!(
-            mail_fsm.process_line(".\n"),
-            Some(String::from("250 Ok: queued as 6\n"))
-        );
-        assert_eq!(
-            mail_fsm.process_line("QUIT\n"),
-            Some(String::from("221 Bye\n"))
+    /// The submitter given with `AUTH=`, as far as the client may assert it: any from a
+    /// trusted network, only its own identity from an authenticated client, and `<>`,
+    /// i.e. unknown, from anyone else.
+    fn asserted_submitter(&self, submitter: &str) -> Option<String> {
+        let submitter = Some(submitter).filter(|submitter| *submitter != "<>")?;
+        let own = self
+            .context
+            .authenticated
+            .as_deref()
+            .is_some_and(|identity| identity.eq_ignore_ascii_case(submitter));
+        if own || self.is_trusted() {
+            return Some(String::from(submitter));
+        }
+        crate::info!("Ignored AUTH={} the client may not assert", submitter);
+        None
+    }
+
+    /// Whether the client may send to recipients outside the accepted domains.
+    fn may_relay(&self) -> bool {
+        self.context.authenticated.is_some() || self.is_trusted()
+    }
+
+    /// Whether the client connects from one of the trusted networks of the policy.
+    fn is_trusted(&self) -> bool {
+        self.context.peer.is_some_and(|peer| {
+            self.policy
+                .trusted_networks
+                .iter()
+                .any(|network| network.contains(peer.ip()))
+        })
+    }
+
+    fn may_use_xclient(&self) -> bool {
+        self.context.peer.is_some_and(|peer| {
+            self.policy
+                .xclient_networks
+                .iter()
+                .any(|network| network.contains(peer.ip()))
+        })
+    }
+
+    /// Takes the client attributes given by a trusted frontend, as in Postfix, and starts
+    /// over as if that client had just connected.
+    fn xclient(&mut self, args: &str) -> Reply {
+        if !self.may_use_xclient() {
+            return self.reply(550, "5.7.0 Insufficient authorization");
+        }
+        let mut context = self.context.clone();
+        let mut helo = None;
+        let mut ip = None;
+        let mut port = None;
+        for attribute in args.split_whitespace() {
+            let (name, value) = match attribute.split_once('=') {
+                Some((name, value)) => (name.to_uppercase(), dsn::xtext_decode(value)),
+                None => {
+                    return self.reply(501, &format!("5.5.4 Bad XCLIENT syntax: {}", attribute))
+                }
+            };
+            let value =
+                Some(value).filter(|value| value != "[UNAVAILABLE]" && value != "[TEMPUNAVAIL]");
+            match name.as_str() {
+                "ADDR" => match value {
+                    Some(value) => {
+                        let address = value
+                            .get(..5)
+                            .filter(|prefix| prefix.eq_ignore_ascii_case("IPV6:"))
+                            .map_or(value.as_str(), |_| &value[5..]);
+                        match address.parse::<IpAddr>() {
+                            Ok(address) => ip = Some(address),
+                            Err(_) => {
+                                return self
+                                    .reply(501, &format!("5.5.4 Bad XCLIENT address: {}", value))
+                            }
+                        }
+                    }
+                    None => context.peer = None,
+                },
+                "PORT" => match value.map(|value| value.parse::<u16>()) {
+                    Some(Ok(value)) => port = Some(value),
+                    Some(Err(_)) => return self.reply(501, "5.5.4 Bad XCLIENT port"),
+                    None => {}
+                },
+                "NAME" => context.client_name = value,
+                "HELO" => helo = value,
+                "LOGIN" => context.authenticated = value,
+                "PROTO" => context.protocol = value,
+                "DESTADDR" | "DESTPORT" => {}
+                _ => {
+                    return self.reply(501, &format!("5.5.4 Bad XCLIENT attribute name: {}", name))
+                }
+            }
+        }
+        if ip.is_some() || port.is_some() {
+            let ip = ip.or_else(|| context.peer.map(|peer| peer.ip()));
+            let port = port
+                .or_else(|| context.peer.map(|peer| peer.port()))
+                .unwrap_or(0);
+            context.peer = ip.map(|ip| SocketAddr::new(ip, port));
+        }
+        crate::info!(
+            "XCLIENT from {} for {}",
+            self.context.peer_name(),
+            context.peer_name()
         );
-        assert!(mail_fsm.is_finished())
+        self.context = context;
+        self.replace_mail(Mail::new());
+        self.mail.helo = helo;
+        self.current_state = State::New;
+        self.greeting()
+    }
+
+    /// Whether AUTH is offered: with an authenticator, over TLS or where the policy lets
+    /// passwords go in the clear.
+    fn offers_auth(&self) -> bool {
+        self.config.authenticator.is_some() && (self.context.tls || self.policy.insecure_auth)
+    }
+
+    /// Answers AUTH (RFC 4954) with the PLAIN mechanism (RFC 4616), the credentials given
+    /// at once or on the next line.
+    fn auth(&mut self, arguments: &str) -> Reply {
+        if self.context.authenticated.is_some() {
+            return self.reply(503, "5.5.1 Already authenticated");
+        }
+        let (mechanism, response) = match arguments.split_once(' ') {
+            Some((mechanism, response)) => (mechanism, Some(response.trim())),
+            None => (arguments, None),
+        };
+        if !mechanism.eq_ignore_ascii_case("PLAIN") {
+            return self.reply(504, "5.5.4 Unrecognized authentication type");
+        }
+        match response {
+            Some(response) => self.authenticate(response),
+            None => {
+                self.authenticating = true;
+                Reply::new(334, "")
+            }
+        }
+    }
+
+    /// Checks the base64 PLAIN response `response`, or takes `*` as AUTH cancelled.
+    fn authenticate(&mut self, response: &str) -> Reply {
+        if response == "*" {
+            return self.reply(501, "5.0.0 Authentication cancelled");
+        }
+        let credentials = match response {
+            "=" => Some(String::new()),
+            response => {
+                encoding::base64_decode(response).and_then(|bytes| String::from_utf8(bytes).ok())
+            }
+        };
+        let credentials = credentials.unwrap_or_default();
+        let (authorization, user, password) = match credentials.split('\0').collect::<Vec<&str>>()[..]
+        {
+            [authorization, user, password] => (authorization, user, password),
+            _ => return self.reply(501, "5.5.2 Invalid PLAIN response"),
+        };
+        // Acting as another user is not supported.
+        if !authorization.is_empty() && authorization != user {
+            return self.reply(535, "5.7.8 Authentication credentials invalid");
+        }
+        let authenticator = match &self.config.authenticator {
+            Some(authenticator) => Arc::clone(authenticator),
+            None => return self.reply(503, "5.5.1 AUTH not available"),
+        };
+        match authenticator.authenticate(user, password) {
+            Ok(true) => {
+                crate::info!("Authenticated as {}", user);
+                self.context.authenticated = Some(String::from(user));
+                self.reply(235, "2.7.0 Authentication successful")
+            }
+            Ok(false) => self.reply(535, "5.7.8 Authentication credentials invalid"),
+            Err(e) => {
+                crate::warn!("Unable to check the credentials of {}: {}", user, e);
+                self.reply(454, "4.7.0 Temporary authentication failure")
+            }
+        }
+    }
+
+    /// Answers ETRN with the replies of RFC 1985.
+    fn etrn(&self, node: &str) -> Reply {
+        if node.is_empty() || node.contains(char::is_whitespace) {
+            return self.reply(501, "5.5.4 Syntax: ETRN <domain>");
+        }
+        if !self.is_trusted() {
+            return self.reply(459, &format!("4.7.1 {}: Node not allowed", node));
+        }
+        let queue = match &self.config.queue {
+            Some(queue) => queue,
+            None => return self.reply(500, "5.5.1 Unknown command"),
+        };
+        match queue.flush(node) {
+            Ok(0) => self.reply(251, &format!("OK, no messages waiting for node {}", node)),
+            Ok(_) => self.reply(250, &format!("Queuing for node {} started", node)),
+            Err(e) => {
+                crate::warn!("Unable to flush the queue of {}: {}", node, e);
+                self.reply(458, &format!("Unable to queue messages for node {}", node))
+            }
+        }
+    }
+
+    /// A reply of the session itself, with its text replaced if the policy says so.
+    fn reply(&self, code: u16, text: &str) -> Reply {
+        let mut reply = Reply::new(code, text);
+        if let Some(text) = self.policy.reply_texts.get(&code) {
+            reply.lines = vec![text.clone()];
+        }
+        reply
+    }
+
+    /// HELO or EHLO, or in LMTP only LHLO.
+    fn is_greeting(&self, curated_line: &str) -> bool {
+        if self.policy.lmtp {
+            return curated_line.starts_with(LHLO);
+        }
+        curated_line.starts_with(HELO) || curated_line.starts_with(EHLO)
+    }
+
+    /// The replies owed after the one `process_line` returned: in LMTP, those for the
+    /// second and further recipients at the end of DATA, to be sent in order.
+    pub fn pending_replies(&mut self) -> Vec<Reply> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Whether the last message accepted was kept in the quarantine rather than handed to
+    /// the outputs.
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined
+    }
+
+    pub fn greeting(&self) -> Reply {
+        let protocol = if self.policy.lmtp { " LMTP" } else { "" };
+        self.reply(
+            220,
+            &format!("{}{} simple-smtp", self.config.server_name, protocol),
+        )
+    }
+}
+
+/// Hands `mail`, accepted as `queue_id`, to the outputs its recipients route to, stopping
+/// at the first that fails.
+pub fn deliver(config: &Config, mail: &Mail, queue_id: &str) -> io::Result<()> {
+    let mut deliveries: Vec<(&dyn Output, Mail)> = Vec::new();
+    for expanded in config.lists.expand(mail) {
+        for (transport, mail) in config.transports.route(&expanded) {
+            let sieve = match (transport, &config.sieve) {
+                (Transport::Local, Some(sieve)) => sieve,
+                _ => {
+                    for output in transport.outputs(&config.outputs) {
+                        deliveries.push((output, mail.clone()));
+                    }
+                    continue;
+                }
+            };
+            let (local, redirected) = sieve.sort(&mail);
+            for mail in local {
+                for output in transport.outputs(&config.outputs) {
+                    deliveries.push((output, mail.clone()));
+                }
+            }
+            for redirect in redirected {
+                for (transport, mail) in config.transports.route(&redirect) {
+                    for output in transport.outputs(&config.outputs) {
+                        deliveries.push((output, mail.clone()));
+                    }
+                }
+            }
+        }
+        for domain in config.domains.iter() {
+            if let Some(mail) = domain.route(&expanded) {
+                for output in domain.outputs.iter() {
+                    deliveries.push((output.as_ref(), mail.clone()));
+                }
+            }
+        }
+    }
+    for (output, mail) in deliveries {
+        deliver_to(output, &mail, queue_id)?;
+    }
+    Ok(())
+}
+
+/// Hands `mail`, accepted as `queue_id`, to `output`, tracing and counting how it went.
+pub fn deliver_to(output: &dyn Output, mail: &Mail, queue_id: &str) -> io::Result<()> {
+    let mut span = trace::start(
+        "output.deliver",
+        trace::Kind::Client,
+        &[("queue_id", &queue_id)],
+    );
+    let started = clock::now();
+    let result = output.deliver(mail);
+    metrics::timing(metrics::OUTPUT_DURATION, clock::elapsed(started));
+    if let Err(e) = &result {
+        metrics::increment(metrics::OUTPUT_FAILURES);
+        span.set_error(e);
+        crate::error!("Output failed: {}", e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mail() {
+        let mut mail = Mail::new();
+        mail.add_hello("server");
+        assert_eq!(mail.helo, Some(String::from("server")));
+        mail.add_mail_from("some@email");
+        assert_eq!(mail.mail_from, Some(String::from("some@email")));
+        mail.add_rcpt_to("email@some");
+        mail.add_rcpt_to("email1@some");
+        assert_eq!(mail.rcpt_to, vec!["email@some", "email1@some"]);
+        mail.add_data_chunk("abc");
+        mail.add_data_chunk("def");
+        assert_eq!(mail.data, Some(String::from("abcdef")));
+    }
+
+    #[test]
+    fn test_mail_json() {
+        let mut mail = Mail::new();
+        mail.add_mail_from("<a@b>");
+        mail.add_rcpt_to("<c@d>");
+        mail.add_data_chunk("Subject: hi\r\n\r\nbody\r\n");
+        let json = mail.to_json().to_string();
+        let parsed = Mail::from_json(&Json::parse(&json).unwrap()).unwrap();
+        assert_eq!(parsed.to_json().to_string(), json);
+        assert_eq!(parsed.helo, None);
+        assert_eq!(parsed.rcpt_to, vec!["<c@d>"]);
+        assert!(!json.contains("dsn") && !json.contains("origin"));
+
+        mail.require_tls = true;
+        mail.priority = 5;
+        mail.origin.protocol = Some(String::from("ESMTP"));
+        let json = mail.to_json().to_string();
+        let parsed = Mail::from_json(&Json::parse(&json).unwrap()).unwrap();
+        assert_eq!(parsed.to_json().to_string(), json);
+        assert!(parsed.require_tls);
+        assert_eq!(parsed.priority, 5);
+        assert_eq!(parsed.origin, mail.origin);
+        assert!(Mail::from_json(&Json::parse("{\"priority\":300}").unwrap()).is_none());
+
+        // Listing every field breaks the build when one is added without a mapping.
+        mail.queue_id = Some(String::from("Q1"));
+        mail.helo = Some(String::from("client"));
+        mail.dsn.envid = Some(String::from("x1"));
+        mail.deliver_by = Some(DeliverBy {
+            deadline: UNIX_EPOCH + std::time::Duration::from_secs(1_800_000_000),
+            mode: deliver_by::Mode::Return,
+            trace: false,
+        });
+        mail.auth = Some(String::from("<a@b>"));
+        mail.folder = Some(String::from("Archive"));
+        let Mail {
+            queue_id,
+            helo,
+            mail_from,
+            rcpt_to,
+            data,
+            dsn,
+            deliver_by,
+            require_tls,
+            priority,
+            auth,
+            origin,
+            folder,
+        } = Mail::from_json(&Json::parse(&mail.to_json().to_string()).unwrap()).unwrap();
+        assert_eq!(queue_id, mail.queue_id);
+        assert_eq!(helo, mail.helo);
+        assert_eq!(mail_from, mail.mail_from);
+        assert_eq!(rcpt_to, mail.rcpt_to);
+        assert_eq!(data, mail.data);
+        assert_eq!(dsn, mail.dsn);
+        assert_eq!(deliver_by, mail.deliver_by);
+        assert_eq!(require_tls, mail.require_tls);
+        assert_eq!(priority, mail.priority);
+        assert_eq!(auth, mail.auth);
+        assert_eq!(origin, mail.origin);
+        assert_eq!(folder, mail.folder);
+    }
+
+    #[test]
+    fn test_domain_of() {
+        assert_eq!(domain_of("<user@example.com>"), Some("example.com"));
+        assert_eq!(domain_of(" user@example.com"), Some("example.com"));
+        assert_eq!(
+            domain_of("<user@example.com> SIZE=100"),
+            Some("example.com")
+        );
+        assert_eq!(domain_of("<>"), None);
+        assert_eq!(local_part_of("<user@example.com>"), "user");
+        assert_eq!(local_part_of("<postmaster>"), "postmaster");
+    }
+
+    #[test]
+    fn test_mail_fsm() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        assert_eq!(
+            mail_fsm.process_line("HELO server\n"),
+            Some(Reply::new(250, "test.server"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM: sender@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt1@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt2@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("DATA\n"),
+            Some(Reply::new(354, "End data with <CR><LF>.<CR><LF>"))
+        );
+        assert_eq!(mail_fsm.process_line("qwert\n"), None);
+        let reply = mail_fsm.process_line(".\n").unwrap();
+        let queue_id = mail_fsm.mail.queue_id.clone().unwrap();
+        assert_eq!(
+            reply,
+            Reply::new(250, &format!("Ok: queued as {}", queue_id))
+        );
+        let data = mail_fsm.mail.data.as_deref().unwrap();
+        assert!(data.starts_with("Received: from server (unknown)\n\tby test.server with SMTP id "));
+        assert!(data.ends_with("\nqwert\n"));
+        assert_eq!(
+            mail_fsm.process_line("QUIT\n"),
+            Some(Reply::new(221, "Bye"))
+        );
+        assert!(mail_fsm.is_finished())
+    }
+
+    #[test]
+    fn test_received_for() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM:<sender@example.com>\n");
+        mail_fsm.process_line("RCPT TO:<a@example.com>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("hi\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+        let data = mail_fsm.mail.data.as_deref().unwrap();
+        let header = data.split(';').next().unwrap();
+        assert!(header.ends_with("\n\tfor <a@example.com>"), "{}", header);
+
+        // Not for several recipients, which would disclose the others.
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM:<sender@example.com>\n");
+        mail_fsm.process_line("RCPT TO:<a@example.com>\n");
+        mail_fsm.process_line("RCPT TO:<b@example.com>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("hi\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+        assert!(!mail_fsm.mail.data.as_deref().unwrap().contains("\tfor "));
+    }
+
+    fn send_message(mail_fsm: &mut MailFSM, data: &str) -> Option<Reply> {
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        mail_fsm.process_line("RCPT TO: rcpt@email\n");
+        mail_fsm.process_line("DATA\n");
+        for line in data.split_inclusive('\n') {
+            mail_fsm.process_line(line);
+        }
+        mail_fsm.process_line(".\n")
+    }
+
+    #[test]
+    fn test_quit_in_body() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        let reply = send_message(&mut mail_fsm, "Quitting time\nQUIT\n");
+        assert_eq!(reply.map(|reply| reply.code), Some(250));
+        assert!(!mail_fsm.is_finished());
+        assert!(mail_fsm
+            .mail
+            .data
+            .as_deref()
+            .unwrap()
+            .ends_with("Quitting time\nQUIT\n"));
+    }
+
+    #[test]
+    fn test_second_transaction() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        assert_eq!(send_message(&mut mail_fsm, "first\n").unwrap().code, 250);
+        mail_fsm.process_line("MAIL FROM: other@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt2@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(mail_fsm.mail.rcpt_to, vec!["rcpt2@email"]);
+        assert_eq!(mail_fsm.mail.data, None);
+        assert_eq!(mail_fsm.mail.helo.as_deref(), Some("server"));
+        assert_eq!(mail_fsm.process_line("QUIT\n").unwrap().code, 221);
+    }
+
+    #[test]
+    fn test_transaction_spans() {
+        let spans =
+            || log::Event::capture(log::Level::Info, &[], format_args!("Message accepted")).spans;
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("HELO server\n");
+        for from in ["<first@a.example>", "<second@b.example>"] {
+            mail_fsm.process_line(&format!("MAIL FROM: {}\n", from));
+            assert_eq!(
+                spans(),
+                vec![("transaction", vec![("from", String::from(from))])]
+            );
+            mail_fsm.process_line("RCPT TO: rcpt@email\n");
+            mail_fsm.process_line("DATA\n");
+            mail_fsm.process_line("hi\n");
+            assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+        }
+        drop(mail_fsm);
+        assert!(spans().is_empty());
+    }
+
+    #[test]
+    fn test_mail_loop() {
+        let mut config = Config::new(String::from("test.server"));
+        config.max_hops = 2;
+        let config = Arc::new(config);
+
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&config));
+        let reply = send_message(
+            &mut mail_fsm,
+            "Received: a\nReceived: b\nReceived: c\n\nhi\n",
+        );
+        assert_eq!(reply, Some(Reply::new(554, "5.4.6 Too many hops (3 > 2)")));
+        assert_eq!(mail_fsm.mail.data, None);
+
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&config));
+        let reply = send_message(
+            &mut mail_fsm,
+            "Received: from x by Test.Server with SMTP\n\nhi\n",
+        );
+        assert_eq!(reply, Some(Reply::new(554, "5.4.6 Mail loop detected")));
+
+        let mut mail_fsm = MailFSM::with_config(config);
+        let reply = send_message(&mut mail_fsm, "Received: from test.server by mx\n\nhi\n");
+        assert_eq!(reply.map(|reply| reply.code), Some(250));
+    }
+
+    struct Policy;
+
+    impl crate::handler::SmtpHandler for Policy {
+        fn on_rcpt_to(&self, _context: &SessionContext, rcpt: &str) -> Verdict {
+            if rcpt.contains("blocked") {
+                Verdict::reject(550, "5.1.1 No such user")
+            } else {
+                Verdict::Accept
+            }
+        }
+
+        fn on_data_chunk(&self, _context: &SessionContext, chunk: &str) -> Verdict {
+            if chunk.contains("viagra") {
+                Verdict::Defer(String::from("4.7.1 Try again later"))
+            } else if chunk.contains("lottery") {
+                Verdict::Quarantine(String::from("spam"))
+            } else {
+                Verdict::Accept
+            }
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        let mut config = Config::new(String::from("test.server"));
+        config.handler = Arc::new(Policy);
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: blocked@email\n"),
+            Some(Reply::new(550, "5.1.1 No such user"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("Buy viagra\n");
+        assert_eq!(
+            mail_fsm.process_line(".\n"),
+            Some(Reply::new(451, "4.7.1 Try again later"))
+        );
+        assert_eq!(mail_fsm.mail.rcpt_to[0], "rcpt@email");
+        assert!(mail_fsm.context().transaction_started_at.is_some());
+    }
+
+    #[test]
+    fn test_quarantine_verdict() {
+        let mut config = Config::new(String::from("test.server"));
+        config.handler = Arc::new(Policy);
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        let reply = send_message(&mut mail_fsm, "You won the lottery\n");
+        assert_eq!(
+            reply,
+            Some(Reply::new(554, "5.7.1 Message refused by policy"))
+        );
+
+        let directory = std::env::temp_dir().join(format!("email-{}", std::process::id()));
+        let quarantine = Arc::new(crate::quarantine::Quarantine::open(&directory).unwrap());
+        let mut config = Config::new(String::from("test.server"));
+        config.handler = Arc::new(Policy);
+        config.quarantine = Some(Arc::clone(&quarantine));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        let reply = send_message(&mut mail_fsm, "You won the lottery\n").unwrap();
+        assert_eq!(reply.code, 250);
+        let listed = quarantine.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].reason, "spam");
+        assert!(reply.text().ends_with(&listed[0].queue_id));
+        assert!(mail_fsm.is_quarantined());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_custom_verb() {
+        let mut config = Config::new(String::from("test.server"));
+        config.verbs.register(
+            "XSTATUS",
+            &[State::Hello],
+            Some("XSTATUS"),
+            |_: &SessionContext, _: &str| Reply::new(250, "All good"),
+        );
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(mail_fsm.process_line("XSTATUS\n").unwrap().code, 500);
+        assert_eq!(
+            mail_fsm.process_line("EHLO client\n").unwrap().to_string(),
+            "250-test.server\r\n250-DSN\r\n250-DELIVERBY\r\n250 XSTATUS\r\n"
+        );
+        assert_eq!(
+            mail_fsm.process_line("xstatus\n"),
+            Some(Reply::new(250, "All good"))
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = crate::policy::Policy::sink()
+            .max_recipients(1)
+            .verbs(&["MAIL", "RCPT", "DATA", "QUIT"])
+            .reply_text(221, "See you");
+        let mut mail_fsm = MailFSM::new(String::from("test.server")).policy(policy);
+        assert_eq!(mail_fsm.process_line("HELO client\n").unwrap().code, 502);
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM: sender@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt1@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt2@email\n"),
+            Some(Reply::new(452, "4.5.3 Too many recipients"))
+        );
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line(".\n");
+        assert_eq!(
+            mail_fsm.process_line("QUIT\n"),
+            Some(Reply::new(221, "See you"))
+        );
+
+        let mut mail_fsm =
+            MailFSM::new(String::from("test.server")).policy(crate::policy::Policy::submission());
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM: sender@email\n"),
+            Some(Reply::new(530, "5.7.0 Authentication required"))
+        );
+    }
+
+    #[test]
+    fn test_auth() {
+        let passwords = crate::auth::Passwords::new(&[("alice", "s3cret")]);
+        let mut config = Config::new(String::from("test.server"));
+        config.authenticator = Some(Arc::new(passwords));
+        let config = Arc::new(config);
+        let submission = || {
+            MailFSM::with_config(Arc::clone(&config))
+                .policy(crate::policy::Policy::submission().tls(true))
+        };
+
+        let mut mail_fsm = submission();
+        assert_eq!(
+            mail_fsm
+                .process_line("AUTH PLAIN AGFsaWNlAHMzY3JldA==\n")
+                .unwrap()
+                .code,
+            503
+        );
+        let ehlo = mail_fsm.process_line("EHLO client\n").unwrap();
+        assert!(ehlo.lines.contains(&String::from("AUTH PLAIN")));
+        assert_eq!(
+            mail_fsm.process_line("AUTH LOGIN\n"),
+            Some(Reply::new(504, "5.5.4 Unrecognized authentication type"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("AUTH PLAIN AGFsaWNlAHdyb25n\n"),
+            Some(Reply::new(535, "5.7.8 Authentication credentials invalid"))
+        );
+        assert_eq!(
+            mail_fsm
+                .process_line("AUTH PLAIN Ym9iAGFsaWNlAHMzY3JldA==\n")
+                .unwrap()
+                .code,
+            535
+        );
+        assert_eq!(mail_fsm.process_line("AUTH PLAIN !!\n").unwrap().code, 501);
+        assert_eq!(mail_fsm.context().authenticated, None);
+        assert_eq!(
+            mail_fsm.process_line("AUTH PLAIN AGFsaWNlAHMzY3JldA==\n"),
+            Some(Reply::new(235, "2.7.0 Authentication successful"))
+        );
+        assert_eq!(mail_fsm.context().authenticated.as_deref(), Some("alice"));
+        assert_eq!(mail_fsm.process_line("AUTH PLAIN\n").unwrap().code, 503);
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<alice@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(mail_fsm.mail.auth.as_deref(), Some("alice"));
+
+        // The credentials on a line of their own, or cancelled.
+        let mut mail_fsm = submission();
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm.process_line("AUTH PLAIN\n").unwrap().to_string(),
+            "334 \r\n"
+        );
+        assert!(mail_fsm.is_authenticating());
+        assert_eq!(mail_fsm.process_line("*\n").unwrap().code, 501);
+        mail_fsm.process_line("auth plain\n");
+        assert_eq!(
+            mail_fsm
+                .process_line("AGFsaWNlAHMzY3JldA==\n")
+                .unwrap()
+                .code,
+            235
+        );
+        assert!(!mail_fsm.is_authenticating());
+
+        // Not offered without an authenticator.
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        let ehlo = mail_fsm.process_line("EHLO client\n").unwrap();
+        assert!(!ehlo.lines.iter().any(|line| line.starts_with("AUTH")));
+        assert_eq!(mail_fsm.process_line("AUTH PLAIN\n").unwrap().code, 500);
+
+        // Nor in the clear, unless the policy allows it.
+        let mut mail_fsm =
+            MailFSM::with_config(Arc::clone(&config)).policy(crate::policy::Policy::submission());
+        let ehlo = mail_fsm.process_line("EHLO client\n").unwrap();
+        assert!(!ehlo.lines.iter().any(|line| line.starts_with("AUTH")));
+        assert_eq!(mail_fsm.process_line("AUTH PLAIN\n").unwrap().code, 500);
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&config))
+            .policy(crate::policy::Policy::submission().insecure_auth(true));
+        let ehlo = mail_fsm.process_line("EHLO client\n").unwrap();
+        assert!(ehlo.lines.contains(&String::from("AUTH PLAIN")));
+    }
+
+    #[test]
+    fn test_unknown_domain() {
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .domains
+            .add(crate::domain::Domain::new("example.com"));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <user@example.org>\n"),
+            Some(Reply::new(554, "5.7.1 Relay access denied"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <user@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+    }
+
+    #[test]
+    fn test_relay() {
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .domains
+            .add(crate::domain::Domain::new("example.com"));
+        let config = Arc::new(config);
+        let peer = "192.0.2.10:40000".parse().ok();
+        let policy = crate::policy::Policy::default().trust("192.0.2.0/24".parse().unwrap());
+        let mut mail_fsm =
+            MailFSM::with_context(Arc::clone(&config), SessionContext::new(peer, None))
+                .policy(policy);
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <user@example.org>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+
+        let mut context = SessionContext::new(None, None);
+        context.authenticated = Some(String::from("alice"));
+        let mut mail_fsm = MailFSM::with_context(config, context);
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <user@example.org>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+    }
+
+    #[test]
+    fn test_sender_quotas() {
+        let config = Config {
+            sender_quotas: Arc::new(crate::quota::SenderQuotas::new(vec![
+                "2 messages/1h".parse().unwrap(),
+                "2 recipients/1h".parse().unwrap(),
+            ])),
+            ..Config::new(String::from("test.server"))
+        };
+        let config = Arc::new(config);
+        let mut context = SessionContext::new(None, None);
+        context.authenticated = Some(String::from("alice"));
+        let mut mail_fsm = MailFSM::with_context(Arc::clone(&config), context.clone());
+        assert_eq!(send_message(&mut mail_fsm, "hi\n").unwrap().code, 250);
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: rcpt@email\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: other@email\n"),
+            Some(Reply::new(
+                452,
+                "4.7.1 Sending quota exceeded, try again later"
+            ))
+        );
+        mail_fsm.process_line("DATA\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+
+        let mut mail_fsm = MailFSM::with_context(Arc::clone(&config), context);
+        mail_fsm.process_line("HELO server\n");
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM: sender@email\n")
+                .unwrap()
+                .code,
+            452
+        );
+        // Anonymous clients are not counted.
+        let mut mail_fsm = MailFSM::with_config(config);
+        assert_eq!(send_message(&mut mail_fsm, "hi\n").unwrap().code, 250);
+    }
+
+    #[test]
+    fn test_unknown_recipient() {
+        let mut config = Config::new(String::from("test.server"));
+        config.recipients = Some(Arc::new(crate::recipient::StaticRecipients::new(&[
+            "alice",
+        ])));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <bob@example.com>\n"),
+            Some(Reply::new(550, "5.1.1 No such user"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <alice@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_policy_service() {
+        use crate::policy_service::PolicyService;
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = std::thread::spawn(move || {
+            let mut states = Vec::new();
+            for stream in listener.incoming().take(3) {
+                let stream = stream.unwrap();
+                let mut recipient = String::new();
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(state) = line.strip_prefix("protocol_state=") {
+                        states.push(String::from(state));
+                    }
+                    if let Some(value) = line.strip_prefix("recipient=") {
+                        recipient = String::from(value);
+                    }
+                }
+                let action = match recipient.as_str() {
+                    "spam@example.com" => "REJECT Not here",
+                    _ => "DUNNO",
+                };
+                write!(&stream, "action={}\n\n", action).unwrap();
+            }
+            states
+        });
+        let mut config = Config::new(String::from("test.server"));
+        config.policy_service = Some(Arc::new(PolicyService::new(&address.to_string())));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM: <a@example.org>\n")
+                .unwrap()
+                .code,
+            250
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <spam@example.com>\n"),
+            Some(Reply::new(554, "5.7.1 Not here"))
+        );
+        assert_eq!(
+            mail_fsm
+                .process_line("RCPT TO: <b@example.com>\n")
+                .unwrap()
+                .code,
+            250
+        );
+        assert_eq!(service.join().unwrap(), vec!["MAIL", "RCPT", "RCPT"]);
+        // The service is gone, so DATA waits for it.
+        assert_eq!(mail_fsm.process_line("DATA\n").unwrap().code, 451);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_milter() {
+        use crate::milter::Milter;
+        use std::io::{Read, Write};
+
+        // Refuses RCPT TO spam@, and adds a header to the messages it accepts.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut length = [0; 4];
+            while stream.read_exact(&mut length).is_ok() {
+                let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut packet).unwrap();
+                let data = String::from_utf8_lossy(&packet[1..]).into_owned();
+                let replies: Vec<(u8, &[u8])> = match packet[0] {
+                    b'O' => vec![(b'O', &[0, 0, 0, 6, 0, 0, 0, 1, 0, 0, 0, 0])],
+                    b'R' if data.contains("spam@") => vec![(b'y', b"550 5.7.1 Not wanted\0")],
+                    b'E' => vec![(b'h', b"X-Milter\0checked\0"), (b'a', b"")],
+                    b'D' | b'A' | b'Q' => vec![],
+                    _ => vec![(b'c', b"")],
+                };
+                for (command, data) in replies {
+                    let mut reply = (data.len() as u32 + 1).to_be_bytes().to_vec();
+                    reply.push(command);
+                    reply.extend_from_slice(data);
+                    stream.write_all(&reply).unwrap();
+                }
+            }
+        });
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .milters
+            .push(Arc::new(Milter::new(&address.to_string())));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(mail_fsm.process_line("HELO client\n").unwrap().code, 250);
+        mail_fsm.process_line("MAIL FROM: <a@example.org>\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <spam@example.com>\n"),
+            Some(Reply::new(550, "5.7.1 Not wanted"))
+        );
+        assert_eq!(
+            mail_fsm
+                .process_line("RCPT TO: <b@example.com>\n")
+                .unwrap()
+                .code,
+            250
+        );
+        assert_eq!(mail_fsm.process_line("DATA\n").unwrap().code, 354);
+        mail_fsm.process_line("Subject: hi\r\n");
+        mail_fsm.process_line("\r\n");
+        mail_fsm.process_line("hello\r\n");
+        assert_eq!(mail_fsm.process_line(".\r\n").unwrap().code, 250);
+        let data = mail_fsm.mail.data.as_deref().unwrap();
+        assert!(data.contains("\r\nSubject: hi\r\nX-Milter: checked\r\n\r\nhello\r\n"));
+
+        // Without the milter, messages wait for it.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .milters
+            .push(Arc::new(Milter::new(&closed.to_string())));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(mail_fsm.process_line("HELO client\n").unwrap().code, 451);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_clamd() {
+        use crate::clamav::Clamd;
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let clamd = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0; 10];
+            stream.read_exact(&mut command).unwrap();
+            let mut length = [0; 4];
+            stream.read_exact(&mut length).unwrap();
+            let mut chunk = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut chunk).unwrap();
+            stream.read_exact(&mut length).unwrap();
+            assert_eq!(length, [0; 4]);
+            stream
+                .write_all(b"stream: Eicar-Signature FOUND\0")
+                .unwrap();
+        });
+        let mut config = Config::new(String::from("test.server"));
+        config.clamd = Some(Arc::new(Clamd::new(&address.to_string())));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: <a@example.org>\n");
+        mail_fsm.process_line("RCPT TO: <b@example.com>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("Subject: hi\r\n");
+        mail_fsm.process_line("\r\n");
+        assert_eq!(
+            mail_fsm.process_line(".\r\n"),
+            Some(Reply::new(554, "5.7.1 Virus found: Eicar-Signature"))
+        );
+        clamd.join().unwrap();
+        assert_eq!(mail_fsm.mail.data, None);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_spam_filter() {
+        use crate::spam::{Scanner, SpamFilter};
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let spamd = std::thread::spawn(move || {
+            for (score, stream) in ["6.5", "20.0"].iter().zip(listener.incoming()) {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    if let Some(value) = line.strip_prefix("Content-length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                write!(
+                    &stream,
+                    "SPAMD/1.1 0 EX_OK\r\nSpam: True ; {} / 5.0\r\n\r\n",
+                    score
+                )
+                .unwrap();
+            }
+        });
+        let send = |mail_fsm: &mut MailFSM| {
+            mail_fsm.process_line("HELO client\n");
+            mail_fsm.process_line("MAIL FROM: <a@example.org>\n");
+            mail_fsm.process_line("RCPT TO: <b@example.com>\n");
+            mail_fsm.process_line("DATA\n");
+            mail_fsm.process_line("Subject: hi\r\n");
+            mail_fsm.process_line("\r\n");
+            mail_fsm.process_line("hello\r\n");
+            mail_fsm.process_line(".\r\n").unwrap()
+        };
+        let mut filter = SpamFilter::new(Scanner::Spamd {
+            host: String::from("127.0.0.1"),
+            port: address.port(),
+        });
+        filter.reject_score = Some(15.0);
+        let mut config = Config::new(String::from("test.server"));
+        config.spam_filter = Some(Arc::new(filter));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(send(&mut mail_fsm).code, 250);
+        let data = mail_fsm.mail.data.as_deref().unwrap();
+        assert!(data.starts_with("X-Spam-Flag: YES\r\n"));
+        assert!(data.contains("X-Spam-Status: Yes, score=6.5 required=5.0\r\n"));
+        mail_fsm.process_line("QUIT\n");
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&mail_fsm.config));
+        assert_eq!(
+            send(&mut mail_fsm),
+            Reply::new(554, "5.7.1 Message rejected as spam")
+        );
+        spamd.join().unwrap();
+
+        // Without spamd, messages wait for it unless the filter fails open.
+        let mut config = Config::new(String::from("test.server"));
+        let mut filter = SpamFilter::new(Scanner::Spamd {
+            host: String::from("127.0.0.1"),
+            port: address.port(),
+        });
+        config.spam_filter = Some(Arc::new(SpamFilter::new(filter.scanner.clone())));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(send(&mut mail_fsm).code, 451);
+        filter.fail_open = true;
+        let mut config = Config::new(String::from("test.server"));
+        config.spam_filter = Some(Arc::new(filter));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert_eq!(send(&mut mail_fsm).code, 250);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_callout() {
+        use crate::{callout::Callout, output::relay::Relay};
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = Config::new(String::from("test.server"));
+        config.transports.add(
+            "example.org",
+            Transport::Relay(Box::new(Relay::new("127.0.0.1", port, "test.server"))),
+        );
+        config.callout = Some(Arc::new(Callout::default()));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <bob@example.org>\n"),
+            Some(Reply::new(451, "4.3.0 Temporary failure, try again later"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <bob@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut config = Config::new(String::from("test.server"));
+        config.aliases.add(
+            "team@example.com",
+            &["alice@example.com", "bob@example.com"],
+        );
+        config.recipients = Some(Arc::new(crate::recipient::StaticRecipients::new(&[
+            "alice",
+        ])));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <team@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.mail.rcpt_to,
+            vec!["<alice@example.com>", "<bob@example.com>"]
+        );
+    }
+
+    #[test]
+    fn test_dsn() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM:<a@email> RET=ALL\n")
+                .unwrap()
+                .code,
+            501
+        );
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<a@email> RET=HDRS ENVID=QQ314159\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO:<b@email> NOTIFY=SUCCESS,FAILURE\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(mail_fsm.mail.dsn.ret, Some(dsn::Ret::Headers));
+        assert_eq!(mail_fsm.mail.dsn.envid.as_deref(), Some("QQ314159"));
+        assert!(mail_fsm.mail.dsn.notify("<b@email>").success);
+        assert!(!mail_fsm.mail.dsn.notify("<b@email>").delay);
+    }
+
+    #[test]
+    fn test_etrn() {
+        let mut config = Config::new(String::from("test.server"));
+        config.queue = Some(Arc::new(|node: &str| match node {
+            "example.com" => Ok(2),
+            "@example.org" => Ok(0),
+            _ => Err(io::Error::other("unknown node")),
+        }));
+        let config = Arc::new(config);
+        let policy = crate::policy::Policy::default().trust("192.0.2.0/24".parse().unwrap());
+        let peer = "192.0.2.10:40000".parse().ok();
+        let mut mail_fsm =
+            MailFSM::with_context(Arc::clone(&config), SessionContext::new(peer, None))
+                .policy(policy.clone());
+        assert!(mail_fsm
+            .process_line("EHLO client\n")
+            .unwrap()
+            .lines
+            .contains(&String::from("ETRN")));
+        assert_eq!(
+            mail_fsm.process_line("ETRN example.com\n"),
+            Some(Reply::new(250, "Queuing for node example.com started"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("ETRN @example.org\n").unwrap().code,
+            251
+        );
+        assert_eq!(
+            mail_fsm.process_line("ETRN example.net\n").unwrap().code,
+            458
+        );
+        assert_eq!(mail_fsm.process_line("ETRN\n").unwrap().code, 501);
+
+        let peer = "198.51.100.1:40000".parse().ok();
+        let mut mail_fsm =
+            MailFSM::with_context(config, SessionContext::new(peer, None)).policy(policy);
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm.process_line("ETRN example.com\n").unwrap().code,
+            459
+        );
+    }
+
+    #[test]
+    fn test_xclient() {
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .domains
+            .add(crate::domain::Domain::new("example.com"));
+        let config = Arc::new(config);
+        let policy = crate::policy::Policy::default()
+            .allow_xclient("192.0.2.1".parse().unwrap())
+            .trust("198.51.100.0/24".parse().unwrap());
+        let frontend = "192.0.2.1:40000".parse().ok();
+        let mut mail_fsm =
+            MailFSM::with_context(Arc::clone(&config), SessionContext::new(frontend, None))
+                .policy(policy.clone());
+        assert!(mail_fsm
+            .process_line("EHLO frontend\n")
+            .unwrap()
+            .lines
+            .iter()
+            .any(|line| line.starts_with("XCLIENT")));
+        assert_eq!(
+            mail_fsm.process_line("XCLIENT ADDR=198.51.100.7 NAME=client.example.net\n"),
+            Some(Reply::new(220, "test.server simple-smtp"))
+        );
+        assert_eq!(mail_fsm.context().peer, "198.51.100.7:40000".parse().ok());
+        assert_eq!(
+            mail_fsm.context().client_name.as_deref(),
+            Some("client.example.net")
+        );
+        assert_eq!(
+            mail_fsm
+                .process_line("XCLIENT ADDR=192.0.2.1\n")
+                .unwrap()
+                .code,
+            550
+        );
+        mail_fsm.process_line("EHLO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@example.net>\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO:<b@example.org>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.mail.origin,
+            Origin {
+                address: "198.51.100.7".parse().ok(),
+                name: Some(String::from("client.example.net")),
+                protocol: Some(String::from("ESMTP")),
+            }
+        );
+
+        let mut mail_fsm =
+            MailFSM::with_context(Arc::clone(&config), SessionContext::new(frontend, None))
+                .policy(policy);
+        assert_eq!(
+            mail_fsm
+                .process_line("XCLIENT ADDR=IPV6:2001:db8::1 LOGIN=alice HELO=[UNAVAILABLE]\n")
+                .unwrap()
+                .code,
+            220
+        );
+        assert_eq!(mail_fsm.context().authenticated.as_deref(), Some("alice"));
+        assert_eq!(
+            mail_fsm.process_line("XCLIENT FOO=bar\n").unwrap().code,
+            550
+        );
+
+        let mut mail_fsm = MailFSM::with_config(config)
+            .policy(crate::policy::Policy::default().allow_xclient("192.0.2.1".parse().unwrap()));
+        assert_eq!(
+            mail_fsm
+                .process_line("XCLIENT ADDR=192.0.2.9\n")
+                .unwrap()
+                .code,
+            550
+        );
+    }
+
+    #[test]
+    fn test_deliver_by() {
+        let mut config = Config::new(String::from("test.server"));
+        config.min_by_time = 60;
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert!(mail_fsm
+            .process_line("EHLO client\n")
+            .unwrap()
+            .lines
+            .contains(&String::from("DELIVERBY 60")));
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM:<a@email> BY=30;R\n")
+                .unwrap()
+                .code,
+            501
+        );
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<a@email> BY=-10;N\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(
+            mail_fsm.mail.deliver_by.map(|by| by.mode),
+            Some(deliver_by::Mode::Notify)
+        );
+        mail_fsm.process_line("RCPT TO:<b@email>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("Subject: late\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+    }
+
+    #[test]
+    fn test_require_tls() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM:<a@email> REQUIRETLS\n")
+                .unwrap()
+                .code,
+            555
+        );
+
+        // Behind a proxy terminating TLS on port 465.
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .profiles
+            .insert(465, crate::policy::Policy::default().tls(true));
+        let local: SocketAddr = "192.0.2.25:465".parse().unwrap();
+        let context = SessionContext::new(None, Some(local));
+        let mut mail_fsm = MailFSM::with_context(Arc::new(config), context);
+        assert!(mail_fsm.context().tls);
+        assert!(mail_fsm
+            .process_line("EHLO client\n")
+            .unwrap()
+            .lines
+            .contains(&String::from("REQUIRETLS")));
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<a@email> REQUIRETLS\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert!(mail_fsm.mail.require_tls);
+    }
+
+    #[test]
+    fn test_lmtp() {
+        struct Full;
+        impl Output for Full {
+            fn deliver(&self, _: &Mail) -> io::Result<()> {
+                Err(output::permanent_failure(String::from("mailbox full")))
+            }
+        }
+        let mut config = Config::new(String::from("test.server"));
+        let mut full = crate::domain::Domain::new("full.example.com");
+        full.outputs.push(Box::new(Full));
+        config.domains.add(full);
+        config
+            .domains
+            .add(crate::domain::Domain::new("example.com"));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config))
+            .policy(crate::policy::Policy::default().lmtp(true));
+        assert_eq!(mail_fsm.greeting().text(), "test.server LMTP simple-smtp");
+        assert_eq!(mail_fsm.process_line("EHLO client\n").unwrap().code, 500);
+        assert_eq!(mail_fsm.process_line("LHLO client\n").unwrap().code, 250);
+        mail_fsm.process_line("MAIL FROM:<a@example.net>\n");
+        mail_fsm.process_line("RCPT TO:<b@example.com>\n");
+        mail_fsm.process_line("RCPT TO:<c@full.example.com>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("Subject: hi\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+        let pending = mail_fsm.pending_replies();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].code, 554);
+        assert!(mail_fsm.pending_replies().is_empty());
+    }
+
+    #[test]
+    fn test_script() {
+        use crate::script::PolicyScript;
+
+        let script = PolicyScript::parse(
+            "on rcpt {\n\
+                 if rcpt.domain == \"example.org\" and not authenticated {\n\
+                     reject 550 \"5.7.1 Relaying denied\";\n\
+                 }\n\
+             }\n\
+             on data {\n\
+                 set_header \"X-Policy\" \"from \" + from.domain;\n\
+             }\n",
+        )
+        .unwrap();
+        let mut config = Config::new(String::from("test.server"));
+        config.script = Some(Arc::new(script));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM: <a@Example.NET>\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <b@example.org>\n"),
+            Some(Reply::new(550, "5.7.1 Relaying denied"))
+        );
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <b@example.com>\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("X-Policy: forged\r\n");
+        mail_fsm.process_line("\r\n");
+        mail_fsm.process_line("hi\r\n");
+        assert_eq!(mail_fsm.process_line(".\r\n").unwrap().code, 250);
+        let data = mail_fsm.mail.data.as_deref().unwrap();
+        assert!(data.contains("\r\nX-Policy: from example.net\r\n\r\nhi\r\n"));
+    }
+
+    #[test]
+    fn test_sieve() {
+        use crate::{
+            sieve::Sieve,
+            store::{MemoryStore, MessageStore, StoreOutput},
+        };
+
+        let directory = std::env::temp_dir().join(format!("email-sieve-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("b@example.com.sieve"),
+            "require \"fileinto\";\n\
+             if header :contains \"Subject\" \"report\" { fileinto \"Reports\"; }\n\
+             redirect \"c@example.com\";\n",
+        )
+        .unwrap();
+        let store = Arc::new(MemoryStore::new(10));
+        let mut config = Config::new(String::from("test.server"));
+        config.outputs = vec![Box::new(StoreOutput(store.clone()))];
+        config.sieve = Some(Arc::new(Sieve::new(&directory)));
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.org>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from("Subject: Weekly report\r\n\r\nhi\r\n"));
+        deliver(&config, &mail, "4Xb2kQ9").unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        let stored: Vec<(Vec<String>, Option<String>)> = store
+            .list()
+            .into_iter()
+            .map(|stored| (stored.mail.rcpt_to, stored.mail.folder))
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                (
+                    vec![String::from("<b@example.com>")],
+                    Some(String::from("Reports"))
+                ),
+                (vec![String::from("<c@example.com>")], None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mailbox_quotas() {
+        use crate::{
+            quota::{MailboxLimits, MailboxQuotas},
+            store::{MemoryStore, MessageStore},
+        };
+
+        let store = Arc::new(MemoryStore::new(10));
+        let mut limits = MailboxLimits::default();
+        limits.add("bob@email 400").unwrap();
+        limits.add("carol@email 10").unwrap();
+        let config = Config {
+            mailbox_quotas: Some(Arc::new(MailboxQuotas::new(store.clone(), limits))),
+            ..Config::new(String::from("test.server"))
+        };
+        let config = Arc::new(config);
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![String::from("<carol@email>")];
+        mail.data = Some(String::from("0123456789"));
+        store.save(&mail).unwrap();
+
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&config));
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <carol@email>\n"),
+            Some(Reply::new(552, "5.2.2 Mailbox full"))
+        );
+        assert_eq!(
+            mail_fsm
+                .process_line("RCPT TO: <bob@email>\n")
+                .unwrap()
+                .code,
+            250
+        );
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line(&format!("{}\n", "x".repeat(400)));
+        assert_eq!(
+            mail_fsm.process_line(".\n"),
+            Some(Reply::new(552, "5.2.2 Mailbox full"))
+        );
+
+        // In LMTP, only the recipient over its quota is refused.
+        let mut mail_fsm =
+            MailFSM::with_config(config).policy(crate::policy::Policy::default().lmtp(true));
+        mail_fsm.process_line("LHLO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@example.net>\n");
+        mail_fsm.process_line("RCPT TO:<bob@email>\n");
+        mail_fsm.process_line("RCPT TO:<dave@email>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line(&format!("{}\n", "x".repeat(400)));
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 552);
+        assert_eq!(mail_fsm.pending_replies()[0].code, 250);
+    }
+
+    #[test]
+    fn test_mt_priority() {
+        let mut config = Config::new(String::from("test.server"));
+        config.priority_policy = Some(crate::priority::PriorityPolicy::Mixer);
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        assert!(mail_fsm
+            .process_line("EHLO client\n")
+            .unwrap()
+            .lines
+            .contains(&String::from("MT-PRIORITY MIXER")));
+        assert_eq!(
+            mail_fsm
+                .process_line("MAIL FROM:<a@email> MT-PRIORITY=12\n")
+                .unwrap()
+                .code,
+            501
+        );
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<a@email> MT-PRIORITY=-3\n"),
+            Some(Reply::new(250, "Ok"))
+        );
+        assert_eq!(mail_fsm.mail.priority, -3);
+    }
+
+    #[test]
+    fn test_auth_parameter() {
+        let config = Arc::new(Config::new(String::from("test.server")));
+        let mut context = SessionContext::new(None, None);
+        context.authenticated = Some(String::from("alice@example.com"));
+        let mut mail_fsm = MailFSM::with_context(Arc::clone(&config), context.clone());
+        mail_fsm.process_line("EHLO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@email> AUTH=bob@example.com\n");
+        assert_eq!(mail_fsm.mail.auth, None);
+        let mut mail_fsm = MailFSM::with_context(Arc::clone(&config), context);
+        mail_fsm.process_line("EHLO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@email>\n");
+        assert_eq!(mail_fsm.mail.auth.as_deref(), Some("alice@example.com"));
+
+        let peer = "192.0.2.10:40000".parse().ok();
+        let mut mail_fsm = MailFSM::with_context(config, SessionContext::new(peer, None))
+            .policy(crate::policy::Policy::default().trust("192.0.2.0/24".parse().unwrap()));
+        mail_fsm.process_line("EHLO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@email> AUTH=bob+2Bx@example.com\n");
+        assert_eq!(mail_fsm.mail.auth.as_deref(), Some("bob+x@example.com"));
+    }
+
+    #[test]
+    fn test_rset() {
+        let mut mail_fsm = MailFSM::new(String::from("test.server"));
+        mail_fsm.process_line("HELO client\n");
+        mail_fsm.process_line("MAIL FROM:<a@email>\n");
+        mail_fsm.process_line("RCPT TO:<b@email>\n");
+        assert_eq!(mail_fsm.process_line("RSET\n").unwrap().code, 250);
+        assert_eq!(mail_fsm.mail.mail_from, None);
+        assert!(mail_fsm.mail.rcpt_to.is_empty());
+        assert_eq!(mail_fsm.mail.helo.as_deref(), Some("client"));
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM:<c@email>\n").unwrap().code,
+            250
+        );
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut config = Config::new(String::from("test.server"));
+        config.memory = Arc::new(crate::memory::MemoryBudget::new(16, 1024));
+        let config = Arc::new(config);
+        let mut mail_fsm = MailFSM::with_config(Arc::clone(&config));
+        let reply = send_message(&mut mail_fsm, "0123456789\n0123456789\n").unwrap();
+        assert_eq!(reply.code, 452);
+        assert_eq!(mail_fsm.mail.data, None);
+        assert_eq!(config.memory.used(), 0);
+
+        mail_fsm.process_line("RSET\n");
+        assert_eq!(send_message(&mut mail_fsm, "short\n").unwrap().code, 250);
+        assert_eq!(config.memory.used(), 6);
+        drop(mail_fsm);
+        assert_eq!(config.memory.used(), 0);
+    }
+
+    #[test]
+    fn test_disk_check() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let free = Arc::new(AtomicU64::new(100));
+        let volume = Arc::clone(&free);
+        let mut config = Config::new(String::from("test.server"));
+        config.disk = Some(crate::disk::DiskCheck::new(
+            Arc::new(move || -> io::Result<u64> { Ok(volume.load(Ordering::Relaxed)) }),
+            1000,
+        ));
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        mail_fsm.process_line("HELO server\n");
+        mail_fsm.process_line("MAIL FROM: sender@email\n");
+        mail_fsm.process_line("RCPT TO: rcpt@email\n");
+        assert_eq!(
+            mail_fsm.process_line("DATA\n"),
+            Some(Reply::new(452, "4.3.1 Insufficient system storage"))
+        );
+        free.store(5000, Ordering::Relaxed);
+        assert_eq!(mail_fsm.process_line("DATA\n").unwrap().code, 354);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut config = Config::new(String::from("test.server"));
+        config
+            .profiles
+            .insert(587, crate::policy::Policy::submission());
+        let config = Arc::new(config);
+        let local = "192.0.2.1:587".parse().ok();
+        let mut mail_fsm =
+            MailFSM::with_context(Arc::clone(&config), SessionContext::new(None, local));
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM: a@email\n").unwrap().code,
+            530
+        );
+
+        let mut context = SessionContext::new(None, local);
+        context.authenticated = Some(String::from("alice"));
+        let mut mail_fsm = MailFSM::with_context(Arc::clone(&config), context);
+        send_message(&mut mail_fsm, "Subject: hi\n\nhi\n");
+        let message = Message::parse(mail_fsm.mail.data.as_deref().unwrap());
+        assert!(message.get("Date").is_some());
+        assert!(message
+            .get("Message-ID")
+            .unwrap()
+            .ends_with("@test.server>"));
+
+        let local = "192.0.2.1:25".parse().ok();
+        let mut mail_fsm = MailFSM::with_context(config, SessionContext::new(None, local));
+        mail_fsm.process_line("EHLO client\n");
+        assert_eq!(
+            mail_fsm.process_line("MAIL FROM: a@email\n").unwrap().code,
+            250
+        );
+    }
+
+    #[test]
+    fn test_transition_table() {
+        let commands = [
+            "HELO client",
+            "EHLO client",
+            "MAIL FROM:<a@example.com>",
+            "RCPT TO:<b@example.com>",
+            "DATA",
+            "Subject: hi",
+            ".",
+            "RSET",
+            "NOOP",
+            "QUIT",
+        ];
+        // A linear congruential generator, for sequences that are random but repeatable.
+        let mut seed: u64 = 7;
+        let mut fsm = MailFSM::new(String::from("mx.example.com"));
+        let table = fsm.transition_table();
+        assert!(table.contains(&Transition {
+            from: State::RcptTo,
+            verb: DATA,
+            to: State::Data,
+            reply: ReplyClass::Intermediate,
+        }));
+        for _ in 0..2000 {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let line = commands[(seed >> 33) as usize % commands.len()];
+            let from = fsm.state();
+            let reply = fsm.process_line(&format!("{}\r\n", line));
+            let transition = table.iter().find(|transition| {
+                transition.from == from && line.to_uppercase().starts_with(transition.verb)
+            });
+            match (transition, reply) {
+                (Some(transition), Some(reply)) => {
+                    let class = ReplyClass::of(reply.code).unwrap();
+                    if class == transition.reply {
+                        assert_eq!(fsm.state(), transition.to, "{:?} {}", from, line);
+                    } else {
+                        assert!(!reply.is_positive(), "{:?} {}: {}", from, line, reply);
+                        assert_eq!(fsm.state(), from, "{:?} {}", from, line);
+                    }
+                }
+                (None, Some(reply)) => {
+                    assert_eq!(reply.code / 100, 5, "{:?} {}: {}", from, line, reply);
+                    assert_eq!(fsm.state(), from, "{:?} {}", from, line);
+                }
+                (transition, None) => {
+                    assert_eq!((transition, from), (None, State::Data), "{}", line)
+                }
+            }
+            if fsm.is_finished() {
+                fsm = MailFSM::new(String::from("mx.example.com"));
+            }
+        }
+
+        let lmtp = MailFSM::new(String::from("mx.example.com")).policy(
+            crate::policy::Policy::default()
+                .lmtp(true)
+                .require_helo(true),
+        );
+        let table = lmtp.transition_table();
+        assert!(table.iter().any(|transition| transition.verb == LHLO));
+        assert!(!table.iter().any(|transition| transition.verb == HELO));
+        assert!(!table
+            .iter()
+            .any(|transition| transition.from == State::New && transition.verb == MAIL_FROM));
     }
 }
@@ -0,0 +1,159 @@
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(input: &[u8]) -> String {
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+/// Encodes `input` as base64 wrapped at 76 characters, as MIME bodies require.
+pub fn base64_encode_lines(input: &[u8], line_ending: &str) -> String {
+    let encoded = base64_encode(input);
+    let mut output = String::with_capacity(encoded.len() + encoded.len() / 38);
+    for line in encoded.as_bytes().chunks(76) {
+        output.push_str(std::str::from_utf8(line).unwrap());
+        output.push_str(line_ending);
+    }
+    output
+}
+
+/// Decodes base64, skipping whitespace. Returns `None` on any other invalid input.
+pub fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() / 4 * 3);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b' ' | b'\t' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        buffer = buffer << 6 | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+pub fn quoted_printable_encode(input: &[u8], line_ending: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut line_length = 0;
+    let mut i = 0;
+    while i < input.len() {
+        let b = input[i];
+        if b == b'\n' || (b == b'\r' && input.get(i + 1) == Some(&b'\n')) {
+            // Trailing whitespace must be encoded so it survives transport.
+            if output.ends_with(' ') || output.ends_with('\t') {
+                let last = output.pop().unwrap();
+                output.push_str(&format!("={:02X}", last as u8));
+            }
+            output.push_str(line_ending);
+            line_length = 0;
+            i += if b == b'\r' { 2 } else { 1 };
+            continue;
+        }
+        let encoded = if b == b'=' || (b < 32 && b != b'\t') || b > 126 {
+            format!("={:02X}", b)
+        } else {
+            (b as char).to_string()
+        };
+        if line_length + encoded.len() > 75 {
+            output.push('=');
+            output.push_str(line_ending);
+            line_length = 0;
+        }
+        line_length += encoded.len();
+        output.push_str(&encoded);
+        i += 1;
+    }
+    output
+}
+
+pub fn quoted_printable_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            output.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        match bytes.get(i + 1) {
+            Some(b'\r') if bytes.get(i + 2) == Some(&b'\n') => i += 3,
+            Some(b'\n') => i += 2,
+            _ => match input
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(b) => {
+                    output.push(b);
+                    i += 3;
+                }
+                None => {
+                    output.push(b'=');
+                    i += 1;
+                }
+            },
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_decode("Zm9v\r\nYmE="), Some(b"fooba".to_vec()));
+        assert_eq!(base64_decode("Zm9v!"), None);
+        let long = vec![b'a'; 100];
+        let lines = base64_encode_lines(&long, "\n");
+        assert!(lines.lines().all(|line| line.len() <= 76));
+        assert_eq!(base64_decode(&lines), Some(long));
+    }
+
+    #[test]
+    fn test_quoted_printable() {
+        assert_eq!(
+            quoted_printable_encode("café = ok \nnext".as_bytes(), "\r\n"),
+            "caf=C3=A9 =3D ok=20\r\nnext"
+        );
+        assert_eq!(
+            quoted_printable_decode("caf=C3=A9 =3D ok=20\r\nsoft=\r\nbreak"),
+            "café = ok \r\nsoftbreak".as_bytes()
+        );
+        let long = "x".repeat(200);
+        let encoded = quoted_printable_encode(long.as_bytes(), "\n");
+        assert!(encoded.lines().all(|line| line.len() <= 76));
+        assert_eq!(quoted_printable_decode(&encoded), long.as_bytes());
+    }
+}
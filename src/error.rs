@@ -0,0 +1,43 @@
+use std::{error::Error, fmt, io};
+
+/// Why a session ended early.
+#[derive(Debug)]
+pub enum SmtpError {
+    /// Reading from or writing to the client failed.
+    Io(io::Error),
+    /// The client sent something that is not SMTP, e.g. a line that is not valid UTF-8.
+    Parse(String),
+    /// The session was ended by a server policy such as a limit or a block list.
+    Policy(String),
+    /// A message could not be handed to storage or another output.
+    Storage(String),
+}
+
+impl fmt::Display for SmtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtpError::Io(e) => write!(f, "I/O error: {}", e),
+            SmtpError::Parse(reason) => write!(f, "invalid input: {}", reason),
+            SmtpError::Policy(reason) => write!(f, "policy: {}", reason),
+            SmtpError::Storage(reason) => write!(f, "storage error: {}", reason),
+        }
+    }
+}
+
+impl Error for SmtpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SmtpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SmtpError {
+    fn from(e: io::Error) -> SmtpError {
+        match e.kind() {
+            io::ErrorKind::InvalidData => SmtpError::Parse(e.to_string()),
+            _ => SmtpError::Io(e),
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use crate::{email::Mail, output::Output};
+
+/// Fans accepted messages out to any number of live subscribers, e.g. `GET /events`.
+pub struct EventFeed {
+    subscribers: Mutex<Vec<Sender<Mail>>>,
+}
+
+impl EventFeed {
+    pub fn new() -> EventFeed {
+        EventFeed {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Mail> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn publish(&self, mail: &Mail) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(mail.clone()).is_ok());
+    }
+}
+
+impl Default for EventFeed {
+    fn default() -> EventFeed {
+        EventFeed::new()
+    }
+}
+
+impl Output for EventFeed {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        self.publish(mail);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let feed = EventFeed::new();
+        let first = feed.subscribe();
+        let second = feed.subscribe();
+        drop(second);
+
+        let mut mail = Mail::new();
+        mail.queue_id = Some(String::from("1"));
+        feed.publish(&mail);
+        assert_eq!(first.recv().unwrap().queue_id, Some(String::from("1")));
+        assert_eq!(feed.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{email::State, reply::Reply, session::SessionContext};
+
+/// Answers a custom verb, given the text after the verb itself.
+pub trait VerbHandler: Send + Sync {
+    fn handle(&self, context: &SessionContext, args: &str) -> Reply;
+}
+
+impl<F> VerbHandler for F
+where
+    F: Fn(&SessionContext, &str) -> Reply + Send + Sync,
+{
+    fn handle(&self, context: &SessionContext, args: &str) -> Reply {
+        self(context, args)
+    }
+}
+
+pub struct Verb {
+    pub name: String,
+    /// The states in which the verb is accepted; elsewhere it is an unknown command.
+    pub states: Vec<State>,
+    /// Advertised in the EHLO reply when set, e.g. `XCLIENT NAME ADDR`.
+    pub capability: Option<String>,
+    handler: Box<dyn VerbHandler>,
+}
+
+/// Verbs added by the embedder on top of the built-in commands, which always take
+/// precedence.
+#[derive(Default)]
+pub struct Verbs {
+    verbs: Vec<Verb>,
+    /// Advertised without a verb of their own, e.g. a parameter of MAIL FROM.
+    advertised: Vec<String>,
+}
+
+impl Verbs {
+    pub fn register<H: VerbHandler + 'static>(
+        &mut self,
+        name: &str,
+        states: &[State],
+        capability: Option<&str>,
+        handler: H,
+    ) {
+        self.verbs.push(Verb {
+            name: name.to_uppercase(),
+            states: states.to_vec(),
+            capability: capability.map(String::from),
+            handler: Box::new(handler),
+        });
+    }
+
+    /// Runs the verb `line` starts with if it is registered for `state`.
+    pub fn dispatch(&self, state: State, context: &SessionContext, line: &str) -> Option<Reply> {
+        let line = line.trim();
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+        let verb = self
+            .verbs
+            .iter()
+            .find(|verb| verb.name.eq_ignore_ascii_case(name) && verb.states.contains(&state))?;
+        Some(verb.handler.handle(context, args.trim()))
+    }
+
+    /// Advertises `capability` in the EHLO reply, for an extension that is not a verb.
+    pub fn advertise(&mut self, capability: &str) {
+        self.advertised.push(String::from(capability));
+    }
+
+    /// The keywords to advertise after the server name in the EHLO reply.
+    pub fn capabilities(&self) -> impl Iterator<Item = &str> {
+        self.verbs
+            .iter()
+            .filter_map(|verb| verb.capability.as_deref())
+            .chain(self.advertised.iter().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch() {
+        let mut verbs = Verbs::default();
+        verbs.register(
+            "xping",
+            &[State::Hello],
+            Some("XPING"),
+            |_: &SessionContext, args: &str| Reply::new(250, &format!("pong {}", args)),
+        );
+        let context = SessionContext::new(None, None);
+        assert_eq!(
+            verbs.dispatch(State::Hello, &context, "XPing  hello\r\n"),
+            Some(Reply::new(250, "pong hello"))
+        );
+        assert_eq!(verbs.dispatch(State::New, &context, "XPING"), None);
+        assert_eq!(verbs.dispatch(State::Hello, &context, "XPINGX"), None);
+        assert_eq!(verbs.capabilities().collect::<Vec<_>>(), ["XPING"]);
+    }
+}
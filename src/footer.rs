@@ -0,0 +1,211 @@
+use crate::{
+    encoding,
+    headers::{parse_parameters, Message},
+};
+
+pub struct Footer {
+    pub text: String,
+    /// HTML variant; when absent the text footer is escaped and used for HTML parts.
+    pub html: Option<String>,
+}
+
+impl Footer {
+    pub fn new(text: &str) -> Footer {
+        Footer {
+            text: String::from(text),
+            html: None,
+        }
+    }
+
+    fn html(&self) -> String {
+        match &self.html {
+            Some(html) => html.clone(),
+            None => {
+                let escaped = self
+                    .text
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('\n', "<br>\n");
+                format!("<p>{}</p>", escaped)
+            }
+        }
+    }
+
+    /// Appends the footer to the message body, returning the rewritten message.
+    pub fn apply(&self, data: &str) -> String {
+        let mut message = Message::parse(data);
+        self.apply_to_part(&mut message);
+        message.to_string()
+    }
+
+    fn apply_to_part(&self, part: &mut Message) {
+        let (content_type, params) =
+            parse_parameters(part.get("Content-Type").unwrap_or("text/plain"));
+        match content_type.as_str() {
+            "text/plain" => {
+                let footer = format!("{}{}", part.line_ending(), self.text);
+                append_text(part, &footer, |body, footer| format!("{}{}", body, footer));
+            }
+            "text/html" => append_text(part, &self.html(), |body, footer| {
+                // Lowercasing the body would move the offsets of characters that change length.
+                let end = body
+                    .as_bytes()
+                    .windows(7)
+                    .rposition(|window| window.eq_ignore_ascii_case(b"</body>"));
+                match end {
+                    Some(position) => {
+                        format!("{}{}{}", &body[..position], footer, &body[position..])
+                    }
+                    None => format!("{}{}", body, footer),
+                }
+            }),
+            multipart if multipart.starts_with("multipart/") => {
+                let boundary = match params.iter().find(|(name, _)| name == "boundary") {
+                    Some((_, boundary)) => boundary,
+                    None => return,
+                };
+                // Every alternative is a rendering of the same content, so each gets the
+                // footer; for mixed/related only the leading body part does.
+                let all_parts = multipart == "multipart/alternative";
+                let body = map_parts(&part.body, boundary, part.line_ending(), |index, part| {
+                    if all_parts || index == 0 {
+                        self.apply_to_part(part);
+                    }
+                });
+                part.body = body;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Decodes the part according to its transfer encoding, lets `append` add the footer and
+/// encodes the result again. Parts whose content can't be decoded are left untouched.
+fn append_text<F>(part: &mut Message, footer: &str, append: F)
+where
+    F: Fn(&str, &str) -> String,
+{
+    let line_ending = part.line_ending();
+    let encoding = part
+        .get("Content-Transfer-Encoding")
+        .unwrap_or("7bit")
+        .trim()
+        .to_lowercase();
+    let decoded = match encoding.as_str() {
+        "base64" => encoding::base64_decode(&part.body),
+        "quoted-printable" => Some(encoding::quoted_printable_decode(&part.body)),
+        _ => Some(part.body.clone().into_bytes()),
+    };
+    let body = match decoded.and_then(|bytes| String::from_utf8(bytes).ok()) {
+        Some(body) => body,
+        None => return,
+    };
+    let trailing_newline = body.ends_with('\n');
+    let mut body = append(body.trim_end_matches(&['\r', '\n'][..]), footer);
+    if trailing_newline {
+        body.push_str(line_ending);
+    }
+
+    part.body = match encoding.as_str() {
+        "base64" => encoding::base64_encode_lines(body.as_bytes(), line_ending),
+        "quoted-printable" => encoding::quoted_printable_encode(body.as_bytes(), line_ending),
+        "7bit" if !footer.is_ascii() => {
+            part.replace("Content-Transfer-Encoding", "quoted-printable");
+            encoding::quoted_printable_encode(body.as_bytes(), line_ending)
+        }
+        _ => body,
+    };
+}
+
+/// Splits a multipart body on `boundary`, calls `f` for each part and joins them back,
+/// keeping the preamble, epilogue and delimiter lines as they were.
+fn map_parts<F>(body: &str, boundary: &str, line_ending: &str, mut f: F) -> String
+where
+    F: FnMut(usize, &mut Message),
+{
+    let delimiter = format!("{}--{}", line_ending, boundary);
+    let text = format!("{}{}", line_ending, body);
+    let mut pieces: Vec<String> = text.split(delimiter.as_str()).map(String::from).collect();
+
+    for (index, piece) in pieces.iter_mut().skip(1).enumerate() {
+        if piece.starts_with("--") {
+            break;
+        }
+        let start = match piece.find(line_ending) {
+            Some(position) => position + line_ending.len(),
+            None => continue,
+        };
+        let mut part = Message::parse(&piece[start..]);
+        f(index, &mut part);
+        let rewritten = format!("{}{}", &piece[..start], part);
+        *piece = rewritten;
+    }
+
+    String::from(&pieces.join(delimiter.as_str())[line_ending.len()..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_footer() {
+        let footer = Footer::new("--\nDisclaimer");
+        assert_eq!(
+            footer.apply("Subject: Hi\n\nHello\n"),
+            "Subject: Hi\n\nHello\n--\nDisclaimer\n"
+        );
+    }
+
+    #[test]
+    fn test_html_footer() {
+        let footer = Footer::new("Bye");
+        // `İ` takes more bytes lowercased.
+        assert_eq!(
+            footer.apply("Content-Type: text/html\n\n<html><BODY>İİİİ</BODY></html>\n"),
+            "Content-Type: text/html\n\n<html><BODY>İİİİ<p>Bye</p></BODY></html>\n"
+        );
+    }
+
+    #[test]
+    fn test_base64_footer() {
+        let footer = Footer::new("Bye");
+        let body = encoding::base64_encode_lines(b"Hello\r\n", "\r\n");
+        let data = format!(
+            "Content-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+            body
+        );
+        let message = Message::parse(&footer.apply(&data));
+        assert_eq!(
+            encoding::base64_decode(&message.body),
+            Some(b"Hello\r\nBye\r\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_alternative_footer() {
+        let footer = Footer::new("Bye");
+        let data = "Content-Type: multipart/alternative; boundary=\"b1\"\n\n\
+                    preamble\n\
+                    --b1\n\
+                    Content-Type: text/plain\n\n\
+                    Hello\n\
+                    --b1\n\
+                    Content-Type: text/html\n\n\
+                    <html><body>Hello</body></html>\n\
+                    --b1--\n";
+        assert_eq!(
+            footer.apply(data),
+            "Content-Type: multipart/alternative; boundary=\"b1\"\n\n\
+             preamble\n\
+             --b1\n\
+             Content-Type: text/plain\n\n\
+             Hello\nBye\n\
+             --b1\n\
+             Content-Type: text/html\n\n\
+             <html><body>Hello<p>Bye</p></body></html>\n\
+             --b1--\n"
+        );
+    }
+}
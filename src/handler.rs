@@ -0,0 +1,74 @@
+use crate::{email::Mail, reply::Reply, session::SessionContext};
+
+/// The answer of a hook: go on as usual, refuse with a permanent error, ask the client
+/// to try again later, or accept the message but keep it in `Config::quarantine` rather
+/// than deliver it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Reject {
+        code: u16,
+        text: String,
+    },
+    Defer(String),
+    /// With the reason, e.g. `spam score 12.5`. Only `on_data_chunk` and
+    /// `on_message_complete` may quarantine, the other hooks take it as `Accept`. The
+    /// message is refused with 554 when there is no quarantine.
+    Quarantine(String),
+}
+
+impl Verdict {
+    pub fn reject(code: u16, text: &str) -> Verdict {
+        Verdict::Reject {
+            code,
+            text: String::from(text),
+        }
+    }
+
+    /// The reply to send instead of the usual one, `None` when accepted. Deferrals use
+    /// `defer_code`, e.g. 451 during a transaction or 421 when closing the connection.
+    pub fn reply(&self, defer_code: u16) -> Option<Reply> {
+        match self {
+            Verdict::Accept | Verdict::Quarantine(_) => None,
+            Verdict::Reject { code, text } => Some(Reply::new(*code, text)),
+            Verdict::Defer(text) => Some(Reply::new(defer_code, text)),
+        }
+    }
+}
+
+/// Callbacks run at each step of a session. Every method accepts by default, so
+/// implementations only override the steps they care about.
+pub trait SmtpHandler: Send + Sync {
+    fn on_connect(&self, _context: &SessionContext) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn on_helo(&self, _context: &SessionContext, _domain: &str) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn on_mail_from(&self, _context: &SessionContext, _from: &str) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn on_rcpt_to(&self, _context: &SessionContext, _rcpt: &str) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Called for every line of the message; a refusal is answered once the data ends.
+    fn on_data_chunk(&self, _context: &SessionContext, _chunk: &str) -> Verdict {
+        Verdict::Accept
+    }
+
+    /// Called after the header rules and footer ran, before the outputs.
+    fn on_message_complete(&self, _context: &SessionContext, _mail: &Mail) -> Verdict {
+        Verdict::Accept
+    }
+
+    fn on_quit(&self, _context: &SessionContext) {}
+}
+
+/// Accepts everything.
+pub struct DefaultHandler;
+
+impl SmtpHandler for DefaultHandler {}
@@ -0,0 +1,245 @@
+use std::{fmt, sync::Arc};
+
+use crate::email::Mail;
+
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+/// Parsed message: header fields in their original order plus the untouched body.
+pub struct Message {
+    pub headers: Vec<Header>,
+    pub body: String,
+    line_ending: &'static str,
+}
+
+impl Message {
+    pub fn parse(data: &str) -> Message {
+        let line_ending = if data.contains("\r\n") { "\r\n" } else { "\n" };
+        let mut headers: Vec<Header> = Vec::new();
+        let mut offset = 0;
+
+        for line in data.split_inclusive('\n') {
+            let content = line.trim_end_matches(&['\r', '\n'][..]);
+            if content.is_empty() {
+                offset += line.len();
+                break;
+            }
+            if content.starts_with(' ') || content.starts_with('\t') {
+                if let Some(last) = headers.last_mut() {
+                    last.value.push_str(line_ending);
+                    last.value.push_str(content);
+                    offset += line.len();
+                    continue;
+                }
+                break;
+            }
+            match content.find(':') {
+                Some(colon) if colon > 0 => {
+                    headers.push(Header {
+                        name: String::from(&content[..colon]),
+                        value: String::from(content[colon + 1..].trim_start()),
+                    });
+                    offset += line.len();
+                }
+                _ => break,
+            }
+        }
+
+        Message {
+            headers,
+            body: String::from(&data[offset..]),
+            line_ending,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value.as_str())
+    }
+
+    pub fn count(&self, name: &str) -> usize {
+        self.headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case(name))
+            .count()
+    }
+
+    /// Adds a header above the existing ones, where trace and filter headers belong.
+    pub fn add(&mut self, name: &str, value: &str) {
+        self.headers.insert(
+            0,
+            Header {
+                name: String::from(name),
+                value: String::from(value),
+            },
+        );
+    }
+
+    /// Replaces every occurrence of `name` with a single header, adding it if missing.
+    pub fn replace(&mut self, name: &str, value: &str) {
+        match self
+            .headers
+            .iter()
+            .position(|h| h.name.eq_ignore_ascii_case(name))
+        {
+            Some(position) => {
+                self.headers[position].value = String::from(value);
+                let mut index = 0;
+                self.headers.retain(|h| {
+                    index += 1;
+                    index - 1 == position || !h.name.eq_ignore_ascii_case(name)
+                });
+            }
+            None => self.add(name, value),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.headers.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    }
+
+    pub fn line_ending(&self) -> &'static str {
+        self.line_ending
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for header in self.headers.iter() {
+            write!(f, "{}: {}{}", header.name, header.value, self.line_ending)?;
+        }
+        write!(f, "{}{}", self.line_ending, self.body)
+    }
+}
+
+/// Splits a structured value such as `text/plain; charset="utf-8"` into its lowercased
+/// main value and its parameters (names lowercased, quotes removed).
+pub fn parse_parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut parts = value.split(';');
+    let main = parts.next().unwrap_or("").trim().to_lowercase();
+    let params = parts
+        .filter_map(|part| {
+            let (name, value) = part.split_once('=')?;
+            Some((
+                name.trim().to_lowercase(),
+                String::from(value.trim().trim_matches('"')),
+            ))
+        })
+        .collect();
+    (main, params)
+}
+
+type HeaderCallback = Arc<dyn Fn(&Mail, &mut Message) + Send + Sync>;
+
+pub enum HeaderRule {
+    Add {
+        name: String,
+        value: String,
+    },
+    Replace {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    /// Prepends `prefix` to the header value unless it is already there, e.g. `[EXTERNAL] `.
+    Prefix {
+        name: String,
+        prefix: String,
+    },
+    Callback(HeaderCallback),
+}
+
+impl HeaderRule {
+    pub fn apply(&self, mail: &Mail, message: &mut Message) {
+        match self {
+            HeaderRule::Add { name, value } => message.add(name, value),
+            HeaderRule::Replace { name, value } => message.replace(name, value),
+            HeaderRule::Remove { name } => message.remove(name),
+            HeaderRule::Prefix { name, prefix } => {
+                let value = message.get(name).unwrap_or("");
+                if !value.starts_with(prefix.as_str()) {
+                    let value = format!("{}{}", prefix, value);
+                    message.replace(name, &value);
+                }
+            }
+            HeaderRule::Callback(callback) => callback(mail, message),
+        }
+    }
+}
+
+pub fn rewrite(mail: &Mail, rules: &[HeaderRule]) -> Option<String> {
+    let data = mail.data.as_ref()?;
+    let mut message = Message::parse(data);
+    for rule in rules.iter() {
+        rule.apply(mail, &mut message);
+    }
+    Some(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let message = Message::parse("Subject: Hi\r\nX-Long: a\r\n\tb\r\n\r\nbody\r\n");
+        assert_eq!(message.get("subject"), Some("Hi"));
+        assert_eq!(message.get("X-Long"), Some("a\r\n\tb"));
+        assert_eq!(message.body, "body\r\n");
+        assert_eq!(
+            message.to_string(),
+            "Subject: Hi\r\nX-Long: a\r\n\tb\r\n\r\nbody\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_parameters() {
+        assert_eq!(
+            parse_parameters("Multipart/Alternative; Boundary=\"abc\"; charset=utf-8"),
+            (
+                String::from("multipart/alternative"),
+                vec![
+                    (String::from("boundary"), String::from("abc")),
+                    (String::from("charset"), String::from("utf-8"))
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let mut mail = Mail::new();
+        mail.rcpt_to.push(String::from("<rcpt@email>"));
+        mail.data = Some(String::from(
+            "Subject: Hello\nX-Mailer: test\nReceived: a\nReceived: b\n\nbody\n",
+        ));
+        let rules = vec![
+            HeaderRule::Prefix {
+                name: String::from("Subject"),
+                prefix: String::from("[EXTERNAL] "),
+            },
+            HeaderRule::Remove {
+                name: String::from("x-mailer"),
+            },
+            HeaderRule::Replace {
+                name: String::from("Received"),
+                value: String::from("c"),
+            },
+            HeaderRule::Callback(Arc::new(|mail: &Mail, message: &mut Message| {
+                message.add("X-Original-To", &mail.rcpt_to.join(", "))
+            })),
+        ];
+        assert_eq!(
+            rewrite(&mail, &rules),
+            Some(String::from(
+                "X-Original-To: <rcpt@email>\nSubject: [EXTERNAL] Hello\nReceived: c\n\nbody\n"
+            ))
+        );
+    }
+}
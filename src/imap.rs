@@ -0,0 +1,1304 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    auth::Authenticator,
+    clock, datetime,
+    headers::Message,
+    store::{MessageStore, StoredMail},
+};
+
+/// How long a client may stay silent, the least RFC 3501 allows.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Longer command lines end the session.
+const MAX_LINE_LENGTH: u64 = 8192;
+
+/// Larger literals end the session; commands of this service carry no message.
+const MAX_LITERAL: usize = 64 * 1024;
+
+/// The flags a client may set, kept as long as the server runs.
+const FLAGS: [&str; 5] = ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+const CAPABILITIES: &str = "IMAP4rev1 LITERAL+";
+
+/// Serves the messages of a `MessageStore` as the INBOX of an IMAP4rev1 server (RFC 3501),
+/// enough for Thunderbird and other mail clients to read a capture server: LOGIN, checked
+/// by an `Authenticator`, LIST, SELECT and EXAMINE of INBOX, FETCH, SEARCH, STORE of the
+/// system flags, EXPUNGE and the UID forms of these.
+///
+/// Every user sees the whole store, as the HTTP API does, and shares its flags, which live
+/// in memory. No message can be added, copied or moved. ENVELOPE, BODYSTRUCTURE, MIME
+/// parts and searches by date are not supported. There is no TLS, passwords cross the
+/// network in plain text.
+pub struct Imap {
+    store: Arc<dyn MessageStore>,
+    auth: Arc<dyn Authenticator>,
+    server_name: String,
+    audit: Option<Arc<AuditLog>>,
+    uid_validity: u32,
+    mailbox: Mutex<Mailbox>,
+}
+
+/// What the sessions share about the stored messages.
+#[derive(Default)]
+struct Mailbox {
+    next_uid: u32,
+    /// By store ID.
+    messages: HashMap<String, Metadata>,
+}
+
+struct Metadata {
+    uid: u32,
+    flags: BTreeSet<String>,
+    /// When the message was first seen, standing for when it was received.
+    internal_date: SystemTime,
+}
+
+/// A message of the selected mailbox, its sequence number its position plus one.
+struct Entry {
+    uid: u32,
+    stored: StoredMail,
+    /// The message as sent, with CRLF line endings.
+    text: String,
+}
+
+enum State {
+    NotAuthenticated,
+    Authenticated,
+    Selected {
+        entries: Vec<Entry>,
+        read_only: bool,
+    },
+}
+
+/// An argument of a command.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    /// Also numbers, sequence sets and fetch items such as `BODY.PEEK[HEADER]<0.100>`.
+    Atom(String),
+    /// A quoted string or a literal.
+    String(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// The text of an atom or string.
+    fn text(&self) -> Option<&str> {
+        match self {
+            Value::Atom(text) | Value::String(text) => Some(text),
+            Value::List(_) => None,
+        }
+    }
+}
+
+/// A search criterion of SEARCH.
+enum Key {
+    All,
+    Flag(&'static str, bool),
+    Header(String, String),
+    Body(String),
+    Text(String),
+    Larger(usize),
+    Smaller(usize),
+    Sequence(Vec<(u32, u32)>),
+    Uid(Vec<(u32, u32)>),
+    Not(Box<Key>),
+    Or(Box<Key>, Box<Key>),
+    And(Vec<Key>),
+}
+
+impl Imap {
+    pub fn new(store: Arc<dyn MessageStore>, auth: Arc<dyn Authenticator>) -> Imap {
+        Imap {
+            store,
+            auth,
+            server_name: String::from("localhost"),
+            audit: None,
+            // UIDs start over with the process.
+            uid_validity: datetime::unix_seconds(clock::now()) as u32,
+            mailbox: Mutex::new(Mailbox {
+                next_uid: 1,
+                messages: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The name given in the greeting, `localhost` by default.
+    pub fn server_name(mut self, server_name: &str) -> Imap {
+        self.server_name = String::from(server_name);
+        self
+    }
+
+    /// Where refused logins are recorded.
+    pub fn audit(mut self, audit: Arc<AuditLog>) -> Imap {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Serves each connection on a thread of its own, until accepting one fails.
+    pub fn serve(self, listener: TcpListener) {
+        let imap = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    crate::warn!("IMAP connection failed: {}", e);
+                    continue;
+                }
+            };
+            let imap = Arc::clone(&imap);
+            thread::spawn(move || {
+                if let Err(e) = imap.handle_stream(&stream) {
+                    crate::debug!("IMAP session failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn handle_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let peer = stream.peer_addr().map(|peer| peer.ip().to_string());
+        let peer = peer.as_deref().unwrap_or("unknown");
+        self.session(peer, BufReader::new(stream), BufWriter::new(stream))
+    }
+
+    /// Runs one session over `reader` and `writer`, until LOGOUT or the end of the input.
+    pub fn handle<R: BufRead, W: Write>(&self, reader: R, writer: W) -> io::Result<()> {
+        self.session("unknown", reader, writer)
+    }
+
+    /// Like `handle`, for a client at `peer`.
+    fn session<R: BufRead, W: Write>(
+        &self,
+        peer: &str,
+        mut reader: R,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let mut state = State::NotAuthenticated;
+        write_line(
+            &mut writer,
+            &format!(
+                "* OK [CAPABILITY {}] {} IMAP4rev1 server ready",
+                CAPABILITIES, self.server_name
+            ),
+        )?;
+        writer.flush()?;
+        loop {
+            let line = match read_command(&mut reader, &mut writer)? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            let (tag, rest) = match line.split_once(' ') {
+                Some((tag, rest)) if !tag.is_empty() => (tag, rest),
+                _ => {
+                    write_line(&mut writer, "* BAD Missing command")?;
+                    writer.flush()?;
+                    continue;
+                }
+            };
+            let (name, arguments) = rest.split_once(' ').unwrap_or((rest, ""));
+            let mut name = name.to_uppercase();
+            let mut arguments = arguments;
+            let uid = name == "UID";
+            if uid {
+                let (command, rest) = arguments.split_once(' ').unwrap_or((arguments, ""));
+                name = command.to_uppercase();
+                arguments = rest;
+            }
+            let result = match parse_values(arguments) {
+                Ok(values) => self.command(peer, &mut state, &name, uid, &values, &mut writer),
+                Err(e) => Ok(Err(format!("BAD {}", e))),
+            };
+            let logout = name == "LOGOUT" && !uid;
+            match result? {
+                Ok(completed) => write_line(&mut writer, &format!("{} OK {}", tag, completed))?,
+                Err(failed) => write_line(&mut writer, &format!("{} {}", tag, failed))?,
+            }
+            writer.flush()?;
+            if logout {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs a command, writing its untagged responses, and returns the text of the tagged
+    /// one: after OK, or with its status when it failed.
+    fn command<W: Write>(
+        &self,
+        peer: &str,
+        state: &mut State,
+        name: &str,
+        uid: bool,
+        arguments: &[Value],
+        writer: &mut W,
+    ) -> io::Result<Result<String, String>> {
+        let bad = |message: &str| Ok(Err(format!("BAD {}", message)));
+        if uid && !matches!(name, "FETCH" | "SEARCH" | "STORE") {
+            return bad("Unknown UID command");
+        }
+        match (name, &*state) {
+            ("CAPABILITY", _) => {
+                write_line(writer, &format!("* CAPABILITY {}", CAPABILITIES))?;
+                Ok(Ok(String::from("CAPABILITY completed")))
+            }
+            ("NOOP", _) | ("CHECK", State::Selected { .. }) => {
+                self.refresh(state, writer)?;
+                Ok(Ok(format!("{} completed", name)))
+            }
+            ("LOGOUT", _) => {
+                write_line(writer, &format!("* BYE {} signing off", self.server_name))?;
+                Ok(Ok(String::from("LOGOUT completed")))
+            }
+            ("LOGIN", State::NotAuthenticated) => {
+                let (user, password) = match arguments {
+                    [user, password] => match (user.text(), password.text()) {
+                        (Some(user), Some(password)) => (user, password),
+                        _ => return bad("Expected user and password"),
+                    },
+                    _ => return bad("Expected user and password"),
+                };
+                match self.auth.authenticate(user, password) {
+                    Ok(true) => {
+                        crate::info!("IMAP login as {}", user);
+                        *state = State::Authenticated;
+                        Ok(Ok(String::from("LOGIN completed")))
+                    }
+                    Ok(false) => {
+                        crate::info!("IMAP login as {} refused", user);
+                        let failed = "NO [AUTHENTICATIONFAILED] Invalid user name or password";
+                        if let Some(audit) = &self.audit {
+                            let fields = [("service", &"imap" as &dyn Display), ("user", &user)];
+                            audit.record(AuditEvent::AuthFailed, peer, "-", &fields, failed);
+                        }
+                        Ok(Err(String::from(failed)))
+                    }
+                    Err(e) => {
+                        crate::warn!("Unable to check the password of {}: {}", user, e);
+                        Ok(Err(String::from("NO [UNAVAILABLE] Try again later")))
+                    }
+                }
+            }
+            (_, State::NotAuthenticated) => bad("Log in first"),
+            ("SELECT", _) | ("EXAMINE", _) => {
+                let mailbox = arguments.first().and_then(Value::text).unwrap_or("");
+                if !mailbox.eq_ignore_ascii_case("INBOX") {
+                    *state = State::Authenticated;
+                    return Ok(Err(String::from("NO [NONEXISTENT] No such mailbox")));
+                }
+                let read_only = name == "EXAMINE";
+                let entries = self.entries();
+                let permanent = if read_only {
+                    ""
+                } else {
+                    "\\Answered \\Flagged \\Deleted \\Seen \\Draft"
+                };
+                write_line(writer, &format!("* FLAGS ({})", FLAGS.join(" ")))?;
+                write_line(
+                    writer,
+                    &format!("* OK [PERMANENTFLAGS ({})] Flags kept", permanent),
+                )?;
+                write_line(writer, &format!("* {} EXISTS", entries.len()))?;
+                write_line(writer, "* 0 RECENT")?;
+                if let Some(unseen) = entries
+                    .iter()
+                    .position(|entry| !self.flags(entry).contains("\\Seen"))
+                {
+                    write_line(
+                        writer,
+                        &format!("* OK [UNSEEN {}] First unseen", unseen + 1),
+                    )?;
+                }
+                write_line(
+                    writer,
+                    &format!("* OK [UIDVALIDITY {}] UIDs valid", self.uid_validity),
+                )?;
+                write_line(
+                    writer,
+                    &format!("* OK [UIDNEXT {}] Predicted next UID", self.uid_next()),
+                )?;
+                *state = State::Selected { entries, read_only };
+                let access = if read_only { "READ-ONLY" } else { "READ-WRITE" };
+                Ok(Ok(format!("[{}] {} completed", access, name)))
+            }
+            ("LIST", _) | ("LSUB", _) => {
+                let pattern = match arguments {
+                    [reference, pattern] => match (reference.text(), pattern.text()) {
+                        (Some(_), Some(pattern)) => pattern,
+                        _ => return bad("Expected reference and mailbox"),
+                    },
+                    _ => return bad("Expected reference and mailbox"),
+                };
+                if pattern.is_empty() {
+                    write_line(writer, &format!("* {} (\\Noselect) \"/\" \"\"", name))?;
+                } else if matches_pattern("INBOX", &pattern.to_uppercase()) {
+                    write_line(writer, &format!("* {} (\\HasNoChildren) \"/\" INBOX", name))?;
+                }
+                Ok(Ok(format!("{} completed", name)))
+            }
+            ("STATUS", _) => {
+                let (mailbox, items) = match arguments {
+                    [mailbox, Value::List(items)] => (mailbox.text().unwrap_or(""), items),
+                    _ => return bad("Expected mailbox and status items"),
+                };
+                if !mailbox.eq_ignore_ascii_case("INBOX") {
+                    return Ok(Err(String::from("NO [NONEXISTENT] No such mailbox")));
+                }
+                let entries = self.entries();
+                let mut status = Vec::new();
+                for item in items {
+                    let item = item.text().unwrap_or("").to_uppercase();
+                    let value = match item.as_str() {
+                        "MESSAGES" => entries.len() as u32,
+                        "RECENT" => 0,
+                        "UIDNEXT" => self.uid_next(),
+                        "UIDVALIDITY" => self.uid_validity,
+                        "UNSEEN" => entries
+                            .iter()
+                            .filter(|entry| !self.flags(entry).contains("\\Seen"))
+                            .count() as u32,
+                        _ => return bad("Unknown status item"),
+                    };
+                    status.push(format!("{} {}", item, value));
+                }
+                write_line(writer, &format!("* STATUS INBOX ({})", status.join(" ")))?;
+                Ok(Ok(String::from("STATUS completed")))
+            }
+            ("SUBSCRIBE", _) | ("UNSUBSCRIBE", _) => Ok(Ok(format!("{} completed", name))),
+            ("CREATE", _)
+            | ("DELETE", _)
+            | ("RENAME", _)
+            | ("APPEND", _)
+            | ("COPY", _)
+            | ("MOVE", _) => Ok(Err(String::from(
+                "NO [CANNOT] There is only INBOX, which the server fills",
+            ))),
+            (_, State::Authenticated) => bad("Select a mailbox first"),
+            ("CLOSE", State::Selected { read_only, .. }) => {
+                if !*read_only {
+                    let mut sink = io::sink();
+                    self.expunge(state, &mut sink)?;
+                }
+                *state = State::Authenticated;
+                Ok(Ok(String::from("CLOSE completed")))
+            }
+            ("EXPUNGE", State::Selected { read_only, .. }) => {
+                if *read_only {
+                    return Ok(Err(String::from("NO Mailbox is read-only")));
+                }
+                self.expunge(state, writer)?;
+                Ok(Ok(String::from("EXPUNGE completed")))
+            }
+            ("FETCH", State::Selected { .. }) => match self.fetch(state, uid, arguments, writer)? {
+                Ok(()) => Ok(Ok(format!(
+                    "{}FETCH completed",
+                    if uid { "UID " } else { "" }
+                ))),
+                Err(e) => bad(&e),
+            },
+            ("SEARCH", State::Selected { .. }) => {
+                match self.search(state, uid, arguments, writer)? {
+                    Ok(()) => Ok(Ok(format!(
+                        "{}SEARCH completed",
+                        if uid { "UID " } else { "" }
+                    ))),
+                    Err(e) => bad(&e),
+                }
+            }
+            ("STORE", State::Selected { read_only, .. }) => {
+                if *read_only {
+                    return Ok(Err(String::from("NO Mailbox is read-only")));
+                }
+                match self.store_flags(state, uid, arguments, writer)? {
+                    Ok(()) => Ok(Ok(format!(
+                        "{}STORE completed",
+                        if uid { "UID " } else { "" }
+                    ))),
+                    Err(e) => bad(&e),
+                }
+            }
+            _ => bad("Unknown command"),
+        }
+    }
+
+    /// The messages of the store in UID order, giving a UID to those seen first.
+    fn entries(&self) -> Vec<Entry> {
+        let stored = self.store.list();
+        let mut mailbox = self.mailbox.lock().unwrap();
+        let mut entries = Vec::new();
+        let mut present = BTreeSet::new();
+        for stored in stored {
+            let uid = match mailbox.messages.get(&stored.id) {
+                Some(metadata) => metadata.uid,
+                None => {
+                    let uid = mailbox.next_uid;
+                    mailbox.next_uid += 1;
+                    let metadata = Metadata {
+                        uid,
+                        flags: BTreeSet::new(),
+                        internal_date: clock::now(),
+                    };
+                    mailbox.messages.insert(stored.id.clone(), metadata);
+                    uid
+                }
+            };
+            present.insert(stored.id.clone());
+            let mut text = String::new();
+            for line in stored.mail.data.as_deref().unwrap_or("").lines() {
+                text.push_str(line);
+                text.push_str("\r\n");
+            }
+            entries.push(Entry { uid, stored, text });
+        }
+        // Those the store dropped are gone for good.
+        mailbox.messages.retain(|id, _| present.contains(id));
+        entries.sort_by_key(|entry| entry.uid);
+        entries
+    }
+
+    fn uid_next(&self) -> u32 {
+        self.mailbox.lock().unwrap().next_uid
+    }
+
+    fn flags(&self, entry: &Entry) -> BTreeSet<String> {
+        let mailbox = self.mailbox.lock().unwrap();
+        mailbox
+            .messages
+            .get(&entry.stored.id)
+            .map(|metadata| metadata.flags.clone())
+            .unwrap_or_default()
+    }
+
+    fn set_flags(&self, entry: &Entry, flags: BTreeSet<String>) {
+        let mut mailbox = self.mailbox.lock().unwrap();
+        if let Some(metadata) = mailbox.messages.get_mut(&entry.stored.id) {
+            metadata.flags = flags;
+        }
+    }
+
+    fn internal_date(&self, entry: &Entry) -> SystemTime {
+        let mailbox = self.mailbox.lock().unwrap();
+        mailbox
+            .messages
+            .get(&entry.stored.id)
+            .map_or_else(clock::now, |metadata| metadata.internal_date)
+    }
+
+    /// Adds the messages stored since the mailbox was selected, telling the client.
+    fn refresh<W: Write>(&self, state: &mut State, writer: &mut W) -> io::Result<()> {
+        if let State::Selected { entries, .. } = state {
+            let last = entries.last().map_or(0, |entry| entry.uid);
+            let before = entries.len();
+            entries.extend(self.entries().into_iter().filter(|entry| entry.uid > last));
+            if entries.len() != before {
+                write_line(writer, &format!("* {} EXISTS", entries.len()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the messages flagged `\Deleted` from the store, telling the client.
+    fn expunge<W: Write>(&self, state: &mut State, writer: &mut W) -> io::Result<()> {
+        let entries = match state {
+            State::Selected { entries, .. } => entries,
+            _ => return Ok(()),
+        };
+        // From the last, so that the numbers given stay right.
+        for index in (0..entries.len()).rev() {
+            if self.flags(&entries[index]).contains("\\Deleted") {
+                let entry = entries.remove(index);
+                self.store.delete(&entry.stored.id);
+                self.mailbox
+                    .lock()
+                    .unwrap()
+                    .messages
+                    .remove(&entry.stored.id);
+                write_line(writer, &format!("* {} EXPUNGE", index + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The positions of the messages `set` names, by sequence number or by UID.
+    fn select(entries: &[Entry], set: &str, uid: bool) -> Result<Vec<usize>, String> {
+        let largest = if uid {
+            entries.last().map_or(0, |entry| entry.uid)
+        } else {
+            entries.len() as u32
+        };
+        let ranges = parse_set(set, largest).ok_or("Invalid sequence set")?;
+        if !uid && ranges.iter().any(|&(_, high)| high > largest) {
+            return Err(String::from("No such message"));
+        }
+        Ok((0..entries.len())
+            .filter(|&index| {
+                let number = if uid {
+                    entries[index].uid
+                } else {
+                    index as u32 + 1
+                };
+                in_set(&ranges, number)
+            })
+            .collect())
+    }
+
+    fn fetch<W: Write>(
+        &self,
+        state: &mut State,
+        uid: bool,
+        arguments: &[Value],
+        writer: &mut W,
+    ) -> io::Result<Result<(), String>> {
+        let (entries, read_only) = match state {
+            State::Selected { entries, read_only } => (entries, *read_only),
+            _ => return Ok(Err(String::from("No mailbox selected"))),
+        };
+        let (set, items) = match arguments {
+            [Value::Atom(set), Value::List(items)] => (set, items.clone()),
+            [Value::Atom(set), item @ Value::Atom(_)] => (set, vec![item.clone()]),
+            _ => return Ok(Err(String::from("Expected sequence set and fetch items"))),
+        };
+        let mut items: Vec<String> = items
+            .iter()
+            .map(|item| item.text().unwrap_or("").to_uppercase())
+            .collect();
+        // The macros.
+        if let [item] = items.as_slice() {
+            let expanded: &[&str] = match item.as_str() {
+                "FAST" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE"],
+                "ALL" | "FULL" => return Ok(Err(String::from("ENVELOPE is not supported"))),
+                _ => &[],
+            };
+            if !expanded.is_empty() {
+                items = expanded.iter().map(|item| String::from(*item)).collect();
+            }
+        }
+        if uid && !items.iter().any(|item| item == "UID") {
+            items.insert(0, String::from("UID"));
+        }
+        let selected = match Imap::select(entries, set, uid) {
+            Ok(selected) => selected,
+            Err(e) => return Ok(Err(e)),
+        };
+        for index in selected {
+            let entry = &entries[index];
+            let mut flags = self.flags(entry);
+            let mut parts = Vec::new();
+            let mut seen = false;
+            for item in &items {
+                let part = match item.as_str() {
+                    "UID" => format!("UID {}", entry.uid),
+                    "FLAGS" => format!("FLAGS ({})", join(&flags)),
+                    "RFC822.SIZE" => format!("RFC822.SIZE {}", entry.text.len()),
+                    "INTERNALDATE" => format!(
+                        "INTERNALDATE \"{}\"",
+                        datetime::imap(self.internal_date(entry))
+                    ),
+                    "RFC822" => {
+                        seen = true;
+                        format!("RFC822 {}", literal(&entry.text))
+                    }
+                    "RFC822.HEADER" => {
+                        format!("RFC822.HEADER {}", literal(split(&entry.text).0))
+                    }
+                    "RFC822.TEXT" => {
+                        seen = true;
+                        format!("RFC822.TEXT {}", literal(split(&entry.text).1))
+                    }
+                    _ => match body_section(item, &entry.text) {
+                        Ok((name, content, peek)) => {
+                            seen |= !peek;
+                            format!("{} {}", name, literal(&content))
+                        }
+                        Err(e) => return Ok(Err(e)),
+                    },
+                };
+                parts.push(part);
+            }
+            if seen && !read_only && flags.insert(String::from("\\Seen")) {
+                self.set_flags(entry, flags.clone());
+                if !items.iter().any(|item| item == "FLAGS") {
+                    parts.push(format!("FLAGS ({})", join(&flags)));
+                }
+            }
+            write_line(
+                writer,
+                &format!("* {} FETCH ({})", index + 1, parts.join(" ")),
+            )?;
+        }
+        Ok(Ok(()))
+    }
+
+    fn search<W: Write>(
+        &self,
+        state: &mut State,
+        uid: bool,
+        arguments: &[Value],
+        writer: &mut W,
+    ) -> io::Result<Result<(), String>> {
+        let entries = match state {
+            State::Selected { entries, .. } => entries,
+            _ => return Ok(Err(String::from("No mailbox selected"))),
+        };
+        let mut arguments = arguments;
+        if let [Value::Atom(charset), Value::Atom(name), rest @ ..] = arguments {
+            if charset.eq_ignore_ascii_case("CHARSET") {
+                if !matches!(name.to_uppercase().as_str(), "UTF-8" | "US-ASCII") {
+                    return Ok(Err(String::from(
+                        "[BADCHARSET (UTF-8 US-ASCII)] Unknown charset",
+                    )));
+                }
+                arguments = rest;
+            }
+        }
+        let largest_uid = entries.last().map_or(0, |entry| entry.uid);
+        let mut keys = Vec::new();
+        let mut rest = arguments;
+        while !rest.is_empty() {
+            match search_key(rest, entries.len() as u32, largest_uid) {
+                Ok((key, remaining)) => {
+                    keys.push(key);
+                    rest = remaining;
+                }
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        if keys.is_empty() {
+            return Ok(Err(String::from("Expected search criteria")));
+        }
+        let key = Key::And(keys);
+        let mut found = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let flags = self.flags(entry);
+            if key.matches(index as u32 + 1, entry, &flags) {
+                found.push(if uid { entry.uid } else { index as u32 + 1 });
+            }
+        }
+        let mut response = String::from("* SEARCH");
+        for number in found {
+            response.push_str(&format!(" {}", number));
+        }
+        write_line(writer, &response)?;
+        Ok(Ok(()))
+    }
+
+    fn store_flags<W: Write>(
+        &self,
+        state: &mut State,
+        uid: bool,
+        arguments: &[Value],
+        writer: &mut W,
+    ) -> io::Result<Result<(), String>> {
+        let entries = match state {
+            State::Selected { entries, .. } => entries,
+            _ => return Ok(Err(String::from("No mailbox selected"))),
+        };
+        let (set, item, given) = match arguments {
+            [Value::Atom(set), Value::Atom(item), Value::List(flags)] => (set, item, flags.clone()),
+            [Value::Atom(set), Value::Atom(item), flag @ Value::Atom(_)] => {
+                (set, item, vec![flag.clone()])
+            }
+            _ => return Ok(Err(String::from("Expected sequence set, item and flags"))),
+        };
+        let item = item.to_uppercase();
+        let silent = item.ends_with(".SILENT");
+        let operation = item.trim_end_matches(".SILENT");
+        if !matches!(operation, "FLAGS" | "+FLAGS" | "-FLAGS") {
+            return Ok(Err(String::from("Unknown store item")));
+        }
+        // Keywords are not kept, as PERMANENTFLAGS tells.
+        let given: BTreeSet<String> = given
+            .iter()
+            .filter_map(Value::text)
+            .filter_map(|flag| FLAGS.iter().find(|known| known.eq_ignore_ascii_case(flag)))
+            .map(|flag| String::from(*flag))
+            .collect();
+        let selected = match Imap::select(entries, set, uid) {
+            Ok(selected) => selected,
+            Err(e) => return Ok(Err(e)),
+        };
+        for index in selected {
+            let entry = &entries[index];
+            let mut flags = self.flags(entry);
+            match operation {
+                "FLAGS" => flags = given.clone(),
+                "+FLAGS" => flags.extend(given.iter().cloned()),
+                _ => flags.retain(|flag| !given.contains(flag)),
+            }
+            self.set_flags(entry, flags.clone());
+            if !silent {
+                let uid = if uid {
+                    format!("UID {} ", entry.uid)
+                } else {
+                    String::new()
+                };
+                write_line(
+                    writer,
+                    &format!("* {} FETCH ({}FLAGS ({}))", index + 1, uid, join(&flags)),
+                )?;
+            }
+        }
+        Ok(Ok(()))
+    }
+}
+
+impl Key {
+    fn matches(&self, number: u32, entry: &Entry, flags: &BTreeSet<String>) -> bool {
+        let contains =
+            |text: &str, pattern: &str| text.to_lowercase().contains(&pattern.to_lowercase());
+        match self {
+            Key::All => true,
+            Key::Flag(flag, set) => flags.contains(*flag) == *set,
+            Key::Header(name, pattern) => {
+                Message::parse(&entry.text).headers.iter().any(|header| {
+                    header.name.eq_ignore_ascii_case(name) && contains(&header.value, pattern)
+                })
+            }
+            Key::Body(pattern) => contains(split(&entry.text).1, pattern),
+            Key::Text(pattern) => contains(&entry.text, pattern),
+            Key::Larger(size) => entry.text.len() > *size,
+            Key::Smaller(size) => entry.text.len() < *size,
+            Key::Sequence(ranges) => in_set(ranges, number),
+            Key::Uid(ranges) => in_set(ranges, entry.uid),
+            Key::Not(key) => !key.matches(number, entry, flags),
+            Key::Or(a, b) => a.matches(number, entry, flags) || b.matches(number, entry, flags),
+            Key::And(keys) => keys.iter().all(|key| key.matches(number, entry, flags)),
+        }
+    }
+}
+
+/// The first search key of `values`, and the values after it.
+fn search_key(
+    values: &[Value],
+    messages: u32,
+    largest_uid: u32,
+) -> Result<(Key, &[Value]), String> {
+    let (first, rest) = match values.split_first() {
+        Some(split) => split,
+        None => return Err(String::from("Incomplete search criteria")),
+    };
+    let atom = match first {
+        Value::List(keys) => {
+            let mut parsed = Vec::new();
+            let mut remaining = keys.as_slice();
+            while !remaining.is_empty() {
+                let (key, after) = search_key(remaining, messages, largest_uid)?;
+                parsed.push(key);
+                remaining = after;
+            }
+            return Ok((Key::And(parsed), rest));
+        }
+        Value::String(_) => return Err(String::from("Expected a search key")),
+        Value::Atom(atom) => atom.to_uppercase(),
+    };
+    let string = |rest: &[Value]| -> Result<(String, usize), String> {
+        match rest.first().and_then(Value::text) {
+            Some(text) => Ok((String::from(text), 1)),
+            None => Err(format!("{} needs a value", atom)),
+        }
+    };
+    let flag = |flag: &'static str, set: bool| Ok((Key::Flag(flag, set), rest));
+    match atom.as_str() {
+        "ALL" => Ok((Key::All, rest)),
+        "SEEN" => flag("\\Seen", true),
+        "UNSEEN" => flag("\\Seen", false),
+        "DELETED" => flag("\\Deleted", true),
+        "UNDELETED" => flag("\\Deleted", false),
+        "FLAGGED" => flag("\\Flagged", true),
+        "UNFLAGGED" => flag("\\Flagged", false),
+        "ANSWERED" => flag("\\Answered", true),
+        "UNANSWERED" => flag("\\Answered", false),
+        "DRAFT" => flag("\\Draft", true),
+        "UNDRAFT" => flag("\\Draft", false),
+        "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => {
+            let (pattern, used) = string(rest)?;
+            Ok((Key::Header(atom.to_lowercase(), pattern), &rest[used..]))
+        }
+        "HEADER" => {
+            let (name, _) = string(rest)?;
+            let (pattern, _) = string(&rest[1..])?;
+            Ok((Key::Header(name, pattern), &rest[2..]))
+        }
+        "BODY" | "TEXT" => {
+            let (pattern, used) = string(rest)?;
+            let key = if atom == "BODY" {
+                Key::Body(pattern)
+            } else {
+                Key::Text(pattern)
+            };
+            Ok((key, &rest[used..]))
+        }
+        "LARGER" | "SMALLER" => {
+            let (size, used) = string(rest)?;
+            let size = size.parse().map_err(|_| format!("Invalid size {}", size))?;
+            let key = if atom == "LARGER" {
+                Key::Larger(size)
+            } else {
+                Key::Smaller(size)
+            };
+            Ok((key, &rest[used..]))
+        }
+        "UID" => {
+            let (set, used) = string(rest)?;
+            let ranges = parse_set(&set, largest_uid).ok_or("Invalid sequence set")?;
+            Ok((Key::Uid(ranges), &rest[used..]))
+        }
+        "NOT" => {
+            let (key, rest) = search_key(rest, messages, largest_uid)?;
+            Ok((Key::Not(Box::new(key)), rest))
+        }
+        "OR" => {
+            let (a, rest) = search_key(rest, messages, largest_uid)?;
+            let (b, rest) = search_key(rest, messages, largest_uid)?;
+            Ok((Key::Or(Box::new(a), Box::new(b)), rest))
+        }
+        _ => match parse_set(&atom, messages) {
+            Some(ranges) => Ok((Key::Sequence(ranges), rest)),
+            None => Err(format!("Unsupported search key {}", atom)),
+        },
+    }
+}
+
+/// The name to answer a `BODY[...]` or `BODY.PEEK[...]` item with, what it fetches of
+/// `text`, and whether it leaves the message unseen.
+fn body_section(item: &str, text: &str) -> Result<(String, String, bool), String> {
+    let unsupported = || format!("Unsupported fetch item {}", item);
+    let (peek, rest) = if let Some(rest) = item.strip_prefix("BODY.PEEK[") {
+        (true, rest)
+    } else if let Some(rest) = item.strip_prefix("BODY[") {
+        (false, rest)
+    } else {
+        return Err(unsupported());
+    };
+    let (section, partial) = rest.split_once(']').ok_or_else(unsupported)?;
+    let (header, body) = split(text);
+    let content = match section {
+        "" => String::from(text),
+        "HEADER" => String::from(header),
+        "TEXT" => String::from(body),
+        _ => {
+            let (fields, not) = if let Some(fields) = section.strip_prefix("HEADER.FIELDS.NOT ") {
+                (fields, true)
+            } else if let Some(fields) = section.strip_prefix("HEADER.FIELDS ") {
+                (fields, false)
+            } else {
+                return Err(unsupported());
+            };
+            let names: Vec<&str> = fields
+                .trim_start_matches('(')
+                .trim_end_matches(')')
+                .split_whitespace()
+                .collect();
+            header_fields(header, &names, not)
+        }
+    };
+    let name = format!("BODY[{}]", section);
+    if partial.is_empty() {
+        return Ok((name, content, peek));
+    }
+    // `<start.length>`, answered as `<start>`.
+    let range = partial
+        .strip_prefix('<')
+        .and_then(|partial| partial.strip_suffix('>'))
+        .and_then(|range| range.split_once('.'))
+        .and_then(|(start, length)| {
+            Some((start.parse::<usize>().ok()?, length.parse::<usize>().ok()?))
+        })
+        .ok_or_else(unsupported)?;
+    let bytes = content.as_bytes();
+    let start = range.0.min(bytes.len());
+    let end = start.saturating_add(range.1).min(bytes.len());
+    let content = String::from_utf8_lossy(&bytes[start..end]).into_owned();
+    Ok((format!("{}<{}>", name, range.0), content, peek))
+}
+
+/// The fields of `header` named in `names`, or not named with `not`, and the blank line.
+fn header_fields(header: &str, names: &[&str], not: bool) -> String {
+    let mut fields = String::new();
+    let mut keep = false;
+    for line in header.split_inclusive("\r\n") {
+        if line == "\r\n" {
+            break;
+        }
+        if !line.starts_with(' ') && !line.starts_with('\t') {
+            let name = line.split(':').next().unwrap_or("").trim();
+            keep = names.iter().any(|n| n.eq_ignore_ascii_case(name)) != not;
+        }
+        if keep {
+            fields.push_str(line);
+        }
+    }
+    fields.push_str("\r\n");
+    fields
+}
+
+/// The header of `text`, its blank line included, and the body.
+fn split(text: &str) -> (&str, &str) {
+    if text.starts_with("\r\n") {
+        return text.split_at(2);
+    }
+    match text.find("\r\n\r\n") {
+        Some(end) => text.split_at(end + 4),
+        None => (text, ""),
+    }
+}
+
+/// Ranges of a sequence set such as `1:3,5,7:*`, `*` standing for `largest`, each with its
+/// ends in order.
+fn parse_set(set: &str, largest: u32) -> Option<Vec<(u32, u32)>> {
+    let number = |n: &str| -> Option<u32> {
+        match n {
+            "*" => Some(largest),
+            _ => n.parse().ok().filter(|&n| n > 0),
+        }
+    };
+    set.split(',')
+        .map(|range| {
+            let (low, high) = range.split_once(':').unwrap_or((range, range));
+            let (low, high) = (number(low)?, number(high)?);
+            Some((low.min(high), low.max(high)))
+        })
+        .collect()
+}
+
+fn in_set(ranges: &[(u32, u32)], number: u32) -> bool {
+    ranges
+        .iter()
+        .any(|&(low, high)| low <= number && number <= high)
+}
+
+/// Whether `name` matches a LIST pattern, `*` and `%` matching anything as there is no
+/// hierarchy.
+fn matches_pattern(name: &str, pattern: &str) -> bool {
+    match pattern.find(['*', '%']) {
+        None => name == pattern,
+        Some(wildcard) => {
+            let (prefix, rest) = (&pattern[..wildcard], &pattern[wildcard + 1..]);
+            name.starts_with(prefix)
+                && (0..=name.len() - prefix.len())
+                    .any(|skip| matches_pattern(&name[prefix.len() + skip..], rest))
+        }
+    }
+}
+
+fn join(flags: &BTreeSet<String>) -> String {
+    flags.iter().cloned().collect::<Vec<String>>().join(" ")
+}
+
+/// `text` as an IMAP literal.
+fn literal(text: &str) -> String {
+    format!("{{{}}}\r\n{}", text.len(), text)
+}
+
+/// Reads a command line, without its line ending, with the literals it carries turned into
+/// quoted strings; the client is told to send each once it announced it. `None` at the
+/// end of the input.
+fn read_command<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        let announced = line
+            .strip_suffix('}')
+            .and_then(|line| line.rsplit_once('{'))
+            .and_then(|(before, size)| {
+                let (size, synchronizing) = match size.strip_suffix('+') {
+                    Some(size) => (size, false),
+                    None => (size, true),
+                };
+                Some((before, size.parse::<usize>().ok()?, synchronizing))
+            });
+        let (before, size, synchronizing) = match announced {
+            Some(announced) => announced,
+            None => {
+                command.push_str(line);
+                return Ok(Some(command));
+            }
+        };
+        if size > MAX_LITERAL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "literal too large",
+            ));
+        }
+        if synchronizing {
+            write_line(writer, "+ Ready for literal")?;
+            writer.flush()?;
+        }
+        let mut literal = vec![0; size];
+        reader.read_exact(&mut literal)?;
+        command.push_str(before);
+        command.push_str(&quote(&String::from_utf8_lossy(&literal)));
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The arguments of a command: atoms, quoted strings and parenthesized lists.
+fn parse_values(input: &str) -> Result<Vec<Value>, String> {
+    let mut chars = input.chars().peekable();
+    let values = parse_list(&mut chars, false)?;
+    Ok(values)
+}
+
+fn parse_list(
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+    nested: bool,
+) -> Result<Vec<Value>, String> {
+    let mut values = Vec::new();
+    loop {
+        match chars.peek() {
+            None if nested => return Err(String::from("Unclosed list")),
+            None => return Ok(values),
+            Some(' ') => {
+                chars.next();
+            }
+            Some(')') if nested => {
+                chars.next();
+                return Ok(values);
+            }
+            Some(')') => return Err(String::from("Unexpected )")),
+            Some('(') => {
+                chars.next();
+                values.push(Value::List(parse_list(chars, true)?));
+            }
+            Some('"') => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(String::from("Unclosed string")),
+                    }
+                }
+                values.push(Value::String(text));
+            }
+            Some(_) => {
+                let mut atom = String::new();
+                let mut brackets = 0;
+                while let Some(&c) = chars.peek() {
+                    if brackets == 0 && (c == ' ' || c == ')' || c == '(') {
+                        break;
+                    }
+                    match c {
+                        '[' => brackets += 1,
+                        ']' => brackets -= 1,
+                        _ => {}
+                    }
+                    atom.push(c);
+                    chars.next();
+                }
+                values.push(Value::Atom(atom));
+            }
+        }
+    }
+}
+
+fn write_line<W: Write>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Passwords, email::Mail, store::MemoryStore};
+
+    fn imap() -> (Arc<MemoryStore>, Imap) {
+        let store = Arc::new(MemoryStore::new(10));
+        let mut mail = Mail::new();
+        for data in [
+            "From: a@example.com\r\nSubject: Welcome\r\n\r\nHello\r\n",
+            "From: b@example.com\r\nSubject: Invoice\r\n\r\nPay\r\n",
+        ] {
+            mail.data = Some(String::from(data));
+            store.save(&mail).unwrap();
+        }
+        let passwords = Passwords::new(&[("alice", "s3cret")]);
+        let imap = Imap::new(store.clone(), Arc::new(passwords)).server_name("mx.example.com");
+        (store, imap)
+    }
+
+    /// The responses to `commands` after the greeting.
+    fn session(imap: &Imap, commands: &str) -> Vec<String> {
+        let mut output = Vec::new();
+        imap.handle(commands.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        output.split("\r\n").skip(1).map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(
+            parse_values("1:* (UID BODY.PEEK[HEADER.FIELDS (FROM TO)]<0.10>) \"a \\\"b\\\"\"")
+                .unwrap(),
+            vec![
+                Value::Atom(String::from("1:*")),
+                Value::List(vec![
+                    Value::Atom(String::from("UID")),
+                    Value::Atom(String::from("BODY.PEEK[HEADER.FIELDS (FROM TO)]<0.10>")),
+                ]),
+                Value::String(String::from("a \"b\"")),
+            ]
+        );
+        assert!(parse_values("(UID").is_err());
+        assert_eq!(parse_set("1:3,7:*", 9), Some(vec![(1, 3), (7, 9)]));
+        assert_eq!(parse_set("0", 9), None);
+        assert!(matches_pattern("INBOX", "*"));
+        assert!(matches_pattern("INBOX", "IN%"));
+        assert!(!matches_pattern("INBOX", "OUT*"));
+    }
+
+    #[test]
+    fn test_login() {
+        let path = std::env::temp_dir().join(format!("imap-audit-{}.log", std::process::id()));
+        let audit = AuditLog::to_file(path.to_str().unwrap(), crate::log::Rotation::Never, 0);
+        let (_, imap) = imap();
+        let imap = imap.audit(Arc::new(audit.unwrap()));
+        let mut output = Vec::new();
+        imap.handle(
+            &b"a SELECT INBOX\r\nb LOGIN alice wrong\r\nc LOGIN {5}\r\nalice \"s3cret\"\r\n"[..],
+            &mut output,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "* OK [CAPABILITY IMAP4rev1 LITERAL+] mx.example.com IMAP4rev1 server ready\r\n\
+             a BAD Log in first\r\n\
+             b NO [AUTHENTICATIONFAILED] Invalid user name or password\r\n\
+             + Ready for literal\r\n\
+             c OK LOGIN completed\r\n"
+        );
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit
+            .contains(" audit event=auth_failed peer=unknown session=- service=imap user=alice "));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_fetch() {
+        let (_, imap) = imap();
+        let responses = session(
+            &imap,
+            "a LOGIN alice s3cret\r\n\
+             b SELECT inbox\r\n\
+             c FETCH 1:* (FLAGS RFC822.SIZE)\r\n\
+             d UID FETCH 2 BODY.PEEK[HEADER.FIELDS (SUBJECT)]\r\n\
+             e FETCH 1 BODY[TEXT]<0.3>\r\n\
+             f FETCH 3 FLAGS\r\n",
+        );
+        assert_eq!(
+            responses,
+            [
+                "a OK LOGIN completed",
+                "* FLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)",
+                "* OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)] Flags kept",
+                "* 2 EXISTS",
+                "* 0 RECENT",
+                "* OK [UNSEEN 1] First unseen",
+                &format!("* OK [UIDVALIDITY {}] UIDs valid", imap.uid_validity),
+                "* OK [UIDNEXT 3] Predicted next UID",
+                "b OK [READ-WRITE] SELECT completed",
+                "* 1 FETCH (FLAGS () RFC822.SIZE 48)",
+                "* 2 FETCH (FLAGS () RFC822.SIZE 46)",
+                "c OK FETCH completed",
+                "* 2 FETCH (UID 2 BODY[HEADER.FIELDS (SUBJECT)] {20}",
+                "Subject: Invoice",
+                "",
+                ")",
+                "d OK UID FETCH completed",
+                "* 1 FETCH (BODY[TEXT]<0> {3}",
+                "Hel FLAGS (\\Seen))",
+                "e OK FETCH completed",
+                "f BAD No such message",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_search_store_expunge() {
+        let (store, imap) = imap();
+        let responses = session(
+            &imap,
+            "a LOGIN alice s3cret\r\n\
+             b EXAMINE INBOX\r\n\
+             c STORE 1 +FLAGS (\\Deleted)\r\n\
+             d SELECT INBOX\r\n",
+        );
+        assert!(responses.contains(&String::from("c NO Mailbox is read-only")));
+
+        let responses = session(
+            &imap,
+            "a LOGIN alice s3cret\r\n\
+             b SELECT INBOX\r\n\
+             c SEARCH SUBJECT invoice\r\n\
+             d UID SEARCH OR FROM a@ BODY pay\r\n\
+             e STORE 1 +FLAGS (\\Deleted \\Seen $Junk)\r\n\
+             f SEARCH UNSEEN NOT DELETED\r\n\
+             g UID STORE 2 FLAGS.SILENT (\\Flagged)\r\n\
+             h EXPUNGE\r\n\
+             i LOGOUT\r\n",
+        );
+        assert_eq!(
+            responses[9..],
+            [
+                "* SEARCH 2",
+                "c OK SEARCH completed",
+                "* SEARCH 1 2",
+                "d OK UID SEARCH completed",
+                "* 1 FETCH (FLAGS (\\Deleted \\Seen))",
+                "e OK STORE completed",
+                "* SEARCH 2",
+                "f OK SEARCH completed",
+                "g OK UID STORE completed",
+                "* 1 EXPUNGE",
+                "h OK EXPUNGE completed",
+                "* BYE mx.example.com signing off",
+                "i OK LOGOUT completed",
+                "",
+            ]
+        );
+        let ids: Vec<String> = store.list().into_iter().map(|stored| stored.id).collect();
+        assert_eq!(ids, ["2"]);
+
+        // Flags outlive the session, UIDs are kept.
+        let responses = session(
+            &imap,
+            "a LOGIN alice s3cret\r\nb SELECT INBOX\r\nc UID FETCH 1:* FLAGS\r\n\
+             d LIST \"\" *\r\n",
+        );
+        assert_eq!(
+            responses[9..],
+            [
+                "* 1 FETCH (UID 2 FLAGS (\\Flagged))",
+                "c OK UID FETCH completed",
+                "* LIST (\\HasNoChildren) \"/\" INBOX",
+                "d OK LIST completed",
+                "",
+            ]
+        );
+    }
+}
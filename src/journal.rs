@@ -0,0 +1,264 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    email::Mail,
+    json::{FromJson, Json, ToJson},
+};
+
+/// The directory of the journal `from_env` opens.
+pub const JOURNAL_ENV: &str = "SIMPLE_SMTP_JOURNAL";
+
+/// A write-ahead journal of accepted messages. Each message is spooled and its envelope
+/// recorded, both on disk, before it is acknowledged, and marked done once it was handed
+/// to the outputs; the messages a crash left in between are recovered at startup.
+///
+/// The journal is a file of lines `+ <queue id> <envelope>` and `- <queue id>` next to a
+/// `spool` directory holding the data of each message under its queue ID. The envelope is
+/// the one `Mail::to_json` writes, with the DSN and other MAIL FROM parameters.
+pub struct Journal {
+    path: PathBuf,
+    spool: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal in `directory`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(directory: P) -> io::Result<Journal> {
+        let directory = directory.as_ref();
+        let spool = directory.join("spool");
+        fs::create_dir_all(&spool)?;
+        let path = directory.join("journal");
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Journal {
+            path,
+            spool,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Spools `mail` and records its envelope, returning once both are on disk.
+    pub fn record(&self, mail: &Mail) -> io::Result<()> {
+        let queue_id = queue_id(mail)?;
+        let mut spooled = File::create(self.spool.join(queue_id))?;
+        spooled.write_all(mail.data.as_deref().unwrap_or("").as_bytes())?;
+        spooled.sync_all()?;
+        let envelope = Mail {
+            data: None,
+            ..mail.clone()
+        };
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "+ {} {}", queue_id, envelope.to_json())?;
+        file.sync_data()
+    }
+
+    /// Marks the message queued as `queue_id` done and drops its spooled data. Not synced:
+    /// a mark lost in a crash only has the message handed on once more.
+    pub fn complete(&self, queue_id: &str) -> io::Result<()> {
+        writeln!(self.file.lock().unwrap(), "- {}", queue_id)?;
+        match fs::remove_file(self.spool.join(queue_id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The messages recorded but not marked done, oldest first, e.g. after a crash. The
+    /// journal is rewritten to hold just these, and spooled data of no message is removed.
+    pub fn recover(&self) -> io::Result<Vec<Mail>> {
+        let mut order = Vec::new();
+        let mut pending: HashMap<String, Mail> = HashMap::new();
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("+"), Some(queue_id), Some(envelope)) => {
+                    match Json::parse(envelope)
+                        .ok()
+                        .as_ref()
+                        .and_then(Mail::from_json)
+                    {
+                        Some(mail) => {
+                            order.push(String::from(queue_id));
+                            pending.insert(String::from(queue_id), mail);
+                        }
+                        // The line a crash cut short, its message was not acknowledged.
+                        None => crate::warn!("Skipping journal entry of {}", queue_id),
+                    }
+                }
+                (Some("-"), Some(queue_id), None) => {
+                    pending.remove(queue_id);
+                }
+                _ => crate::warn!("Skipping invalid journal line"),
+            }
+        }
+
+        let mut recovered = Vec::new();
+        for queue_id in order {
+            let mut mail = match pending.remove(&queue_id) {
+                Some(mail) => mail,
+                None => continue,
+            };
+            match fs::read_to_string(self.spool.join(&queue_id)) {
+                Ok(data) => {
+                    mail.data = Some(data);
+                    recovered.push(mail);
+                }
+                Err(e) => crate::error!("Unable to recover {}: {}", queue_id, e),
+            }
+        }
+        self.compact(&recovered)?;
+        Ok(recovered)
+    }
+
+    /// Rewrites the journal with the entries of `pending` alone.
+    fn compact(&self, pending: &[Mail]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let rewritten = self.path.with_extension("new");
+        let mut new = File::create(&rewritten)?;
+        let mut kept = Vec::new();
+        for mail in pending {
+            let queue_id = queue_id(mail)?;
+            let envelope = Mail {
+                data: None,
+                ..mail.clone()
+            };
+            writeln!(new, "+ {} {}", queue_id, envelope.to_json())?;
+            kept.push(queue_id);
+        }
+        new.sync_all()?;
+        fs::rename(&rewritten, &self.path)?;
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        for entry in fs::read_dir(&self.spool)? {
+            let entry = entry?;
+            if !kept.iter().any(|queue_id| entry.file_name() == **queue_id) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Opens the journal in the directory `SIMPLE_SMTP_JOURNAL` names, none if it is unset or
+/// unusable.
+pub fn from_env() -> Option<Arc<Journal>> {
+    let directory = std::env::var(JOURNAL_ENV).ok()?;
+    match Journal::open(&directory) {
+        Ok(journal) => Some(Arc::new(journal)),
+        Err(e) => {
+            eprintln!(
+                "Ignoring {}: unable to open {}: {}",
+                JOURNAL_ENV, directory, e
+            );
+            None
+        }
+    }
+}
+
+fn queue_id(mail: &Mail) -> io::Result<&str> {
+    mail.queue_id
+        .as_deref()
+        .filter(|queue_id| !queue_id.is_empty() && !queue_id.contains(['/', ' ', '.']))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid queue ID"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deliver_by::{DeliverBy, Mode},
+        dsn::{Notify, RecipientDsn, Ret},
+    };
+
+    fn mail(queue_id: &str) -> Mail {
+        let mut mail = Mail::new();
+        mail.queue_id = Some(String::from(queue_id));
+        mail.mail_from = Some(String::from("<a@example.com>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(format!("Subject: {}\r\n\r\nhi\r\n", queue_id));
+        mail
+    }
+
+    #[test]
+    fn test_recover() {
+        let directory = std::env::temp_dir().join(format!("journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let journal = Journal::open(&directory).unwrap();
+        journal.record(&mail("A1")).unwrap();
+        journal.record(&mail("B2")).unwrap();
+        journal.record(&mail("C3")).unwrap();
+        journal.complete("B2").unwrap();
+        assert!(journal.record(&mail("../x")).is_err());
+        drop(journal);
+
+        let journal = Journal::open(&directory).unwrap();
+        let recovered = journal.recover().unwrap();
+        let recovered: Vec<(Option<String>, Vec<String>, Option<String>)> = recovered
+            .into_iter()
+            .map(|mail| (mail.queue_id, mail.rcpt_to, mail.data))
+            .collect();
+        let expected: Vec<_> = vec![mail("A1"), mail("C3")]
+            .into_iter()
+            .map(|mail| (mail.queue_id, mail.rcpt_to, mail.data))
+            .collect();
+        assert_eq!(recovered, expected);
+        journal.complete("A1").unwrap();
+        journal.complete("C3").unwrap();
+        assert!(journal.recover().unwrap().is_empty());
+        assert_eq!(fs::read_dir(directory.join("spool")).unwrap().count(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_recover_envelope() {
+        let directory =
+            std::env::temp_dir().join(format!("journal-envelope-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        let journal = Journal::open(&directory).unwrap();
+        let mut mail = mail("A1");
+        mail.dsn.ret = Some(Ret::Headers);
+        mail.dsn.envid = Some(String::from("x1"));
+        mail.dsn.recipients.push(RecipientDsn {
+            address: String::from("b@example.com"),
+            notify: Some(Notify::NEVER),
+            orcpt: Some(String::from("rfc822;b@example.com")),
+        });
+        mail.deliver_by = Some(DeliverBy {
+            deadline: std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_800_000_123),
+            mode: Mode::Notify,
+            trace: true,
+        });
+        mail.require_tls = true;
+        mail.priority = -3;
+        mail.auth = Some(String::from("<a@example.com>"));
+        mail.origin.address = Some("192.0.2.1".parse().unwrap());
+        mail.origin.name = Some(String::from("client.example.com"));
+        mail.origin.protocol = Some(String::from("ESMTPSA"));
+        mail.folder = Some(String::from("Archive"));
+        journal.record(&mail).unwrap();
+        drop(journal);
+
+        // Recovering twice also covers the envelope compaction writes back.
+        for _ in 0..2 {
+            let journal = Journal::open(&directory).unwrap();
+            let recovered = journal.recover().unwrap();
+            assert_eq!(recovered.len(), 1);
+            let recovered = &recovered[0];
+            assert_eq!(recovered.mail_from, mail.mail_from);
+            assert_eq!(recovered.rcpt_to, mail.rcpt_to);
+            assert_eq!(recovered.data, mail.data);
+            assert_eq!(recovered.dsn, mail.dsn);
+            assert_eq!(recovered.deliver_by, mail.deliver_by);
+            assert!(recovered.require_tls);
+            assert_eq!(recovered.priority, mail.priority);
+            assert_eq!(recovered.auth, mail.auth);
+            assert_eq!(recovered.origin, mail.origin);
+            assert_eq!(recovered.folder, mail.folder);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,377 @@
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Conversion into a JSON value, for embedders persisting or transmitting captured mail.
+/// `Mail` maps every field of its envelope, and `Reply` is covered too. This stands in for
+/// a `serde` feature, which the crate cannot offer without taking dependencies.
+pub trait ToJson {
+    fn to_json(&self) -> Json;
+}
+
+/// Conversion back from a JSON value produced by `ToJson`.
+pub trait FromJson: Sized {
+    fn from_json(json: &Json) -> Option<Self>;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonError {
+    UnexpectedEnd,
+    UnexpectedCharacter(usize),
+    TrailingCharacters(usize),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnexpectedEnd => write!(f, "unexpected end of JSON input"),
+            JsonError::UnexpectedCharacter(at) => write!(f, "unexpected character at {}", at),
+            JsonError::TrailingCharacters(at) => write!(f, "trailing characters at {}", at),
+        }
+    }
+}
+
+impl Json {
+    pub fn parse(input: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.position != parser.input.len() {
+            return Err(JsonError::TrailingCharacters(parser.position));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(value) => Some(*value as f64),
+            Json::Float(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// `Some(None)` for null, `Some(Some(..))` for a string, `None` for anything else.
+    pub fn as_optional_string(&self) -> Option<Option<String>> {
+        match self {
+            Json::Null => Some(None),
+            Json::String(value) => Some(Some(value.clone())),
+            _ => None,
+        }
+    }
+
+    pub fn string(value: &str) -> Json {
+        Json::String(String::from(value))
+    }
+
+    pub fn optional(value: &Option<String>) -> Json {
+        match value {
+            Some(value) => Json::string(value),
+            None => Json::Null,
+        }
+    }
+
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| (String::from(name), value))
+                .collect(),
+        )
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.input.get(self.position) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Result<u8, JsonError> {
+        self.input
+            .get(self.position)
+            .copied()
+            .ok_or(JsonError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), JsonError> {
+        if self.input[self.position..].starts_with(literal.as_bytes()) {
+            self.position += literal.len();
+            Ok(())
+        } else {
+            Err(JsonError::UnexpectedCharacter(self.position))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.whitespace();
+        match self.peek()? {
+            b'n' => self.expect("null").map(|_| Json::Null),
+            b't' => self.expect("true").map(|_| Json::Bool(true)),
+            b'f' => self.expect("false").map(|_| Json::Bool(false)),
+            b'"' => self.string().map(Json::String),
+            b'[' => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.peek()? == b']' {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.peek()? {
+                        b',' => self.position += 1,
+                        b']' => {
+                            self.position += 1;
+                            return Ok(Json::Array(values));
+                        }
+                        _ => return Err(JsonError::UnexpectedCharacter(self.position)),
+                    }
+                }
+            }
+            b'{' => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                self.whitespace();
+                if self.peek()? == b'}' {
+                    self.position += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.whitespace();
+                    if self.peek()? != b'"' {
+                        return Err(JsonError::UnexpectedCharacter(self.position));
+                    }
+                    let name = self.string()?;
+                    self.whitespace();
+                    self.expect(":")?;
+                    fields.push((name, self.value()?));
+                    self.whitespace();
+                    match self.peek()? {
+                        b',' => self.position += 1,
+                        b'}' => {
+                            self.position += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(JsonError::UnexpectedCharacter(self.position)),
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.position;
+                self.position += 1;
+                let mut float = false;
+                while let Some(b) = self.input.get(self.position) {
+                    match b {
+                        b'0'..=b'9' => {}
+                        b'.' | b'e' | b'E' | b'+' | b'-' => float = true,
+                        _ => break,
+                    }
+                    self.position += 1;
+                }
+                let number = std::str::from_utf8(&self.input[start..self.position]).unwrap();
+                let value = if float {
+                    number.parse().ok().map(Json::Float)
+                } else {
+                    number.parse().ok().map(Json::Number)
+                };
+                value.ok_or(JsonError::UnexpectedCharacter(start))
+            }
+            _ => Err(JsonError::UnexpectedCharacter(self.position)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.position += 1;
+        let mut output = Vec::new();
+        loop {
+            let b = self.peek()?;
+            self.position += 1;
+            match b {
+                b'"' => {
+                    return String::from_utf8(output)
+                        .map_err(|_| JsonError::UnexpectedCharacter(self.position))
+                }
+                b'\\' => {
+                    let escaped = self.peek()?;
+                    self.position += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => return Err(JsonError::UnexpectedCharacter(self.position - 1)),
+                    };
+                    let mut buffer = [0u8; 4];
+                    output.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                b => output.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let hex = self
+            .input
+            .get(self.position..self.position + 4)
+            .ok_or(JsonError::UnexpectedEnd)?;
+        let value = std::str::from_utf8(hex)
+            .ok()
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or(JsonError::UnexpectedCharacter(self.position))?;
+        self.position += 4;
+        Ok(value)
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            self.expect("\\u")?;
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or(JsonError::UnexpectedCharacter(self.position))
+    }
+}
+
+pub fn escape(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::Float(value) if value.is_finite() => write!(f, "{}", value),
+            Json::Float(_) => write!(f, "null"),
+            Json::String(value) => write!(f, "{}", escape(value)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", escape(name), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let json = Json::object(vec![
+            ("name", Json::string("a \"quoted\"\nvalue")),
+            ("count", Json::Number(-42)),
+            ("list", Json::Array(vec![Json::Bool(true), Json::Null])),
+            ("empty", Json::Object(Vec::new())),
+        ]);
+        assert_eq!(Json::parse(&json.to_string()), Ok(json));
+    }
+
+    #[test]
+    fn test_parse() {
+        let json = Json::parse(" { \"a\" : [ 1 , \"\\u00e9\\ud83d\\ude00\" ] } ").unwrap();
+        let list = json.get("a").and_then(|a| a.as_array()).unwrap();
+        assert_eq!(list[0].as_i64(), Some(1));
+        assert_eq!(Json::parse("-1.5e1").unwrap().as_f64(), Some(-15.0));
+        assert_eq!(list[1].as_str(), Some("é😀"));
+        assert_eq!(Json::parse("[1,"), Err(JsonError::UnexpectedEnd));
+        assert_eq!(Json::parse("{} x"), Err(JsonError::TrailingCharacters(3)));
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use crate::{
+    alias::AliasLookup,
+    email::{domain_of, local_part_of},
+    recipient::RecipientVerifier,
+};
+
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+
+/// Looks recipients and aliases up in an LDAP directory, with a new connection for every
+/// lookup. Filters are templates where `%s` stands for the address, `%u` for its local part
+/// and `%d` for its domain, e.g. `(&(objectClass=inetOrgPerson)(mail=%s))`.
+pub struct Ldap {
+    /// `host:port` of the directory, which is spoken to in plain text.
+    pub server: String,
+    /// Simple bind with these credentials when set, anonymous otherwise.
+    pub bind: Option<(String, String)>,
+    pub base: String,
+    /// Matches the entries of existing recipients.
+    pub recipient_filter: String,
+    /// Matches the entries of aliases; no alias is looked up when unset.
+    pub alias_filter: Option<String>,
+    /// Attribute of alias entries holding the target addresses.
+    pub alias_attribute: String,
+    pub timeout: Duration,
+}
+
+impl Ldap {
+    pub fn new(server: &str, base: &str) -> Ldap {
+        Ldap {
+            server: String::from(server),
+            bind: None,
+            base: String::from(base),
+            recipient_filter: String::from("(mail=%s)"),
+            alias_filter: None,
+            alias_attribute: String::from("mailForwardingAddress"),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Every entry matching `filter`, as its values of `attribute`.
+    fn search(&self, filter: &str, attribute: &str) -> io::Result<Vec<Vec<String>>> {
+        let filter = parse_filter(filter)?;
+        let stream = TcpStream::connect(self.server.as_str())?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut connection = Connection { stream, next_id: 1 };
+        if let Some((dn, password)) = &self.bind {
+            let bind = tlv(
+                BIND_REQUEST,
+                &[
+                    integer(3),
+                    octet_string(dn.as_bytes()),
+                    tlv(0x80, password.as_bytes()),
+                ]
+                .concat(),
+            );
+            connection.send(&bind)?;
+            let (tag, response) = connection.receive()?;
+            if tag != BIND_RESPONSE {
+                return Err(invalid("unexpected response to bind"));
+            }
+            check_result(&response, "bind")?;
+        }
+        let request = tlv(
+            SEARCH_REQUEST,
+            &[
+                octet_string(self.base.as_bytes()),
+                tlv(0x0a, &[2]), // whole subtree
+                tlv(0x0a, &[0]), // never dereference aliases
+                integer(0),
+                integer(self.timeout.as_secs().min(255) as u8),
+                tlv(0x01, &[0]),
+                filter,
+                tlv(0x30, &octet_string(attribute.as_bytes())),
+            ]
+            .concat(),
+        );
+        connection.send(&request)?;
+        let mut entries = Vec::new();
+        loop {
+            let (tag, content) = connection.receive()?;
+            match tag {
+                SEARCH_RESULT_ENTRY => entries.push(attribute_values(&content, attribute)?),
+                SEARCH_RESULT_REFERENCE => {}
+                SEARCH_RESULT_DONE => {
+                    check_result(&content, "search")?;
+                    break;
+                }
+                _ => return Err(invalid("unexpected response to search")),
+            }
+        }
+        let _ = connection.send(&tlv(UNBIND_REQUEST, &[]));
+        Ok(entries)
+    }
+}
+
+impl RecipientVerifier for Ldap {
+    fn verify(&self, local_part: &str, domain: Option<&str>) -> io::Result<bool> {
+        let address = match domain {
+            Some(domain) => format!("{}@{}", local_part, domain),
+            None => String::from(local_part),
+        };
+        let filter = expand_template(&self.recipient_filter, &address);
+        Ok(!self.search(&filter, "1.1")?.is_empty())
+    }
+}
+
+impl AliasLookup for Ldap {
+    fn lookup(&self, address: &str) -> io::Result<Option<Vec<String>>> {
+        let template = match &self.alias_filter {
+            Some(template) => template,
+            None => return Ok(None),
+        };
+        let address = address.trim().trim_start_matches('<');
+        let address = address.split('>').next().unwrap_or("");
+        let filter = expand_template(template, address);
+        let targets: Vec<String> = self
+            .search(&filter, &self.alias_attribute)?
+            .into_iter()
+            .flatten()
+            .map(|target| format!("<{}>", target))
+            .collect();
+        Ok(Some(targets).filter(|targets| !targets.is_empty()))
+    }
+}
+
+/// Replaces `%s`, `%u` and `%d` with the escaped address, local part and domain.
+pub fn expand_template(template: &str, address: &str) -> String {
+    template
+        .replace("%s", &escape(address))
+        .replace("%u", &escape(local_part_of(address)))
+        .replace("%d", &escape(domain_of(address).unwrap_or("")))
+}
+
+/// Escapes a value for a filter as RFC 4515 requires.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' | '(' | ')' | '\\' | '\0' => escaped.push_str(&format!("\\{:02x}", c as u8)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct Connection {
+    stream: TcpStream,
+    next_id: u8,
+}
+
+impl Connection {
+    fn send(&mut self, operation: &[u8]) -> io::Result<()> {
+        let message = tlv(0x30, &[integer(self.next_id), operation.to_vec()].concat());
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        self.stream.write_all(&message)
+    }
+
+    /// The tag and content of the operation in the next message.
+    fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let (tag, message) = read_tlv(&mut self.stream)?;
+        if tag != 0x30 {
+            return Err(invalid("malformed LDAP message"));
+        }
+        let mut fields = Fields(&message);
+        fields.next()?; // message ID
+        let (tag, content) = fields.next()?;
+        Ok((tag, content.to_vec()))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn length(length: usize) -> Vec<u8> {
+    if length < 0x80 {
+        return vec![length as u8];
+    }
+    let bytes: Vec<u8> = length
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|byte| *byte == 0)
+        .collect();
+    let mut encoded = vec![0x80 | bytes.len() as u8];
+    encoded.extend(bytes);
+    encoded
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    encoded.extend(length(content.len()));
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn integer(value: u8) -> Vec<u8> {
+    if value < 0x80 {
+        tlv(0x02, &[value])
+    } else {
+        tlv(0x02, &[0, value])
+    }
+}
+
+fn octet_string(value: &[u8]) -> Vec<u8> {
+    tlv(0x04, value)
+}
+
+fn read_tlv<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let size = if header[1] & 0x80 == 0 {
+        usize::from(header[1])
+    } else {
+        let mut bytes = vec![0u8; usize::from(header[1] & 0x7f)];
+        if bytes.len() > 4 {
+            return Err(invalid("LDAP message too large"));
+        }
+        reader.read_exact(&mut bytes)?;
+        bytes
+            .iter()
+            .fold(0, |size, byte| (size << 8) | usize::from(*byte))
+    };
+    let mut content = vec![0u8; size];
+    reader.read_exact(&mut content)?;
+    Ok((header[0], content))
+}
+
+/// The elements of a constructed value, in order.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn next(&mut self) -> io::Result<(u8, &'a [u8])> {
+        let mut reader = self.0;
+        let (tag, content) = read_tlv(&mut reader)?;
+        let start = self.0.len() - reader.len() - content.len();
+        let field = &self.0[start..start + content.len()];
+        self.0 = reader;
+        Ok((tag, field))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Fails unless the LDAPResult in `content` has the success code.
+fn check_result(content: &[u8], operation: &str) -> io::Result<()> {
+    let mut fields = Fields(content);
+    let (_, code) = fields.next()?;
+    if code == [0] {
+        return Ok(());
+    }
+    fields.next()?; // matched DN
+    let (_, message) = fields.next()?;
+    Err(io::Error::other(format!(
+        "LDAP {} failed with code {}: {}",
+        operation,
+        code.last().copied().unwrap_or(0),
+        String::from_utf8_lossy(message)
+    )))
+}
+
+/// The values of `attribute` in a SearchResultEntry.
+fn attribute_values(content: &[u8], attribute: &str) -> io::Result<Vec<String>> {
+    let mut fields = Fields(content);
+    fields.next()?; // object name
+    let (_, attributes) = fields.next()?;
+    let mut attributes = Fields(attributes);
+    let mut values = Vec::new();
+    while !attributes.is_empty() {
+        let (_, partial) = attributes.next()?;
+        let mut partial = Fields(partial);
+        let (_, name) = partial.next()?;
+        if !String::from_utf8_lossy(name).eq_ignore_ascii_case(attribute) {
+            continue;
+        }
+        let (_, set) = partial.next()?;
+        let mut set = Fields(set);
+        while !set.is_empty() {
+            let (_, value) = set.next()?;
+            values.push(String::from_utf8_lossy(value).into_owned());
+        }
+    }
+    Ok(values)
+}
+
+/// Encodes a filter in the string form of RFC 4515. Substring matches are not supported.
+pub fn parse_filter(filter: &str) -> io::Result<Vec<u8>> {
+    let mut parser = FilterParser {
+        input: filter.trim().as_bytes(),
+        position: 0,
+    };
+    let encoded = parser.filter()?;
+    if parser.position != parser.input.len() {
+        return Err(invalid("trailing characters after LDAP filter"));
+    }
+    Ok(encoded)
+}
+
+struct FilterParser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl FilterParser<'_> {
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        if self.input.get(self.position) != Some(&byte) {
+            return Err(invalid("malformed LDAP filter"));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn filter(&mut self) -> io::Result<Vec<u8>> {
+        self.expect(b'(')?;
+        let encoded = match self.input.get(self.position) {
+            Some(b'&') => {
+                self.position += 1;
+                tlv(0xa0, &self.filter_list()?)
+            }
+            Some(b'|') => {
+                self.position += 1;
+                tlv(0xa1, &self.filter_list()?)
+            }
+            Some(b'!') => {
+                self.position += 1;
+                tlv(0xa2, &self.filter()?)
+            }
+            _ => self.item()?,
+        };
+        self.expect(b')')?;
+        Ok(encoded)
+    }
+
+    fn filter_list(&mut self) -> io::Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        while self.input.get(self.position) == Some(&b'(') {
+            encoded.extend(self.filter()?);
+        }
+        Ok(encoded)
+    }
+
+    fn item(&mut self) -> io::Result<Vec<u8>> {
+        let start = self.position;
+        while !matches!(
+            self.input.get(self.position),
+            Some(b'=') | Some(b')') | None
+        ) {
+            self.position += 1;
+        }
+        let attribute = &self.input[start..self.position];
+        self.expect(b'=')?;
+        let start = self.position;
+        while !matches!(self.input.get(self.position), Some(b')') | None) {
+            self.position += 1;
+        }
+        let value = &self.input[start..self.position];
+        if attribute.is_empty() {
+            return Err(invalid("LDAP filter without attribute"));
+        }
+        if value == b"*" {
+            return Ok(tlv(0x87, attribute));
+        }
+        if value.contains(&b'*') {
+            return Err(invalid("LDAP substring filters are not supported"));
+        }
+        Ok(tlv(
+            0xa3,
+            &[octet_string(attribute), octet_string(&unescape(value)?)].concat(),
+        ))
+    }
+}
+
+fn unescape(value: &[u8]) -> io::Result<Vec<u8>> {
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut bytes = value.iter();
+    while let Some(byte) = bytes.next() {
+        if *byte != b'\\' {
+            unescaped.push(*byte);
+            continue;
+        }
+        let hex: Vec<u8> = bytes.by_ref().take(2).copied().collect();
+        let hex = std::str::from_utf8(&hex).map_err(|_| invalid("malformed LDAP escape"))?;
+        unescaped.push(u8::from_str_radix(hex, 16).map_err(|_| invalid("malformed LDAP escape"))?);
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            parse_filter("(mail=a\\2ab)").unwrap(),
+            b"\xa3\x0b\x04\x04mail\x04\x03a*b".to_vec()
+        );
+        assert_eq!(
+            parse_filter("(&(objectClass=*)(!(uid=x)))").unwrap(),
+            b"\xa0\x19\x87\x0bobjectClass\xa2\x0a\xa3\x08\x04\x03uid\x04\x01x".to_vec()
+        );
+        assert!(parse_filter("(mail=a*)").is_err());
+        assert!(parse_filter("(mail=a").is_err());
+        assert_eq!(
+            expand_template("(&(uid=%u)(domain=%d))", "a(b)@example.com"),
+            "(&(uid=a\\28b\\29)(domain=example.com))"
+        );
+    }
+
+    #[test]
+    fn test_attribute_values() {
+        let attribute = |name: &str, values: &[&str]| {
+            let values: Vec<u8> = values
+                .iter()
+                .flat_map(|value| octet_string(value.as_bytes()))
+                .collect();
+            tlv(
+                0x30,
+                &[octet_string(name.as_bytes()), tlv(0x31, &values)].concat(),
+            )
+        };
+        let entry = [
+            octet_string(b"uid=sales,dc=example,dc=com"),
+            tlv(
+                0x30,
+                &[
+                    attribute("cn", &["Sales"]),
+                    attribute("mailForwardingAddress", &["a@example.com", "b@example.com"]),
+                ]
+                .concat(),
+            ),
+        ]
+        .concat();
+        assert_eq!(
+            attribute_values(&entry, "mailforwardingaddress").unwrap(),
+            vec!["a@example.com", "b@example.com"]
+        );
+    }
+}
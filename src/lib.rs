@@ -1,38 +1,684 @@
+//! The SMTP protocol core is always built; with the default `server` feature the crate also
+//! provides the TCP server, the networked outputs and the exporters. Without it, sessions
+//! are driven through `handle_connection` or `email::MailFSM` by the embedder's runtime.
+//! The core uses neither `std::net` nor the system clock directly, see `clock`, so that it
+//! also builds for `wasm32-unknown-unknown`.
+
+use core::net::SocketAddr;
+#[cfg(all(feature = "server", unix))]
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "server")]
+use std::{io::BufReader, net::TcpStream};
 use std::{
-    io::{BufRead, BufReader, BufWriter, Write},
-    net::TcpStream,
+    io::{self, BufRead, Write},
+    sync::Arc,
+    time::Duration,
 };
 
+use error::SmtpError;
+use line_reader::{Line, LineReader};
+#[cfg(all(feature = "server", unix))]
+use policy::Policy;
+use session::SessionContext;
+
+#[cfg(feature = "server")]
+pub mod admin;
+pub mod alias;
+#[cfg(feature = "http-api")]
+pub mod api;
+pub mod audit;
+pub mod auth;
+pub mod backscatter;
+pub mod ban;
+#[cfg(feature = "server")]
+pub mod bench;
+pub mod buffer_pool;
+#[cfg(feature = "server")]
+pub mod callout;
+#[cfg(feature = "server")]
+pub mod clamav;
+pub mod clock;
+pub mod config;
+#[cfg(feature = "server")]
+pub mod connect;
+#[cfg(feature = "server")]
+pub mod control;
+#[cfg(feature = "server")]
+pub mod daemon;
+pub mod dane;
+pub mod datetime;
+pub mod deliver_by;
+#[cfg(feature = "server")]
+pub mod delivery;
+pub mod digest;
+pub mod disk;
+#[cfg(feature = "server")]
+pub mod dns;
+pub mod domain;
+pub mod dsn;
 pub mod email;
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "server")]
+pub mod events;
+pub mod extension;
+pub mod footer;
+pub mod handler;
+pub mod headers;
+#[cfg(feature = "server")]
+pub mod http;
+#[cfg(feature = "imap")]
+pub mod imap;
+pub mod journal;
+pub mod json;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub mod line_reader;
+pub mod list;
+pub mod log;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod milter;
+pub mod mta_sts;
+pub mod network;
+pub mod output;
+pub mod plugin;
+pub mod policy;
+#[cfg(feature = "server")]
+pub mod policy_service;
+#[cfg(feature = "server")]
+pub mod pop3;
+pub mod priority;
+pub mod quarantine;
+pub mod queue;
+pub mod quota;
+pub mod recipient;
+pub mod replay;
+pub mod reply;
+pub mod retry;
+#[cfg(feature = "server")]
+pub mod sandbox;
+pub mod script;
+#[cfg(feature = "server")]
+pub mod send;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod session;
+pub mod settings;
+pub mod sieve;
+#[cfg(feature = "server")]
+pub mod signal;
+#[cfg(feature = "server")]
+pub mod spam;
+pub mod store;
+#[cfg(feature = "server")]
+pub mod testing;
+#[cfg(feature = "server")]
 pub mod thread_pool;
+pub mod tls_rpt;
+pub mod trace;
+pub mod transcript;
+pub mod transport;
+pub mod verp;
 
-pub fn handle_connection(stream: TcpStream) {
-    let mut reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    let mut mail_fsm = email::MailFSM::new(String::from("my.server"));
+/// What happened during a session that ended normally.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub peer: Option<SocketAddr>,
+    /// Messages answered with 250 at the end of DATA.
+    pub messages_accepted: usize,
+    /// Replies with a 4xx or 5xx code, or no code at all.
+    pub commands_rejected: usize,
+    /// Whether the client said QUIT rather than just closing the connection.
+    pub quit: bool,
+    pub duration: Duration,
+}
 
-    writer.write(&mail_fsm.greeting().as_bytes()[..]).unwrap();
-    writer.flush().unwrap();
+/// Runs a session over a TCP connection, logging the error that ended it if any.
+#[cfg(feature = "server")]
+pub fn handle_stream(stream: TcpStream, config: Arc<config::Config>) {
+    let context = SessionContext::new(stream.peer_addr().ok(), stream.local_addr().ok());
+    let _registration = config.control.register(&context.session_id, &stream);
+    let peer = context.peer_name();
+    if let Err(e) = stream.set_read_timeout(config.command_timeout) {
+        warn!(peer = peer; "Unable to set the command timeout: {}", e);
+    }
+    if let Err(e) = handle_connection(BufReader::new(&stream), &stream, context, config) {
+        warn!(peer = peer; "Session failed: {}", e);
+    }
+}
 
-    loop {
-        let mut buf = String::new();
-        let data_size = reader.read_line(&mut buf).expect("Unable to read line");
+/// Runs one session over standard input and output, e.g. for inetd, which starts the
+/// server for each connection, or at the end of an ssh tunnel. The peer is the one of the
+/// socket standard input is, if any. Log to stderr or a file, see `log::Target`, since
+/// stdout carries the replies.
+#[cfg(feature = "server")]
+pub fn handle_stdio(config: Arc<config::Config>) -> Result<SessionSummary, SmtpError> {
+    let (peer, local) = stdin_addresses();
+    let context = SessionContext::new(peer, local);
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    handle_connection(stdin.lock(), stdout.lock(), context, config)
+}
 
-        if data_size == 0 {
-            break;
+/// The peer and local addresses of standard input, when it is a TCP socket.
+#[cfg(all(feature = "server", unix))]
+fn stdin_addresses() -> (Option<SocketAddr>, Option<SocketAddr>) {
+    use std::{mem::ManuallyDrop, os::unix::io::FromRawFd};
+
+    // SAFETY: descriptor 0 stays open for the process and is never closed through this
+    // stream; calls on anything but a TCP socket just fail.
+    let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(0) });
+    (stream.peer_addr().ok(), stream.local_addr().ok())
+}
+
+#[cfg(all(feature = "server", not(unix)))]
+fn stdin_addresses() -> (Option<SocketAddr>, Option<SocketAddr>) {
+    (None, None)
+}
+
+/// Runs a session over a Unix socket connection with `policy`, e.g. LMTP from the MTA in
+/// front, logging the error that ended it if any.
+#[cfg(all(feature = "server", unix))]
+pub fn handle_unix_stream(stream: UnixStream, config: Arc<config::Config>, policy: Policy) {
+    let context = SessionContext::new(None, None);
+    if let Err(e) = stream.set_read_timeout(config.command_timeout) {
+        warn!(peer = "unix"; "Unable to set the command timeout: {}", e);
+    }
+    let mail_fsm = email::MailFSM::with_context(config, context).policy(policy);
+    if let Err(e) = handle_session(BufReader::new(&stream), &stream, mail_fsm) {
+        warn!(peer = "unix"; "Session failed: {}", e);
+    }
+}
+
+/// Runs a session reading commands from `reader` and writing replies to `writer`, so that
+/// embedders and tests can drive it over any duplex stream.
+pub fn handle_connection<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    context: SessionContext,
+    config: Arc<config::Config>,
+) -> Result<SessionSummary, SmtpError> {
+    handle_session(
+        reader,
+        writer,
+        email::MailFSM::with_context(config, context),
+    )
+}
+
+/// Like `handle_connection`, with a state machine set up by the caller, e.g. with a
+/// policy of its own.
+pub fn handle_session<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    mut mail_fsm: email::MailFSM,
+) -> Result<SessionSummary, SmtpError> {
+    let config = mail_fsm.config();
+    let context = mail_fsm.context();
+    let address = context.peer;
+    let peer_ip = address.map(|addr| addr.ip());
+    let peer = context.peer_name();
+    let session_id = context.session_id.clone();
+    let _session = log::span("session", &[("peer", &peer), ("id", &session_id)]);
+    let _session_trace = trace::start(
+        "session",
+        trace::Kind::Server,
+        &[("net.peer", &peer), ("session.id", &session_id)],
+    );
+    info!("Connection established");
+    metrics::increment(metrics::CONNECTIONS);
+    let started = clock::now();
+
+    let mut transcript = config.transcripts.start(peer_ip, &session_id);
+    let audit_peer = peer_ip.map_or_else(|| String::from("unknown"), |ip| ip.to_string());
+    let mut writer = ReplyWriter {
+        writer,
+        buffer: config.buffers.take(4096),
+    };
+    // One buffer serves the whole session, lines are borrowed from it.
+    let mut bytes = config.buffers.take(config.max_line_length);
+    let mut messages_accepted = 0;
+    let mut commands_rejected = 0;
+
+    let mut memory = config.memory.allocation();
+    if !memory.try_grow(bytes.capacity() + writer.buffer.capacity()) {
+        let reply = reply::Reply::new(421, "4.3.2 Insufficient system resources");
+        reply.write_to(&mut writer)?;
+        writer.flush()?;
+        warn!("Connection refused: memory budget exhausted");
+        return Err(SmtpError::Policy(reply.text()));
+    }
+
+    // Counts what the client did wrong towards a ban.
+    let offend = |offence: ban::Offence| {
+        if let (Some(bans), Some(ip)) = (&config.bans, peer_ip) {
+            bans.record(ip, offence);
+        }
+    };
+
+    let refusal = config
+        .handler
+        .on_connect(mail_fsm.context())
+        .reply(421)
+        .or_else(|| {
+            let scope = script::Scope {
+                context: mail_fsm.context(),
+                helo: "",
+                from: "",
+                rcpt: "",
+                recipients: &[],
+                data: None,
+            };
+            let script = config.script.as_ref()?;
+            script.run(script::Hook::Connect, &scope).0.reply(421)
+        });
+    if let Some(reply) = refusal {
+        writer.write_all(reply.to_string().as_bytes())?;
+        writer.flush()?;
+        info!("Connection refused: {}", reply.text());
+        if reply.code >= 500 {
+            offend(ban::Offence::Blocklisted);
+        }
+        if let Some(audit) = &config.audit {
+            let event = audit::AuditEvent::Blocklisted;
+            audit.record(event, &audit_peer, &session_id, &[], &reply.to_string());
+        }
+        return Err(SmtpError::Policy(reply.text()));
+    }
+
+    let greeting = mail_fsm.greeting().to_string();
+    if let Some(transcript) = &mut transcript {
+        transcript.server(&greeting);
+    }
+    writer.write_all(greeting.as_bytes())?;
+    writer.flush()?;
+
+    let mut reader = LineReader::new(reader, config.max_line_length);
+    // Whether the bytes read next continue a data line too long to be read at once.
+    let mut continued = false;
+    loop {
+        let line = match reader.read_line(&mut bytes) {
+            // The read timeout of the socket, see `Config::command_timeout`.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let text = format!("4.4.2 {} Timeout exceeded", config.server_name);
+                let reply = reply::Reply::new(421, &text);
+                if let Some(transcript) = &mut transcript {
+                    transcript.server(&reply.to_string());
+                }
+                reply.write_to(&mut writer)?;
+                info!("Connection timed out");
+                break;
+            }
+            line => line?,
         };
+        if line == Line::End {
+            break;
+        }
+        if line == Line::Partial && !mail_fsm.is_receiving_data() {
+            reader.skip_line()?;
+            bytes.clear();
+            let reply = reply::Reply::new(500, "5.5.2 Line too long");
+            if let Some(transcript) = &mut transcript {
+                transcript.server(&reply.to_string());
+            }
+            commands_rejected += 1;
+            reply.write_to(&mut writer)?;
+            if !reader.has_buffered_line() {
+                writer.flush()?;
+            }
+            continue;
+        }
 
-        if let Some(msg) = mail_fsm.process_line(&buf) {
-            writer
-                .write(&msg.as_bytes()[..])
-                .expect("Unable to write to stream");
-            println!("{}", mail_fsm.mail);
-            writer.flush().unwrap();
+        // A part of a line may end inside a character, which is left for the next read.
+        let valid = match std::str::from_utf8(&bytes) {
+            Ok(_) => bytes.len(),
+            Err(e) if line == Line::Partial && e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(SmtpError::Parse(e.to_string())),
+        };
+        let buf =
+            std::str::from_utf8(&bytes[..valid]).map_err(|e| SmtpError::Parse(e.to_string()))?;
+        if let Some(transcript) = &mut transcript {
+            transcript.client(buf);
+        }
+        if continued {
+            mail_fsm.process_data_fragment(buf);
+            continued = line == Line::Partial;
+            bytes.drain(..valid);
+            continue;
+        }
+        continued = line == Line::Partial;
+        // What AUTH asked for is credentials, which stay out of the logs.
+        let command = match mail_fsm.is_authenticating() {
+            true => String::from("AUTH"),
+            false => buf.split_whitespace().next().unwrap_or("").to_uppercase(),
+        };
+        if let Some(reply) = mail_fsm.process_line(buf) {
+            let mut replies = vec![reply];
+            replies.extend(mail_fsm.pending_replies());
+            let end_of_data = buf.trim() == ".";
+            if end_of_data && replies.iter().any(|reply| reply.code == 250) {
+                messages_accepted += 1;
+                // Quarantined messages are not handed to receivers either.
+                #[cfg(feature = "server")]
+                if !mail_fsm.is_quarantined() {
+                    for sender in config.incoming.iter() {
+                        // The receiver may have been dropped, which only stops its delivery.
+                        let _ = sender.send(server::ReceivedMail {
+                            mail: mail_fsm.mail.clone(),
+                            peer: address,
+                            session_id: session_id.clone(),
+                            received_at: clock::now(),
+                        });
+                    }
+                }
+            }
+            for reply in replies.iter() {
+                if let Some(transcript) = &mut transcript {
+                    transcript.server(&reply.to_string());
+                }
+                let rejected = !reply.is_positive();
+                if rejected {
+                    commands_rejected += 1;
+                }
+                match reply.code {
+                    535 => offend(ban::Offence::AuthFailed),
+                    500..=599 => offend(ban::Offence::Rejected),
+                    _ => {}
+                }
+                if let Some(audit) = &config.audit {
+                    if rejected {
+                        audit.record(
+                            match reply.code {
+                                535 => audit::AuditEvent::AuthFailed,
+                                _ => audit::AuditEvent::CommandRejected,
+                            },
+                            &audit_peer,
+                            &session_id,
+                            &[("command", &command)],
+                            &reply.to_string(),
+                        );
+                    }
+                }
+                reply.write_to(&mut writer)?;
+                debug!(
+                    command = command,
+                    code = reply.code;
+                    "Replied"
+                );
+            }
+            // Replies to pipelined commands go out together once the client's are read.
+            if !reader.has_buffered_line() {
+                writer.flush()?;
+            }
         } else {
-            println!("Not sending back {}", buf);
+            trace!("Not sending back {}", buf.trim_end());
         }
+        bytes.drain(..valid);
         if mail_fsm.is_finished() {
             break;
         }
     }
+    writer.flush()?;
+    config.buffers.give(writer.buffer);
+    config.buffers.give(bytes);
+    if let Some(data) = mail_fsm.mail.data.take() {
+        config.buffers.give_string(data);
+    }
+    if let Some(Err(e)) = transcript.as_mut().map(|transcript| transcript.flush()) {
+        warn!("Unable to write transcript: {}", e);
+    }
+    metrics::timing(metrics::SESSION_DURATION, clock::elapsed(started));
+    info!("Connection closed");
+    Ok(SessionSummary {
+        session_id,
+        peer: address,
+        messages_accepted,
+        commands_rejected,
+        quit: mail_fsm.is_finished(),
+        duration: clock::elapsed(started),
+    })
+}
+
+/// Collects replies in a buffer from the pool and writes them out together at `flush`.
+struct ReplyWriter<W: Write> {
+    writer: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Write for ReplyWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.write_all(&self.buffer)?;
+        self.buffer.clear();
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_connection() {
+        let input = "HELO client\nMAIL FROM:<a@example.com>\nRCPT TO:<b@example.com>\nDATA\n\
+                     Subject: hi\n.\nQUIT\n";
+        let mut output = Vec::new();
+        let config = Arc::new(config::Config::new(String::from("mx.example.com")));
+        let summary = handle_connection(
+            input.as_bytes(),
+            &mut output,
+            SessionContext::new(None, None),
+            config,
+        )
+        .unwrap();
+        assert_eq!(summary.messages_accepted, 1);
+        assert_eq!(summary.commands_rejected, 0);
+        assert!(summary.quit);
+        let output = String::from_utf8(output).unwrap();
+        let codes: Vec<&str> = output.lines().map(|line| &line[..3]).collect();
+        assert_eq!(codes, ["220", "250", "250", "250", "354", "250", "221"]);
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_long_lines() {
+        let long = "é".repeat(600);
+        let input = format!(
+            "HELO {}\nHELO client\nMAIL FROM:<a@example.com>\nRCPT TO:<b@example.com>\nDATA\n\
+             {}\n.\nQUIT\n",
+            long, long
+        );
+        let mut output = Vec::new();
+        let mut config = config::Config::new(String::from("mx.example.com"));
+        config.max_line_length = 101;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        config.incoming.push(sender);
+        let summary = handle_connection(
+            input.as_bytes(),
+            &mut output,
+            SessionContext::new(None, None),
+            Arc::new(config),
+        )
+        .unwrap();
+        assert_eq!(summary.messages_accepted, 1);
+        assert_eq!(summary.commands_rejected, 1);
+        let output = String::from_utf8(output).unwrap();
+        let codes: Vec<&str> = output.lines().map(|line| &line[..3]).collect();
+        assert_eq!(
+            codes,
+            ["220", "500", "250", "250", "250", "354", "250", "221"]
+        );
+        let data = receiver.recv().unwrap().mail.data.unwrap();
+        assert!(data.contains(&format!("{}\n", long)));
+    }
+
+    /// Records the size of every write.
+    struct Writes(Vec<usize>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let input = "EHLO client\nMAIL FROM:<a@example.com>\nRCPT TO:<b@example.com>\n\
+                     RCPT TO:<c@example.com>\nDATA\n";
+        let mut writes = Writes(Vec::new());
+        let config = Arc::new(config::Config::new(String::from("mx.example.com")));
+        handle_connection(
+            input.as_bytes(),
+            &mut writes,
+            SessionContext::new(None, None),
+            config,
+        )
+        .unwrap();
+        // The greeting, then all the replies at once.
+        assert_eq!(writes.0.len(), 2);
+    }
+
+    #[test]
+    fn test_bans() {
+        let bans = Arc::new(ban::BanList::new());
+        let config = Arc::new(config::Config {
+            bans: Some(Arc::clone(&bans)),
+            ..config::Config::default()
+        });
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        let input = "HELO client\n".to_string() + &"BOGUS\n".repeat(9);
+        let context = SessionContext::new(Some(peer), None);
+        handle_connection(input.as_bytes(), Vec::new(), context, Arc::clone(&config)).unwrap();
+        assert!(bans.banned_until(peer.ip()).is_none());
+        let context = SessionContext::new(Some(peer), None);
+        handle_connection(&b"BOGUS\n"[..], Vec::new(), context, config).unwrap();
+        assert!(bans.banned_until(peer.ip()).is_some());
+    }
+
+    #[test]
+    fn test_auth_failed() {
+        let path = std::env::temp_dir().join(format!("lib-auth-{}.log", std::process::id()));
+        let audit = audit::AuditLog::to_file(path.to_str().unwrap(), log::Rotation::Never, 0);
+        let bans = Arc::new(ban::BanList::new());
+        let config = Arc::new(config::Config {
+            audit: Some(Arc::new(audit.unwrap())),
+            bans: Some(Arc::clone(&bans)),
+            authenticator: Some(Arc::new(auth::Passwords::new(&[("alice", "s3cret")]))),
+            policy: policy::Policy::default().insecure_auth(true),
+            ..config::Config::default()
+        });
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        // Three points each, banned at ten.
+        let input = "EHLO client\n".to_string() + &"AUTH PLAIN\nAGFsaWNlAHdyb25n\n".repeat(4);
+        let context = SessionContext::new(Some(peer), None);
+        handle_connection(input.as_bytes(), Vec::new(), context, config).unwrap();
+        assert!(bans.banned_until(peer.ip()).is_some());
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(audit.matches(" audit event=auth_failed ").count(), 4);
+        assert!(audit.contains(" command=AUTH "));
+        assert!(!audit.contains("AGFsaWNlAHdyb25n"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    struct Greylist;
+
+    impl handler::SmtpHandler for Greylist {
+        fn on_rcpt_to(&self, _context: &SessionContext, _rcpt: &str) -> handler::Verdict {
+            handler::Verdict::reject(450, "4.2.0 Greylisted, try again later")
+        }
+    }
+
+    #[test]
+    fn test_temporary_failures_not_banned() {
+        let bans = Arc::new(ban::BanList::new());
+        let config = Arc::new(config::Config {
+            bans: Some(Arc::clone(&bans)),
+            handler: Arc::new(Greylist),
+            ..config::Config::default()
+        });
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        let input = "HELO client\nMAIL FROM:<a@example.com>\n".to_string()
+            + &"RCPT TO:<b@example.com>\n".repeat(20);
+        let context = SessionContext::new(Some(peer), None);
+        let summary = handle_connection(input.as_bytes(), Vec::new(), context, config).unwrap();
+        assert_eq!(summary.commands_rejected, 20);
+        assert!(bans.banned_until(peer.ip()).is_none());
+    }
+
+    struct Blocklist;
+
+    impl handler::SmtpHandler for Blocklist {
+        fn on_connect(&self, _context: &SessionContext) -> handler::Verdict {
+            handler::Verdict::reject(554, "5.7.1 Listed at bl.example.org")
+        }
+    }
+
+    #[test]
+    fn test_audit_blocklisted() {
+        let path = std::env::temp_dir().join(format!("lib-audit-{}.log", std::process::id()));
+        let audit = audit::AuditLog::to_file(path.to_str().unwrap(), log::Rotation::Never, 0);
+        let config = Arc::new(config::Config {
+            audit: Some(Arc::new(audit.unwrap())),
+            handler: Arc::new(Blocklist),
+            ..config::Config::default()
+        });
+        let peer: SocketAddr = "192.0.2.1:2525".parse().unwrap();
+        let context = SessionContext::new(Some(peer), None);
+        let session_id = context.session_id.clone();
+        assert!(handle_connection(&b"HELO client\n"[..], Vec::new(), context, config).is_err());
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert!(audit.ends_with(&format!(
+            " audit event=blocklisted peer=192.0.2.1 session={} \
+             reason=\"554 5.7.1 Listed at bl.example.org\"\n",
+            session_id
+        )));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_command_timeout() {
+        use std::{io::Read, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let config = Arc::new(config::Config {
+            command_timeout: Some(Duration::from_millis(100)),
+            ..config::Config::new(String::from("mx.example.com"))
+        });
+        client.write_all(b"HELO client\r\n").unwrap();
+        handle_stream(stream, config);
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        let codes: Vec<&str> = output.lines().map(|line| &line[..3]).collect();
+        assert_eq!(codes, ["220", "250", "421"]);
+        assert!(output.ends_with("421 4.4.2 mx.example.com Timeout exceeded\r\n"));
+    }
+
+    #[test]
+    fn test_handle_connection_error() {
+        let input: &[u8] = b"HELO client\n\xff\xfe\n";
+        let config = Arc::new(config::Config::default());
+        match handle_connection(input, Vec::new(), SessionContext::new(None, None), config) {
+            Err(SmtpError::Parse(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
 }
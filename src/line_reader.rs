@@ -0,0 +1,155 @@
+use std::io::{self, BufRead};
+
+/// What `LineReader::read_line` found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Line {
+    /// A whole line, ending with LF or CRLF.
+    Complete,
+    /// The start of a line longer than the limit, the rest follows with the next reads.
+    Partial,
+    /// The input ended, with nothing left of a line.
+    End,
+}
+
+/// Reads lines ending with LF or CRLF alike without ever holding more than `max_length`
+/// bytes of one, so a client sending no line ending cannot make it grow without bound.
+pub struct LineReader<R> {
+    inner: R,
+    pub max_length: usize,
+    buffered_line: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub fn new(inner: R, max_length: usize) -> LineReader<R> {
+        LineReader {
+            inner,
+            max_length,
+            buffered_line: false,
+        }
+    }
+
+    /// Whether a whole line was already read past the last one, as when a client pipelines
+    /// commands, so that reading it does not wait for the client.
+    pub fn has_buffered_line(&self) -> bool {
+        self.buffered_line
+    }
+
+    /// Appends the next line to `line`, its ending included, until `line` holds
+    /// `max_length` bytes. An input that ends in the middle of a line gives that line as
+    /// complete.
+    pub fn read_line(&mut self, line: &mut Vec<u8>) -> io::Result<Line> {
+        let max_length = self.max_length.max(1);
+        loop {
+            let room = max_length.saturating_sub(line.len());
+            if room == 0 {
+                self.buffered_line = false;
+                return Ok(Line::Partial);
+            }
+            let available = match self.inner.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.buffered_line = false;
+            if available.is_empty() {
+                return Ok(if line.is_empty() {
+                    Line::End
+                } else {
+                    Line::Complete
+                });
+            }
+            let available_all = available;
+            let available = &available[..available.len().min(room)];
+            match available.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..=end]);
+                    self.buffered_line = available_all[end + 1..].contains(&b'\n');
+                    self.inner.consume(end + 1);
+                    return Ok(Line::Complete);
+                }
+                None => {
+                    let read = available.len();
+                    line.extend_from_slice(available);
+                    self.inner.consume(read);
+                }
+            }
+        }
+    }
+
+    /// Throws away the rest of a line `read_line` gave as partial.
+    pub fn skip_line(&mut self) -> io::Result<()> {
+        loop {
+            let available = match self.inner.fill_buf() {
+                Ok(available) => available,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                return Ok(());
+            }
+            match available.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    self.inner.consume(end + 1);
+                    return Ok(());
+                }
+                None => {
+                    let read = available.len();
+                    self.inner.consume(read);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_line() {
+        let input: &[u8] = b"EHLO a\r\nNOOP\nabcdefghij\r\nlast";
+        let mut reader = LineReader::new(io::BufReader::with_capacity(3, input), 8);
+        let mut line = Vec::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Complete);
+        assert_eq!(line, b"EHLO a\r\n");
+        assert!(!reader.has_buffered_line());
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Complete);
+        assert_eq!(line, b"NOOP\n");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Partial);
+        assert_eq!(line, b"abcdefgh");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Complete);
+        assert_eq!(line, b"ij\r\n");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Complete);
+        assert_eq!(line, b"last");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::End);
+    }
+
+    #[test]
+    fn test_skip_line() {
+        let long = [b'x'; 10_000];
+        let input = [&long[..], b"\r\nQUIT\r\n"].concat();
+        let mut reader = LineReader::new(&input[..], 512);
+        let mut line = Vec::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Partial);
+        assert_eq!(line.len(), 512);
+        reader.skip_line().unwrap();
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), Line::Complete);
+        assert_eq!(line, b"QUIT\r\n");
+    }
+
+    #[test]
+    fn test_has_buffered_line() {
+        let mut reader = LineReader::new(&b"RSET\r\nNOOP\r\nQU"[..], 512);
+        let mut line = Vec::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(reader.has_buffered_line());
+        reader.read_line(&mut line).unwrap();
+        assert!(!reader.has_buffered_line());
+    }
+}
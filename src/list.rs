@@ -0,0 +1,187 @@
+use crate::{
+    email::{domain_of, local_part_of, Mail},
+    headers::Message,
+    verp,
+};
+
+/// An address whose messages are sent on to every member.
+pub struct MailingList {
+    pub address: String,
+    pub members: Vec<String>,
+    /// Becomes the envelope sender, so that bounces go to the owner instead of the author.
+    pub owner: Option<String>,
+    /// Put in front of the subject, e.g. `[users]`, unless it already is there.
+    pub subject_prefix: Option<String>,
+    /// Whether every member gets a copy of its own with a return path naming the member,
+    /// see `verp::encode`, so that bounces point at the failing member.
+    pub verp: bool,
+}
+
+impl MailingList {
+    pub fn new(address: &str, members: &[&str]) -> MailingList {
+        MailingList {
+            address: String::from(address),
+            members: members.iter().map(|member| String::from(*member)).collect(),
+            owner: None,
+            subject_prefix: None,
+            verp: false,
+        }
+    }
+
+    fn matches(&self, address: &str) -> bool {
+        let bare = address.trim().trim_start_matches('<').trim_end_matches('>');
+        bare.eq_ignore_ascii_case(&self.address)
+    }
+
+    /// The message as sent to the members, with the `List-*` headers of RFC 2369 and 2919:
+    /// one copy for all of them, or one each with VERP.
+    fn explode(&self, mail: &Mail) -> Vec<Mail> {
+        let mut exploded = Mail {
+            rcpt_to: self
+                .members
+                .iter()
+                .map(|member| format!("<{}>", member))
+                .collect(),
+            ..mail.clone()
+        };
+        if let Some(owner) = &self.owner {
+            exploded.mail_from = Some(format!("<{}>", owner));
+        }
+        if let Some(data) = &mail.data {
+            let mut message = Message::parse(data);
+            if let Some(prefix) = &self.subject_prefix {
+                let subject = message.get("Subject").unwrap_or("");
+                if !subject.contains(prefix.as_str()) {
+                    let subject = format!("{} {}", prefix, subject);
+                    message.replace("Subject", subject.trim_end());
+                }
+            }
+            message.replace("List-Post", &format!("<mailto:{}>", self.address));
+            if let Some(owner) = &self.owner {
+                message.replace("List-Owner", &format!("<mailto:{}>", owner));
+            }
+            let list_id = format!(
+                "<{}.{}>",
+                local_part_of(&self.address),
+                domain_of(&self.address).unwrap_or("localhost")
+            );
+            message.replace("List-Id", &list_id);
+            exploded.data = Some(message.to_string());
+        }
+        let sender = exploded
+            .mail_from
+            .as_deref()
+            .map(|from| from.trim().trim_start_matches('<'))
+            .map(|from| String::from(from.split('>').next().unwrap_or("")))
+            .unwrap_or_default();
+        if !self.verp || sender.is_empty() {
+            return vec![exploded];
+        }
+        self.members
+            .iter()
+            .map(|member| Mail {
+                mail_from: Some(format!("<{}>", verp::encode(&sender, member))),
+                rcpt_to: vec![format!("<{}>", member)],
+                ..exploded.clone()
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct MailingLists {
+    lists: Vec<MailingList>,
+}
+
+impl MailingLists {
+    pub fn add(&mut self, list: MailingList) {
+        self.lists.push(list);
+    }
+
+    /// The messages to deliver in place of `mail`: one for the recipients that are not
+    /// lists, if any, and one per list among the recipients.
+    pub fn expand(&self, mail: &Mail) -> Vec<Mail> {
+        let mut direct = Vec::new();
+        let mut exploded = Vec::new();
+        for rcpt in mail.rcpt_to.iter() {
+            match self.lists.iter().find(|list| list.matches(rcpt)) {
+                Some(list) => exploded.extend(list.explode(mail)),
+                None => direct.push(rcpt.clone()),
+            }
+        }
+        if exploded.is_empty() {
+            return vec![mail.clone()];
+        }
+        if !direct.is_empty() {
+            exploded.insert(
+                0,
+                Mail {
+                    rcpt_to: direct,
+                    ..mail.clone()
+                },
+            );
+        }
+        exploded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let mut list = MailingList::new("users@example.com", &["a@example.com", "b@example.org"]);
+        list.owner = Some(String::from("users-owner@example.com"));
+        list.subject_prefix = Some(String::from("[users]"));
+        let mut lists = MailingLists::default();
+        lists.add(list);
+
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<author@example.net>"));
+        mail.rcpt_to = vec![
+            String::from("<Users@example.com>"),
+            String::from("<c@example.com>"),
+        ];
+        mail.data = Some(String::from("Subject: Hello\r\n\r\nhi\r\n"));
+        let expanded = lists.expand(&mail);
+        assert_eq!(expanded.len(), 2);
+        assert_eq!(expanded[0].rcpt_to, vec!["<c@example.com>"]);
+        assert_eq!(expanded[0].data, mail.data);
+        assert_eq!(
+            expanded[1].rcpt_to,
+            vec!["<a@example.com>", "<b@example.org>"]
+        );
+        assert_eq!(
+            expanded[1].mail_from.as_deref(),
+            Some("<users-owner@example.com>")
+        );
+        let message = Message::parse(expanded[1].data.as_deref().unwrap());
+        assert_eq!(message.get("Subject"), Some("[users] Hello"));
+        assert_eq!(message.get("List-Id"), Some("<users.example.com>"));
+        assert_eq!(message.get("List-Post"), Some("<mailto:users@example.com>"));
+
+        mail.rcpt_to.remove(0);
+        assert_eq!(lists.expand(&mail).len(), 1);
+    }
+
+    #[test]
+    fn test_verp() {
+        let mut list = MailingList::new("users@example.com", &["a@example.com", "b@example.org"]);
+        list.owner = Some(String::from("users-owner@example.com"));
+        list.verp = true;
+        let mut lists = MailingLists::default();
+        lists.add(list);
+
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<author@example.net>"));
+        mail.rcpt_to = vec![String::from("<users@example.com>")];
+        let expanded = lists.expand(&mail);
+        assert_eq!(expanded.len(), 2);
+        assert_eq!(
+            expanded[1].mail_from.as_deref(),
+            Some("<users-owner+b=example.org@example.com>")
+        );
+        assert_eq!(expanded[1].rcpt_to, vec!["<b@example.org>"]);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    io::{self, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::{datetime, json::Json};
+
+mod file;
+#[cfg(feature = "server")]
+mod syslog;
+
+pub use file::{LogFile, Rotation};
+#[cfg(feature = "server")]
+pub use syslog::{Facility, Syslog};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Level, String> {
+        match value.trim().to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" | "warning" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {}", value)),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Environment variable holding the log level (`error`, `warn`, `info`, `debug`, `trace`).
+pub const LEVEL_ENV: &str = "SIMPLE_SMTP_LOG";
+/// Environment variable selecting the `text` or `json` log format.
+pub const FORMAT_ENV: &str = "SIMPLE_SMTP_LOG_FORMAT";
+/// Environment variable naming a file to append logs to instead of stdout.
+pub const FILE_ENV: &str = "SIMPLE_SMTP_LOG_FILE";
+/// Environment variable with the log file rotation: `never`, `hourly`, `daily` or
+/// `size:<bytes>` such as `size:50M`.
+pub const ROTATE_ENV: &str = "SIMPLE_SMTP_LOG_ROTATE";
+/// Environment variable with the number of rotated log files to keep, 7 by default.
+pub const KEEP_ENV: &str = "SIMPLE_SMTP_LOG_KEEP";
+/// Environment variable with a syslog address (`unix:/dev/log`, `udp:host:514` or
+/// `tcp:host:514`) to send logs to instead of stdout.
+#[cfg(feature = "server")]
+pub const SYSLOG_ENV: &str = "SIMPLE_SMTP_LOG_SYSLOG";
+/// Environment variable with the syslog facility, `mail` by default.
+#[cfg(feature = "server")]
+pub const FACILITY_ENV: &str = "SIMPLE_SMTP_LOG_FACILITY";
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Applies the `SIMPLE_SMTP_LOG*` variables, keeping the current settings for variables
+/// that are unset or invalid.
+pub fn init_from_env() {
+    if let Ok(value) = std::env::var(LEVEL_ENV) {
+        match value.parse() {
+            Ok(level) => set_level(level),
+            Err(e) => eprintln!("Ignoring {}: {}", LEVEL_ENV, e),
+        }
+    }
+    if let Ok(value) = std::env::var(FORMAT_ENV) {
+        match value.parse() {
+            Ok(format) => set_format(format),
+            Err(e) => eprintln!("Ignoring {}: {}", FORMAT_ENV, e),
+        }
+    }
+    if let Ok(path) = std::env::var(FILE_ENV) {
+        let rotation = match std::env::var(ROTATE_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", ROTATE_ENV, e);
+                Rotation::Never
+            }),
+            Err(_) => Rotation::Never,
+        };
+        let keep = match std::env::var(KEEP_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                eprintln!("Ignoring {}: invalid count {}", KEEP_ENV, value);
+                7
+            }),
+            Err(_) => 7,
+        };
+        if let Err(e) = log_to_file(&path, rotation, keep) {
+            eprintln!("Ignoring {}: unable to open {}: {}", FILE_ENV, path, e);
+        }
+    }
+    #[cfg(feature = "server")]
+    if let Ok(address) = std::env::var(SYSLOG_ENV) {
+        let facility = match std::env::var(FACILITY_ENV) {
+            Ok(value) => value.parse().unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", FACILITY_ENV, e);
+                Facility::MAIL
+            }),
+            Err(_) => Facility::MAIL,
+        };
+        match Syslog::connect(&address, facility) {
+            Ok(syslog) => set_target(Target::Syslog(syslog)),
+            Err(e) => eprintln!("Ignoring {}: {}", SYSLOG_ENV, e),
+        }
+    }
+}
+
+struct Span {
+    id: u64,
+    name: &'static str,
+    fields: Vec<(&'static str, String)>,
+}
+
+thread_local! {
+    static SPANS: RefCell<Vec<Span>> = const { RefCell::new(Vec::new()) };
+}
+
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Leaves the span when dropped, even if spans entered after it are still active.
+pub struct SpanGuard {
+    id: u64,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().retain(|span| span.id != self.id));
+    }
+}
+
+/// Enters a span on the current thread; its fields are attached to every event logged
+/// until the returned guard is dropped.
+pub fn span(name: &'static str, fields: &[(&'static str, &dyn Display)]) -> SpanGuard {
+    let fields = fields
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    let id = NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed);
+    SPANS.with(|spans| spans.borrow_mut().push(Span { id, name, fields }));
+    SpanGuard { id }
+}
+
+/// Adds or updates a field on the innermost span, for values learnt after it was entered.
+pub fn record(key: &'static str, value: &dyn Display) {
+    SPANS.with(|spans| {
+        if let Some(span) = spans.borrow_mut().last_mut() {
+            let value = value.to_string();
+            match span.fields.iter_mut().find(|(k, _)| *k == key) {
+                Some(field) => field.1 = value,
+                None => span.fields.push((key, value)),
+            }
+        }
+    });
+}
+
+/// A single log event with the spans that were active when it was emitted.
+pub struct Event {
+    pub time: SystemTime,
+    pub level: Level,
+    pub spans: Vec<(&'static str, Vec<(&'static str, String)>)>,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl Event {
+    pub(crate) fn capture(
+        level: Level,
+        fields: &[(&str, &dyn Display)],
+        message: fmt::Arguments,
+    ) -> Event {
+        let spans = SPANS.with(|spans| {
+            spans
+                .borrow()
+                .iter()
+                .map(|span| (span.name, span.fields.clone()))
+                .collect()
+        });
+        Event {
+            time: crate::clock::now(),
+            level,
+            spans,
+            message: message.to_string(),
+            fields: fields
+                .iter()
+                .map(|(key, value)| (String::from(*key), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// `<timestamp> <LEVEL> span{k=v}: message k=v`
+    pub fn to_text(&self) -> String {
+        format!(
+            "{} {:5} {}",
+            datetime::rfc3339(self.time),
+            self.level.as_str(),
+            self.summary()
+        )
+    }
+
+    /// `span{k=v}: message k=v`, for targets that record the time and level themselves.
+    pub fn summary(&self) -> String {
+        let mut line = String::new();
+        for (name, fields) in self.spans.iter() {
+            line.push_str(name);
+            line.push('{');
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            line.push_str(&fields.join(" "));
+            line.push_str("}: ");
+        }
+        line.push_str(&self.message);
+        for (key, value) in self.fields.iter() {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+        line
+    }
+
+    /// One JSON object, spans nested under their names and event fields under `fields`.
+    pub fn to_json(&self) -> Json {
+        let mut object = vec![
+            (
+                String::from("timestamp"),
+                Json::String(datetime::rfc3339(self.time)),
+            ),
+            (String::from("level"), Json::string(self.level.as_str())),
+            (String::from("message"), Json::string(&self.message)),
+        ];
+        for (name, fields) in self.spans.iter() {
+            object.push((
+                String::from(*name),
+                Json::Object(
+                    fields
+                        .iter()
+                        .map(|(key, value)| (String::from(*key), Json::string(value)))
+                        .collect(),
+                ),
+            ));
+        }
+        object.push((
+            String::from("fields"),
+            Json::Object(
+                self.fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), Json::string(value)))
+                    .collect(),
+            ),
+        ));
+        Json::Object(object)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Format, String> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format {}", value)),
+        }
+    }
+}
+
+pub enum Target {
+    Stdout,
+    /// For when stdout carries something else, e.g. a session, see `handle_stdio`, or
+    /// messages, see `output::sink`.
+    Stderr,
+    File(LogFile),
+    #[cfg(feature = "server")]
+    Syslog(Syslog),
+}
+
+struct Logger {
+    format: Format,
+    target: Target,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    format: Format::Text,
+    target: Target::Stdout,
+});
+
+pub fn set_format(format: Format) {
+    LOGGER.lock().unwrap().format = format;
+}
+
+pub fn set_target(target: Target) {
+    LOGGER.lock().unwrap().target = target;
+}
+
+/// Appends log lines to `path` instead of writing them to stdout, rotating it as configured.
+pub fn log_to_file(path: &str, rotation: Rotation, keep: usize) -> io::Result<()> {
+    set_target(Target::File(LogFile::open(path, rotation, keep)?));
+    Ok(())
+}
+
+pub fn log(level: Level, fields: &[(&str, &dyn Display)], message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let event = Event::capture(level, fields, message);
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    let format = logger.format;
+    let line = || match format {
+        Format::Text => event.to_text(),
+        Format::Json => event.to_json().to_string(),
+    };
+    let result = match &mut logger.target {
+        Target::Stdout => writeln!(io::stdout(), "{}", line()),
+        Target::Stderr => writeln!(io::stderr(), "{}", line()),
+        Target::File(file) => file.write_line(&line()),
+        #[cfg(feature = "server")]
+        Target::Syslog(syslog) => match format {
+            Format::Text => syslog.send(&event, &event.summary()),
+            Format::Json => syslog.send(&event, &event.to_json().to_string()),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to write log: {}", e);
+    }
+}
+
+/// `log!(Level::Info, key = value, ...; "format", args)`, the field list being optional.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::log::log($level, &[$((stringify!($key), &$value)),+], format_args!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, &[], format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_format_event() {
+        let _session = span("session", &[("peer", &"127.0.0.1:5000")]);
+        let _transaction = span("transaction", &[]);
+        record("queue_id", &"ABC");
+        let mut event = Event::capture(
+            Level::Info,
+            &[("code", &250)],
+            format_args!("Message {}", "accepted"),
+        );
+        event.time = UNIX_EPOCH;
+        assert_eq!(
+            event.to_text(),
+            "1970-01-01T00:00:00.000Z INFO  session{peer=127.0.0.1:5000}: \
+             transaction{queue_id=ABC}: Message accepted code=250"
+        );
+        assert_eq!(
+            event.to_json().to_string(),
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"level\":\"INFO\",\
+             \"message\":\"Message accepted\",\"session\":{\"peer\":\"127.0.0.1:5000\"},\
+             \"transaction\":{\"queue_id\":\"ABC\"},\"fields\":{\"code\":\"250\"}}"
+        );
+    }
+
+    #[test]
+    fn test_drop_out_of_order() {
+        let first = span("transaction", &[("from", &"a")]);
+        let _second = span("transaction", &[("from", &"b")]);
+        drop(first);
+        let event = Event::capture(Level::Info, &[], format_args!("Message accepted"));
+        assert_eq!(event.summary(), "transaction{from=b}: Message accepted");
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!("DEBUG".parse(), Ok(Level::Debug));
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
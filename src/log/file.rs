@@ -0,0 +1,159 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use crate::datetime;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rotation {
+    Never,
+    /// Rotate once the file would grow past this many bytes.
+    Size(u64),
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    fn period(&self, now: SystemTime) -> u64 {
+        match self {
+            Rotation::Hourly => datetime::unix_seconds(now) / 3600,
+            Rotation::Daily => datetime::unix_seconds(now) / 86400,
+            _ => 0,
+        }
+    }
+}
+
+/// `never`, `hourly`, `daily` or `size:<bytes>` with an optional `K`, `M` or `G` suffix.
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Rotation, String> {
+        let value = value.trim().to_lowercase();
+        match value.as_str() {
+            "never" => return Ok(Rotation::Never),
+            "hourly" => return Ok(Rotation::Hourly),
+            "daily" => return Ok(Rotation::Daily),
+            _ => {}
+        }
+        let invalid = || format!("unknown log rotation {}", value);
+        let size = value.strip_prefix("size:").ok_or_else(invalid)?;
+        let (digits, multiplier) = match size.chars().last() {
+            Some('k') => (&size[..size.len() - 1], 1 << 10),
+            Some('m') => (&size[..size.len() - 1], 1 << 20),
+            Some('g') => (&size[..size.len() - 1], 1 << 30),
+            _ => (size, 1),
+        };
+        match digits.parse::<u64>() {
+            Ok(size) if size > 0 => Ok(Rotation::Size(size * multiplier)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// A log file that is rotated to `<path>.1`, `<path>.2`, ... keeping at most `keep` old files.
+pub struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    period: u64,
+    rotation: Rotation,
+    keep: usize,
+}
+
+impl LogFile {
+    pub fn open(path: &str, rotation: Rotation, keep: usize) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(LogFile {
+            path: String::from(path),
+            file,
+            size: metadata.len(),
+            period: rotation.period(modified),
+            rotation,
+            keep,
+        })
+    }
+
+    fn due(&self, length: u64, now: SystemTime) -> bool {
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(limit) => self.size > 0 && self.size + length > limit,
+            Rotation::Hourly | Rotation::Daily => self.rotation.period(now) != self.period,
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| format!("{}.{}", self.path, n);
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // Shifting onto the oldest name drops it.
+            for n in (1..self.keep).rev() {
+                if fs::metadata(rotated(n)).is_ok() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let now = SystemTime::now();
+        let length = line.len() as u64 + 1;
+        if self.due(length, now) {
+            self.rotate()?;
+        }
+        self.period = self.rotation.period(now);
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rotation() {
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert_eq!("size:10M".parse(), Ok(Rotation::Size(10 << 20)));
+        assert_eq!("size:512".parse(), Ok(Rotation::Size(512)));
+        assert!("size:".parse::<Rotation>().is_err());
+        assert!("weekly".parse::<Rotation>().is_err());
+    }
+
+    #[test]
+    fn test_size_rotation() {
+        let dir = std::env::temp_dir().join(format!("simple-smtp-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("smtp.log");
+        let path = path.to_str().unwrap();
+
+        let mut log = LogFile::open(path, Rotation::Size(10), 2).unwrap();
+        for line in ["one", "two", "three", "four", "five", "six"].iter() {
+            log.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(path).unwrap(), "six\n");
+        assert_eq!(
+            fs::read_to_string(format!("{}.1", path)).unwrap(),
+            "four\nfive\n"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "three\n"
+        );
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    time::Duration,
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use super::{Event, Level};
+use crate::datetime;
+
+const APP_NAME: &str = "simple-smtp";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Facility(pub u8);
+
+impl Facility {
+    pub const USER: Facility = Facility(1);
+    pub const MAIL: Facility = Facility(2);
+    pub const DAEMON: Facility = Facility(3);
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Facility, String> {
+        let value = value.trim().to_lowercase();
+        let code = match value.as_str() {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            _ => match value
+                .strip_prefix("local")
+                .and_then(|n| n.parse::<u8>().ok())
+            {
+                Some(n) if n <= 7 => 16 + n,
+                _ => return Err(format!("unknown syslog facility {}", value)),
+            },
+        };
+        Ok(Facility(code))
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Unix(UnixDatagram, String),
+    Udp(UdpSocket, SocketAddr),
+    Tcp(Option<TcpStream>, SocketAddr),
+}
+
+/// Sends log events to a syslog daemon. The local socket gets the traditional
+/// `<PRI>tag[pid]: message` line the daemon timestamps itself; remote servers get RFC 5424
+/// messages, octet-counted over TCP (RFC 6587).
+pub struct Syslog {
+    transport: Transport,
+    facility: Facility,
+    hostname: String,
+}
+
+impl Syslog {
+    #[cfg(unix)]
+    pub fn unix(path: &str, facility: Facility) -> io::Result<Syslog> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Syslog::with_transport(
+            Transport::Unix(socket, String::from(path)),
+            facility,
+        ))
+    }
+
+    pub fn udp(address: &str, facility: Facility) -> io::Result<Syslog> {
+        let address = resolve(address)?;
+        let bind: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        Ok(Syslog::with_transport(
+            Transport::Udp(socket, address),
+            facility,
+        ))
+    }
+
+    pub fn tcp(address: &str, facility: Facility) -> io::Result<Syslog> {
+        let address = resolve(address)?;
+        let stream = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        Ok(Syslog::with_transport(
+            Transport::Tcp(Some(stream), address),
+            facility,
+        ))
+    }
+
+    /// Parses `unix:/dev/log`, `udp:host:514` or `tcp:host:514`.
+    pub fn connect(spec: &str, facility: Facility) -> io::Result<Syslog> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid syslog address {}", spec),
+            )
+        };
+        let (scheme, address) = spec.split_once(':').ok_or_else(invalid)?;
+        match scheme {
+            #[cfg(unix)]
+            "unix" => Syslog::unix(address, facility),
+            "udp" => Syslog::udp(address, facility),
+            "tcp" => Syslog::tcp(address, facility),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn with_transport(transport: Transport, facility: Facility) -> Syslog {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| String::from(name.trim()))
+            .unwrap_or_default();
+        Syslog {
+            transport,
+            facility,
+            hostname: if hostname.is_empty() {
+                String::from("-")
+            } else {
+                hostname
+            },
+        }
+    }
+
+    fn priority(&self, level: Level) -> u8 {
+        self.facility.0 * 8 + severity(level)
+    }
+
+    fn rfc5424(&self, event: &Event, message: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.priority(event.level),
+            datetime::rfc3339(event.time),
+            self.hostname,
+            APP_NAME,
+            std::process::id(),
+            message
+        )
+    }
+
+    pub fn send(&mut self, event: &Event, message: &str) -> io::Result<()> {
+        let line = match &self.transport {
+            #[cfg(unix)]
+            Transport::Unix(..) => format!(
+                "<{}>{}[{}]: {}",
+                self.priority(event.level),
+                APP_NAME,
+                std::process::id(),
+                message
+            ),
+            _ => self.rfc5424(event, message),
+        };
+        match &mut self.transport {
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => match socket.send(line.as_bytes()) {
+                Ok(_) => Ok(()),
+                // The daemon was restarted and recreated its socket.
+                Err(_) => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect(path.as_str())?;
+                    socket.send(line.as_bytes())?;
+                    self.transport = Transport::Unix(socket, path.clone());
+                    Ok(())
+                }
+            },
+            Transport::Udp(socket, address) => {
+                socket.send_to(line.as_bytes(), *address).map(|_| ())
+            }
+            Transport::Tcp(stream, address) => {
+                let frame = format!("{} {}", line.len(), line);
+                if let Some(connected) = stream {
+                    if connected.write_all(frame.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+                *stream = None;
+                let mut connected = TcpStream::connect_timeout(address, CONNECT_TIMEOUT)?;
+                connected.write_all(frame.as_bytes())?;
+                *stream = Some(connected);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_parse_facility() {
+        assert_eq!("mail".parse(), Ok(Facility::MAIL));
+        assert_eq!("LOCAL3".parse(), Ok(Facility(19)));
+        assert!("local8".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn test_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut syslog = Syslog::udp(&address, Facility::MAIL).unwrap();
+        syslog.hostname = String::from("mx1");
+        let event = Event {
+            time: UNIX_EPOCH,
+            level: Level::Warn,
+            spans: Vec::new(),
+            message: String::from("Message rejected"),
+            fields: Vec::new(),
+        };
+        syslog.send(&event, "Message rejected").unwrap();
+
+        let mut buffer = [0u8; 256];
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buffer[..size]).unwrap(),
+            format!(
+                "<20>1 1970-01-01T00:00:00.000Z mx1 simple-smtp {} - - Message rejected",
+                std::process::id()
+            )
+        );
+    }
+}
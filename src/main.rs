@@ -1,16 +1,706 @@
-use std::net::TcpListener;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use simple_smtp::{handle_connection, thread_pool::ThreadPool};
+#[cfg(feature = "imap")]
+use simple_smtp::imap::Imap;
+use simple_smtp::{
+    admin::Admin,
+    audit,
+    auth::Passwords,
+    ban::BanList,
+    bench::Bench,
+    config::Config,
+    control::Control,
+    daemon::{self, PidFile},
+    datetime, http,
+    journal::{self, Journal},
+    json::Json,
+    log, metrics,
+    output::sink::Sink,
+    pop3::Pop3,
+    quarantine::Quarantine,
+    quota::{MailboxLimits, MailboxQuotas, SenderQuotas},
+    replay::Replay,
+    send::SendMail,
+    server::{Reloader, Server},
+    settings::{Settings, CONFIG_ENV},
+    signal::{self, Signal},
+    store::{MemoryStore, MessageStore, StoreOutput},
+    trace,
+};
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("bench") {
+        match Bench::from_args(&args[1..]) {
+            Ok(bench) => println!("{}", bench.run()),
+            Err(e) => {
+                eprintln!("simple-smtp bench: {}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("send") {
+        match SendMail::from_args(&args[1..]).map(|send| send.run()) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("simple-smtp send: {}", e);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("simple-smtp send: {}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
+    }
+    if args.first().map(String::as_str) == Some("replay") {
+        std::process::exit(replay(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("check-config") {
+        std::process::exit(check_config(args.get(1)));
+    }
+    if args.first().map(String::as_str) == Some("bans") {
+        std::process::exit(bans(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("queue") {
+        std::process::exit(queue(&args[1..]));
+    }
+    if args.first().map(String::as_str) == Some("quarantine") {
+        std::process::exit(quarantine(&args[1..]));
+    }
+
+    let options = Options::from_args(&args).unwrap_or_else(|e| {
+        eprintln!("simple-smtp: {}", e);
+        std::process::exit(2);
+    });
+
+    if options.stdio || options.sink {
+        log::set_target(log::Target::Stderr);
+    }
+    log::init_from_env();
+    metrics::init_from_env();
+    let path = std::env::var(CONFIG_ENV).ok();
+    let settings = Settings::from_env().unwrap_or_else(|e| {
+        eprintln!("simple-smtp: {}", e);
+        std::process::exit(2);
+    });
+    let journal = match &settings.journal {
+        // Nothing is kept in sink mode.
+        _ if options.sink => None,
+        Some(directory) => Some(Arc::new(Journal::open(directory).unwrap_or_else(|e| {
+            eprintln!("simple-smtp: unable to open journal {}: {}", directory, e);
+            std::process::exit(2);
+        }))),
+        None => journal::from_env(),
+    };
+    let bans = settings.bans.as_ref().map(|path| {
+        Arc::new(BanList::open(path).unwrap_or_else(|e| {
+            eprintln!("simple-smtp: unable to read bans {}: {}", path, e);
+            std::process::exit(2);
+        }))
+    });
+    let quarantine = match &settings.quarantine {
+        _ if options.sink => None,
+        Some(directory) => Some(Arc::new(Quarantine::open(directory).unwrap_or_else(|e| {
+            eprintln!(
+                "simple-smtp: unable to open quarantine {}: {}",
+                directory, e
+            );
+            std::process::exit(2);
+        }))),
+        None => None,
+    };
+    // Created as root, and written to once running as the configured user.
+    let mut owned = Vec::new();
+    if journal.is_some() {
+        owned.extend(
+            settings
+                .journal
+                .clone()
+                .or_else(|| std::env::var(journal::JOURNAL_ENV).ok()),
+        );
+    }
+    if quarantine.is_some() {
+        owned.extend(settings.quarantine.clone());
+    }
+    // One file, however often the configuration is reloaded.
+    let audit = audit::from_env().map(Arc::new);
+    let admin_quarantine = quarantine.clone();
+    let reloaded_bans = bans.clone();
+    let admin_bans = bans.clone();
+    let control = Arc::new(Control::new());
+    let sender_quotas = Arc::new(SenderQuotas::default());
+    let admin_control = Arc::clone(&control);
+
+    // Messages are kept for those who read them, the HTTP API and POP3.
+    let store: Arc<dyn MessageStore> = Arc::new(MemoryStore::new(1000));
+    let keep = !options.sink
+        && (cfg!(feature = "http-api")
+            || settings.pop3_listen.is_some()
+            || settings.imap_listen.is_some());
+    // Limits set by the configuration, once it is read.
+    let mailbox_quotas = Arc::new(MailboxQuotas::new(
+        Arc::clone(&store),
+        MailboxLimits::default(),
+    ));
+    let admin_mailbox_quotas = Some(Arc::clone(&mailbox_quotas)).filter(|_| keep);
+    let pop3_store = Arc::clone(&store);
+    let login_audit = audit.clone();
+    #[cfg(feature = "imap")]
+    let imap_store = Arc::clone(&store);
+
+    #[cfg(feature = "http-api")]
+    let (events, transcripts) = {
+        use simple_smtp::{events::EventFeed, transcript::Transcripts};
 
+        (Arc::new(EventFeed::new()), Arc::new(Transcripts::default()))
+    };
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        println!("Connection established!");
+    #[cfg(feature = "http-api")]
+    let (api_store, api_events, api_transcripts) = (
+        Arc::clone(&store),
+        Arc::clone(&events),
+        Arc::clone(&transcripts),
+    );
+    let sink = options.sink;
+    // What lives as long as the process is shared by every configuration built.
+    let build = move |settings: &Settings| -> io::Result<Config> {
+        #[allow(unused_mut)]
+        let mut config = Config {
+            audit: audit.clone(),
+            journal: journal.clone(),
+            bans: bans.clone(),
+            quarantine: quarantine.clone(),
+            control: Arc::clone(&control),
+            sender_quotas: Arc::clone(&sender_quotas),
+            ..Config::default()
+        };
+        if sink {
+            config.outputs.push(Box::new(Sink::stdout()));
+        }
+        if keep {
+            config
+                .outputs
+                .push(Box::new(StoreOutput(Arc::clone(&store))));
+            config.mailbox_quotas = Some(Arc::clone(&mailbox_quotas));
+        }
+        #[cfg(feature = "http-api")]
+        if !sink {
+            config.outputs.push(Box::new(Arc::clone(&events)));
+        }
+        #[cfg(feature = "http-api")]
+        {
+            config.transcripts = Arc::clone(&transcripts);
+        }
+        settings.apply(&mut config)?;
+        Ok(config)
+    };
+    let config = build(&settings).unwrap_or_else(|e| {
+        eprintln!("simple-smtp: {}", e);
+        std::process::exit(2);
+    });
 
-        pool.execute(|| {handle_connection(stream)});
+    if options.stdio {
+        let status = match simple_smtp::handle_stdio(Arc::new(config)) {
+            Ok(_) => 0,
+            Err(e) => {
+                simple_smtp::warn!("Session failed: {}", e);
+                1
+            }
+        };
+        std::process::exit(status);
+    }
+
+    let admin_queue = config.queue.clone();
+    // Released messages go where accepted ones do, as the configuration was at startup.
+    let admin_quarantine = admin_quarantine.map(|quarantine| {
+        let config = build(&settings).unwrap_or_else(|e| {
+            eprintln!("simple-smtp: {}", e);
+            std::process::exit(2);
+        });
+        (quarantine, Arc::new(config))
+    });
+    let server_name = config.server_name.clone();
+    let mut addresses = settings.listen.iter();
+    let first = addresses.next().map_or("127.0.0.1:7878", String::as_str);
+    let sandbox = Some(&settings)
+        .filter(|settings| settings.sandbox)
+        .map(|settings| settings.sandbox(path.as_deref(), options.pid_file.as_deref(), &config));
+    let mut server = Server::new(bind(first), config);
+    for address in addresses {
+        server = server.listen(bind(address));
+    }
+    #[cfg(feature = "http-api")]
+    let api_listener = bind("127.0.0.1:8025");
+    let admin_listener = settings.admin_listen.as_ref().map(|address| {
+        if settings.admin_token.as_deref().unwrap_or("").is_empty() {
+            eprintln!("simple-smtp: admin_listen needs an admin_token");
+            std::process::exit(2);
+        }
+        bind(address)
+    });
+    let pop3 = settings.pop3_listen.as_ref().map(|address| {
+        let passwords = passwords(settings.pop3_passwords.as_deref(), "pop3");
+        let mut pop3 = Pop3::new(pop3_store, Arc::new(passwords)).server_name(&server_name);
+        if let Some(audit) = &login_audit {
+            pop3 = pop3.audit(Arc::clone(audit));
+        }
+        (pop3, bind(address))
+    });
+    #[cfg(feature = "imap")]
+    let imap = settings.imap_listen.as_ref().map(|address| {
+        let passwords = passwords(settings.imap_passwords.as_deref(), "imap");
+        let mut imap = Imap::new(imap_store, Arc::new(passwords)).server_name(&server_name);
+        if let Some(audit) = &login_audit {
+            imap = imap.audit(Arc::clone(audit));
+        }
+        (imap, bind(address))
+    });
+    #[cfg(not(feature = "imap"))]
+    if settings.imap_listen.is_some() {
+        eprintln!("simple-smtp: imap_listen needs a build with the imap feature");
+        std::process::exit(2);
+    }
+
+    // Errors so far went to the terminal; no thread may be started before this.
+    if options.daemon {
+        if let Err(e) = daemon::daemonize() {
+            eprintln!("simple-smtp: unable to run in the background: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let pid_file = options.pid_file.as_ref().map(|path| {
+        PidFile::create(path).unwrap_or_else(|e| {
+            simple_smtp::error!("Unable to write {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    if let Some(user) = &settings.user {
+        let group = settings.group.as_deref();
+        let mut owned = owned.iter().chain(&options.pid_file);
+        let result = daemon::owner(user, group)
+            .and_then(|(uid, gid)| owned.try_for_each(|path| daemon::chown_all(path, uid, gid)));
+        if let Err(e) = result.and_then(|_| daemon::drop_privileges(user, group)) {
+            simple_smtp::error!("Unable to run as {}: {}", user, e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(sandbox) = sandbox {
+        if let Err(e) = sandbox.apply() {
+            simple_smtp::error!("Unable to enter the sandbox: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let pid_file = Mutex::new(pid_file);
+    let terminate = move || {
+        // Messages not acknowledged yet are sent again by their clients, those
+        // acknowledged were journaled if they are to survive this.
+        simple_smtp::info!("Shutting down");
+        drop(pid_file.lock().unwrap().take());
+        std::process::exit(0);
+    };
+    if let Err(e) = signal::watch(Signal::Terminate, terminate) {
+        simple_smtp::warn!("Not handling SIGTERM: {}", e);
+    }
+
+    trace::init_from_env();
+    #[cfg(feature = "http-api")]
+    std::thread::spawn(move || {
+        simple_smtp::api::serve(api_listener, api_store, api_events, api_transcripts)
+    });
+    // The environment is the same on every reload, only the file may change.
+    let reloader = path.map(|_| Reloader::new(move || build(&Settings::from_env()?)));
+    if let Some(reloader) = reloader.clone() {
+        server = server.reloader(Arc::clone(&reloader));
+        let reload = move || {
+            if let Err(e) = reloader.reload() {
+                simple_smtp::error!("Configuration not reloaded: {}", e);
+            }
+            if let Some(Err(e)) = reloaded_bans.as_ref().map(|bans| bans.reload()) {
+                simple_smtp::error!("Bans not reloaded: {}", e);
+            }
+        };
+        if let Err(e) = signal::watch(Signal::Hangup, reload) {
+            simple_smtp::warn!("Not reloading on SIGHUP: {}", e);
+        }
+    }
+    if let Some(listener) = admin_listener {
+        let token = settings.admin_token.as_deref().unwrap_or("");
+        let mut admin = Admin::new(token, admin_control);
+        if let Some(reloader) = reloader {
+            admin = admin.reloader(reloader);
+        }
+        if let Some(queue) = admin_queue {
+            admin = admin.queue(queue);
+        }
+        if let Some(bans) = admin_bans {
+            admin = admin.bans(bans);
+        }
+        if let Some(quotas) = admin_mailbox_quotas {
+            admin = admin.mailbox_quotas(quotas);
+        }
+        if let Some((quarantine, config)) = admin_quarantine {
+            admin = admin.quarantine(quarantine, config);
+        }
+        std::thread::spawn(move || admin.serve(listener));
+    }
+    if let Some((pop3, listener)) = pop3 {
+        std::thread::spawn(move || pop3.serve(listener));
+    }
+    #[cfg(feature = "imap")]
+    if let Some((imap, listener)) = imap {
+        std::thread::spawn(move || imap.serve(listener));
+    }
+    if let Err(e) = server.run() {
+        simple_smtp::error!("Server stopped: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// A listener on `address`, exiting when it cannot be bound, e.g. taken by another server.
+fn bind(address: &str) -> TcpListener {
+    TcpListener::bind(address).unwrap_or_else(|e| {
+        eprintln!("simple-smtp: unable to listen on {}: {}", address, e);
+        std::process::exit(2);
+    })
+}
+
+/// The users of the POP3 or IMAP service, from the file `path` names, exiting when there
+/// is none.
+fn passwords(path: Option<&str>, service: &str) -> Passwords {
+    match path.map(Passwords::load) {
+        Some(Ok(passwords)) => passwords,
+        Some(Err(e)) => {
+            eprintln!("simple-smtp: {}", e);
+            std::process::exit(2);
+        }
+        None => {
+            eprintln!(
+                "simple-smtp: {}_listen needs {}_passwords",
+                service, service
+            );
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Plays the transcript `args` names back against the configuration, see
+/// `Settings::from_env`, from the client `--peer` gives, printing the replies that differ.
+/// Nothing is delivered. Returns the exit status: 1 when replies differ.
+fn replay(args: &[String]) -> i32 {
+    let (path, peer) = match args {
+        [path] => (path, None),
+        [path, option, peer] if option == "--peer" => {
+            let parsed = peer
+                .parse::<SocketAddr>()
+                .or_else(|_| peer.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)));
+            match parsed {
+                Ok(peer) => (path, Some(peer)),
+                Err(_) => {
+                    eprintln!("simple-smtp replay: invalid address {}", peer);
+                    return 2;
+                }
+            }
+        }
+        _ => {
+            eprintln!("usage: simple-smtp replay <transcript> [--peer <address>]");
+            return 2;
+        }
+    };
+    let replay = match std::fs::read_to_string(path).map(|text| Replay::parse(&text)) {
+        Ok(Ok(replay)) => replay,
+        Ok(Err(e)) => {
+            eprintln!("{}:{}", path, e.trim_start_matches("line "));
+            return 2;
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return 2;
+        }
+    };
+    // Stdout carries the differences.
+    log::set_target(log::Target::Stderr);
+    let mut config = Config::default();
+    if let Err(e) = Settings::from_env().and_then(|settings| settings.apply(&mut config)) {
+        eprintln!("simple-smtp replay: {}", e);
+        return 2;
+    }
+    match replay.run(Arc::new(config), peer) {
+        Ok(differences) if differences.is_empty() => {
+            println!("{}: replies match", path);
+            0
+        }
+        Ok(differences) => {
+            for difference in &differences {
+                println!("{}", difference);
+            }
+            println!("{}: {} reply lines differ", path, differences.len());
+            1
+        }
+        Err(e) => {
+            eprintln!("simple-smtp replay: {}", e);
+            2
+        }
+    }
+}
+
+/// Lists the banned clients, or with `unban <address>` lifts a ban, in the file the
+/// configuration names; the server reads it again on SIGHUP. Returns the exit status.
+fn bans(args: &[String]) -> i32 {
+    let settings = match Settings::from_env() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("simple-smtp bans: {}", e);
+            return 2;
+        }
+    };
+    let bans = match settings.bans.as_ref().map(BanList::open) {
+        Some(Ok(bans)) => bans,
+        Some(Err(e)) => {
+            eprintln!("simple-smtp bans: {}", e);
+            return 2;
+        }
+        None => {
+            eprintln!("simple-smtp bans: no bans file configured");
+            return 2;
+        }
+    };
+    match args {
+        [] => {
+            for (address, until) in bans.list() {
+                println!("{} until {}", address, datetime::rfc3339(until));
+            }
+            0
+        }
+        [command, address] if command == "unban" => match address.parse() {
+            Ok(address) if bans.unban(address) => 0,
+            Ok(_) => {
+                eprintln!("simple-smtp bans: {} is not banned", address);
+                1
+            }
+            Err(_) => {
+                eprintln!("simple-smtp bans: invalid address {}", address);
+                2
+            }
+        },
+        _ => {
+            eprintln!("usage: simple-smtp bans [unban <address>]");
+            2
+        }
+    }
+}
+
+/// Lists the holds on queued messages, or holds or releases a message or, with
+/// `--sender`, a sender domain, through the admin API of the running server, where the
+/// queue is. Returns the exit status.
+fn queue(args: &[String]) -> i32 {
+    let target = match args {
+        [] => String::from("/queue/holds"),
+        [action, queue_id] if action == "hold" || action == "release" => {
+            format!("/queue/{}/{}", queue_id, action)
+        }
+        [action, option, domain]
+            if (action == "hold" || action == "release") && option == "--sender" =>
+        {
+            format!("/queue/senders/{}/{}", domain, action)
+        }
+        _ => {
+            eprintln!("usage: simple-smtp queue [hold|release <queue id>|--sender <domain>]");
+            return 2;
+        }
+    };
+    let settings = match Settings::from_env() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("simple-smtp queue: {}", e);
+            return 2;
+        }
+    };
+    let (address, token) = match (&settings.admin_listen, &settings.admin_token) {
+        (Some(address), Some(token)) => (address, token),
+        _ => {
+            eprintln!("simple-smtp queue: no admin_listen and admin_token configured");
+            return 2;
+        }
+    };
+    let url = match http::Url::parse(&format!("http://{}{}", address, target)) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("simple-smtp queue: {}", e);
+            return 2;
+        }
+    };
+    let method = if args.is_empty() { "GET" } else { "POST" };
+    let headers = [("Authorization", format!("Bearer {}", token))];
+    let response = match http::request(method, &url, &headers, b"", Duration::from_secs(10)) {
+        Ok(response) => response,
+        Err(e) => {
+            eprintln!("simple-smtp queue: {}: {}", address, e);
+            return 1;
+        }
+    };
+    let body = String::from_utf8_lossy(&response.body);
+    match (response.status, Json::parse(&body)) {
+        (200, Ok(Json::Array(holds))) => {
+            for hold in holds {
+                let messages = hold.get("messages").and_then(Json::as_i64).unwrap_or(0);
+                match (hold.get("message"), hold.get("sender")) {
+                    (Some(queue_id), _) => println!("{}", queue_id.as_str().unwrap_or("")),
+                    (_, Some(domain)) => println!(
+                        "--sender {} ({} messages)",
+                        domain.as_str().unwrap_or(""),
+                        messages
+                    ),
+                    _ => {}
+                }
+            }
+            0
+        }
+        (200, _) => 0,
+        (404, _) if args.len() == 2 => {
+            eprintln!("simple-smtp queue: {} is not queued", args[1]);
+            1
+        }
+        (status, _) => {
+            eprintln!(
+                "simple-smtp queue: the server answered {}: {}",
+                status, body
+            );
+            1
+        }
+    }
+}
+
+/// Lists the quarantined messages, or with `purge <queue id>` deletes one, in the
+/// directory the configuration names. Releasing needs the outputs of the running server,
+/// see `Admin`. Returns the exit status.
+fn quarantine(args: &[String]) -> i32 {
+    let settings = match Settings::from_env() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("simple-smtp quarantine: {}", e);
+            return 2;
+        }
+    };
+    let quarantine = match settings.quarantine.as_ref().map(Quarantine::open) {
+        Some(Ok(quarantine)) => quarantine,
+        Some(Err(e)) => {
+            eprintln!("simple-smtp quarantine: {}", e);
+            return 2;
+        }
+        None => {
+            eprintln!("simple-smtp quarantine: no quarantine directory configured");
+            return 2;
+        }
+    };
+    match args {
+        [] => match quarantine.list() {
+            Ok(list) => {
+                for quarantined in list {
+                    println!(
+                        "{} {} {} ({})",
+                        quarantined.queue_id,
+                        datetime::rfc3339(quarantined.quarantined_at),
+                        quarantined.mail.mail_from.as_deref().unwrap_or("<>"),
+                        quarantined.reason
+                    );
+                }
+                0
+            }
+            Err(e) => {
+                eprintln!("simple-smtp quarantine: {}", e);
+                2
+            }
+        },
+        [command, queue_id] if command == "purge" => match quarantine.purge(queue_id) {
+            Ok(true) => 0,
+            Ok(false) => {
+                eprintln!("simple-smtp quarantine: {} is not quarantined", queue_id);
+                1
+            }
+            Err(e) => {
+                eprintln!("simple-smtp quarantine: {}", e);
+                2
+            }
+        },
+        _ => {
+            eprintln!("usage: simple-smtp quarantine [purge <queue id>]");
+            2
+        }
+    }
+}
+
+/// How the server runs, from the command line.
+#[derive(Default)]
+struct Options {
+    /// Run in the background, see `daemon::daemonize`.
+    daemon: bool,
+    pid_file: Option<String>,
+    /// Serve one session over stdin and stdout, see `handle_stdio`.
+    stdio: bool,
+    /// Print each message to stdout and keep none, see `output::sink`.
+    sink: bool,
+}
+
+impl Options {
+    /// Reads `--daemon`, `--pidfile <path>`, `--stdio` and `--sink`.
+    fn from_args(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            match option.as_str() {
+                "--daemon" => options.daemon = true,
+                "--stdio" => options.stdio = true,
+                "--sink" => options.sink = true,
+                "--pidfile" => {
+                    let path = args.next().ok_or("--pidfile needs a value")?;
+                    options.pid_file = Some(path.clone());
+                }
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        if options.stdio && options.sink {
+            return Err(String::from("--stdio and --sink both write to stdout"));
+        }
+        Ok(options)
+    }
+}
+
+/// Reports every error of the configuration file at `path`, or the one `SIMPLE_SMTP_CONFIG`
+/// names, returning the exit status: 1 when there are errors, 2 when there is no file.
+fn check_config(path: Option<&String>) -> i32 {
+    let path = match path.cloned().or_else(|| std::env::var(CONFIG_ENV).ok()) {
+        Some(path) => path,
+        None => {
+            eprintln!(
+                "simple-smtp check-config: no file given and {} unset",
+                CONFIG_ENV
+            );
+            return 2;
+        }
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return 2;
+        }
+    };
+    let errors = Settings::check(&text);
+    for e in &errors {
+        eprintln!("{}:{}: {}", path, e.line, e.message);
+    }
+    if errors.is_empty() {
+        println!("{}: OK", path);
+        0
+    } else {
+        eprintln!("{}: {} errors", path, errors.len());
+        1
     }
 }
@@ -0,0 +1,107 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// The memory sessions may hold for buffered commands and spooled messages, each and all
+/// together, so that no client behaviour makes the server use more.
+pub struct MemoryBudget {
+    pub per_session: usize,
+    pub total: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(per_session: usize, total: usize) -> MemoryBudget {
+        MemoryBudget {
+            per_session,
+            total,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// The bytes all sessions hold.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// An empty allocation for a session to grow.
+    pub fn allocation(self: &Arc<Self>) -> Allocation {
+        Allocation {
+            budget: Arc::clone(self),
+            bytes: 0,
+        }
+    }
+}
+
+impl Default for MemoryBudget {
+    /// 64 MiB a session, 1 GiB in all.
+    fn default() -> MemoryBudget {
+        MemoryBudget::new(64 << 20, 1 << 30)
+    }
+}
+
+/// The bytes one session holds of a `MemoryBudget`, given back when dropped.
+pub struct Allocation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Allocation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Takes `bytes` more, unless that goes over either ceiling of the budget.
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        let held = match self.bytes.checked_add(bytes) {
+            Some(held) if held <= self.budget.per_session => held,
+            _ => return false,
+        };
+        let total = self.budget.total;
+        let grown = self
+            .budget
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|used| *used <= total)
+            })
+            .is_ok();
+        if grown {
+            self.bytes = held;
+        }
+        grown
+    }
+
+    /// Gives back everything held.
+    pub fn clear(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocation() {
+        let budget = Arc::new(MemoryBudget::new(100, 150));
+        let mut first = budget.allocation();
+        let mut second = budget.allocation();
+        assert!(first.try_grow(60));
+        assert!(!first.try_grow(41));
+        assert!(second.try_grow(90));
+        assert!(!first.try_grow(1));
+        assert_eq!(budget.used(), 150);
+        drop(second);
+        assert!(first.try_grow(40));
+        first.clear();
+        assert_eq!(budget.used(), 0);
+    }
+}
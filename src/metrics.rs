@@ -0,0 +1,102 @@
+#[cfg(feature = "server")]
+use std::sync::OnceLock;
+use std::{sync::Mutex, time::Duration};
+
+#[cfg(feature = "server")]
+mod statsd;
+
+#[cfg(feature = "server")]
+pub use statsd::StatsD;
+
+/// Environment variable with the `host:port` of a StatsD server to push metrics to.
+#[cfg(feature = "server")]
+pub const STATSD_ENV: &str = "SIMPLE_SMTP_STATSD";
+/// Environment variable with the prefix of every StatsD metric name, `simple_smtp` by default.
+#[cfg(feature = "server")]
+pub const STATSD_PREFIX_ENV: &str = "SIMPLE_SMTP_STATSD_PREFIX";
+/// Environment variable with DogStatsD tags as `key:value,key:value`.
+#[cfg(feature = "server")]
+pub const STATSD_TAGS_ENV: &str = "SIMPLE_SMTP_STATSD_TAGS";
+
+pub const CONNECTIONS: &str = "connections";
+pub const MESSAGES_ACCEPTED: &str = "messages_accepted";
+pub const MESSAGES_REJECTED: &str = "messages_rejected";
+pub const MESSAGES_QUARANTINED: &str = "messages_quarantined";
+pub const QUOTA_EXCEEDED: &str = "quota_exceeded";
+pub const RECEIVED_BYTES: &str = "received_bytes";
+pub const OUTPUT_FAILURES: &str = "output_failures";
+pub const BOUNCES_SUPPRESSED: &str = "bounces_suppressed";
+pub const SESSION_DURATION: &str = "session_duration";
+pub const OUTPUT_DURATION: &str = "output_duration";
+pub const DNS_QUERIES: &str = "dns_queries";
+pub const DNS_CACHE_HITS: &str = "dns_cache_hits";
+pub const DNS_FAILURES: &str = "dns_failures";
+pub const DNS_DURATION: &str = "dns_duration";
+pub const BUFFERS_ALLOCATED: &str = "buffers_allocated";
+pub const BUFFERS_REUSED: &str = "buffers_reused";
+
+static COUNTERS: Mutex<Vec<(&'static str, u64)>> = Mutex::new(Vec::new());
+#[cfg(feature = "server")]
+static STATSD: OnceLock<StatsD> = OnceLock::new();
+
+pub fn count(name: &'static str, value: u64) {
+    {
+        let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+        match counters.iter_mut().find(|(counter, _)| *counter == name) {
+            Some(counter) => counter.1 += value,
+            None => counters.push((name, value)),
+        }
+    }
+    #[cfg(feature = "server")]
+    if let Some(statsd) = STATSD.get() {
+        statsd.count(name, value);
+    }
+}
+
+pub fn increment(name: &'static str) {
+    count(name, 1);
+}
+
+#[cfg_attr(not(feature = "server"), allow(unused_variables))]
+pub fn timing(name: &'static str, duration: Duration) {
+    #[cfg(feature = "server")]
+    if let Some(statsd) = STATSD.get() {
+        statsd.timing(name, duration);
+    }
+}
+
+/// Current value of every counter incremented so far.
+pub fn counters() -> Vec<(&'static str, u64)> {
+    COUNTERS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Pushes every metric to `statsd` from now on. Only the first call has an effect.
+#[cfg(feature = "server")]
+pub fn push_to(statsd: StatsD) {
+    let _ = STATSD.set(statsd);
+}
+
+/// Enables StatsD when `SIMPLE_SMTP_STATSD` is set.
+#[cfg(feature = "server")]
+pub fn init_from_env() {
+    let address = match std::env::var(STATSD_ENV) {
+        Ok(address) => address,
+        Err(_) => return,
+    };
+    let prefix = std::env::var(STATSD_PREFIX_ENV).unwrap_or_else(|_| String::from("simple_smtp"));
+    let tags = std::env::var(STATSD_TAGS_ENV)
+        .map(|tags| {
+            tags.split(',')
+                .filter(|tag| !tag.trim().is_empty())
+                .map(|tag| match tag.split_once(':') {
+                    Some((key, value)) => (String::from(key.trim()), String::from(value.trim())),
+                    None => (String::from(tag.trim()), String::new()),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    match StatsD::new(&address, &prefix, tags) {
+        Ok(statsd) => push_to(statsd),
+        Err(e) => eprintln!("Ignoring {}: {}", STATSD_ENV, e),
+    }
+}
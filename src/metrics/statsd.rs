@@ -0,0 +1,102 @@
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    time::Duration,
+};
+
+/// Sends each metric as a StatsD UDP datagram, with DogStatsD `|#key:value` tags if any.
+/// Losing a datagram only loses that sample, so send errors are ignored.
+pub struct StatsD {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<(String, String)>,
+}
+
+impl StatsD {
+    pub fn new(address: &str, prefix: &str, tags: Vec<(String, String)>) -> io::Result<StatsD> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host not found"))?;
+        let bind: SocketAddr = if address.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsD {
+            socket,
+            prefix: String::from(prefix),
+            tags,
+        })
+    }
+
+    fn format(&self, name: &str, value: &str, kind: &str) -> String {
+        let mut line = if self.prefix.is_empty() {
+            format!("{}:{}|{}", name, value, kind)
+        } else {
+            format!("{}.{}:{}|{}", self.prefix, name, value, kind)
+        };
+        if !self.tags.is_empty() {
+            let tags: Vec<String> = self
+                .tags
+                .iter()
+                .map(|(key, value)| {
+                    if value.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}:{}", key, value)
+                    }
+                })
+                .collect();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        let _ = self
+            .socket
+            .send(self.format(name, &value.to_string(), "c").as_bytes());
+    }
+
+    pub fn timing(&self, name: &str, duration: Duration) {
+        let millis = duration.as_millis().to_string();
+        let _ = self
+            .socket
+            .send(self.format(name, &millis, "ms").as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let tags = vec![
+            (String::from("env"), String::from("prod")),
+            (String::from("canary"), String::new()),
+        ];
+        let statsd = StatsD::new(&address, "smtp", tags).unwrap();
+        statsd.count("messages_accepted", 2);
+        statsd.timing("session_duration", Duration::from_millis(1500));
+
+        let mut buffer = [0u8; 128];
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..size],
+            b"smtp.messages_accepted:2|c|#env:prod,canary"
+        );
+        let size = server.recv(&mut buffer).unwrap();
+        assert_eq!(
+            &buffer[..size],
+            b"smtp.session_duration:1500|ms|#env:prod,canary"
+        );
+    }
+}
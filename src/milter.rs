@@ -0,0 +1,679 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, Read, Write},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use crate::{
+    connect,
+    email::Mail,
+    headers::{Header, Message},
+    reply::Reply,
+};
+
+/// The version of the protocol spoken, that of sendmail 8.14 and Postfix.
+const VERSION: u32 = 6;
+/// The modifications this server applies, SMFIF_ADDHDRS to SMFIF_ADDRCPT_PAR.
+const ACTIONS: u32 = 0xff;
+/// The steps a filter may ask to be left out of or not to reply to: all of version 6 but
+/// SMFIP_RCPT_REJ and SMFIP_HDR_LEADSPC.
+const PROTOCOL: u32 = 0xf_f7ff;
+
+const NO_CONNECT: u32 = 0x1;
+const NO_HELO: u32 = 0x2;
+const NO_MAIL: u32 = 0x4;
+const NO_RCPT: u32 = 0x8;
+const NO_BODY: u32 = 0x10;
+const NO_HEADERS: u32 = 0x20;
+const NO_EOH: u32 = 0x40;
+const NR_HEADER: u32 = 0x80;
+const NO_DATA: u32 = 0x200;
+const NR_CONNECT: u32 = 0x1000;
+const NR_HELO: u32 = 0x2000;
+const NR_MAIL: u32 = 0x4000;
+const NR_RCPT: u32 = 0x8000;
+const NR_DATA: u32 = 0x1_0000;
+const NR_EOH: u32 = 0x4_0000;
+const NR_BODY: u32 = 0x8_0000;
+
+/// The largest body chunk a filter is sent.
+const CHUNK_SIZE: usize = 65535;
+/// The largest packet read from a filter.
+const MAX_PACKET: usize = 1 << 20;
+
+/// What a filter answered a step with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    Continue,
+    /// The message is accepted, the filter wants to see no more of it.
+    Accept,
+    Reject,
+    TempFail,
+    /// The message is accepted and dropped.
+    Discard,
+    /// Refused or deferred with this reply.
+    Reply(Reply),
+}
+
+/// A change a filter makes to the message once it saw all of it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Modification {
+    /// Appended after the other headers.
+    AddHeader {
+        name: String,
+        value: String,
+    },
+    /// Inserted at `index`, 0 being the top.
+    InsertHeader {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    /// Replaces the `index`th header named `name`, counting from 1, or removes it when
+    /// `value` is empty.
+    ChangeHeader {
+        index: usize,
+        name: String,
+        value: String,
+    },
+    AddRecipient(String),
+    DeleteRecipient(String),
+    ChangeSender(String),
+    /// A part of the new body; the parts sent are joined.
+    ReplaceBody(String),
+    /// Keep the message in the quarantine, for this reason.
+    Quarantine(String),
+}
+
+/// A filter speaking the sendmail milter protocol, e.g. OpenDKIM, clamav-milter or the
+/// rspamd proxy. Each session opens its own connection to it, see `MilterSession`.
+pub struct Milter {
+    /// `host:port`, or `unix:` and the path of a socket.
+    pub address: String,
+    pub timeout: Duration,
+    /// Whether messages go on unfiltered when the filter cannot be reached, rather than
+    /// being deferred.
+    pub fail_open: bool,
+}
+
+impl Milter {
+    pub fn new(address: &str) -> Milter {
+        Milter {
+            address: String::from(address),
+            timeout: Duration::from_secs(30),
+            fail_open: false,
+        }
+    }
+
+    /// Connects to the filter and agrees with it on the steps it takes part in.
+    pub fn open(&self) -> io::Result<MilterSession> {
+        let stream: Box<dyn Stream> = match self.address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Box::new(stream)
+            }
+            _ => {
+                let (host, port) = self
+                    .address
+                    .rsplit_once(':')
+                    .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid milter address {}", self.address),
+                        )
+                    })?;
+                let host = host.trim_matches(&['[', ']'][..]);
+                let stream = connect::connect(host, port, self.timeout)?;
+                stream.set_read_timeout(Some(self.timeout))?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                Box::new(stream)
+            }
+        };
+        let mut session = MilterSession {
+            stream,
+            actions: 0,
+            protocol: 0,
+            accepted: false,
+        };
+        let mut offer = Vec::with_capacity(12);
+        for value in [VERSION, ACTIONS, PROTOCOL].iter() {
+            offer.extend_from_slice(&value.to_be_bytes());
+        }
+        session.send(b'O', &offer)?;
+        let (command, data) = session.read()?;
+        if command != b'O' || data.len() < 12 {
+            return Err(io::Error::other("milter refused to negotiate"));
+        }
+        let word =
+            |at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        session.actions = word(4) & ACTIONS;
+        session.protocol = word(8) & PROTOCOL;
+        Ok(session)
+    }
+}
+
+trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// The connection of one SMTP session to a filter, which is told about each step of the
+/// session in turn and answers with a `Response`.
+pub struct MilterSession {
+    stream: Box<dyn Stream>,
+    /// The modifications the filter may make.
+    actions: u32,
+    /// The steps the filter asked to be left out of or not to reply to.
+    protocol: u32,
+    /// Whether the filter accepted the current message, and is told no more of it.
+    accepted: bool,
+}
+
+impl MilterSession {
+    /// Defines macros, e.g. `j` or `{auth_authen}`, for the filter to read from the step
+    /// `command` on, `C` for connect, `M` for MAIL FROM, `E` for the end of the message.
+    pub fn macros(&mut self, command: u8, macros: &[(&str, &str)]) -> io::Result<()> {
+        if self.accepted {
+            return Ok(());
+        }
+        let mut data = vec![command];
+        for (name, value) in macros.iter() {
+            data.extend(c_string(name));
+            data.extend(c_string(value));
+        }
+        self.send(b'D', &data)
+    }
+
+    /// Tells the filter about the client, `address` being unknown for a local one.
+    pub fn connect(&mut self, hostname: &str, address: Option<SocketAddr>) -> io::Result<Response> {
+        let mut data = c_string(hostname);
+        match address {
+            Some(address) => {
+                data.push(if address.is_ipv4() { b'4' } else { b'6' });
+                data.extend_from_slice(&address.port().to_be_bytes());
+                data.extend(c_string(&address.ip().to_string()));
+            }
+            None => data.push(b'U'),
+        }
+        self.step(NO_CONNECT, NR_CONNECT, b'C', &data)
+    }
+
+    pub fn helo(&mut self, name: &str) -> io::Result<Response> {
+        self.step(NO_HELO, NR_HELO, b'H', &c_string(name))
+    }
+
+    /// Starts a message from `sender`, followed by its ESMTP parameters.
+    pub fn mail(&mut self, sender: &[&str]) -> io::Result<Response> {
+        self.accepted = false;
+        self.step(NO_MAIL, NR_MAIL, b'M', &c_strings(sender))
+    }
+
+    /// Adds a recipient, followed by its ESMTP parameters.
+    pub fn rcpt(&mut self, recipient: &[&str]) -> io::Result<Response> {
+        self.step(NO_RCPT, NR_RCPT, b'R', &c_strings(recipient))
+    }
+
+    pub fn data(&mut self) -> io::Result<Response> {
+        self.step(NO_DATA, NR_DATA, b'T', &[])
+    }
+
+    /// Sends the headers and body of `data` and ends the message, returning what the
+    /// filter answered and the modifications it asked for, which are only kept when
+    /// the message is accepted.
+    pub fn end_of_message(&mut self, data: &str) -> io::Result<(Response, Vec<Modification>)> {
+        if self.accepted {
+            return Ok((Response::Accept, Vec::new()));
+        }
+        let message = Message::parse(data);
+        for header in message.headers.iter() {
+            let mut data = c_string(&header.name);
+            data.extend(c_string(&header.value));
+            let response = self.step(NO_HEADERS, NR_HEADER, b'L', &data)?;
+            if response != Response::Continue {
+                return Ok((response, Vec::new()));
+            }
+        }
+        let response = self.step(NO_EOH, NR_EOH, b'N', &[])?;
+        if response != Response::Continue {
+            return Ok((response, Vec::new()));
+        }
+        if self.protocol & NO_BODY == 0 {
+            for chunk in message.body.as_bytes().chunks(CHUNK_SIZE) {
+                self.send(b'B', chunk)?;
+                if self.protocol & NR_BODY != 0 {
+                    continue;
+                }
+                match self.read_response()? {
+                    // SMFIR_SKIP, the filter has seen enough of the body.
+                    None => break,
+                    Some(Response::Continue) => {}
+                    Some(response) => return Ok((self.settle(response), Vec::new())),
+                }
+            }
+        }
+        self.send(b'E', &[])?;
+        let mut modifications = Vec::new();
+        loop {
+            let (command, data) = self.read()?;
+            if let Some((action, modification)) = MilterSession::modification(command, &data) {
+                if self.actions & action == 0 {
+                    crate::warn!("Ignoring a modification the milter did not negotiate");
+                } else {
+                    modifications.push(modification);
+                }
+                continue;
+            }
+            match parse_response(command, &data)? {
+                Some(response) => {
+                    self.accepted = false;
+                    return Ok((response, modifications));
+                }
+                None => continue,
+            }
+        }
+    }
+
+    /// Forgets the current message, e.g. on RSET.
+    pub fn abort(&mut self) -> io::Result<()> {
+        self.accepted = false;
+        self.send(b'A', &[])
+    }
+
+    /// Ends the session.
+    pub fn quit(mut self) -> io::Result<()> {
+        self.send(b'Q', &[])
+    }
+
+    /// Sends a step unless the filter asked to be left out of it, reading the response
+    /// unless it asked not to reply to it.
+    fn step(&mut self, skip: u32, no_reply: u32, command: u8, data: &[u8]) -> io::Result<Response> {
+        if self.accepted || self.protocol & skip != 0 {
+            return Ok(Response::Continue);
+        }
+        self.send(command, data)?;
+        if self.protocol & no_reply != 0 {
+            return Ok(Response::Continue);
+        }
+        let response = self.read_response()?.unwrap_or(Response::Continue);
+        Ok(self.settle(response))
+    }
+
+    fn settle(&mut self, response: Response) -> Response {
+        if response == Response::Accept {
+            self.accepted = true;
+        }
+        response
+    }
+
+    /// Reads the response to a step, `None` for SMFIR_SKIP, skipping progress reports.
+    fn read_response(&mut self) -> io::Result<Option<Response>> {
+        loop {
+            let (command, data) = self.read()?;
+            if command == b's' {
+                return Ok(None);
+            }
+            if let Some(response) = parse_response(command, &data)? {
+                return Ok(Some(response));
+            }
+        }
+    }
+
+    /// The modification `command` asks for, if it is one, with the SMFIF flag of the
+    /// action it takes.
+    fn modification(command: u8, data: &[u8]) -> Option<(u32, Modification)> {
+        let (action, modification) = match command {
+            b'h' => {
+                let fields = split(data);
+                (
+                    0x1,
+                    Modification::AddHeader {
+                        name: field(&fields, 0),
+                        value: field(&fields, 1),
+                    },
+                )
+            }
+            b'i' | b'm' if data.len() >= 4 => {
+                let index = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                let fields = split(&data[4..]);
+                let (name, value) = (field(&fields, 0), field(&fields, 1));
+                if command == b'i' {
+                    (0x1, Modification::InsertHeader { index, name, value })
+                } else {
+                    (0x10, Modification::ChangeHeader { index, name, value })
+                }
+            }
+            b'+' => (0x4, Modification::AddRecipient(field(&split(data), 0))),
+            b'2' => (0x80, Modification::AddRecipient(split(data).join(" "))),
+            b'-' => (0x8, Modification::DeleteRecipient(field(&split(data), 0))),
+            b'e' => (0x40, Modification::ChangeSender(split(data).join(" "))),
+            b'b' => (
+                0x2,
+                Modification::ReplaceBody(String::from_utf8_lossy(data).into_owned()),
+            ),
+            b'q' => (0x20, Modification::Quarantine(field(&split(data), 0))),
+            _ => return None,
+        };
+        Some((action, modification))
+    }
+
+    fn send(&mut self, command: u8, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 5);
+        packet.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+        packet.push(command);
+        packet.extend_from_slice(data);
+        self.stream.write_all(&packet)?;
+        self.stream.flush()
+    }
+
+    fn read(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut length = [0; 4];
+        self.stream.read_exact(&mut length)?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length == 0 || length > MAX_PACKET {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("milter sent a packet of {} bytes", length),
+            ));
+        }
+        let mut packet = vec![0; length];
+        self.stream.read_exact(&mut packet)?;
+        let data = packet.split_off(1);
+        Ok((packet[0], data))
+    }
+}
+
+/// The response `command` gives, `None` for a progress report.
+fn parse_response(command: u8, data: &[u8]) -> io::Result<Option<Response>> {
+    Ok(Some(match command {
+        b'c' => Response::Continue,
+        b'a' => Response::Accept,
+        b'r' => Response::Reject,
+        b't' => Response::TempFail,
+        b'd' => Response::Discard,
+        b'y' => {
+            let text = field(&split(data), 0);
+            let (code, text) = text.split_once(' ').unwrap_or((&text, ""));
+            match code.parse::<u16>() {
+                Ok(code @ 400..=599) => Response::Reply(Reply::new(code, text)),
+                _ => return Err(io::Error::other(format!("milter replied {}", code))),
+            }
+        }
+        b'p' => return Ok(None),
+        _ => {
+            return Err(io::Error::other(format!(
+                "unexpected milter response {}",
+                command as char
+            )))
+        }
+    }))
+}
+
+/// Applies `modifications` to `mail`. Quarantine requests are left to the caller.
+pub fn apply(modifications: &[Modification], mail: &mut Mail) {
+    let mut message = Message::parse(mail.data.as_deref().unwrap_or(""));
+    let line_ending = message.line_ending();
+    let folded = |value: &str| value.replace("\r\n", "\n").replace('\n', line_ending);
+    let mut body: Option<String> = None;
+    let mut rewritten = false;
+    for modification in modifications.iter() {
+        match modification {
+            Modification::AddHeader { name, value } => {
+                message.headers.push(Header {
+                    name: name.clone(),
+                    value: folded(value),
+                });
+                rewritten = true;
+            }
+            Modification::InsertHeader { index, name, value } => {
+                let index = (*index).min(message.headers.len());
+                let header = Header {
+                    name: name.clone(),
+                    value: folded(value),
+                };
+                message.headers.insert(index, header);
+                rewritten = true;
+            }
+            Modification::ChangeHeader { index, name, value } => {
+                let position = message
+                    .headers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, header)| header.name.eq_ignore_ascii_case(name))
+                    .nth(index.saturating_sub(1))
+                    .map(|(position, _)| position);
+                match position {
+                    Some(position) if value.is_empty() => {
+                        message.headers.remove(position);
+                    }
+                    Some(position) => message.headers[position].value = folded(value),
+                    None if value.is_empty() => continue,
+                    None => message.headers.push(Header {
+                        name: name.clone(),
+                        value: folded(value),
+                    }),
+                }
+                rewritten = true;
+            }
+            Modification::AddRecipient(rcpt) => {
+                // Before the empty recipient DATA leaves at the end.
+                let at = mail
+                    .rcpt_to
+                    .iter()
+                    .position(String::is_empty)
+                    .unwrap_or(mail.rcpt_to.len());
+                mail.rcpt_to.insert(at, bracketed(rcpt));
+            }
+            Modification::DeleteRecipient(rcpt) => {
+                let rcpt = bare(rcpt).to_lowercase();
+                mail.rcpt_to.retain(|existing| {
+                    existing.is_empty() || bare(existing).to_lowercase() != rcpt
+                });
+            }
+            Modification::ChangeSender(from) => mail.mail_from = Some(bracketed(from)),
+            Modification::ReplaceBody(chunk) => {
+                body.get_or_insert_with(String::new).push_str(chunk);
+            }
+            Modification::Quarantine(_) => {}
+        }
+    }
+    if let Some(body) = body {
+        message.body = body;
+        rewritten = true;
+    }
+    if rewritten {
+        mail.data = Some(message.to_string());
+    }
+}
+
+/// The address of `rcpt`, without its parameters and angle brackets.
+fn bare(address: &str) -> &str {
+    let address = address.trim();
+    let address = address.split_whitespace().next().unwrap_or("");
+    address.trim_start_matches('<').trim_end_matches('>')
+}
+
+/// `address` in angle brackets, followed by the parameters it came with.
+fn bracketed(address: &str) -> String {
+    let address = address.trim();
+    if address.starts_with('<') {
+        return String::from(address);
+    }
+    match address.split_once(' ') {
+        Some((address, parameters)) => format!("<{}> {}", address, parameters),
+        None => format!("<{}>", address),
+    }
+}
+
+fn c_string(value: &str) -> Vec<u8> {
+    let mut bytes = value.replace('\0', "").into_bytes();
+    bytes.push(0);
+    bytes
+}
+
+fn c_strings(values: &[&str]) -> Vec<u8> {
+    values.iter().flat_map(|value| c_string(value)).collect()
+}
+
+/// The NUL-terminated strings of `data`.
+fn split(data: &[u8]) -> Vec<String> {
+    data.split(|byte| *byte == 0)
+        .filter(|field| !field.is_empty())
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect()
+}
+
+fn field(fields: &[String], index: usize) -> String {
+    fields.get(index).cloned().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    /// Reads a packet as a filter does.
+    fn read_packet(stream: &mut impl Read) -> Option<(u8, Vec<u8>)> {
+        let mut length = [0; 4];
+        stream.read_exact(&mut length).ok()?;
+        let mut packet = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut packet).ok()?;
+        let data = packet.split_off(1);
+        Some((packet[0], data))
+    }
+
+    fn write_packet(stream: &mut impl Write, command: u8, data: &[u8]) {
+        stream
+            .write_all(&(data.len() as u32 + 1).to_be_bytes())
+            .unwrap();
+        stream.write_all(&[command]).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    /// A filter taking no part in HELO and not replying to headers, which adds a header
+    /// and deletes a recipient at the end of the message. Returns the commands it read.
+    fn filter() -> (String, thread::JoinHandle<Vec<char>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut commands = Vec::new();
+            while let Some((command, _)) = read_packet(&mut stream) {
+                commands.push(command as char);
+                match command {
+                    b'O' => {
+                        let mut data = Vec::new();
+                        for value in [6u32, 0x1 | 0x8, NO_HELO | NR_HEADER].iter() {
+                            data.extend_from_slice(&value.to_be_bytes());
+                        }
+                        write_packet(&mut stream, b'O', &data);
+                    }
+                    b'E' => {
+                        write_packet(&mut stream, b'h', b"X-Filtered\0yes\0");
+                        write_packet(&mut stream, b'-', b"<b@example.com>\0");
+                        write_packet(&mut stream, b'e', b"<other@example.com>\0");
+                        write_packet(&mut stream, b'p', b"");
+                        write_packet(&mut stream, b'a', b"");
+                    }
+                    b'R' => write_packet(&mut stream, b'y', b"550 5.1.1 No such user\0"),
+                    b'L' | b'A' | b'Q' | b'D' => {}
+                    _ => write_packet(&mut stream, b'c', b""),
+                }
+            }
+            commands
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_session() {
+        let (address, filter) = filter();
+        let mut session = Milter::new(&address).open().unwrap();
+        let client = "192.0.2.7:4321".parse().ok();
+        assert_eq!(
+            session.connect("client.example.net", client).unwrap(),
+            Response::Continue
+        );
+        assert_eq!(
+            session.helo("client.example.net").unwrap(),
+            Response::Continue
+        );
+        assert_eq!(
+            session.mail(&["<a@example.org>"]).unwrap(),
+            Response::Continue
+        );
+        assert_eq!(
+            session.rcpt(&["<c@example.com>"]).unwrap(),
+            Response::Reply(Reply::new(550, "5.1.1 No such user"))
+        );
+        let (response, modifications) = session
+            .end_of_message("Subject: hi\r\n\r\nhello\r\n")
+            .unwrap();
+        assert_eq!(response, Response::Accept);
+        // The filter did not negotiate changing the sender.
+        assert_eq!(
+            modifications,
+            vec![
+                Modification::AddHeader {
+                    name: String::from("X-Filtered"),
+                    value: String::from("yes")
+                },
+                Modification::DeleteRecipient(String::from("<b@example.com>"))
+            ]
+        );
+        session.quit().unwrap();
+        assert_eq!(
+            filter.join().unwrap(),
+            vec!['O', 'C', 'M', 'R', 'L', 'N', 'B', 'E', 'Q']
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![
+            String::from("<a@example.com>"),
+            String::from("<B@example.com> NOTIFY=NEVER"),
+            String::new(),
+        ];
+        mail.data = Some(String::from(
+            "Received: by mx\r\nSubject: hi\r\nSubject: again\r\n\r\nhello\r\n",
+        ));
+        apply(
+            &[
+                Modification::InsertHeader {
+                    index: 0,
+                    name: String::from("X-Spam"),
+                    value: String::from("no"),
+                },
+                Modification::ChangeHeader {
+                    index: 2,
+                    name: String::from("subject"),
+                    value: String::new(),
+                },
+                Modification::AddHeader {
+                    name: String::from("X-Scanned"),
+                    value: String::from("by\n\tclamav"),
+                },
+                Modification::DeleteRecipient(String::from("<b@example.com>")),
+                Modification::AddRecipient(String::from("c@example.com")),
+                Modification::ChangeSender(String::from("bounces@example.com")),
+                Modification::ReplaceBody(String::from("bye\r\n")),
+            ],
+            &mut mail,
+        );
+        assert_eq!(mail.rcpt_to, vec!["<a@example.com>", "<c@example.com>", ""]);
+        assert_eq!(mail.mail_from.as_deref(), Some("<bounces@example.com>"));
+        assert_eq!(
+            mail.data.as_deref(),
+            Some(
+                "X-Spam: no\r\nReceived: by mx\r\nSubject: hi\r\nX-Scanned: by\r\n\tclamav\r\n\
+                 \r\nbye\r\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::clock;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Deliver only over validated TLS to a listed MX.
+    Enforce,
+    /// Deliver anyway, the policy is only being tried out.
+    Testing,
+    /// The domain withdrew its policy.
+    None,
+}
+
+/// The policy file a domain publishes at
+/// `https://mta-sts.<domain>/.well-known/mta-sts.txt` (RFC 8461).
+#[derive(Clone, Debug, PartialEq)]
+pub struct StsPolicy {
+    pub mode: Mode,
+    /// The MX hosts mail may go to, `*.example.com` matching one label.
+    pub mx: Vec<String>,
+    /// For how many seconds the policy may be cached.
+    pub max_age: u64,
+}
+
+impl StsPolicy {
+    pub fn parse(text: &str) -> Result<StsPolicy, String> {
+        let mut version = None;
+        let mut mode = None;
+        let mut mx = Vec::new();
+        let mut max_age = None;
+        for line in text.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some(field) => field,
+                None => continue,
+            };
+            let value = value.trim();
+            match key.trim() {
+                "version" => version = Some(value),
+                "mode" => {
+                    mode = Some(match value {
+                        "enforce" => Mode::Enforce,
+                        "testing" => Mode::Testing,
+                        "none" => Mode::None,
+                        _ => return Err(format!("invalid mode {}", value)),
+                    })
+                }
+                "mx" => mx.push(value.to_lowercase()),
+                "max_age" => {
+                    max_age = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid max_age {}", value))?,
+                    )
+                }
+                _ => {}
+            }
+        }
+        if version != Some("STSv1") {
+            return Err(String::from("not an STSv1 policy"));
+        }
+        let mode = mode.ok_or("policy without mode")?;
+        if mode != Mode::None && mx.is_empty() {
+            return Err(String::from("policy without mx"));
+        }
+        Ok(StsPolicy {
+            mode,
+            mx,
+            max_age: max_age.ok_or("policy without max_age")?,
+        })
+    }
+
+    /// Whether `host` is one of the MX hosts the policy allows.
+    pub fn matches_mx(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.mx
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(suffix) => host
+                    .split_once('.')
+                    .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+                None => *pattern == host,
+            })
+    }
+}
+
+/// The policy id in the `_mta-sts.<domain>` TXT record, e.g. `v=STSv1; id=20240101`.
+pub fn record_id(txt: &str) -> Option<&str> {
+    let mut fields = txt.split(';').map(str::trim);
+    if fields.next() != Some("v=STSv1") {
+        return None;
+    }
+    fields
+        .find_map(|field| field.strip_prefix("id="))
+        .filter(|id| !id.is_empty())
+}
+
+/// Looks up what MTA-STS needs, which takes DNS and HTTPS the crate leaves to the embedder.
+pub trait PolicySource: Send + Sync {
+    /// The TXT record of `_mta-sts.<domain>`, `None` if there is none.
+    fn txt_record(&self, domain: &str) -> io::Result<Option<String>>;
+    /// The policy file of `domain`, fetched over HTTPS with a validated certificate.
+    fn policy_file(&self, domain: &str) -> io::Result<String>;
+}
+
+struct CachedPolicy {
+    id: String,
+    policy: StsPolicy,
+    expires: SystemTime,
+}
+
+/// Policies of the domains mail went to, kept for their `max_age` and fetched again only
+/// when the TXT record announces a new id. A cached policy stays in force when the record
+/// or the policy file cannot be fetched, so that an attacker cannot strip it.
+pub struct StsCache {
+    source: Arc<dyn PolicySource>,
+    policies: Mutex<HashMap<String, CachedPolicy>>,
+}
+
+impl StsCache {
+    pub fn new(source: Arc<dyn PolicySource>) -> StsCache {
+        StsCache {
+            source,
+            policies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The policy `domain` has in force, `None` if it has none. The cache is not locked
+    /// while the record and the policy file are fetched.
+    pub fn policy(&self, domain: &str) -> Option<StsPolicy> {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        let now = clock::now();
+        let cached = {
+            let mut policies = self.policies.lock().unwrap();
+            policies.retain(|_, cached| cached.expires > now);
+            policies
+                .get(&domain)
+                .map(|cached| (cached.id.clone(), cached.policy.clone()))
+        };
+        let id = match self.source.txt_record(&domain) {
+            Ok(Some(txt)) => record_id(&txt).map(String::from),
+            _ => None,
+        };
+        let id = match id {
+            Some(id) => id,
+            None => return cached.map(|(_, policy)| policy),
+        };
+        match cached {
+            Some((cached_id, policy)) if cached_id == id => return Some(policy),
+            _ => {}
+        }
+        match self
+            .source
+            .policy_file(&domain)
+            .ok()
+            .and_then(|text| StsPolicy::parse(&text).ok())
+        {
+            Some(policy) => {
+                let expires = now + Duration::from_secs(policy.max_age);
+                self.policies.lock().unwrap().insert(
+                    domain,
+                    CachedPolicy {
+                        id,
+                        policy: policy.clone(),
+                        expires,
+                    },
+                );
+                Some(policy)
+            }
+            None => cached.map(|(_, policy)| policy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+        thread,
+    };
+
+    const POLICY: &str = "version: STSv1\r\nmode: enforce\r\nmx: mail.example.com\r\n\
+                          mx: *.example.net\r\nmax_age: 86400\r\n";
+
+    #[test]
+    fn test_parse() {
+        let policy = StsPolicy::parse(POLICY).unwrap();
+        assert_eq!(policy.mode, Mode::Enforce);
+        assert_eq!(policy.max_age, 86400);
+        assert!(policy.matches_mx("MAIL.example.com."));
+        assert!(policy.matches_mx("mx1.example.net"));
+        assert!(!policy.matches_mx("a.mx1.example.net"));
+        assert!(!policy.matches_mx("example.net"));
+        assert!(StsPolicy::parse("version: STSv1\nmode: enforce\nmax_age: 1\n").is_err());
+        assert!(StsPolicy::parse("mode: testing\nmx: a\nmax_age: 1\n").is_err());
+
+        assert_eq!(record_id("v=STSv1; id=2024a"), Some("2024a"));
+        assert_eq!(record_id("v=spf1 -all"), None);
+    }
+
+    struct Source {
+        txt: Mutex<Option<String>>,
+        fetches: AtomicUsize,
+    }
+
+    impl PolicySource for Source {
+        fn txt_record(&self, _: &str) -> io::Result<Option<String>> {
+            Ok(self.txt.lock().unwrap().clone())
+        }
+
+        fn policy_file(&self, _: &str) -> io::Result<String> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Ok(String::from(POLICY))
+        }
+    }
+
+    #[test]
+    fn test_cache() {
+        let source = Arc::new(Source {
+            txt: Mutex::new(Some(String::from("v=STSv1; id=1"))),
+            fetches: AtomicUsize::new(0),
+        });
+        let cache = StsCache::new(Arc::clone(&source) as Arc<dyn PolicySource>);
+        assert_eq!(cache.policy("example.com").unwrap().mode, Mode::Enforce);
+        cache.policy("example.com").unwrap();
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 1);
+
+        // A missing record does not remove a cached policy.
+        *source.txt.lock().unwrap() = None;
+        assert!(cache.policy("example.com").is_some());
+        assert!(cache.policy("example.org").is_none());
+
+        *source.txt.lock().unwrap() = Some(String::from("v=STSv1; id=2"));
+        cache.policy("example.com").unwrap();
+        assert_eq!(source.fetches.load(Ordering::SeqCst), 2);
+    }
+
+    /// Holds the fetch of slow.example.com's policy until told to go on.
+    struct Slow {
+        fetching: Mutex<mpsc::Sender<()>>,
+        go_on: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl PolicySource for Slow {
+        fn txt_record(&self, _: &str) -> io::Result<Option<String>> {
+            Ok(Some(String::from("v=STSv1; id=1")))
+        }
+
+        fn policy_file(&self, domain: &str) -> io::Result<String> {
+            if domain == "slow.example.com" {
+                self.fetching.lock().unwrap().send(()).unwrap();
+                self.go_on.lock().unwrap().recv().unwrap();
+            }
+            Ok(String::from(POLICY))
+        }
+    }
+
+    #[test]
+    fn test_fetch_unlocked() {
+        let (fetching, started) = mpsc::channel();
+        let (go_on, waiting) = mpsc::channel();
+        let cache = Arc::new(StsCache::new(Arc::new(Slow {
+            fetching: Mutex::new(fetching),
+            go_on: Mutex::new(waiting),
+        })));
+        let slow = {
+            let cache = Arc::clone(&cache);
+            thread::spawn(move || cache.policy("slow.example.com"))
+        };
+        started.recv().unwrap();
+        assert!(cache.policy("example.com").is_some());
+        go_on.send(()).unwrap();
+        assert!(slow.join().unwrap().is_some());
+    }
+}
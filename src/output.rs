@@ -0,0 +1,115 @@
+use std::{error::Error, fmt, io, sync::Arc};
+
+use crate::{email::Mail, json::Json};
+
+#[cfg(feature = "server")]
+pub mod amqp;
+#[cfg(feature = "server")]
+pub mod chat;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "server")]
+pub mod nats;
+#[cfg(feature = "server")]
+pub mod pipe;
+#[cfg(feature = "server")]
+pub mod redis;
+#[cfg(feature = "server")]
+pub mod relay;
+pub mod sink;
+#[cfg(feature = "server")]
+pub mod webhook;
+
+/// Destination for accepted messages. Returning an error makes the server answer the
+/// end of DATA with a temporary failure instead of acknowledging the message.
+pub trait Output: Send + Sync {
+    fn deliver(&self, mail: &Mail) -> io::Result<()>;
+}
+
+/// Marks a delivery error as final: the message is refused with 554 instead of 451, so
+/// that the client bounces it rather than trying again.
+#[derive(Debug)]
+pub struct PermanentFailure(pub String);
+
+impl fmt::Display for PermanentFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for PermanentFailure {}
+
+pub fn permanent_failure(message: String) -> io::Error {
+    io::Error::other(PermanentFailure(message))
+}
+
+pub fn is_permanent(error: &io::Error) -> bool {
+    match error.get_ref() {
+        Some(inner) => match inner.downcast_ref::<PartialDelivery>() {
+            Some(partial) => is_permanent(&partial.error),
+            None => inner.is::<PermanentFailure>(),
+        },
+        None => false,
+    }
+}
+
+/// A delivery that failed after some recipients were accepted, which are not to be
+/// tried again nor bounced.
+#[derive(Debug)]
+pub struct PartialDelivery {
+    /// The recipients that were accepted, as in `Mail::rcpt_to`.
+    pub delivered: Vec<String>,
+    /// Why the rest were not.
+    pub error: io::Error,
+}
+
+impl fmt::Display for PartialDelivery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for PartialDelivery {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// `error`, noting that the recipients in `delivered` were accepted before it. Keeps
+/// the kind of `error`, and `error` itself when there are none.
+pub fn partial_delivery(delivered: Vec<String>, error: io::Error) -> io::Error {
+    if delivered.is_empty() {
+        return error;
+    }
+    io::Error::new(error.kind(), PartialDelivery { delivered, error })
+}
+
+/// The recipients accepted before `error`, if it is a partial delivery.
+pub fn delivered(error: &io::Error) -> &[String] {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<PartialDelivery>())
+        .map_or(&[], |partial| &partial.delivered)
+}
+
+impl<T: Output + ?Sized> Output for Arc<T> {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        (**self).deliver(mail)
+    }
+}
+
+pub fn envelope_json(mail: &Mail) -> Json {
+    Json::object(vec![
+        ("queue_id", Json::optional(&mail.queue_id)),
+        ("helo", Json::optional(&mail.helo)),
+        ("mail_from", Json::optional(&mail.mail_from)),
+        (
+            "rcpt_to",
+            Json::Array(mail.rcpt_to.iter().map(|rcpt| Json::string(rcpt)).collect()),
+        ),
+        (
+            "size",
+            Json::Number(mail.data.as_ref().map_or(0, |data| data.len()) as i64),
+        ),
+    ])
+}
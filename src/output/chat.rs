@@ -0,0 +1,106 @@
+use std::{io, thread, time::Duration};
+
+use crate::{
+    email::Mail,
+    headers::Message,
+    http::{self, Url},
+    json::Json,
+    output::Output,
+};
+
+pub enum ChatFormat {
+    /// `{"text": "..."}` as expected by Slack incoming webhooks.
+    Slack,
+    /// `{"content": "..."}` as expected by Discord webhooks.
+    Discord,
+    /// `{"queue_id", "from", "to", "subject", "size"}` for anything else.
+    Generic,
+}
+
+/// Posts a one-line summary of every accepted message to a chat webhook.
+///
+/// Only plain `http://` endpoints can be reached, so Slack and Discord webhooks have to be
+/// fronted by a forwarding proxy that terminates TLS.
+pub struct ChatNotifier {
+    pub url: Url,
+    pub format: ChatFormat,
+    pub timeout: Duration,
+}
+
+impl ChatNotifier {
+    pub fn new(url: &str, format: ChatFormat) -> io::Result<ChatNotifier> {
+        Ok(ChatNotifier {
+            url: Url::parse(url)?,
+            format,
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    fn payload(&self, mail: &Mail) -> Json {
+        let data = mail.data.as_deref().unwrap_or("");
+        let subject = Message::parse(data)
+            .get("Subject")
+            .map(String::from)
+            .unwrap_or_default();
+        let from = mail.mail_from.as_deref().unwrap_or("<>");
+        let to = mail.rcpt_to.join(", ");
+        let queue_id = mail.queue_id.as_deref().unwrap_or("-");
+
+        let summary = format!(
+            "New mail {} from {} to {}: \"{}\" ({} bytes)",
+            queue_id,
+            from,
+            to,
+            subject,
+            data.len()
+        );
+        match self.format {
+            ChatFormat::Slack => Json::object(vec![("text", Json::String(summary))]),
+            ChatFormat::Discord => Json::object(vec![("content", Json::String(summary))]),
+            ChatFormat::Generic => Json::object(vec![
+                ("queue_id", Json::string(queue_id)),
+                ("from", Json::string(from)),
+                ("to", Json::string(&to)),
+                ("subject", Json::String(subject)),
+                ("size", Json::Number(data.len() as i64)),
+            ]),
+        }
+    }
+}
+
+impl Output for ChatNotifier {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let body = self.payload(mail).to_string();
+        let url = self.url.clone();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            let headers = [("Content-Type", String::from("application/json"))];
+            match http::post(&url, &headers, body.as_bytes(), timeout) {
+                Ok(response) if (200..300).contains(&response.status) => {}
+                Ok(response) => crate::warn!("Chat notification answered {}", response.status),
+                Err(e) => crate::warn!("Chat notification failed: {}", e),
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slack_payload() {
+        let notifier = ChatNotifier::new("http://localhost/hook", ChatFormat::Slack).unwrap();
+        let mut mail = Mail::new();
+        mail.queue_id = Some(String::from("ABC123"));
+        mail.mail_from = Some(String::from("<app@example.com>"));
+        mail.rcpt_to = vec![String::from("<user@example.com>")];
+        mail.data = Some(String::from("Subject: Welcome!\r\n\r\nHi\r\n"));
+        assert_eq!(
+            notifier.payload(&mail).to_string(),
+            "{\"text\":\"New mail ABC123 from <app@example.com> to <user@example.com>: \
+             \\\"Welcome!\\\" (25 bytes)\"}"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    digest,
+    email::Mail,
+    output::{envelope_json, Output},
+};
+
+const PRODUCE: i16 = 0;
+const PRODUCE_VERSION: i16 = 3;
+
+/// Publishes accepted messages to a Kafka topic: the raw message is the record value and
+/// the envelope JSON travels in the `envelope` record header.
+///
+/// There is no metadata discovery, `broker` must be the leader for `partition`.
+pub struct Kafka {
+    pub broker: String,
+    pub topic: String,
+    pub partition: i32,
+    pub client_id: String,
+    /// Wait for the write to be acknowledged by all in-sync replicas before answering 250.
+    /// Otherwise the record is queued for a background thread to send and failures are
+    /// only logged.
+    pub require_ack: bool,
+    pub timeout: Duration,
+    /// How many records may wait for the background thread while the broker is slow;
+    /// messages beyond are deferred.
+    pub queue_length: usize,
+    connection: Arc<Mutex<Option<TcpStream>>>,
+    correlation_id: Arc<AtomicI32>,
+    /// Feeds the background thread, started by the first delivery without `require_ack`.
+    producer: Mutex<Option<SyncSender<Vec<u8>>>>,
+}
+
+impl Kafka {
+    pub fn new(broker: &str, topic: &str) -> Kafka {
+        Kafka {
+            broker: String::from(broker),
+            topic: String::from(topic),
+            partition: 0,
+            client_id: String::from("simple-smtp"),
+            require_ack: false,
+            timeout: Duration::from_secs(10),
+            queue_length: 1000,
+            connection: Arc::new(Mutex::new(None)),
+            correlation_id: Arc::new(AtomicI32::new(0)),
+            producer: Mutex::new(None),
+        }
+    }
+
+    /// Starts the thread sending queued records one after the other, which stops once the
+    /// output is dropped and the queue drained.
+    fn start_producer(&self) -> SyncSender<Vec<u8>> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(self.queue_length);
+        let connection = Arc::clone(&self.connection);
+        let broker = self.broker.clone();
+        let timeout = self.timeout;
+        thread::spawn(move || {
+            for request in receiver {
+                if let Err(e) = send(&connection, &broker, timeout, &request) {
+                    crate::error!("Kafka delivery to {} failed: {}", broker, e);
+                }
+            }
+        });
+        sender
+    }
+
+    fn produce_request(&self, mail: &Mail, correlation_id: i32, acks: i16) -> Vec<u8> {
+        let envelope = envelope_json(mail).to_string();
+        let value = mail.data.as_deref().unwrap_or("").as_bytes();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as i64);
+
+        let mut record = Vec::new();
+        record.push(0); // attributes
+        put_varint(&mut record, 0); // timestamp delta
+        put_varint(&mut record, 0); // offset delta
+        put_varint(&mut record, -1); // null key
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 1);
+        put_varint(&mut record, "envelope".len() as i64);
+        record.extend_from_slice(b"envelope");
+        put_varint(&mut record, envelope.len() as i64);
+        record.extend_from_slice(envelope.as_bytes());
+
+        // Everything after the CRC field, which the CRC covers.
+        let mut batch_tail = Vec::new();
+        batch_tail.extend_from_slice(&0i16.to_be_bytes()); // attributes
+        batch_tail.extend_from_slice(&0i32.to_be_bytes()); // last offset delta
+        batch_tail.extend_from_slice(&timestamp.to_be_bytes());
+        batch_tail.extend_from_slice(&timestamp.to_be_bytes());
+        batch_tail.extend_from_slice(&(-1i64).to_be_bytes()); // producer id
+        batch_tail.extend_from_slice(&(-1i16).to_be_bytes()); // producer epoch
+        batch_tail.extend_from_slice(&(-1i32).to_be_bytes()); // base sequence
+        batch_tail.extend_from_slice(&1i32.to_be_bytes()); // record count
+        put_varint(&mut batch_tail, record.len() as i64);
+        batch_tail.extend_from_slice(&record);
+
+        let mut batch = Vec::new();
+        batch.extend_from_slice(&0i64.to_be_bytes()); // base offset
+        batch.extend_from_slice(&((batch_tail.len() + 9) as i32).to_be_bytes());
+        batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition leader epoch
+        batch.push(2); // magic
+        batch.extend_from_slice(&digest::crc32c(&batch_tail).to_be_bytes());
+        batch.extend_from_slice(&batch_tail);
+
+        let mut request = Vec::new();
+        request.extend_from_slice(&PRODUCE.to_be_bytes());
+        request.extend_from_slice(&PRODUCE_VERSION.to_be_bytes());
+        request.extend_from_slice(&correlation_id.to_be_bytes());
+        put_string(&mut request, &self.client_id);
+        request.extend_from_slice(&(-1i16).to_be_bytes()); // no transactional id
+        request.extend_from_slice(&acks.to_be_bytes());
+        request.extend_from_slice(&(self.timeout.as_millis() as i32).to_be_bytes());
+        request.extend_from_slice(&1i32.to_be_bytes());
+        put_string(&mut request, &self.topic);
+        request.extend_from_slice(&1i32.to_be_bytes());
+        request.extend_from_slice(&self.partition.to_be_bytes());
+        request.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+        request.extend_from_slice(&batch);
+
+        let mut framed = (request.len() as i32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&request);
+        framed
+    }
+}
+
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+        buffer.push((zigzag as u8) | 0x80);
+        zigzag >>= 7;
+    }
+    buffer.push(zigzag as u8);
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as i16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+fn send(
+    connection: &Mutex<Option<TcpStream>>,
+    broker: &str,
+    timeout: Duration,
+    request: &[u8],
+) -> io::Result<()> {
+    let mut connection = connection.lock().unwrap();
+    if connection.is_none() {
+        let stream = TcpStream::connect(broker)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        *connection = Some(stream);
+    }
+    let result = exchange(connection.as_mut().unwrap(), request);
+    if result.is_err() {
+        *connection = None;
+    }
+    result
+}
+
+fn exchange(stream: &mut TcpStream, request: &[u8]) -> io::Result<()> {
+    stream.write_all(request)?;
+
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let mut response = vec![0u8; i32::from_be_bytes(length).max(0) as usize];
+    stream.read_exact(&mut response)?;
+
+    // correlation id, topic count, topic name, partition count, partition id, error code
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid produce response");
+    let topic_length = i16::from_be_bytes([
+        *response.get(8).ok_or_else(invalid)?,
+        *response.get(9).ok_or_else(invalid)?,
+    ]);
+    let offset = 10 + topic_length.max(0) as usize + 4 + 4;
+    let error_code = i16::from_be_bytes([
+        *response.get(offset).ok_or_else(invalid)?,
+        *response.get(offset + 1).ok_or_else(invalid)?,
+    ]);
+    if error_code != 0 {
+        return Err(io::Error::other(format!(
+            "Kafka produce failed with error code {}",
+            error_code
+        )));
+    }
+    Ok(())
+}
+
+impl Output for Kafka {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let correlation_id = self.correlation_id.fetch_add(1, Ordering::Relaxed);
+        if self.require_ack {
+            let request = self.produce_request(mail, correlation_id, -1);
+            return send(&self.connection, &self.broker, self.timeout, &request);
+        }
+
+        let request = self.produce_request(mail, correlation_id, 1);
+        let mut producer = self.producer.lock().unwrap();
+        let sender = producer.get_or_insert_with(|| self.start_producer());
+        match sender.try_send(request) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::other(format!(
+                "Kafka queue to {} is full",
+                self.broker
+            ))),
+            // The thread is gone, the next delivery starts another.
+            Err(TrySendError::Disconnected(_)) => {
+                *producer = None;
+                Err(io::Error::other("Kafka producer stopped"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut buffer = Vec::new();
+        put_varint(&mut buffer, -1);
+        put_varint(&mut buffer, 1);
+        put_varint(&mut buffer, 300);
+        assert_eq!(buffer, vec![0x01, 0x02, 0xd8, 0x04]);
+    }
+
+    /// Reads the produce requests of one connection, answering the first `answered`, and
+    /// returns their correlation IDs once the client is gone.
+    fn broker(answered: usize) -> (String, thread::JoinHandle<Vec<i32>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut ids = Vec::new();
+            let mut length = [0u8; 4];
+            while stream.read_exact(&mut length).is_ok() {
+                let mut request = vec![0u8; i32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut request).unwrap();
+                let id = [request[4], request[5], request[6], request[7]];
+                ids.push(i32::from_be_bytes(id));
+                if ids.len() > answered {
+                    continue;
+                }
+                let mut response = id.to_vec();
+                response.extend_from_slice(&1i32.to_be_bytes());
+                put_string(&mut response, "mail");
+                response.extend_from_slice(&1i32.to_be_bytes());
+                response.extend_from_slice(&0i32.to_be_bytes());
+                response.extend_from_slice(&0i16.to_be_bytes());
+                stream
+                    .write_all(&(response.len() as i32).to_be_bytes())
+                    .unwrap();
+                stream.write_all(&response).unwrap();
+            }
+            ids
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_fire_and_forget() {
+        let (address, broker) = broker(usize::MAX);
+        let kafka = Kafka::new(&address, "mail");
+        let mut mail = Mail::new();
+        mail.data = Some(String::from("hi"));
+        for _ in 0..5 {
+            kafka.deliver(&mail).unwrap();
+        }
+        // Dropping the output lets the thread drain the queue and close the connection.
+        drop(kafka);
+        assert_eq!(broker.join().unwrap(), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_queue_full() {
+        // Never answering, so that the thread waits on the first record.
+        let (address, _broker) = broker(0);
+        let mut kafka = Kafka::new(&address, "mail");
+        kafka.queue_length = 1;
+        let mut mail = Mail::new();
+        mail.data = Some(String::from("hi"));
+        kafka.deliver(&mail).unwrap();
+        let e = (0..5)
+            .find_map(|_| kafka.deliver(&mail).err())
+            .expect("the queue never filled");
+        assert_eq!(e.to_string(), format!("Kafka queue to {} is full", address));
+        assert!(!crate::output::is_permanent(&e));
+    }
+
+    #[test]
+    fn test_produce_request() {
+        let kafka = Kafka::new("localhost:9092", "mail");
+        let mut mail = Mail::new();
+        mail.data = Some(String::from("hi"));
+        let request = kafka.produce_request(&mail, 7, -1);
+
+        assert_eq!(
+            i32::from_be_bytes([request[0], request[1], request[2], request[3]]) as usize,
+            request.len() - 4
+        );
+        assert_eq!(&request[4..12], &[0, 0, 0, 3, 0, 0, 0, 7]);
+        // size, request header, client id, transactional id, acks, timeout, topic array,
+        // partition array and the record batch size precede the batch.
+        let batch_start = 4 + 8 + (2 + 11) + 2 + 2 + 4 + 4 + (2 + 4) + 4 + 4 + 4;
+        let batch_crc_offset = batch_start + 17;
+        let crc = u32::from_be_bytes([
+            request[batch_crc_offset],
+            request[batch_crc_offset + 1],
+            request[batch_crc_offset + 2],
+            request[batch_crc_offset + 3],
+        ]);
+        assert_eq!(crc, digest::crc32c(&request[batch_crc_offset + 4..]));
+    }
+}
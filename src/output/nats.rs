@@ -0,0 +1,208 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    digest,
+    email::Mail,
+    output::{envelope_json, Output},
+};
+
+const INBOX: &str = "_INBOX.simple-smtp";
+
+/// Publishes accepted messages to a NATS JetStream subject. Every publish waits for the
+/// stream's acknowledgement and is retried on failure; retries carry the same
+/// `Nats-Msg-Id`, so JetStream de-duplicates them and delivery is at-least-once.
+pub struct Nats {
+    pub server: String,
+    pub subject: String,
+    pub max_attempts: u32,
+    pub timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Nats {
+    pub fn new(server: &str, subject: &str) -> Nats {
+        Nats {
+            server: String::from(server),
+            subject: String::from(subject),
+            max_attempts: 3,
+            timeout: Duration::from_secs(5),
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let stream = TcpStream::connect(self.server.as_str())?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+
+        let info = connection.read_line()?;
+        if !info.starts_with("INFO ") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected NATS greeting: {}", info),
+            ));
+        }
+        if !info.contains("\"headers\":true") {
+            return Err(io::Error::other("NATS server does not support headers"));
+        }
+        connection.writer.write_all(
+            format!(
+                "CONNECT {{\"verbose\":false,\"pedantic\":false,\"headers\":true,\"name\":\"simple-smtp\"}}\r\n\
+                 SUB {}.* 1\r\n",
+                INBOX
+            )
+            .as_bytes(),
+        )?;
+        Ok(connection)
+    }
+}
+
+fn publish_frame(
+    subject: &str,
+    reply_to: &str,
+    headers: &[(&str, String)],
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut header_block = String::from("NATS/1.0\r\n");
+    for (name, value) in headers.iter() {
+        header_block.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    header_block.push_str("\r\n");
+
+    let mut frame = format!(
+        "HPUB {} {} {} {}\r\n",
+        subject,
+        reply_to,
+        header_block.len(),
+        header_block.len() + payload.len()
+    )
+    .into_bytes();
+    frame.extend_from_slice(header_block.as_bytes());
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "NATS server closed the connection",
+            ));
+        }
+        Ok(String::from(line.trim_end()))
+    }
+
+    /// Publishes and waits for the JetStream acknowledgement on `reply_to`.
+    fn publish(&mut self, frame: &[u8], reply_to: &str) -> io::Result<()> {
+        self.writer.write_all(frame)?;
+        loop {
+            let line = self.read_line()?;
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("PING") => self.writer.write_all(b"PONG\r\n")?,
+                Some("-ERR") => return Err(io::Error::other(line.clone())),
+                Some(verb @ "MSG") | Some(verb @ "HMSG") => {
+                    let fields: Vec<&str> = parts.collect();
+                    let subject = fields.first().copied().unwrap_or("");
+                    let mut sizes = fields.iter().rev().map(|n| n.parse().unwrap_or(0));
+                    let total: usize = sizes.next().unwrap_or(0);
+                    let header_size = if verb == "HMSG" {
+                        sizes.next().unwrap_or(0)
+                    } else {
+                        0
+                    };
+                    let mut payload = vec![0u8; total + 2];
+                    self.reader.read_exact(&mut payload)?;
+                    if subject != reply_to {
+                        continue;
+                    }
+                    let ack = String::from_utf8_lossy(&payload[header_size.min(total)..total]);
+                    if ack.contains("\"error\"") {
+                        return Err(io::Error::other(format!(
+                            "JetStream rejected the message: {}",
+                            ack
+                        )));
+                    }
+                    return Ok(());
+                }
+                _ => continue,
+            }
+        }
+    }
+}
+
+impl Output for Nats {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let data = mail.data.as_deref().unwrap_or("");
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos());
+        let digest = digest::to_hex(&digest::sha256(format!("{}{}", nanos, data).as_bytes()));
+        let id = String::from(&digest[..32]);
+        let reply_to = format!("{}.{}", INBOX, id);
+        let headers = vec![
+            ("Nats-Msg-Id", id),
+            ("Content-Type", String::from("message/rfc822")),
+            ("Envelope", envelope_json(mail).to_string()),
+        ];
+        let frame = publish_frame(&self.subject, &reply_to, &headers, data.as_bytes());
+
+        let mut connection = self.connection.lock().unwrap();
+        let mut last_error = io::Error::other("no publish attempts configured");
+        for _ in 0..self.max_attempts {
+            if connection.is_none() {
+                match self.connect() {
+                    Ok(connected) => *connection = Some(connected),
+                    Err(e) => {
+                        last_error = e;
+                        continue;
+                    }
+                }
+            }
+            match connection.as_mut().unwrap().publish(&frame, &reply_to) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    *connection = None;
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_frame() {
+        let frame = publish_frame(
+            "mail.in",
+            "_INBOX.1",
+            &[("Nats-Msg-Id", String::from("abc"))],
+            b"hello",
+        );
+        assert_eq!(
+            String::from_utf8(frame).unwrap(),
+            "HPUB mail.in _INBOX.1 30 35\r\nNATS/1.0\r\nNats-Msg-Id: abc\r\n\r\nhello\r\n"
+        );
+    }
+}
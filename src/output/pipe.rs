@@ -0,0 +1,149 @@
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use crate::{
+    email::Mail,
+    output::{permanent_failure, Output},
+};
+
+/// `EX_TEMPFAIL` of sysexits.h, the exit code asking to try again later.
+const EX_TEMPFAIL: i32 = 75;
+
+/// Hands accepted messages to a local delivery command such as procmail or maildrop,
+/// writing the message to its standard input.
+///
+/// In `args`, `{sender}` and `{recipient}` are replaced by the envelope addresses without
+/// angle brackets; with `{recipient}` the command runs once per recipient. The command
+/// also finds them in the `SENDER`, `RECIPIENT` (space separated), `QUEUE_ID` and `HELO`
+/// environment variables.
+///
+/// Exiting with 0 delivers the message and with 75 defers it; any other code, or a
+/// signal, bounces it.
+pub struct Pipe {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl Pipe {
+    pub fn new(command: &str, args: &[&str]) -> Pipe {
+        Pipe {
+            command: String::from(command),
+            args: args.iter().map(|arg| String::from(*arg)).collect(),
+        }
+    }
+
+    /// The file `command` runs, looked up in `PATH` as `Command` does when it is a bare
+    /// name; `None` when there is none.
+    pub fn path(&self) -> Option<PathBuf> {
+        if self.command.contains('/') {
+            return Some(PathBuf::from(&self.command));
+        }
+        std::env::split_paths(&std::env::var_os("PATH")?)
+            .map(|directory| directory.join(&self.command))
+            .find(|path| path.is_file())
+    }
+
+    fn run(&self, mail: &Mail, sender: &str, recipients: &[String]) -> io::Result<()> {
+        let recipient = recipients.join(" ");
+        let args = self.args.iter().map(|arg| {
+            arg.replace("{sender}", sender)
+                .replace("{recipient}", &recipient)
+        });
+        let mut child = Command::new(&self.command)
+            .args(args)
+            .env("SENDER", sender)
+            .env("RECIPIENT", &recipient)
+            .env("QUEUE_ID", mail.queue_id.as_deref().unwrap_or(""))
+            .env("HELO", mail.helo.as_deref().unwrap_or(""))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A command that does not read its input can still exit successfully.
+            match stdin.write_all(mail.data.as_deref().unwrap_or("").as_bytes()) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                _ => {}
+            }
+        }
+        let status = child.wait()?;
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(EX_TEMPFAIL) => Err(io::Error::other(format!(
+                "{} asked to try again later",
+                self.command
+            ))),
+            _ => Err(permanent_failure(format!(
+                "{} exited with {}",
+                self.command, status
+            ))),
+        }
+    }
+}
+
+impl Output for Pipe {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let sender = bare(mail.mail_from.as_deref().unwrap_or(""));
+        let recipients: Vec<String> = mail
+            .rcpt_to
+            .iter()
+            .filter(|rcpt| !rcpt.is_empty())
+            .map(|rcpt| bare(rcpt))
+            .collect();
+        if self.args.iter().any(|arg| arg.contains("{recipient}")) {
+            for recipient in recipients.iter() {
+                self.run(mail, &sender, std::slice::from_ref(recipient))?;
+            }
+            Ok(())
+        } else {
+            self.run(mail, &sender, &recipients)
+        }
+    }
+}
+
+fn bare(address: &str) -> String {
+    let address = address.trim().trim_start_matches('<');
+    String::from(address.split('>').next().unwrap_or(""))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::output::is_permanent;
+
+    fn mail() -> Mail {
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.com>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from("Subject: hi\n\nhi\n"));
+        mail
+    }
+
+    #[test]
+    fn test_deliver() {
+        let pipe = Pipe::new(
+            "sh",
+            &[
+                "-c",
+                "test \"$1 $SENDER\" = 'b@example.com a@example.com' && grep -q Subject",
+                "sh",
+                "{recipient}",
+            ],
+        );
+        pipe.deliver(&mail()).unwrap();
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let error = Pipe::new("sh", &["-c", "exit 75"])
+            .deliver(&mail())
+            .unwrap_err();
+        assert!(!is_permanent(&error));
+        let error = Pipe::new("sh", &["-c", "exit 67"])
+            .deliver(&mail())
+            .unwrap_err();
+        assert!(is_permanent(&error));
+    }
+}
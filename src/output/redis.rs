@@ -0,0 +1,199 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    email::Mail,
+    json::Json,
+    output::{envelope_json, Output},
+};
+
+pub enum RedisTarget {
+    /// `XADD key MAXLEN ~ <max_length> * envelope <json> data <message>`
+    Stream,
+    /// `LPUSH key <json>` followed by `LTRIM` to `max_length` entries.
+    List,
+}
+
+/// Pushes accepted messages into a Redis stream or list.
+pub struct Redis {
+    pub address: String,
+    pub password: Option<String>,
+    pub database: u32,
+    pub key: String,
+    pub target: RedisTarget,
+    /// Oldest entries are trimmed once the key holds more than this; 0 keeps everything.
+    pub max_length: usize,
+    pub timeout: Duration,
+    connection: Mutex<Option<Connection>>,
+}
+
+impl Redis {
+    pub fn new(address: &str, key: &str) -> Redis {
+        Redis {
+            address: String::from(address),
+            password: None,
+            database: 0,
+            key: String::from(key),
+            target: RedisTarget::Stream,
+            max_length: 10000,
+            timeout: Duration::from_secs(5),
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let stream = TcpStream::connect(self.address.as_str())?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        if let Some(password) = &self.password {
+            connection.command(&[b"AUTH", password.as_bytes()])?;
+        }
+        if self.database != 0 {
+            connection.command(&[b"SELECT", self.database.to_string().as_bytes()])?;
+        }
+        Ok(connection)
+    }
+
+    fn commands(&self, mail: &Mail) -> Vec<Vec<Vec<u8>>> {
+        let envelope = envelope_json(mail).to_string();
+        let data = mail.data.as_deref().unwrap_or("");
+        let key = self.key.as_bytes().to_vec();
+        match self.target {
+            RedisTarget::Stream => {
+                let mut xadd = vec![b"XADD".to_vec(), key];
+                if self.max_length > 0 {
+                    xadd.push(b"MAXLEN".to_vec());
+                    xadd.push(b"~".to_vec());
+                    xadd.push(self.max_length.to_string().into_bytes());
+                }
+                xadd.extend(vec![
+                    b"*".to_vec(),
+                    b"envelope".to_vec(),
+                    envelope.into_bytes(),
+                    b"data".to_vec(),
+                    data.as_bytes().to_vec(),
+                ]);
+                vec![xadd]
+            }
+            RedisTarget::List => {
+                let entry = Json::object(vec![
+                    ("envelope", envelope_json(mail)),
+                    ("data", Json::string(data)),
+                ]);
+                let mut commands = vec![vec![
+                    b"LPUSH".to_vec(),
+                    key.clone(),
+                    entry.to_string().into_bytes(),
+                ]];
+                if self.max_length > 0 {
+                    commands.push(vec![
+                        b"LTRIM".to_vec(),
+                        key,
+                        b"0".to_vec(),
+                        (self.max_length - 1).to_string().into_bytes(),
+                    ]);
+                }
+                commands
+            }
+        }
+    }
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args.iter() {
+        buffer.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buffer.extend_from_slice(arg);
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn command(&mut self, args: &[&[u8]]) -> io::Result<()> {
+        self.writer.write_all(&encode_command(args))?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Redis closed the connection",
+            ));
+        }
+        let line = line.trim_end();
+        match line.chars().next() {
+            Some('-') => Err(io::Error::other(format!("Redis error: {}", &line[1..]))),
+            Some('$') => {
+                // Bulk reply (the new stream entry id), skip its payload.
+                let length: i64 = line[1..].parse().unwrap_or(-1);
+                if length >= 0 {
+                    let mut payload = vec![0u8; length as usize + 2];
+                    self.reader.read_exact(&mut payload)?;
+                }
+                Ok(())
+            }
+            Some('+') | Some(':') => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected Redis reply: {}", line),
+            )),
+        }
+    }
+}
+
+impl Output for Redis {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+        for command in self.commands(mail) {
+            let args: Vec<&[u8]> = command.iter().map(|arg| arg.as_slice()).collect();
+            if let Err(e) = connection.as_mut().unwrap().command(&args) {
+                *connection = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(&[b"LPUSH", b"mail", b"hi"]),
+            b"*3\r\n$5\r\nLPUSH\r\n$4\r\nmail\r\n$2\r\nhi\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_stream_command() {
+        let redis = Redis::new("localhost:6379", "mail");
+        let mut mail = Mail::new();
+        mail.data = Some(String::from("hi"));
+        let commands = redis.commands(&mail);
+        let args: Vec<String> = commands[0]
+            .iter()
+            .map(|arg| String::from_utf8(arg.clone()).unwrap())
+            .collect();
+        assert_eq!(args[..6], ["XADD", "mail", "MAXLEN", "~", "10000", "*"]);
+        assert_eq!(args[8..], ["data", "hi"]);
+    }
+}
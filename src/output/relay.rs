@@ -0,0 +1,884 @@
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    clock,
+    connect::{self, Socks5},
+    dane::Dane,
+    deliver_by::Mode,
+    dns::Resolver,
+    dsn,
+    email::Mail,
+    encoding::base64_encode,
+    mta_sts::{self, StsCache},
+    output::{partial_delivery, permanent_failure, Output},
+    tls_rpt::{self, TlsReports},
+    verp,
+};
+
+/// The replies to RCPT TO that accept the recipient, 251 when the server forwards it.
+const RCPT_ACCEPTED: &[u16] = &[250, 251];
+
+/// Hands accepted messages on to another SMTP server, e.g. a smarthost. A refusal by that
+/// server fails the delivery, which the client of this server sees as a temporary failure.
+/// The DSN parameters of the envelope, the deliver-by time and priority, and with XFORWARD
+/// the client the message came from, are passed on when that server supports them. The
+/// connection is never encrypted, so messages sent with REQUIRETLS are returned rather
+/// than relayed, and ones to domains enforcing MTA-STS or to a host with DANE records are
+/// deferred.
+pub struct Relay {
+    pub host: String,
+    pub port: u16,
+    /// The name this server gives in its EHLO.
+    pub helo: String,
+    pub timeout: Duration,
+    /// Looks up the addresses of `host` instead of the system resolver.
+    pub resolver: Option<Arc<Resolver>>,
+    /// Connects through this proxy, which then resolves `host`, instead of directly.
+    pub proxy: Option<Socks5>,
+    /// The local addresses mail goes out from, at most one IPv4 and one IPv6 address,
+    /// when not connecting through `proxy`. The system picks one when empty.
+    pub source_addresses: Vec<IpAddr>,
+    /// Whether each recipient is sent a copy of its own, with the recipient encoded in the
+    /// envelope sender, see `verp`.
+    pub verp: bool,
+    /// Whether that server trusts this one to say who submitted each message, so that the
+    /// `AUTH` parameter of MAIL FROM is passed on when it supports AUTH.
+    pub send_auth: bool,
+    /// Logged in with after EHLO. They are only sent over TLS, which the relay
+    /// connection lacks, unless `insecure_auth` is set.
+    pub credentials: Option<Credentials>,
+    /// Sends `credentials` over the unencrypted connection, for a relay on the same host
+    /// or a network where nobody can listen in.
+    pub insecure_auth: bool,
+    /// Where the MTA-STS policies of recipient domains are looked up; without it they are
+    /// not checked.
+    pub mta_sts: Option<Arc<StsCache>>,
+    /// Checks the relay host for TLSA records; without it they are not looked up. As the
+    /// relay connection lacks TLS, mail waits while the host has usable records.
+    pub dane: Option<Dane>,
+    /// Where sessions are counted for TLS reporting: failed for recipient domains with an
+    /// MTA-STS policy, successful for the others once connected.
+    pub tls_reports: Option<Arc<TlsReports>>,
+    /// How long a connection is kept open after a delivery, to be used again by the next
+    /// one. Zero closes it right away.
+    pub idle_timeout: Duration,
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+/// A SASL mechanism of AUTH (RFC 4954).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mechanism {
+    Plain,
+    Login,
+    /// An OAuth 2.0 bearer token as the secret, as Gmail and Outlook take it.
+    XOAuth2,
+}
+
+impl Mechanism {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::Login => "LOGIN",
+            Mechanism::XOAuth2 => "XOAUTH2",
+        }
+    }
+}
+
+/// What this server logs in to the relay with.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user: String,
+    /// The password, or the access token with XOAUTH2.
+    pub secret: String,
+    /// The mechanism to use, the first the relay offers of PLAIN and LOGIN when unset.
+    pub mechanism: Option<Mechanism>,
+}
+
+struct IdleConnection {
+    connection: Connection,
+    capabilities: Vec<String>,
+    since: SystemTime,
+}
+
+impl Relay {
+    pub fn new(host: &str, port: u16, helo: &str) -> Relay {
+        Relay {
+            host: String::from(host),
+            port,
+            helo: String::from(helo),
+            timeout: Duration::from_secs(30),
+            resolver: None,
+            proxy: None,
+            source_addresses: Vec::new(),
+            verp: false,
+            send_auth: false,
+            credentials: None,
+            insecure_auth: false,
+            mta_sts: None,
+            dane: None,
+            tls_reports: None,
+            idle_timeout: Duration::ZERO,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A connection that greeted this server, with the capabilities it gave in reply to
+    /// EHLO: an idle one that still answers RSET, or a new one. Idle connections found
+    /// expired on the way are closed.
+    fn checkout(&self) -> io::Result<(Connection, Vec<String>)> {
+        if self.credentials.is_some() && !self.insecure_auth {
+            return Err(io::Error::other(
+                "relay credentials require TLS, which the relay connection lacks",
+            ));
+        }
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            let mut idle = match idle {
+                Some(idle) => idle,
+                None => break,
+            };
+            if clock::elapsed(idle.since) > self.idle_timeout {
+                let _ = idle.connection.command("QUIT", 221);
+                continue;
+            }
+            if idle.connection.command("RSET", 250).is_ok() {
+                return Ok((idle.connection, idle.capabilities));
+            }
+        }
+        let stream = match (&self.proxy, &self.resolver) {
+            (Some(proxy), _) => proxy.connect(&self.host, self.port, self.timeout)?,
+            (None, Some(resolver)) => {
+                let addresses = resolver
+                    .ip_addrs(&self.host)?
+                    .into_iter()
+                    .map(|address| SocketAddr::new(address, self.port))
+                    .collect();
+                connect::connect_any_from(
+                    &connect::interleave(addresses),
+                    &self.source_addresses,
+                    self.timeout,
+                )?
+            }
+            (None, None) => {
+                connect::connect_from(&self.host, self.port, &self.source_addresses, self.timeout)?
+            }
+        };
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        connection.expect(220)?;
+        let capabilities = connection.command(&format!("EHLO {}", self.helo), 250)?;
+        if let Some(credentials) = &self.credentials {
+            self.authenticate(&mut connection, &capabilities, credentials)?;
+        }
+        Ok((connection, capabilities))
+    }
+
+    fn authenticate(
+        &self,
+        connection: &mut Connection,
+        capabilities: &[String],
+        credentials: &Credentials,
+    ) -> io::Result<()> {
+        let offered: Vec<String> = capability(capabilities, "AUTH")
+            .unwrap_or("")
+            .split_whitespace()
+            .map(str::to_uppercase)
+            .collect();
+        let mechanism = match credentials.mechanism {
+            Some(mechanism) => mechanism,
+            None => [Mechanism::Plain, Mechanism::Login]
+                .iter()
+                .copied()
+                .find(|mechanism| offered.iter().any(|name| name == mechanism.as_str()))
+                .ok_or_else(|| io::Error::other("relay offers no known AUTH mechanism"))?,
+        };
+        let (user, secret) = (&credentials.user, &credentials.secret);
+        match mechanism {
+            Mechanism::Plain => {
+                let response = base64_encode(format!("\0{}\0{}", user, secret).as_bytes());
+                connection.command(&format!("AUTH PLAIN {}", response), 235)?;
+            }
+            Mechanism::Login => {
+                connection.command("AUTH LOGIN", 334)?;
+                connection.command(&base64_encode(user.as_bytes()), 334)?;
+                connection.command(&base64_encode(secret.as_bytes()), 235)?;
+            }
+            Mechanism::XOAuth2 => {
+                let response = format!("user={}\x01auth=Bearer {}\x01\x01", user, secret);
+                let command = format!("AUTH XOAUTH2 {}", base64_encode(response.as_bytes()));
+                if let Err(e) = connection.command(&command, 235) {
+                    // A refused token comes with a 334 challenge that an empty line ends.
+                    let _ = connection.command("", 535);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps `connection` for the next delivery, or ends the session when connections are
+    /// not kept.
+    fn checkin(&self, mut connection: Connection, capabilities: Vec<String>) {
+        if self.idle_timeout == Duration::ZERO {
+            // The message is accepted at this point, a failing QUIT changes nothing.
+            let _ = connection.command("QUIT", 221);
+            return;
+        }
+        self.idle.lock().unwrap().push(IdleConnection {
+            connection,
+            capabilities,
+            since: clock::now(),
+        });
+    }
+
+    /// Fails if a recipient domain of `mail` enforces an MTA-STS policy this relay cannot
+    /// meet. That takes validated TLS to one of the MX hosts it lists, so such messages
+    /// wait for the policy to change rather than go out in the clear. Each domain with a
+    /// policy, enforced or tested, counts as a failed session for TLS reporting; the
+    /// domains without one are returned.
+    fn check_mta_sts<'a>(&self, mail: &'a Mail) -> io::Result<Vec<&'a str>> {
+        let mut domains: Vec<&str> = Vec::new();
+        for rcpt in mail.rcpt_to.iter() {
+            let address = dsn::split_parameters(rcpt).0;
+            if let Some((_, domain)) = address.trim_end_matches('>').rsplit_once('@') {
+                if !domains.iter().any(|seen| seen.eq_ignore_ascii_case(domain)) {
+                    domains.push(domain);
+                }
+            }
+        }
+        let cache = match &self.mta_sts {
+            Some(cache) => cache,
+            None => return Ok(domains),
+        };
+        let mut unprotected = Vec::new();
+        let mut refusal = None;
+        for domain in domains {
+            let policy = match cache.policy(domain) {
+                Some(policy) if policy.mode != mta_sts::Mode::None => policy,
+                _ => {
+                    unprotected.push(domain);
+                    continue;
+                }
+            };
+            let (result, reason) = if policy.matches_mx(&self.host) {
+                (tls_rpt::ResultType::StarttlsNotSupported, "requires TLS")
+            } else {
+                (
+                    tls_rpt::ResultType::ValidationFailure,
+                    "does not list the relay host",
+                )
+            };
+            if let Some(reports) = &self.tls_reports {
+                reports.failure(domain, tls_rpt::PolicyType::Sts, result, &self.host);
+            }
+            if policy.mode == mta_sts::Mode::Enforce && refusal.is_none() {
+                refusal = Some(io::Error::other(format!(
+                    "MTA-STS policy of {} {}",
+                    domain, reason
+                )));
+            }
+        }
+        match refusal {
+            Some(error) => Err(error),
+            None => Ok(unprotected),
+        }
+    }
+
+    /// Sends `mail` from `from` to `recipients`, with what `capabilities` allows of its
+    /// envelope.
+    fn transaction(
+        &self,
+        connection: &mut Connection,
+        capabilities: &[String],
+        mail: &Mail,
+        from: &str,
+        recipients: &[&String],
+    ) -> io::Result<()> {
+        let supports_dsn = capability(capabilities, "DSN").is_some();
+        if let Some(attributes) = capability(capabilities, "XFORWARD") {
+            connection.command(&xforward_command(mail, attributes), 250)?;
+        }
+        let mut command = format!("MAIL FROM:{}", bracketed(from));
+        if supports_dsn {
+            command.push_str(&mail.dsn.mail_parameters());
+        }
+        if let Some(deliver_by) = &mail.deliver_by {
+            if capability(capabilities, "DELIVERBY").is_some() {
+                command.push(' ');
+                command.push_str(&deliver_by.to_parameter(clock::now()));
+            } else if deliver_by.mode == Mode::Return {
+                // RFC 2852 has the message returned rather than relayed without its deadline.
+                return Err(permanent_failure(String::from(
+                    "relay does not support DELIVERBY",
+                )));
+            }
+        }
+        if self.send_auth && capability(capabilities, "AUTH").is_some() {
+            let submitter = mail
+                .auth
+                .as_deref()
+                .map_or_else(|| String::from("<>"), dsn::xtext_encode);
+            command.push_str(&format!(" AUTH={}", submitter));
+        }
+        if mail.priority != 0 && capability(capabilities, "MT-PRIORITY").is_some() {
+            command.push_str(&format!(" MT-PRIORITY={}", mail.priority));
+        }
+        let rcpt_commands = recipients.iter().map(|rcpt| {
+            let mut command = format!("RCPT TO:{}", bracketed(dsn::split_parameters(rcpt).0));
+            if supports_dsn {
+                command.push_str(&mail.dsn.rcpt_parameters(rcpt));
+            }
+            command
+        });
+        if capability(capabilities, "PIPELINING").is_some() {
+            // MAIL FROM and every RCPT TO go out together, the replies are read after.
+            let mut batch = format!("{}\r\n", command);
+            for command in rcpt_commands {
+                batch.push_str(&command);
+                batch.push_str("\r\n");
+            }
+            connection.writer.write_all(batch.as_bytes())?;
+            connection.expect(250)?;
+            for _ in recipients {
+                connection.expect_any(RCPT_ACCEPTED)?;
+            }
+        } else {
+            connection.command(&command, 250)?;
+            for command in rcpt_commands {
+                connection
+                    .writer
+                    .write_all(format!("{}\r\n", command).as_bytes())?;
+                connection.expect_any(RCPT_ACCEPTED)?;
+            }
+        }
+        connection.command("DATA", 354)?;
+        connection
+            .writer
+            .write_all(&encode_data(mail.data.as_deref().unwrap_or("")))?;
+        connection.expect(250)?;
+        Ok(())
+    }
+
+    /// Asks the relay whether it takes mail for `address`, with `MAIL FROM:<>` and
+    /// `RCPT TO` but no message: a 2xx reply means it does and a 5xx one that it does
+    /// not. Anything else is an error, for the recipient to be tried again later.
+    pub fn callout(&self, address: &str) -> io::Result<bool> {
+        let (mut connection, capabilities) = self.checkout()?;
+        connection.command("MAIL FROM:<>", 250)?;
+        let address = bracketed(dsn::split_parameters(address).0);
+        connection
+            .writer
+            .write_all(format!("RCPT TO:{}\r\n", address).as_bytes())?;
+        let (reply, lines) = connection.reply()?;
+        let exists = match reply {
+            Some(200..=299) => true,
+            Some(500..=599) => false,
+            _ => {
+                return Err(io::Error::other(format!(
+                    "relay replied {}",
+                    lines[lines.len() - 1]
+                )))
+            }
+        };
+        // An idle connection is reset before it is used again, ending the transaction.
+        self.checkin(connection, capabilities);
+        Ok(exists)
+    }
+}
+
+impl Output for Relay {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        if mail.require_tls {
+            return Err(permanent_failure(String::from(
+                "REQUIRETLS asks for TLS, which the relay connection lacks",
+            )));
+        }
+        let unprotected = self.check_mta_sts(mail)?;
+        if let Some(dane) = &self.dane {
+            dane.verify(&self.host, self.port, None)?;
+        }
+        let (mut connection, capabilities) = self.checkout()?;
+        if let Some(reports) = &self.tls_reports {
+            for domain in unprotected {
+                reports.success(domain, tls_rpt::PolicyType::NoPolicyFound);
+            }
+        }
+        let from = dsn::split_parameters(mail.mail_from.as_deref().unwrap_or("<>")).0;
+        let from = from.trim_start_matches('<').trim_end_matches('>');
+        let recipients: Vec<&String> = mail
+            .rcpt_to
+            .iter()
+            .filter(|rcpt| !rcpt.is_empty())
+            .collect();
+        if self.verp && !from.is_empty() {
+            // One transaction each: once one fails, it and the rest are left to the
+            // caller, and those already accepted are not sent twice.
+            let mut delivered = Vec::new();
+            for rcpt in recipients {
+                let recipient = dsn::split_parameters(rcpt).0;
+                let sender = verp::encode(
+                    from,
+                    recipient.trim_start_matches('<').trim_end_matches('>'),
+                );
+                if let Err(e) =
+                    self.transaction(&mut connection, &capabilities, mail, &sender, &[rcpt])
+                {
+                    return Err(partial_delivery(delivered, e));
+                }
+                delivered.push(rcpt.clone());
+            }
+        } else {
+            self.transaction(&mut connection, &capabilities, mail, from, &recipients)?;
+        }
+        self.checkin(connection, capabilities);
+        Ok(())
+    }
+}
+
+/// The parameters of `keyword` in the EHLO reply `lines`, if the server advertised it.
+fn capability<'a>(lines: &'a [String], keyword: &str) -> Option<&'a str> {
+    lines.iter().skip(1).find_map(|line| {
+        let line = line.get(4..)?;
+        let (name, parameters) = line.split_once(' ').unwrap_or((line, ""));
+        Some(parameters).filter(|_| name.eq_ignore_ascii_case(keyword))
+    })
+}
+
+fn bracketed(address: &str) -> String {
+    let address = address.trim();
+    if address.starts_with('<') {
+        String::from(address)
+    } else {
+        format!("<{}>", address)
+    }
+}
+
+/// The XFORWARD command (as in Postfix) giving the origin of `mail`, restricted to the
+/// `attributes` the server advertised.
+fn xforward_command(mail: &Mail, attributes: &str) -> String {
+    let origin = &mail.origin;
+    let mut command = String::from("XFORWARD");
+    for attribute in attributes.split_whitespace() {
+        let value = match attribute.to_uppercase().as_str() {
+            "ADDR" => origin.address.map(|address| match address {
+                IpAddr::V4(address) => address.to_string(),
+                IpAddr::V6(address) => format!("IPV6:{}", address),
+            }),
+            "NAME" => origin.name.clone(),
+            "PROTO" => origin.protocol.clone(),
+            "HELO" => mail.helo.clone(),
+            _ => continue,
+        };
+        let value = value.map_or_else(|| String::from("[UNAVAILABLE]"), |v| dsn::xtext_encode(&v));
+        command.push_str(&format!(" {}={}", attribute.to_uppercase(), value));
+    }
+    command
+}
+
+/// The message with CRLF line endings, dot-stuffed and followed by the final dot.
+fn encode_data(data: &str) -> Vec<u8> {
+    let mut encoded = String::with_capacity(data.len() + 5);
+    for line in data.lines() {
+        if line.starts_with('.') {
+            encoded.push('.');
+        }
+        encoded.push_str(line);
+        encoded.push_str("\r\n");
+    }
+    encoded.push_str(".\r\n");
+    encoded.into_bytes()
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Connection {
+    fn command(&mut self, command: &str, code: u16) -> io::Result<Vec<String>> {
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())?;
+        self.expect(code)
+    }
+
+    /// Reads a possibly multi-line reply, failing unless it has `code`.
+    fn expect(&mut self, code: u16) -> io::Result<Vec<String>> {
+        self.expect_any(&[code])
+    }
+
+    /// Reads a possibly multi-line reply, failing unless it has one of `codes`.
+    fn expect_any(&mut self, codes: &[u16]) -> io::Result<Vec<String>> {
+        let (reply, lines) = self.reply()?;
+        if reply.is_some_and(|reply| codes.contains(&reply)) {
+            return Ok(lines);
+        }
+        Err(io::Error::other(format!(
+            "relay replied {}",
+            lines[lines.len() - 1]
+        )))
+    }
+
+    /// Reads a possibly multi-line reply, with its code unless the last line lacks one.
+    fn reply(&mut self) -> io::Result<(Option<u16>, Vec<String>)> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "relay closed the connection",
+                ));
+            }
+            let line = String::from(line.trim_end());
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            lines.push(line);
+            if !last {
+                continue;
+            }
+            let reply = lines[lines.len() - 1]
+                .get(..3)
+                .and_then(|reply| reply.parse::<u16>().ok());
+            return Ok((reply, lines));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, deliver_by::DeliverBy, server::Server};
+    use std::thread;
+
+    #[test]
+    fn test_encode_data() {
+        assert_eq!(
+            encode_data("Subject: hi\n\n.hidden\nend\n"),
+            b"Subject: hi\r\n\r\n..hidden\r\nend\r\n.\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_capability() {
+        let lines = vec![
+            String::from("250-mx.example.com"),
+            String::from("250-DSN"),
+            String::from("250 XFORWARD NAME ADDR"),
+        ];
+        assert_eq!(capability(&lines, "dsn"), Some(""));
+        assert_eq!(capability(&lines, "XFORWARD"), Some("NAME ADDR"));
+        assert_eq!(capability(&lines, "DELIVERBY"), None);
+    }
+
+    #[test]
+    fn test_xforward_command() {
+        let mut mail = Mail::new();
+        mail.helo = Some(String::from("client.example.net"));
+        mail.origin.address = "2001:db8::7".parse().ok();
+        mail.origin.protocol = Some(String::from("ESMTP"));
+        assert_eq!(
+            xforward_command(&mail, "NAME ADDR PROTO HELO SOURCE"),
+            "XFORWARD NAME=[UNAVAILABLE] ADDR=IPV6:2001:db8::7 PROTO=ESMTP \
+             HELO=client.example.net"
+        );
+    }
+
+    /// Accepts one connection and answers each line it reads with the next of `replies`,
+    /// returning the lines read.
+    fn scripted_server(replies: &'static [&'static str]) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 relay.example.net\r\n").unwrap();
+            let mut lines = Vec::new();
+            for reply in replies.iter() {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                lines.push(String::from(line.trim_end()));
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            lines
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let (port, server) = scripted_server(&[
+            "250-relay.example.net\r\n250 AUTH CRAM-MD5 LOGIN\r\n",
+            "334 VXNlcm5hbWU6\r\n",
+            "334 UGFzc3dvcmQ6\r\n",
+            "235 2.7.0 Authentication successful\r\n",
+            "221 Bye\r\n",
+        ]);
+        let mut relay = Relay::new("127.0.0.1", port, "relay.example.com");
+        relay.credentials = Some(Credentials {
+            user: String::from("bob"),
+            secret: String::from("secret"),
+            mechanism: None,
+        });
+        assert!(relay.checkout().is_err());
+        relay.insecure_auth = true;
+        let (connection, capabilities) = relay.checkout().unwrap();
+        relay.checkin(connection, capabilities);
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "EHLO relay.example.com",
+                "AUTH LOGIN",
+                "Ym9i",
+                "c2VjcmV0",
+                "QUIT"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pipelining() {
+        let (port, server) = scripted_server(&[
+            "250-relay.example.net\r\n250 PIPELINING\r\n",
+            "250 2.1.0 Ok\r\n",
+            "250 2.1.5 Ok\r\n",
+            "251 2.1.5 User not local; will forward\r\n",
+            "354 Go ahead\r\n",
+            "",
+            "250 2.0.0 Ok\r\n",
+            "221 Bye\r\n",
+        ]);
+        let relay = Relay::new("127.0.0.1", port, "relay.example.com");
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.com>"));
+        mail.rcpt_to = vec![
+            String::from("<b@example.com>"),
+            String::from("<c@example.com>"),
+        ];
+        mail.data = Some(String::from("Subject: hi\n"));
+        relay.deliver(&mail).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "EHLO relay.example.com",
+                "MAIL FROM:<a@example.com>",
+                "RCPT TO:<b@example.com>",
+                "RCPT TO:<c@example.com>",
+                "DATA",
+                "Subject: hi",
+                ".",
+                "QUIT"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_require_tls() {
+        let mut mail = Mail::new();
+        mail.require_tls = true;
+        let error = Relay::new("127.0.0.1", 25, "relay.example.com")
+            .deliver(&mail)
+            .unwrap_err();
+        assert!(crate::output::is_permanent(&error));
+    }
+
+    #[test]
+    fn test_mta_sts() {
+        struct Enforce;
+
+        impl mta_sts::PolicySource for Enforce {
+            fn txt_record(&self, domain: &str) -> io::Result<Option<String>> {
+                Ok(Some(String::from("v=STSv1; id=1")).filter(|_| domain == "example.com"))
+            }
+
+            fn policy_file(&self, _: &str) -> io::Result<String> {
+                Ok(String::from(
+                    "version: STSv1\nmode: enforce\nmx: 127.0.0.1\nmax_age: 600\n",
+                ))
+            }
+        }
+
+        let mut relay = Relay::new("127.0.0.1", 25, "relay.example.com");
+        relay.mta_sts = Some(Arc::new(StsCache::new(Arc::new(Enforce))));
+        let reports = Arc::new(TlsReports::new("Example", "postmaster@example.net"));
+        relay.tls_reports = Some(Arc::clone(&reports));
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![String::from("<user@example.org>")];
+        assert_eq!(relay.check_mta_sts(&mail).unwrap(), ["example.org"]);
+        mail.rcpt_to.push(String::from("<user@Example.com>"));
+        let error = relay.check_mta_sts(&mail).unwrap_err();
+        assert!(!crate::output::is_permanent(&error));
+        assert_eq!(
+            error.to_string(),
+            "MTA-STS policy of Example.com requires TLS"
+        );
+        let reports = reports.take();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0, "example.com");
+        assert!(reports[0]
+            .1
+            .to_string()
+            .contains("\"result-type\":\"starttls-not-supported\""));
+    }
+
+    #[test]
+    fn test_idle_connection() {
+        let mut server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        let incoming = server.incoming();
+        thread::spawn(move || server.run());
+
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<app@example.com>"));
+        mail.rcpt_to = vec![String::from("<user@example.com>")];
+        mail.data = Some(String::from("Subject: hi\n\nhi\n"));
+        let mut relay = Relay::new("127.0.0.1", address.port(), "relay.example.com");
+        relay.idle_timeout = Duration::from_secs(60);
+        relay.deliver(&mail).unwrap();
+        relay.deliver(&mail).unwrap();
+        assert_eq!(relay.idle.lock().unwrap().len(), 1);
+
+        let first = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first.session_id, second.session_id);
+    }
+
+    #[test]
+    fn test_verp() {
+        let mut server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        let incoming = server.incoming();
+        thread::spawn(move || server.run());
+
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<list@example.com>"));
+        mail.rcpt_to = vec![
+            String::from("<a@example.org>"),
+            String::from("<b@example.org>"),
+        ];
+        mail.data = Some(String::from("Subject: news\n\nhi\n"));
+        let mut relay = Relay::new("127.0.0.1", address.port(), "relay.example.com");
+        relay.verp = true;
+        relay.deliver(&mail).unwrap();
+
+        for rcpt in ["a", "b"].iter() {
+            let received = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(
+                received.mail.mail_from,
+                Some(format!("<list+{}=example.org@example.com>", rcpt))
+            );
+            assert_eq!(received.mail.rcpt_to[0], format!("<{}@example.org>", rcpt));
+        }
+    }
+
+    #[test]
+    fn test_verp_partial() {
+        let (port, server) = scripted_server(&[
+            "250 relay.example.net\r\n",
+            "250 2.1.0 Ok\r\n",
+            "251 2.1.5 User not local; will forward\r\n",
+            "354 Go ahead\r\n",
+            "",
+            "250 2.0.0 Ok\r\n",
+            "250 2.1.0 Ok\r\n",
+            "451 4.2.1 Mailbox busy\r\n",
+        ]);
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<list@example.com>"));
+        mail.rcpt_to = vec![
+            String::from("<a@example.org>"),
+            String::from("<b@example.org>"),
+        ];
+        mail.data = Some(String::from("Subject: news\n"));
+        let mut relay = Relay::new("127.0.0.1", port, "relay.example.com");
+        relay.verp = true;
+        let error = relay.deliver(&mail).unwrap_err();
+        assert_eq!(crate::output::delivered(&error), ["<a@example.org>"]);
+        assert!(!crate::output::is_permanent(&error));
+        assert!(server
+            .join()
+            .unwrap()
+            .contains(&String::from("RCPT TO:<b@example.org>")));
+    }
+
+    #[test]
+    fn test_callout() {
+        let (port, server) = scripted_server(&[
+            "250 relay.example.net\r\n",
+            "250 Ok\r\n",
+            "550 5.1.1 No such user\r\n",
+            "221 Bye\r\n",
+        ]);
+        let relay = Relay::new("127.0.0.1", port, "mx.example.com");
+        assert!(!relay.callout("<nobody@example.com>").unwrap());
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "EHLO mx.example.com",
+                "MAIL FROM:<>",
+                "RCPT TO:<nobody@example.com>",
+                "QUIT"
+            ]
+        );
+
+        let (port, _server) = scripted_server(&[
+            "250 relay.example.net\r\n",
+            "250 Ok\r\n",
+            "451 4.3.0 Try again later\r\n",
+        ]);
+        let relay = Relay::new("127.0.0.1", port, "mx.example.com");
+        assert!(relay.callout("<user@example.com>").is_err());
+    }
+
+    #[test]
+    fn test_deliver() {
+        let mut server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        let incoming = server.incoming();
+        thread::spawn(move || server.run());
+
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("app@example.com"));
+        mail.rcpt_to = vec![String::from("<user@example.com>")];
+        mail.data = Some(String::from("Subject: Welcome\n\nhi\n"));
+        mail.dsn.ret = Some(dsn::Ret::Headers);
+        mail.deliver_by = Some(DeliverBy::parse("600;R", clock::now(), 0).unwrap());
+        mail.dsn.recipients.push(dsn::RecipientDsn {
+            address: String::from("user@example.com"),
+            notify: Some(dsn::Notify::NEVER),
+            orcpt: None,
+        });
+        let mut relay = Relay::new("127.0.0.1", address.port(), "relay.example.com");
+        let reports = Arc::new(TlsReports::new("Example", "postmaster@example.net"));
+        relay.tls_reports = Some(Arc::clone(&reports));
+        relay.deliver(&mail).unwrap();
+        let reports = reports.take();
+        assert!(reports[0]
+            .1
+            .to_string()
+            .contains("\"policy-type\":\"no-policy-found\""));
+
+        let received = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received.mail.helo.as_deref(), Some("relay.example.com"));
+        assert!(received.mail.deliver_by.is_some());
+        assert_eq!(received.mail.rcpt_to[0], "<user@example.com> NOTIFY=NEVER");
+        assert_eq!(received.mail.dsn.ret, Some(dsn::Ret::Headers));
+        assert_eq!(
+            received.mail.dsn.notify("<user@example.com>"),
+            dsn::Notify::NEVER
+        );
+        let data = received.mail.data.unwrap();
+        assert!(data.starts_with("Received: from relay.example.com ([127.0.0.1])\r\n"));
+        assert!(data.ends_with("\r\nSubject: Welcome\r\n\r\nhi\r\n"));
+    }
+}
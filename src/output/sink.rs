@@ -0,0 +1,64 @@
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+};
+
+use crate::{email::Mail, json::ToJson, output::Output};
+
+/// Writes every accepted message as one line of JSON, its envelope and content as
+/// `Mail::to_json` gives them, and keeps nothing: what `--sink` prints to stdout, for
+/// piping into `jq` during development.
+pub struct Sink<W> {
+    writer: Mutex<W>,
+}
+
+impl Sink<io::Stdout> {
+    pub fn stdout() -> Sink<io::Stdout> {
+        Sink::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send> Sink<W> {
+    pub fn new(writer: W) -> Sink<W> {
+        Sink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap()
+    }
+}
+
+impl<W: Write + Send> Output for Sink<W> {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{}", mail.to_json())?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::{FromJson, Json};
+
+    #[test]
+    fn test_deliver() {
+        let sink = Sink::new(Vec::new());
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.com>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from("Subject: hi\r\n\r\nhi\r\n"));
+        sink.deliver(&mail).unwrap();
+        sink.deliver(&mail).unwrap();
+
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let json = Json::parse(lines[0]).unwrap();
+        let parsed = Mail::from_json(&json).unwrap();
+        assert_eq!(parsed.rcpt_to, mail.rcpt_to);
+        assert_eq!(parsed.data, mail.data);
+    }
+}
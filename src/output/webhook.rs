@@ -0,0 +1,195 @@
+use std::{
+    io, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    digest,
+    email::Mail,
+    encoding,
+    http::{self, Url},
+    json::Json,
+    output::{envelope_json, Output},
+    retry::{Backoff, Failure, RetrySchedule},
+};
+
+pub enum WebhookFormat {
+    /// `{"envelope": {...}, "data": "<raw message>"}`
+    Json,
+    /// Same as `Json` but the message is sent base64 encoded in `data_base64`.
+    JsonBase64,
+    /// `multipart/form-data` with an `envelope` JSON part and a `message` part.
+    Multipart,
+}
+
+/// POSTs every accepted message to an HTTP endpoint. Delivery happens in the background
+/// and is retried according to `retry`, so a slow endpoint never delays the session.
+pub struct Webhook {
+    pub url: Url,
+    pub format: WebhookFormat,
+    /// When set, requests carry `X-Webhook-Signature: sha256=<hex>`, an HMAC-SHA256 of
+    /// `<X-Webhook-Timestamp>.<body>`.
+    pub secret: Option<String>,
+    /// Error statuses count as refusals. By default 5 attempts, 1, 2, 4 and 8 seconds
+    /// apart.
+    pub retry: RetrySchedule,
+    pub timeout: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> io::Result<Webhook> {
+        Ok(Webhook {
+            url: Url::parse(url)?,
+            format: WebhookFormat::Json,
+            secret: None,
+            retry: RetrySchedule {
+                max_attempts: Some(5),
+                max_lifetime: Duration::from_secs(3600),
+                ..RetrySchedule::from_backoff(Backoff::Exponential {
+                    initial: Duration::from_secs(1),
+                    factor: 2,
+                    max: Duration::from_secs(60),
+                })
+            },
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    fn request(&self, mail: &Mail) -> (Vec<(&'static str, String)>, Vec<u8>) {
+        let data = mail.data.as_deref().unwrap_or("");
+        let (content_type, body) = match self.format {
+            WebhookFormat::Json => (
+                String::from("application/json"),
+                Json::object(vec![
+                    ("envelope", envelope_json(mail)),
+                    ("data", Json::string(data)),
+                ])
+                .to_string(),
+            ),
+            WebhookFormat::JsonBase64 => (
+                String::from("application/json"),
+                Json::object(vec![
+                    ("envelope", envelope_json(mail)),
+                    (
+                        "data_base64",
+                        Json::String(encoding::base64_encode(data.as_bytes())),
+                    ),
+                ])
+                .to_string(),
+            ),
+            WebhookFormat::Multipart => {
+                let digest = digest::to_hex(&digest::sha256(data.as_bytes()));
+                let boundary = format!("simple-smtp-{}", &digest[..24]);
+                let body = format!(
+                    "--{b}\r\nContent-Disposition: form-data; name=\"envelope\"\r\n\
+                     Content-Type: application/json\r\n\r\n{}\r\n\
+                     --{b}\r\nContent-Disposition: form-data; name=\"message\"; filename=\"message.eml\"\r\n\
+                     Content-Type: message/rfc822\r\n\r\n{}\r\n--{b}--\r\n",
+                    envelope_json(mail),
+                    data,
+                    b = boundary
+                );
+                (format!("multipart/form-data; boundary={}", boundary), body)
+            }
+        };
+
+        let mut headers = vec![("Content-Type", content_type)];
+        if let Some(secret) = &self.secret {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+                .to_string();
+            let signed = format!("{}.{}", timestamp, body);
+            let signature = digest::hmac_sha256(secret.as_bytes(), signed.as_bytes());
+            headers.push(("X-Webhook-Timestamp", timestamp));
+            headers.push((
+                "X-Webhook-Signature",
+                format!("sha256={}", digest::to_hex(&signature)),
+            ));
+        }
+        (headers, body.into_bytes())
+    }
+}
+
+impl Output for Webhook {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let (headers, body) = self.request(mail);
+        let url = self.url.clone();
+        let retry = self.retry.clone();
+        let timeout = self.timeout;
+
+        thread::spawn(move || {
+            let queued_at = SystemTime::now();
+            let mut failures = 0;
+            loop {
+                let failure = match http::post(&url, &headers, &body, timeout) {
+                    Ok(response) if (200..300).contains(&response.status) => return,
+                    Ok(response) => {
+                        crate::warn!(
+                            "Webhook {} answered {} (attempt {})",
+                            url.path,
+                            response.status,
+                            failures + 1
+                        );
+                        Failure::Refused
+                    }
+                    Err(e) => {
+                        crate::warn!(
+                            "Webhook {} failed: {} (attempt {})",
+                            url.path,
+                            e,
+                            failures + 1
+                        );
+                        Failure::of(&e)
+                    }
+                };
+                failures += 1;
+                let now = SystemTime::now();
+                match retry.next_attempt(failure, failures, queued_at, now) {
+                    Some(next) => thread::sleep(next.duration_since(now).unwrap_or_default()),
+                    None => break,
+                }
+            }
+            crate::error!(
+                "Giving up on webhook {} after {} attempts",
+                url.path,
+                failures
+            );
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_request() {
+        let mut webhook = Webhook::new("http://localhost:9000/hook").unwrap();
+        webhook.secret = Some(String::from("secret"));
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@b>"));
+        mail.data = Some(String::from("Subject: x\r\n\r\nhi\r\n"));
+
+        let (headers, body) = webhook.request(&mail);
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(
+            body,
+            "{\"envelope\":{\"queue_id\":null,\"helo\":null,\"mail_from\":\"<a@b>\",\"rcpt_to\":[],\"size\":18},\
+             \"data\":\"Subject: x\\r\\n\\r\\nhi\\r\\n\"}"
+        );
+        let timestamp = &headers
+            .iter()
+            .find(|(n, _)| *n == "X-Webhook-Timestamp")
+            .unwrap()
+            .1;
+        let signature = &headers
+            .iter()
+            .find(|(n, _)| *n == "X-Webhook-Signature")
+            .unwrap()
+            .1;
+        let expected = digest::hmac_sha256(b"secret", format!("{}.{}", timestamp, body).as_bytes());
+        assert_eq!(*signature, format!("sha256={}", digest::to_hex(&expected)));
+    }
+}
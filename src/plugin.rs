@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use crate::{
+    config::Config,
+    email::Mail,
+    extension::Verbs,
+    handler::{SmtpHandler, Verdict},
+    output::Output,
+    session::SessionContext,
+};
+
+/// An extension of the server bundling hooks, EHLO keywords, verbs and outputs, e.g. a
+/// storage backend or a policy from another crate. The hooks are those of `SmtpHandler`,
+/// run once the configured handler and the plugins registered before accepted.
+pub trait Plugin: SmtpHandler {
+    /// Identifies the plugin in logs.
+    fn name(&self) -> &str;
+
+    /// Keywords to advertise in the EHLO reply besides those of its verbs.
+    fn capabilities(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Registers the verbs the plugin answers, see `Verbs::register`.
+    fn register_verbs(&self, _verbs: &mut Verbs) {}
+
+    /// Where accepted messages are handed to besides the configured outputs.
+    fn outputs(&self) -> Vec<Box<dyn Output>> {
+        Vec::new()
+    }
+}
+
+/// The plugins to extend a configuration with, in order, see `Server::plugins`.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl Plugins {
+    pub fn register<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.plugins.push(Arc::new(plugin));
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.plugins.iter().map(|plugin| plugin.name())
+    }
+
+    /// Adds the hooks, keywords, verbs and outputs of every plugin to `config`. The hooks
+    /// run after those of `config.handler`, which should be set first.
+    pub fn install(&self, config: &mut Config) {
+        if self.plugins.is_empty() {
+            return;
+        }
+        for plugin in self.plugins.iter() {
+            crate::info!("Installing plugin {}", plugin.name());
+            for capability in plugin.capabilities() {
+                config.verbs.advertise(&capability);
+            }
+            plugin.register_verbs(&mut config.verbs);
+            config.outputs.extend(plugin.outputs());
+        }
+        config.handler = Arc::new(Chain {
+            handler: Arc::clone(&config.handler),
+            plugins: self.plugins.clone(),
+        });
+    }
+}
+
+/// Runs a handler then the hooks of each plugin, up to the first that does not accept.
+struct Chain {
+    handler: Arc<dyn SmtpHandler>,
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl Chain {
+    fn first_refusal(&self, hook: impl Fn(&dyn SmtpHandler) -> Verdict) -> Verdict {
+        let handlers = std::iter::once(self.handler.as_ref() as &dyn SmtpHandler).chain(
+            self.plugins
+                .iter()
+                .map(|plugin| plugin.as_ref() as &dyn SmtpHandler),
+        );
+        for handler in handlers {
+            let verdict = hook(handler);
+            if verdict != Verdict::Accept {
+                return verdict;
+            }
+        }
+        Verdict::Accept
+    }
+}
+
+impl SmtpHandler for Chain {
+    fn on_connect(&self, context: &SessionContext) -> Verdict {
+        self.first_refusal(|handler| handler.on_connect(context))
+    }
+
+    fn on_helo(&self, context: &SessionContext, domain: &str) -> Verdict {
+        self.first_refusal(|handler| handler.on_helo(context, domain))
+    }
+
+    fn on_mail_from(&self, context: &SessionContext, from: &str) -> Verdict {
+        self.first_refusal(|handler| handler.on_mail_from(context, from))
+    }
+
+    fn on_rcpt_to(&self, context: &SessionContext, rcpt: &str) -> Verdict {
+        self.first_refusal(|handler| handler.on_rcpt_to(context, rcpt))
+    }
+
+    fn on_data_chunk(&self, context: &SessionContext, chunk: &str) -> Verdict {
+        self.first_refusal(|handler| handler.on_data_chunk(context, chunk))
+    }
+
+    fn on_message_complete(&self, context: &SessionContext, mail: &Mail) -> Verdict {
+        self.first_refusal(|handler| handler.on_message_complete(context, mail))
+    }
+
+    fn on_quit(&self, context: &SessionContext) {
+        self.handler.on_quit(context);
+        for plugin in self.plugins.iter() {
+            plugin.on_quit(context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        email::{MailFSM, State},
+        reply::Reply,
+        store::{MemoryStore, MessageStore, StoreOutput},
+    };
+
+    /// Refuses a domain, answers XSTATS and keeps messages in a store.
+    struct Example {
+        store: Arc<MemoryStore>,
+    }
+
+    impl SmtpHandler for Example {
+        fn on_rcpt_to(&self, _context: &SessionContext, rcpt: &str) -> Verdict {
+            if rcpt.ends_with("@example.org>") {
+                Verdict::reject(550, "5.7.1 Not here")
+            } else {
+                Verdict::Accept
+            }
+        }
+    }
+
+    impl Plugin for Example {
+        fn name(&self) -> &str {
+            "example"
+        }
+
+        fn capabilities(&self) -> Vec<String> {
+            vec![String::from("X-EXAMPLE")]
+        }
+
+        fn register_verbs(&self, verbs: &mut Verbs) {
+            let store = Arc::clone(&self.store);
+            verbs.register(
+                "XSTATS",
+                &[State::Hello],
+                Some("XSTATS"),
+                move |_: &SessionContext, _: &str| {
+                    Reply::new(250, &format!("{} stored", store.list().len()))
+                },
+            );
+        }
+
+        fn outputs(&self) -> Vec<Box<dyn Output>> {
+            vec![Box::new(StoreOutput(self.store.clone()))]
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        let store = Arc::new(MemoryStore::new(10));
+        let plugins = Plugins::default().register(Example {
+            store: Arc::clone(&store),
+        });
+        assert_eq!(plugins.names().collect::<Vec<_>>(), ["example"]);
+        let mut config = Config::new(String::from("test.server"));
+        plugins.install(&mut config);
+        let mut mail_fsm = MailFSM::with_config(Arc::new(config));
+        let ehlo = mail_fsm.process_line("EHLO client\n").unwrap().to_string();
+        assert!(ehlo.contains("250-XSTATS\r\n") && ehlo.contains("250 X-EXAMPLE\r\n"));
+        mail_fsm.process_line("MAIL FROM: <a@example.com>\n");
+        assert_eq!(
+            mail_fsm.process_line("RCPT TO: <b@example.org>\n"),
+            Some(Reply::new(550, "5.7.1 Not here"))
+        );
+        mail_fsm.process_line("RCPT TO: <b@example.com>\n");
+        mail_fsm.process_line("DATA\n");
+        mail_fsm.process_line("hi\n");
+        assert_eq!(mail_fsm.process_line(".\n").unwrap().code, 250);
+        assert_eq!(
+            mail_fsm.process_line("XSTATS\n"),
+            Some(Reply::new(250, "1 stored"))
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use crate::network::Cidr;
+
+/// What a session allows and how it answers, so that the same state machine can act as
+/// an MTA, a submission server or a sink. Built by chaining, e.g.
+/// `Policy::default().require_auth(true).max_recipients(50)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub(crate) require_helo: bool,
+    pub(crate) max_recipients: Option<usize>,
+    pub(crate) require_auth: bool,
+    pub(crate) verbs: Option<Vec<String>>,
+    pub(crate) reply_texts: HashMap<u16, String>,
+    pub(crate) trusted_networks: Vec<Cidr>,
+    pub(crate) fix_headers: bool,
+    pub(crate) xclient_networks: Vec<Cidr>,
+    pub(crate) lmtp: bool,
+    pub(crate) tls: bool,
+    pub(crate) insecure_auth: bool,
+}
+
+impl Policy {
+    /// Relaying between servers: HELO first and at most 100 recipients, as RFC 5321 asks
+    /// servers to accept.
+    pub fn mta() -> Policy {
+        Policy::default().max_recipients(100)
+    }
+
+    /// Accepting mail from users, who have to authenticate first, see
+    /// `Config::authenticator`, and may then relay, as on port 587. Messages missing
+    /// `Date` or `Message-ID` get one.
+    pub fn submission() -> Policy {
+        Policy::default()
+            .require_auth(true)
+            .max_recipients(100)
+            .fix_headers(true)
+    }
+
+    /// Taking whatever a client sends, e.g. in front of an application under test.
+    pub fn sink() -> Policy {
+        Policy::default().require_helo(false)
+    }
+
+    /// Whether MAIL FROM has to follow HELO or EHLO, true by default.
+    pub fn require_helo(mut self, require_helo: bool) -> Policy {
+        self.require_helo = require_helo;
+        self
+    }
+
+    /// Further RCPT TO commands are deferred with 452 once a transaction has this many.
+    pub fn max_recipients(mut self, max_recipients: usize) -> Policy {
+        self.max_recipients = Some(max_recipients);
+        self
+    }
+
+    /// Whether accepted messages without a `Date` or `Message-ID` header get one, as RFC
+    /// 6409 lets submission servers do.
+    pub fn fix_headers(mut self, fix_headers: bool) -> Policy {
+        self.fix_headers = fix_headers;
+        self
+    }
+
+    /// Whether MAIL FROM is refused with 530 until the client authenticated, with AUTH or
+    /// as XCLIENT says.
+    pub fn require_auth(mut self, require_auth: bool) -> Policy {
+        self.require_auth = require_auth;
+        self
+    }
+
+    /// Only these verbs are accepted, the others are answered with 502. All are by default.
+    pub fn verbs(mut self, verbs: &[&str]) -> Policy {
+        self.verbs = Some(verbs.iter().map(|verb| verb.to_uppercase()).collect());
+        self
+    }
+
+    /// Replaces the text of the replies with `code` the session gives itself, keeping
+    /// their enhanced code. Replies decided by the handler or custom verbs are left alone.
+    pub fn reply_text(mut self, code: u16, text: &str) -> Policy {
+        self.reply_texts.insert(code, String::from(text));
+        self
+    }
+
+    /// Clients in `network` may relay, i.e. send to recipients outside the accepted
+    /// domains. Authenticated clients always may.
+    pub fn trust(mut self, network: Cidr) -> Policy {
+        self.trusted_networks.push(network);
+        self
+    }
+
+    /// Clients in `network`, such as a proxy or filter in front of this server, may use
+    /// XCLIENT to give the address, name, HELO and login of the client they act for.
+    pub fn allow_xclient(mut self, network: Cidr) -> Policy {
+        self.xclient_networks.push(network);
+        self
+    }
+
+    /// Whether sessions speak LMTP (RFC 2033) instead of SMTP: LHLO in place of HELO and
+    /// EHLO, and a reply for each recipient at the end of DATA. For a delivery endpoint
+    /// behind another MTA, e.g. on a Unix socket, see `Server::listen_unix`.
+    pub fn lmtp(mut self, lmtp: bool) -> Policy {
+        self.lmtp = lmtp;
+        self
+    }
+
+    /// Whether sessions arrive over TLS, which simple-smtp does not speak itself but a
+    /// proxy in front may terminate, e.g. stunnel on port 465 handing connections to a
+    /// profile with this set. Sessions are then marked encrypted and REQUIRETLS is offered.
+    pub fn tls(mut self, tls: bool) -> Policy {
+        self.tls = tls;
+        self
+    }
+
+    /// Whether AUTH is offered to sessions that do not arrive over TLS, where the password
+    /// goes in the clear: for clients on the same host or a network nobody can listen in
+    /// on. Sessions over TLS, see `tls`, are offered it whenever `Config::authenticator`
+    /// is set.
+    pub fn insecure_auth(mut self, insecure_auth: bool) -> Policy {
+        self.insecure_auth = insecure_auth;
+        self
+    }
+
+    pub fn allows(&self, verb: &str) -> bool {
+        match &self.verbs {
+            Some(verbs) => verbs
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(verb)),
+            None => true,
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            require_helo: true,
+            max_recipients: None,
+            require_auth: false,
+            verbs: None,
+            reply_texts: HashMap::new(),
+            trusted_networks: Vec::new(),
+            fix_headers: false,
+            xclient_networks: Vec::new(),
+            lmtp: false,
+            tls: false,
+            insecure_auth: false,
+        }
+    }
+}
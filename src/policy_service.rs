@@ -0,0 +1,216 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    time::Duration,
+};
+
+use crate::{connect, handler::Verdict};
+
+/// Where in the transaction a policy service is asked, its `protocol_state`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Mail,
+    Rcpt,
+    Data,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Mail => "MAIL",
+            Stage::Rcpt => "RCPT",
+            Stage::Data => "DATA",
+        }
+    }
+}
+
+/// An external policy service speaking the Postfix policy delegation protocol, e.g.
+/// postfwd or policyd-spf: each MAIL FROM, RCPT TO and DATA is described to it as
+/// `name=value` lines, and it answers with an action such as `DUNNO`, `REJECT` or `DEFER`,
+/// see `parse_action`. Each query opens a new connection.
+pub struct PolicyService {
+    /// `host:port`, or `unix:` and the path of a socket.
+    pub address: String,
+    pub timeout: Duration,
+    /// Whether the client goes on when the service cannot be asked, rather than being told
+    /// to try again later.
+    pub fail_open: bool,
+}
+
+impl PolicyService {
+    pub fn new(address: &str) -> PolicyService {
+        PolicyService {
+            address: String::from(address),
+            timeout: Duration::from_secs(10),
+            fail_open: false,
+        }
+    }
+
+    /// What the service decides about the request `attributes`, `request` and
+    /// `protocol_state` aside.
+    pub fn check(&self, stage: Stage, attributes: &[(&str, &str)]) -> Verdict {
+        match self.query(stage, attributes) {
+            Ok(action) => parse_action(&action),
+            Err(e) => {
+                crate::warn!("Unable to query policy service {}: {}", self.address, e);
+                if self.fail_open {
+                    Verdict::Accept
+                } else {
+                    Verdict::Defer(String::from("4.3.0 Temporary failure, try again later"))
+                }
+            }
+        }
+    }
+
+    /// The `action` the service answered the request with.
+    pub fn query(&self, stage: Stage, attributes: &[(&str, &str)]) -> io::Result<String> {
+        let mut request = format!(
+            "request=smtpd_access_policy\nprotocol_state={}\n",
+            stage.as_str()
+        );
+        for (name, value) in attributes.iter() {
+            // A line break would end the attribute, or the request, early.
+            let value = value.replace(['\r', '\n'], " ");
+            request.push_str(&format!("{}={}\n", name, value));
+        }
+        request.push('\n');
+        #[cfg(unix)]
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            let stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            return exchange(&stream, &stream, &request);
+        }
+        let (host, port) = self
+            .address
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid policy service address {}", self.address),
+                )
+            })?;
+        let stream = connect::connect(host.trim_matches(&['[', ']'][..]), port, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        exchange(&stream, &stream, &request)
+    }
+}
+
+/// Writes `request` and reads the reply up to its empty line, returning its action.
+fn exchange(reader: impl Read, mut writer: impl Write, request: &str) -> io::Result<String> {
+    writer.write_all(request.as_bytes())?;
+    writer.flush()?;
+    let mut action = None;
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.is_empty() {
+            return action.ok_or_else(|| io::Error::other("policy service sent no action"));
+        }
+        if let Some(value) = line.strip_prefix("action=") {
+            action = Some(String::from(value.trim()));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "policy service closed the connection",
+    ))
+}
+
+/// The verdict of a policy service action: `REJECT` and `5xx` codes refuse, `DEFER`,
+/// `DEFER_IF_PERMIT`, `DEFER_IF_REJECT` and `4xx` codes defer, with the text given if
+/// any. `OK`, `DUNNO` and the actions this server does not implement, e.g. `PREPEND` or
+/// `HOLD`, go on as usual.
+pub fn parse_action(action: &str) -> Verdict {
+    let (name, text) = action.split_once(' ').unwrap_or((action, ""));
+    let text = text.trim();
+    let with_default = |default: &str| {
+        if text.is_empty() {
+            String::from(default)
+        } else {
+            String::from(text)
+        }
+    };
+    match name.to_uppercase().as_str() {
+        "REJECT" => Verdict::reject(554, &format!("5.7.1 {}", with_default("Access denied"))),
+        "DEFER" | "DEFER_IF_PERMIT" | "DEFER_IF_REJECT" => {
+            Verdict::Defer(format!("4.7.1 {}", with_default("Try again later")))
+        }
+        "OK" | "DUNNO" => Verdict::Accept,
+        code => match code.parse::<u16>() {
+            Ok(code @ 400..=499) => Verdict::reject(code, &with_default("Try again later")),
+            Ok(code @ 500..=599) => Verdict::reject(code, &with_default("Access denied")),
+            _ => {
+                crate::debug!("Ignoring policy service action {}", action);
+                Verdict::Accept
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(parse_action("DUNNO"), Verdict::Accept);
+        assert_eq!(parse_action("ok"), Verdict::Accept);
+        assert_eq!(
+            parse_action("REJECT Listed at example.org"),
+            Verdict::reject(554, "5.7.1 Listed at example.org")
+        );
+        assert_eq!(
+            parse_action("DEFER_IF_PERMIT"),
+            Verdict::Defer(String::from("4.7.1 Try again later"))
+        );
+        assert_eq!(
+            parse_action("450 4.7.1 Greylisted"),
+            Verdict::reject(450, "4.7.1 Greylisted")
+        );
+        assert_eq!(parse_action("PREPEND X-Checked: yes"), Verdict::Accept);
+    }
+
+    #[test]
+    fn test_query() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push(line);
+            }
+            (&stream)
+                .write_all(b"action=DEFER_IF_PERMIT Service unavailable\n\n")
+                .unwrap();
+            request
+        });
+        let service = PolicyService::new(&address.to_string());
+        assert_eq!(
+            service.check(Stage::Rcpt, &[("recipient", "a@example.com\r\n")]),
+            Verdict::Defer(String::from("4.7.1 Service unavailable"))
+        );
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "request=smtpd_access_policy",
+                "protocol_state=RCPT",
+                "recipient=a@example.com  "
+            ]
+        );
+
+        let service = PolicyService {
+            fail_open: true,
+            ..PolicyService::new(&address.to_string())
+        };
+        assert_eq!(service.check(Stage::Mail, &[]), Verdict::Accept);
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    audit::{AuditEvent, AuditLog},
+    auth::Authenticator,
+    store::{MessageStore, StoredMail},
+};
+
+/// How long a client may stay silent, the least RFC 1939 allows.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Longer command lines end the session.
+const MAX_LINE_LENGTH: u64 = 512;
+
+/// Serves the messages of a `MessageStore` over POP3 (RFC 1939), so that ordinary mail
+/// clients can fetch what the server accepted: USER and PASS, checked by an
+/// `Authenticator`, then STAT, LIST, UIDL, RETR, DELE, RSET, NOOP and QUIT.
+///
+/// Every user sees the whole store, as the HTTP API does: this is the mailbox of a capture
+/// server, not delivery to users. Deleted messages leave the store at QUIT, as the
+/// protocol has it; a connection that drops deletes nothing. There is no TLS, passwords
+/// cross the network in plain text.
+pub struct Pop3 {
+    store: Arc<dyn MessageStore>,
+    auth: Arc<dyn Authenticator>,
+    server_name: String,
+    audit: Option<Arc<AuditLog>>,
+}
+
+/// Where a session is: logging in, or logged in with the messages it found then and
+/// whether each is marked deleted.
+enum State {
+    Authorization { user: Option<String> },
+    Transaction { messages: Vec<Message> },
+}
+
+struct Message {
+    stored: StoredMail,
+    /// The message as sent, with CRLF line endings.
+    text: String,
+    deleted: bool,
+}
+
+impl Pop3 {
+    pub fn new(store: Arc<dyn MessageStore>, auth: Arc<dyn Authenticator>) -> Pop3 {
+        Pop3 {
+            store,
+            auth,
+            server_name: String::from("localhost"),
+            audit: None,
+        }
+    }
+
+    /// The name given in the greeting, `localhost` by default.
+    pub fn server_name(mut self, server_name: &str) -> Pop3 {
+        self.server_name = String::from(server_name);
+        self
+    }
+
+    /// Where refused logins are recorded.
+    pub fn audit(mut self, audit: Arc<AuditLog>) -> Pop3 {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Serves each connection on a thread of its own, until accepting one fails.
+    pub fn serve(self, listener: TcpListener) {
+        let pop3 = Arc::new(self);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    crate::warn!("POP3 connection failed: {}", e);
+                    continue;
+                }
+            };
+            let pop3 = Arc::clone(&pop3);
+            thread::spawn(move || {
+                if let Err(e) = pop3.handle_stream(&stream) {
+                    crate::debug!("POP3 session failed: {}", e);
+                }
+            });
+        }
+    }
+
+    fn handle_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let peer = stream.peer_addr().map(|peer| peer.ip().to_string());
+        let peer = peer.as_deref().unwrap_or("unknown");
+        self.session(peer, BufReader::new(stream), BufWriter::new(stream))
+    }
+
+    /// Runs one session over `reader` and `writer`, until QUIT or the end of the input.
+    pub fn handle<R: BufRead, W: Write>(&self, reader: R, writer: W) -> io::Result<()> {
+        self.session("unknown", reader, writer)
+    }
+
+    /// Like `handle`, for a client at `peer`.
+    fn session<R: BufRead, W: Write>(
+        &self,
+        peer: &str,
+        mut reader: R,
+        mut writer: W,
+    ) -> io::Result<()> {
+        let mut state = State::Authorization { user: None };
+        writeln_crlf(
+            &mut writer,
+            &format!("+OK {} POP3 server ready", self.server_name),
+        )?;
+        writer.flush()?;
+        loop {
+            let mut line = String::new();
+            if reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !line.ends_with('\n') {
+                writeln_crlf(&mut writer, "-ERR Line too long")?;
+                return writer.flush();
+            }
+            let line = line.trim_end_matches(&['\r', '\n'][..]);
+            let (command, argument) = match line.split_once(' ') {
+                Some((command, argument)) => (command, argument),
+                None => (line, ""),
+            };
+            let command = command.to_uppercase();
+            if command == "QUIT" {
+                let reply = self.quit(&state);
+                writeln_crlf(&mut writer, &reply)?;
+                return writer.flush();
+            }
+            let reply = match &mut state {
+                State::Authorization { user } => {
+                    let (reply, messages) = self.authorize(peer, user, &command, argument);
+                    if let Some(messages) = messages {
+                        state = State::Transaction { messages };
+                    }
+                    reply
+                }
+                State::Transaction { messages } => transact(messages, &command, argument),
+            };
+            writeln_crlf(&mut writer, &reply)?;
+            writer.flush()?;
+        }
+    }
+
+    /// Handles a command before the client logged in, returning the reply, and the
+    /// messages found once it did.
+    fn authorize(
+        &self,
+        peer: &str,
+        user: &mut Option<String>,
+        command: &str,
+        argument: &str,
+    ) -> (String, Option<Vec<Message>>) {
+        let reply = match command {
+            "CAPA" => capabilities(),
+            "USER" if !argument.is_empty() => {
+                *user = Some(String::from(argument));
+                String::from("+OK")
+            }
+            "PASS" => {
+                let name = match user.take() {
+                    Some(name) => name,
+                    None => return (String::from("-ERR USER first"), None),
+                };
+                match self.auth.authenticate(&name, argument) {
+                    Ok(true) => {
+                        crate::info!("POP3 login as {}", name);
+                        let messages = self.messages();
+                        return (format!("+OK {} messages", messages.len()), Some(messages));
+                    }
+                    Ok(false) => {
+                        crate::info!("POP3 login as {} refused", name);
+                        let reply = String::from("-ERR [AUTH] Invalid user name or password");
+                        if let Some(audit) = &self.audit {
+                            let fields = [("service", &"pop3" as &dyn Display), ("user", &name)];
+                            audit.record(AuditEvent::AuthFailed, peer, "-", &fields, &reply);
+                        }
+                        reply
+                    }
+                    Err(e) => {
+                        crate::warn!("Unable to check the password of {}: {}", name, e);
+                        String::from("-ERR [SYS/TEMP] Try again later")
+                    }
+                }
+            }
+            "NOOP" => String::from("+OK"),
+            _ => String::from("-ERR Log in first"),
+        };
+        (reply, None)
+    }
+
+    fn messages(&self) -> Vec<Message> {
+        self.store
+            .list()
+            .into_iter()
+            .map(|stored| {
+                let mut text = String::new();
+                for line in stored.mail.data.as_deref().unwrap_or("").lines() {
+                    text.push_str(line);
+                    text.push_str("\r\n");
+                }
+                Message {
+                    stored,
+                    text,
+                    deleted: false,
+                }
+            })
+            .collect()
+    }
+
+    /// Deletes the messages marked, once logged in.
+    fn quit(&self, state: &State) -> String {
+        let messages = match state {
+            State::Authorization { .. } => return format!("+OK {} signing off", self.server_name),
+            State::Transaction { messages } => messages,
+        };
+        let mut deleted = 0;
+        for message in messages.iter().filter(|message| message.deleted) {
+            if self.store.delete(&message.stored.id) {
+                deleted += 1;
+            }
+        }
+        format!(
+            "+OK {} signing off ({} messages deleted)",
+            self.server_name, deleted
+        )
+    }
+}
+
+/// Handles a command once logged in, returning the reply, lines of a multi-line one
+/// separated and ended by CRLF but for the last.
+fn transact(messages: &mut [Message], command: &str, argument: &str) -> String {
+    let listing = |messages: &[Message], line: &dyn Fn(usize, &Message) -> String| {
+        if !argument.is_empty() {
+            return match message(messages, argument) {
+                Ok(number) => format!("+OK {}", line(number, &messages[number - 1])),
+                Err(reply) => reply,
+            };
+        }
+        let mut reply = String::from("+OK\r\n");
+        for (index, message) in messages.iter().enumerate() {
+            if !message.deleted {
+                reply.push_str(&line(index + 1, message));
+                reply.push_str("\r\n");
+            }
+        }
+        reply.push('.');
+        reply
+    };
+    match command {
+        "CAPA" => capabilities(),
+        "STAT" => {
+            let present = messages.iter().filter(|message| !message.deleted);
+            let (count, size) = present.fold((0, 0), |(count, size), message| {
+                (count + 1, size + message.text.len())
+            });
+            format!("+OK {} {}", count, size)
+        }
+        "LIST" => listing(messages, &|number, message| {
+            format!("{} {}", number, message.text.len())
+        }),
+        "UIDL" => listing(messages, &|number, message| {
+            format!("{} {}", number, message.stored.id)
+        }),
+        "RETR" => match message(messages, argument) {
+            Ok(number) => {
+                let text = &messages[number - 1].text;
+                let mut reply = format!("+OK {} octets\r\n", text.len());
+                for line in text.lines() {
+                    if line.starts_with('.') {
+                        reply.push('.');
+                    }
+                    reply.push_str(line);
+                    reply.push_str("\r\n");
+                }
+                reply.push('.');
+                reply
+            }
+            Err(reply) => reply,
+        },
+        "DELE" => match message(messages, argument) {
+            Ok(number) => {
+                messages[number - 1].deleted = true;
+                format!("+OK message {} deleted", number)
+            }
+            Err(reply) => reply,
+        },
+        "RSET" => {
+            for message in messages.iter_mut() {
+                message.deleted = false;
+            }
+            format!("+OK {} messages", messages.len())
+        }
+        "NOOP" => String::from("+OK"),
+        "USER" | "PASS" => String::from("-ERR Already logged in"),
+        _ => String::from("-ERR Unknown command"),
+    }
+}
+
+/// The number of the message `argument` names, if it is there and not deleted.
+fn message(messages: &[Message], argument: &str) -> Result<usize, String> {
+    match argument.trim().parse::<usize>() {
+        Ok(number) if number >= 1 && number <= messages.len() => {
+            if messages[number - 1].deleted {
+                Err(format!("-ERR message {} already deleted", number))
+            } else {
+                Ok(number)
+            }
+        }
+        Ok(number) => Err(format!("-ERR no message {}", number)),
+        Err(_) => Err(String::from("-ERR invalid message number")),
+    }
+}
+
+fn capabilities() -> String {
+    String::from("+OK Capability list follows\r\nUSER\r\nUIDL\r\n.")
+}
+
+fn writeln_crlf<W: Write>(writer: &mut W, line: &str) -> io::Result<()> {
+    writer.write_all(line.as_bytes())?;
+    writer.write_all(b"\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Passwords, email::Mail, store::MemoryStore};
+
+    fn session(pop3: &Pop3, commands: &str) -> String {
+        let mut output = Vec::new();
+        pop3.handle(commands.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let store = Arc::new(MemoryStore::new(10));
+        let mut mail = Mail::new();
+        mail.data = Some(String::from("Subject: one\r\n\r\n.hidden\r\n"));
+        store.save(&mail).unwrap();
+        mail.data = Some(String::from("Subject: two\r\n"));
+        store.save(&mail).unwrap();
+        let passwords = Passwords::new(&[("alice", "s3cret")]);
+        let pop3 = Pop3::new(store.clone(), Arc::new(passwords)).server_name("mx.example.com");
+
+        let output = session(&pop3, "STAT\r\nUSER alice\r\nPASS wrong\r\nPASS s3cret\r\n");
+        assert_eq!(
+            output,
+            "+OK mx.example.com POP3 server ready\r\n\
+             -ERR Log in first\r\n\
+             +OK\r\n\
+             -ERR [AUTH] Invalid user name or password\r\n\
+             -ERR USER first\r\n"
+        );
+
+        let output = session(
+            &pop3,
+            "user alice\r\npass s3cret\r\nSTAT\r\nLIST\r\nUIDL 2\r\nRETR 1\r\nDELE 2\r\n\
+             DELE 2\r\nLIST 2\r\nQUIT\r\n",
+        );
+        assert_eq!(
+            output,
+            "+OK mx.example.com POP3 server ready\r\n\
+             +OK\r\n\
+             +OK 2 messages\r\n\
+             +OK 2 39\r\n\
+             +OK\r\n1 25\r\n2 14\r\n.\r\n\
+             +OK 2 2\r\n\
+             +OK 25 octets\r\nSubject: one\r\n\r\n..hidden\r\n.\r\n\
+             +OK message 2 deleted\r\n\
+             -ERR message 2 already deleted\r\n\
+             -ERR message 2 already deleted\r\n\
+             +OK mx.example.com signing off (1 messages deleted)\r\n"
+        );
+        let ids: Vec<String> = store.list().into_iter().map(|stored| stored.id).collect();
+        assert_eq!(ids, ["1"]);
+
+        // Without QUIT, nothing is deleted.
+        session(&pop3, "USER alice\r\nPASS s3cret\r\nDELE 1\r\n");
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn test_audit() {
+        let path = std::env::temp_dir().join(format!("pop3-audit-{}.log", std::process::id()));
+        let audit = AuditLog::to_file(path.to_str().unwrap(), crate::log::Rotation::Never, 0);
+        let passwords = Passwords::new(&[("alice", "s3cret")]);
+        let pop3 = Pop3::new(Arc::new(MemoryStore::new(10)), Arc::new(passwords))
+            .audit(Arc::new(audit.unwrap()));
+        session(
+            &pop3,
+            "USER alice\r\nPASS wrong\r\nUSER alice\r\nPASS s3cret\r\n",
+        );
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(audit.lines().count(), 1);
+        assert!(audit.contains(
+            " audit event=auth_failed peer=unknown session=- service=pop3 user=alice \
+             reason=\"-ERR [AUTH] Invalid user name or password\""
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
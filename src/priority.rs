@@ -0,0 +1,39 @@
+/// The priority assignment policies of RFC 6710, named after MT-PRIORITY in the EHLO reply
+/// so that clients know how priorities map onto their own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriorityPolicy {
+    Mixer,
+    Stanag4406,
+    Nsep,
+}
+
+impl PriorityPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PriorityPolicy::Mixer => "MIXER",
+            PriorityPolicy::Stanag4406 => "STANAG4406",
+            PriorityPolicy::Nsep => "NSEP",
+        }
+    }
+}
+
+/// Reads the value of an `MT-PRIORITY` parameter, from -9 (lowest) to 9 (highest).
+pub fn parse(value: &str) -> Option<i8> {
+    value
+        .parse()
+        .ok()
+        .filter(|priority: &i8| (-9..=9).contains(priority))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("-4"), Some(-4));
+        assert_eq!(parse("+9"), Some(9));
+        assert_eq!(parse("10"), None);
+        assert_eq!(parse("high"), None);
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clock,
+    config::Config,
+    datetime,
+    email::{self, Mail},
+    json::{FromJson, Json, ToJson},
+};
+
+/// Where messages a check flagged, see `Verdict::Quarantine`, are kept instead of being
+/// delivered, until they are released to the outputs or purged. Each is a file of its own
+/// in the directory, `<queue id>.json`, holding the message as `Mail::to_json` writes it,
+/// the reason and when it was quarantined, so that it survives restarts.
+pub struct Quarantine {
+    directory: PathBuf,
+}
+
+/// A message in quarantine.
+#[derive(Clone, Debug)]
+pub struct QuarantinedMail {
+    pub queue_id: String,
+    /// What the check that flagged it said, e.g. `spam score 12.5`.
+    pub reason: String,
+    pub quarantined_at: SystemTime,
+    pub mail: Mail,
+}
+
+impl ToJson for QuarantinedMail {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("queue_id", Json::string(&self.queue_id)),
+            ("reason", Json::string(&self.reason)),
+            (
+                "quarantined_at",
+                Json::string(&datetime::rfc3339(self.quarantined_at)),
+            ),
+            (
+                "quarantined_at_unix",
+                Json::Number(datetime::unix_seconds(self.quarantined_at) as i64),
+            ),
+            ("mail", self.mail.to_json()),
+        ])
+    }
+}
+
+impl FromJson for QuarantinedMail {
+    fn from_json(json: &Json) -> Option<QuarantinedMail> {
+        let seconds = json.get("quarantined_at_unix")?.as_i64()?;
+        Some(QuarantinedMail {
+            queue_id: String::from(json.get("queue_id")?.as_str()?),
+            reason: String::from(json.get("reason")?.as_str()?),
+            quarantined_at: UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64),
+            mail: Mail::from_json(json.get("mail")?)?,
+        })
+    }
+}
+
+impl Quarantine {
+    /// Opens the quarantine in `directory`, creating it if need be.
+    pub fn open<P: AsRef<Path>>(directory: P) -> io::Result<Quarantine> {
+        fs::create_dir_all(directory.as_ref())?;
+        Ok(Quarantine {
+            directory: directory.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Keeps `mail`, which has its queue ID, for `reason`, returning once it is on disk.
+    pub fn add(&self, mail: &Mail, reason: &str) -> io::Result<()> {
+        let queue_id = mail.queue_id.clone().unwrap_or_default();
+        let path = self.path(&queue_id)?;
+        let quarantined = QuarantinedMail {
+            queue_id,
+            reason: String::from(reason),
+            quarantined_at: clock::now(),
+            mail: mail.clone(),
+        };
+        let written = path.with_extension("new");
+        let mut file = File::create(&written)?;
+        file.write_all(quarantined.to_json().to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&written, &path)
+    }
+
+    /// The messages in quarantine, oldest first. Files that cannot be read are skipped.
+    pub fn list(&self) -> io::Result<Vec<QuarantinedMail>> {
+        let mut quarantined = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            match read(&path) {
+                Ok(mail) => quarantined.push(mail),
+                Err(e) => crate::warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+        quarantined.sort_by_key(|mail| mail.quarantined_at);
+        Ok(quarantined)
+    }
+
+    /// The message quarantined as `queue_id`, if any.
+    pub fn get(&self, queue_id: &str) -> io::Result<Option<QuarantinedMail>> {
+        match read(&self.path(queue_id)?) {
+            Ok(mail) => Ok(Some(mail)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hands the message quarantined as `queue_id` to the outputs of `config` its
+    /// recipients route to, without checking it again, and drops it from the quarantine
+    /// once they took it. False if there is no such message.
+    pub fn release(&self, queue_id: &str, config: &Config) -> io::Result<bool> {
+        let quarantined = match self.get(queue_id)? {
+            Some(quarantined) => quarantined,
+            None => return Ok(false),
+        };
+        email::deliver(config, &quarantined.mail, queue_id)?;
+        crate::info!("Released {} from quarantine", queue_id);
+        self.purge(queue_id)
+    }
+
+    /// Deletes the message quarantined as `queue_id`. False if there is no such message.
+    pub fn purge(&self, queue_id: &str) -> io::Result<bool> {
+        match fs::remove_file(self.path(queue_id)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes the messages quarantined longer than `age` ago, returning how many.
+    pub fn purge_older_than(&self, age: Duration) -> io::Result<usize> {
+        let now = clock::now();
+        let mut purged = 0;
+        for quarantined in self.list()? {
+            let elapsed = now
+                .duration_since(quarantined.quarantined_at)
+                .unwrap_or_default();
+            if elapsed > age && self.purge(&quarantined.queue_id)? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    /// The file of `queue_id`, which must be a queue ID rather than a path.
+    fn path(&self, queue_id: &str) -> io::Result<PathBuf> {
+        if queue_id.is_empty() || !queue_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid queue ID {}", queue_id),
+            ));
+        }
+        Ok(self.directory.join(format!("{}.json", queue_id)))
+    }
+}
+
+fn read(path: &Path) -> io::Result<QuarantinedMail> {
+    let text = fs::read_to_string(path)?;
+    Json::parse(&text)
+        .ok()
+        .as_ref()
+        .and_then(QuarantinedMail::from_json)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid quarantine entry"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+    use std::sync::{Arc, Mutex};
+
+    struct Collect(Arc<Mutex<Vec<Mail>>>);
+
+    impl Output for Collect {
+        fn deliver(&self, mail: &Mail) -> io::Result<()> {
+            self.0.lock().unwrap().push(mail.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let directory = std::env::temp_dir().join(format!("quarantine-{}", std::process::id()));
+        let quarantine = Quarantine::open(&directory).unwrap();
+        let mut mail = Mail::new();
+        mail.queue_id = Some(String::from("4j5RNq31Gc8cgV"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from("Subject: cheap\r\n\r\nbuy\r\n"));
+        quarantine.add(&mail, "spam score 12.5").unwrap();
+        mail.queue_id = Some(String::from("4j5RNq31Gc8cgW"));
+        quarantine.add(&mail, "virus Eicar-Test-Signature").unwrap();
+
+        let listed = quarantine.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        let first = quarantine.get("4j5RNq31Gc8cgV").unwrap().unwrap();
+        assert_eq!(first.reason, "spam score 12.5");
+        assert_eq!(first.mail.data, mail.data);
+        assert!(quarantine.get("../journal").is_err());
+
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut config = Config::default();
+        config
+            .outputs
+            .push(Box::new(Collect(Arc::clone(&delivered))));
+        assert!(quarantine.release("4j5RNq31Gc8cgV", &config).unwrap());
+        assert!(!quarantine.release("4j5RNq31Gc8cgV", &config).unwrap());
+        assert_eq!(delivered.lock().unwrap().len(), 1);
+
+        assert_eq!(
+            quarantine
+                .purge_older_than(Duration::from_secs(3600))
+                .unwrap(),
+            0
+        );
+        assert!(quarantine.purge("4j5RNq31Gc8cgW").unwrap());
+        assert!(quarantine.list().unwrap().is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
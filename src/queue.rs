@@ -0,0 +1,108 @@
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::UNIX_EPOCH,
+};
+
+use crate::{clock, trace};
+
+/// Starts delivering the mail held for a node named in ETRN (RFC 1985): a domain such as
+/// `example.com`, `@example.com` for it and its subdomains, or `#name` for a queue. Returns
+/// how many messages are waiting, so that the client can be told when there are none.
+pub trait QueueFlush: Send + Sync {
+    fn flush(&self, node: &str) -> io::Result<usize>;
+}
+
+impl<F> QueueFlush for F
+where
+    F: Fn(&str) -> io::Result<usize> + Send + Sync,
+{
+    fn flush(&self, node: &str) -> io::Result<usize> {
+        self(node)
+    }
+}
+
+/// What a hold stops delivery of, see `QueueHold`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Hold {
+    /// The message queued with this ID.
+    Message(String),
+    /// Every message from this sender domain, lowercased, including those queued later.
+    Sender(String),
+}
+
+impl fmt::Display for Hold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Hold::Message(queue_id) => write!(f, "message {}", queue_id),
+            Hold::Sender(domain) => write!(f, "sender domain {}", domain),
+        }
+    }
+}
+
+/// Stops and resumes delivery of queued messages, e.g. while an incident is looked into.
+/// Held messages are not attempted until released, the messages a worker took are
+/// delivered all the same. Both return how many messages the hold applies to.
+pub trait QueueHold: Send + Sync {
+    fn hold(&self, hold: &Hold) -> io::Result<usize>;
+
+    /// Lifts `hold`; messages another hold applies to stay held.
+    fn release(&self, hold: &Hold) -> io::Result<usize>;
+
+    /// The holds in place, with how many messages each applies to.
+    fn holds(&self) -> Vec<(Hold, usize)>;
+}
+
+/// The digits of queue IDs, without vowels so that no words come up.
+const DIGITS: &[u8] = b"0123456789BCDFGHJKLMNPQRSTVWXYZbcdfghjklmnpqrstvwxyz";
+
+/// A new queue ID, unique and sorting by the time it was made in, like Postfix's long
+/// queue IDs: 6 digits of seconds, 4 of microseconds and 4 of a sequence starting at a
+/// random number.
+pub fn new_queue_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    static START: OnceLock<u64> = OnceLock::new();
+    let since_epoch = clock::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let start = *START.get_or_init(trace::random_u64);
+    let sequence = start.wrapping_add(SEQUENCE.fetch_add(1, Ordering::Relaxed));
+    let mut id = String::with_capacity(14);
+    push_digits(&mut id, since_epoch.as_secs(), 6);
+    push_digits(&mut id, u64::from(since_epoch.subsec_micros()), 4);
+    push_digits(&mut id, sequence, 4);
+    id
+}
+
+/// Appends the last `count` digits of `value`, most significant first.
+fn push_digits(id: &mut String, mut value: u64, count: u32) {
+    let base = DIGITS.len() as u64;
+    let mut digits = [0u8; 6];
+    for digit in digits[..count as usize].iter_mut().rev() {
+        *digit = DIGITS[(value % base) as usize];
+        value /= base;
+    }
+    id.extend(
+        digits[..count as usize]
+            .iter()
+            .map(|digit| char::from(*digit)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_queue_id() {
+        let first = new_queue_id();
+        let second = new_queue_id();
+        assert_eq!(first.len(), 14);
+        assert_ne!(first, second);
+        assert!(first.bytes().all(|byte| DIGITS.contains(&byte)));
+        let mut id = String::new();
+        push_digits(&mut id, 52 * 52 + 1, 4);
+        assert_eq!(id, "0101");
+    }
+}
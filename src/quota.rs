@@ -0,0 +1,379 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::{clock, dsn, retry, store::MessageStore};
+
+/// What a quota counts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counted {
+    Messages,
+    Recipients,
+}
+
+/// At most `limit` messages or recipients from one identity within `window`, e.g.
+/// `100 messages/1h` or `1000 recipients/1d`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quota {
+    pub limit: u32,
+    pub counted: Counted,
+    pub window: Duration,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Quota, String> {
+        let invalid = || {
+            format!(
+                "invalid quota {}, expected e.g. 100 messages/1h or 500 recipients/1d",
+                value
+            )
+        };
+        let (count, window) = value.split_once('/').ok_or_else(invalid)?;
+        let (limit, counted) = count.trim().split_once(' ').ok_or_else(invalid)?;
+        Ok(Quota {
+            limit: limit.parse().map_err(|_| invalid())?,
+            counted: match counted.trim() {
+                "messages" => Counted::Messages,
+                "recipients" => Counted::Recipients,
+                _ => return Err(invalid()),
+            },
+            window: retry::parse_duration(window)?,
+        })
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counted = match self.counted {
+            Counted::Messages => "messages",
+            Counted::Recipients => "recipients",
+        };
+        let seconds = self.window.as_secs();
+        let (number, unit) = [(86400, "d"), (3600, "h"), (60, "m")]
+            .iter()
+            .find(|(size, _)| seconds > 0 && seconds.is_multiple_of(*size))
+            .map_or((seconds, "s"), |(size, unit)| (seconds / size, *unit));
+        write!(f, "{} {}/{}{}", self.limit, counted, number, unit)
+    }
+}
+
+/// Limits what each authenticated client may send, so that a stolen password only sends
+/// so much mail. Messages accepted are counted against the identity
+/// the client logged in as, and a client that would go over a `Quota` is told to try
+/// again later. Anonymous clients are not limited here.
+///
+/// The counts are kept in memory, across configuration reloads which replace the quotas.
+#[derive(Debug, Default)]
+pub struct SenderQuotas {
+    quotas: RwLock<Vec<Quota>>,
+    /// When each identity had a message accepted within the longest window, and for how
+    /// many recipients.
+    sent: Mutex<HashMap<String, Vec<(SystemTime, u32)>>>,
+}
+
+impl SenderQuotas {
+    pub fn new(quotas: Vec<Quota>) -> SenderQuotas {
+        SenderQuotas {
+            quotas: RwLock::new(quotas),
+            sent: Mutex::default(),
+        }
+    }
+
+    /// Enforces `quotas` from now on, counting what was sent before.
+    pub fn set_quotas(&self, quotas: Vec<Quota>) {
+        *self.quotas.write().unwrap() = quotas;
+    }
+
+    pub fn quotas(&self) -> Vec<Quota> {
+        self.quotas.read().unwrap().clone()
+    }
+
+    /// The quota `identity` would go over with one more message to `recipients`
+    /// recipients, if any.
+    pub fn exceeded(&self, identity: &str, recipients: u32) -> Option<Quota> {
+        let quotas = self.quotas.read().unwrap();
+        if quotas.is_empty() {
+            return None;
+        }
+        let now = clock::now();
+        let sent = self.sent.lock().unwrap();
+        let sent = sent
+            .get(&identity.to_lowercase())
+            .map_or(&[][..], Vec::as_slice);
+        quotas
+            .iter()
+            .find(|quota| {
+                let within = sent
+                    .iter()
+                    .filter(|(at, _)| now.duration_since(*at).unwrap_or_default() < quota.window);
+                let (used, adding) = match quota.counted {
+                    Counted::Messages => (within.count() as u32, 1),
+                    Counted::Recipients => (within.map(|(_, count)| count).sum(), recipients),
+                };
+                used.saturating_add(adding) > quota.limit
+            })
+            .cloned()
+    }
+
+    /// Counts a message accepted from `identity` for `recipients` recipients.
+    pub fn record(&self, identity: &str, recipients: u32) {
+        let longest = match self.quotas.read().unwrap().iter().map(|q| q.window).max() {
+            Some(longest) => longest,
+            None => return,
+        };
+        let now = clock::now();
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, times| {
+            times.retain(|(at, _)| now.duration_since(*at).unwrap_or_default() < longest);
+            !times.is_empty()
+        });
+        sent.entry(identity.to_lowercase())
+            .or_default()
+            .push((now, recipients));
+    }
+}
+
+/// How many bytes each mailbox may hold: those listed their own, the others `default`,
+/// unlimited when `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MailboxLimits {
+    pub default: Option<u64>,
+    /// By address, lowercased and without angle brackets.
+    pub mailboxes: HashMap<String, u64>,
+}
+
+impl MailboxLimits {
+    /// Reads `50M`, the default, or `alice@example.com 1G`, the limit of one mailbox.
+    pub fn add(&mut self, value: &str) -> Result<(), String> {
+        match value.trim().rsplit_once(' ') {
+            Some((mailbox, size)) => {
+                self.mailboxes
+                    .insert(mailbox_of(mailbox), parse_size(size)?);
+            }
+            None => self.default = Some(parse_size(value)?),
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.mailboxes.is_empty()
+    }
+
+    pub fn limit(&self, mailbox: &str) -> Option<u64> {
+        self.mailboxes
+            .get(&mailbox_of(mailbox))
+            .copied()
+            .or(self.default)
+    }
+}
+
+/// What a mailbox holds, as `MailboxQuotas::usage` tells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MailboxUsage {
+    pub mailbox: String,
+    /// The bytes of the messages for it.
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+/// Limits how much of a local store the messages of each recipient may take, so that one
+/// mailbox cannot fill it: a message is refused with 552 for a recipient whose messages
+/// already take their limit, at RCPT TO, or would with it, at the end of DATA.
+///
+/// The usage of a mailbox is counted from the store, every message listing the recipient
+/// counting its whole size, so that deleting messages frees the space at once. Reloading
+/// replaces the limits.
+pub struct MailboxQuotas {
+    store: Arc<dyn MessageStore>,
+    limits: RwLock<MailboxLimits>,
+}
+
+impl MailboxQuotas {
+    pub fn new(store: Arc<dyn MessageStore>, limits: MailboxLimits) -> MailboxQuotas {
+        MailboxQuotas {
+            store,
+            limits: RwLock::new(limits),
+        }
+    }
+
+    pub fn set_limits(&self, limits: MailboxLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn limit(&self, mailbox: &str) -> Option<u64> {
+        self.limits.read().unwrap().limit(mailbox)
+    }
+
+    /// The bytes the messages for `mailbox` take in the store.
+    pub fn used(&self, mailbox: &str) -> u64 {
+        let mailbox = mailbox_of(mailbox);
+        self.store
+            .list()
+            .iter()
+            .filter(|stored| {
+                let recipients = stored.mail.rcpt_to.iter();
+                recipients
+                    .map(|rcpt| mailbox_of(rcpt))
+                    .any(|rcpt| rcpt == mailbox)
+            })
+            .map(|stored| stored.mail.data.as_ref().map_or(0, String::len) as u64)
+            .sum()
+    }
+
+    /// Whether a message of `size` bytes for `mailbox` stays within its limit; `size` 0
+    /// tells whether there is room left at all.
+    pub fn fits(&self, mailbox: &str, size: u64) -> bool {
+        match self.limit(mailbox) {
+            Some(limit) if size == 0 => self.used(mailbox) < limit,
+            Some(limit) => self.used(mailbox).saturating_add(size) <= limit,
+            None => true,
+        }
+    }
+
+    /// What each mailbox with messages in the store or a limit of its own holds, by
+    /// address.
+    pub fn usage(&self) -> Vec<MailboxUsage> {
+        let limits = self.limits.read().unwrap().clone();
+        let mut used: BTreeMap<String, u64> = limits
+            .mailboxes
+            .keys()
+            .map(|mailbox| (mailbox.clone(), 0))
+            .collect();
+        for stored in self.store.list() {
+            let size = stored.mail.data.as_ref().map_or(0, String::len) as u64;
+            for rcpt in stored.mail.rcpt_to.iter().filter(|rcpt| !rcpt.is_empty()) {
+                *used.entry(mailbox_of(rcpt)).or_default() += size;
+            }
+        }
+        used.into_iter()
+            .map(|(mailbox, used)| MailboxUsage {
+                limit: limits.limit(&mailbox),
+                mailbox,
+                used,
+            })
+            .collect()
+    }
+}
+
+/// The address `rcpt` names, lowercased, without angle brackets or ESMTP parameters.
+fn mailbox_of(rcpt: &str) -> String {
+    let (address, _) = dsn::split_parameters(rcpt.trim());
+    address
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_lowercase()
+}
+
+/// A size such as `512`, `100K`, `50M` or `2G`, in bytes by 1024.
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiple) = match value.char_indices().last() {
+        Some((index, 'K')) | Some((index, 'k')) => (&value[..index], 1 << 10),
+        Some((index, 'M')) | Some((index, 'm')) => (&value[..index], 1 << 20),
+        Some((index, 'G')) | Some((index, 'g')) => (&value[..index], 1 << 30),
+        _ => (value, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiple))
+        .ok_or_else(|| format!("invalid size {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota() {
+        let quota: Quota = "100 messages/1h".parse().unwrap();
+        assert_eq!(
+            quota,
+            Quota {
+                limit: 100,
+                counted: Counted::Messages,
+                window: Duration::from_secs(3600),
+            }
+        );
+        assert_eq!(quota.to_string(), "100 messages/1h");
+        assert!("100 letters/1h".parse::<Quota>().is_err());
+        assert!("100 messages".parse::<Quota>().is_err());
+        assert!("many messages/1h".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn test_sender_quotas() {
+        let quotas = SenderQuotas::new(vec![
+            "2 messages/1h".parse().unwrap(),
+            "5 recipients/1d".parse().unwrap(),
+        ]);
+        assert_eq!(quotas.exceeded("alice", 5), None);
+        assert_eq!(
+            quotas.exceeded("alice", 6).map(|quota| quota.counted),
+            Some(Counted::Recipients)
+        );
+        quotas.record("Alice", 3);
+        assert_eq!(quotas.exceeded("alice", 2), None);
+        assert!(quotas.exceeded("alice", 3).is_some());
+        quotas.record("alice", 1);
+        assert_eq!(
+            quotas.exceeded("alice", 1).map(|quota| quota.counted),
+            Some(Counted::Messages)
+        );
+        assert_eq!(quotas.exceeded("bob", 1), None);
+
+        quotas.set_quotas(Vec::new());
+        assert_eq!(quotas.exceeded("alice", 100), None);
+    }
+
+    #[test]
+    fn test_mailbox_quotas() {
+        use crate::{email::Mail, store::MemoryStore};
+
+        let mut limits = MailboxLimits::default();
+        limits.add("20").unwrap();
+        limits.add("<Bob@example.com> 1K").unwrap();
+        assert!(limits.add("alice@example.com lots").is_err());
+        assert_eq!(parse_size("50M"), Ok(50 << 20));
+
+        let store = Arc::new(MemoryStore::new(10));
+        let quotas = MailboxQuotas::new(store.clone(), limits);
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![
+            String::from("<alice@example.com> NOTIFY=NEVER"),
+            String::from("<bob@example.com>"),
+        ];
+        mail.data = Some(String::from("0123456789"));
+        store.save(&mail).unwrap();
+        assert_eq!(quotas.used("ALICE@example.com"), 10);
+        assert!(quotas.fits("alice@example.com", 10));
+        assert!(!quotas.fits("alice@example.com", 11));
+        assert!(quotas.fits("bob@example.com", 1000));
+        store.save(&mail).unwrap();
+        assert!(!quotas.fits("<alice@example.com>", 0));
+        assert_eq!(
+            quotas.usage(),
+            vec![
+                MailboxUsage {
+                    mailbox: String::from("alice@example.com"),
+                    used: 20,
+                    limit: Some(20),
+                },
+                MailboxUsage {
+                    mailbox: String::from("bob@example.com"),
+                    used: 20,
+                    limit: Some(1024),
+                },
+            ]
+        );
+        quotas.set_limits(MailboxLimits::default());
+        assert!(quotas.fits("alice@example.com", 1000));
+    }
+}
@@ -0,0 +1,70 @@
+use std::{collections::HashSet, fs, io};
+
+/// Tells whether a recipient exists, so that unknown users are refused at RCPT TO rather
+/// than accepted and dropped. An error defers the recipient.
+pub trait RecipientVerifier: Send + Sync {
+    fn verify(&self, local_part: &str, domain: Option<&str>) -> io::Result<bool>;
+}
+
+/// A fixed set of recipients, each either `user@domain` or a bare `user` that exists in
+/// every domain. Matching ignores case.
+#[derive(Clone, Debug, Default)]
+pub struct StaticRecipients {
+    addresses: HashSet<String>,
+}
+
+impl StaticRecipients {
+    pub fn new(addresses: &[&str]) -> StaticRecipients {
+        let mut recipients = StaticRecipients::default();
+        for address in addresses.iter() {
+            recipients.add(address);
+        }
+        recipients
+    }
+
+    /// Reads one recipient per line, skipping blank lines and `#` comments.
+    pub fn load(path: &str) -> io::Result<StaticRecipients> {
+        let mut recipients = StaticRecipients::default();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if !line.is_empty() {
+                recipients.add(line);
+            }
+        }
+        Ok(recipients)
+    }
+
+    pub fn add(&mut self, address: &str) {
+        self.addresses.insert(address.trim().to_lowercase());
+    }
+}
+
+impl RecipientVerifier for StaticRecipients {
+    fn verify(&self, local_part: &str, domain: Option<&str>) -> io::Result<bool> {
+        let local_part = local_part.to_lowercase();
+        if self.addresses.contains(&local_part) {
+            return Ok(true);
+        }
+        Ok(domain.is_some_and(|domain| {
+            let address = format!("{}@{}", local_part, domain.to_lowercase());
+            self.addresses.contains(&address)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_recipients() {
+        let recipients = StaticRecipients::new(&["postmaster", "Alice@Example.com"]);
+        assert!(recipients
+            .verify("Postmaster", Some("example.org"))
+            .unwrap());
+        assert!(recipients.verify("postmaster", None).unwrap());
+        assert!(recipients.verify("alice", Some("example.COM")).unwrap());
+        assert!(!recipients.verify("alice", Some("example.org")).unwrap());
+        assert!(!recipients.verify("bob", Some("example.com")).unwrap());
+    }
+}
@@ -0,0 +1,161 @@
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+use crate::{config::Config, error::SmtpError, handle_connection, session::SessionContext};
+
+/// A recorded session, as `transcript` writes it, to be played back against the protocol
+/// engine in process: the `C:` lines are sent as the client sent them and the replies
+/// compared with the `S:` lines, so that a session captured from a real client becomes a
+/// regression test. Run by `simple-smtp replay`.
+///
+/// Queue IDs, which differ on every run, are not compared. DATA a transcript elided is
+/// sent as the `[n bytes elided]` line it left.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Replay {
+    /// What the client sent, line by line, without line endings.
+    pub client: Vec<String>,
+    /// The reply lines recorded, without line endings.
+    pub server: Vec<String>,
+}
+
+/// A reply line that differs from the recorded one; `None` where there is no line on
+/// that side.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Difference {
+    /// Counted from 1 among the reply lines.
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "reply line {}:", self.line)?;
+        writeln!(f, "- {}", self.expected.as_deref().unwrap_or("(none)"))?;
+        write!(f, "+ {}", self.actual.as_deref().unwrap_or("(none)"))
+    }
+}
+
+impl Replay {
+    /// Reads a transcript, skipping blank lines. Any line not starting with `C:` or `S:`
+    /// is an error naming its number.
+    pub fn parse(text: &str) -> Result<Replay, String> {
+        let mut replay = Replay::default();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (side, rest) = line.split_at(line.len().min(2));
+            // The space `transcript` writes after the prefix.
+            let rest = String::from(rest.strip_prefix(' ').unwrap_or(rest));
+            match side {
+                "C:" => replay.client.push(rest),
+                "S:" => replay.server.push(rest),
+                _ => return Err(format!("line {}: expected C: or S:", number + 1)),
+            }
+        }
+        Ok(replay)
+    }
+
+    /// Plays the client side against `config`, as if from `peer`, and returns the reply
+    /// lines that differ from the recorded ones, none when the session went the same way.
+    pub fn run(
+        &self,
+        config: Arc<Config>,
+        peer: Option<SocketAddr>,
+    ) -> Result<Vec<Difference>, SmtpError> {
+        let mut input = String::new();
+        for line in &self.client {
+            input.push_str(line);
+            input.push_str("\r\n");
+        }
+        let mut output = Vec::new();
+        let context = SessionContext::new(peer, None);
+        handle_connection(input.as_bytes(), &mut output, context, config)?;
+        let output = String::from_utf8_lossy(&output);
+        let actual: Vec<&str> = output.lines().collect();
+
+        let mut differences = Vec::new();
+        for line in 0..actual.len().max(self.server.len()) {
+            let expected = self.server.get(line).map(String::as_str);
+            let actual = actual.get(line).copied();
+            if expected.map(mask_queue_id) != actual.map(mask_queue_id) {
+                differences.push(Difference {
+                    line: line + 1,
+                    expected: expected.map(String::from),
+                    actual: actual.map(String::from),
+                });
+            }
+        }
+        Ok(differences)
+    }
+}
+
+/// `line` with the queue ID after `queued as` replaced by `*`.
+fn mask_queue_id(line: &str) -> String {
+    const QUEUED_AS: &str = "queued as ";
+    match line.find(QUEUED_AS) {
+        Some(start) => {
+            let id_start = start + QUEUED_AS.len();
+            let id_end = line[id_start..]
+                .find(char::is_whitespace)
+                .map_or(line.len(), |end| id_start + end);
+            format!("{}*{}", &line[..id_start], &line[id_end..])
+        }
+        None => String::from(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSCRIPT: &str = "S: 220 mx.example.com simple-smtp\n\
+                              C: HELO client\n\
+                              S: 250 mx.example.com\n\
+                              C: MAIL FROM:<a@example.com>\n\
+                              S: 250 Ok\n\
+                              C: RCPT TO:<b@example.com>\n\
+                              S: 250 Ok\n\
+                              C: DATA\n\
+                              S: 354 End data with <CR><LF>.<CR><LF>\n\
+                              C: Subject: hi\n\
+                              C: \n\
+                              C: .\n\
+                              S: 250 Ok: queued as 4j5RNq31Gc8cgV\n\
+                              C: QUIT\n\
+                              S: 221 Bye\n";
+
+    #[test]
+    fn test_parse() {
+        let replay = Replay::parse(TRANSCRIPT).unwrap();
+        assert_eq!(replay.client.len(), 8);
+        assert_eq!(replay.client[5], "");
+        assert_eq!(replay.server[0], "220 mx.example.com simple-smtp");
+        assert_eq!(
+            Replay::parse("C: HELO client\nhello\n").unwrap_err(),
+            "line 2: expected C: or S:"
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        let replay = Replay::parse(TRANSCRIPT).unwrap();
+        let config = Arc::new(Config::new(String::from("mx.example.com")));
+        assert_eq!(replay.run(Arc::clone(&config), None).unwrap(), Vec::new());
+
+        let other = Arc::new(Config::new(String::from("other.example.com")));
+        let differences = replay.run(other, None).unwrap();
+        assert_eq!(differences.len(), 2);
+        assert_eq!(
+            differences[0].to_string(),
+            "reply line 1:\n- 220 mx.example.com simple-smtp\n\
+             + 220 other.example.com simple-smtp"
+        );
+
+        let mut shorter = replay.clone();
+        shorter.server.pop();
+        let differences = shorter.run(config, None).unwrap();
+        assert_eq!(differences[0].expected, None);
+        assert_eq!(differences[0].actual.as_deref(), Some("221 Bye"));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+};
+
+use crate::json::{FromJson, Json, ToJson};
+
+/// An RFC 3463 enhanced status code such as `5.1.1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnhancedCode(pub u8, pub u16, pub u16);
+
+impl EnhancedCode {
+    pub fn parse(value: &str) -> Option<EnhancedCode> {
+        let mut parts = value.split('.');
+        let class = parts.next()?.parse().ok()?;
+        let subject = parts.next()?.parse().ok()?;
+        let detail = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || !(2..=5).contains(&class) {
+            return None;
+        }
+        Some(EnhancedCode(class, subject, detail))
+    }
+}
+
+impl fmt::Display for EnhancedCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// A server reply. `Display` gives the wire format: CRLF line endings, with `-` after the
+/// code on every line but the last and the enhanced code repeated on each line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub code: u16,
+    pub enhanced: Option<EnhancedCode>,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// A one-line reply; a leading enhanced code in `text`, as in `"5.1.1 No such user"`,
+    /// is split off into `enhanced`.
+    pub fn new(code: u16, text: &str) -> Reply {
+        let (enhanced, text) = match text.split_once(' ') {
+            Some((first, rest)) => match EnhancedCode::parse(first) {
+                Some(enhanced) => (Some(enhanced), rest),
+                None => (None, text),
+            },
+            None => (None, text),
+        };
+        Reply {
+            code,
+            enhanced,
+            lines: vec![String::from(text)],
+        }
+    }
+
+    pub fn multiline(code: u16, enhanced: Option<EnhancedCode>, lines: Vec<String>) -> Reply {
+        Reply {
+            code,
+            enhanced,
+            lines,
+        }
+    }
+
+    /// 2xx and 3xx replies.
+    pub fn is_positive(&self) -> bool {
+        self.code < 400
+    }
+
+    /// Writes the wire format to `writer` without building it as a string first.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{}", self)
+    }
+
+    /// The text of all lines, without codes.
+    pub fn text(&self) -> String {
+        self.lines.join(" ")
+    }
+
+    /// Reads a reply in the wire format, as a client does. The enhanced code is split off
+    /// when every line starts with the same one, of the class of the reply code.
+    pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Reply> {
+        let mut code = None;
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the reply",
+                ));
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid reply line {:?}", line),
+                )
+            };
+            let line_code = line
+                .get(..3)
+                .and_then(|digits| digits.parse::<u16>().ok())
+                .filter(|line_code| (200..600).contains(line_code))
+                .ok_or_else(invalid)?;
+            if *code.get_or_insert(line_code) != line_code {
+                return Err(invalid());
+            }
+            lines.push(String::from(line.get(4..).unwrap_or("")));
+            match line.as_bytes().get(3) {
+                Some(b'-') => continue,
+                None | Some(b' ') => break,
+                Some(_) => return Err(invalid()),
+            }
+        }
+        let code = code.unwrap_or_default();
+        let enhanced = lines
+            .first()
+            .and_then(|line| line.split(' ').next())
+            .and_then(EnhancedCode::parse)
+            .filter(|enhanced| u16::from(enhanced.0) == code / 100)
+            .filter(|enhanced| {
+                let prefix = format!("{} ", enhanced);
+                lines.iter().all(|line| line.starts_with(&prefix))
+            });
+        if let Some(enhanced) = enhanced {
+            let prefix = enhanced.to_string().len() + 1;
+            for line in &mut lines {
+                line.replace_range(..prefix, "");
+            }
+        }
+        Ok(Reply {
+            code,
+            enhanced,
+            lines,
+        })
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.lines.len().saturating_sub(1);
+        for (i, line) in self.lines.iter().enumerate() {
+            let separator = if i == last { ' ' } else { '-' };
+            match &self.enhanced {
+                Some(enhanced) => write!(f, "{}{}{} {}\r\n", self.code, separator, enhanced, line)?,
+                None => write!(f, "{}{}{}\r\n", self.code, separator, line)?,
+            }
+        }
+        if self.lines.is_empty() {
+            write!(f, "{}\r\n", self.code)?;
+        }
+        Ok(())
+    }
+}
+
+impl ToJson for Reply {
+    fn to_json(&self) -> Json {
+        Json::object(vec![
+            ("code", Json::Number(self.code.into())),
+            (
+                "enhanced",
+                Json::optional(&self.enhanced.map(|enhanced| enhanced.to_string())),
+            ),
+            (
+                "lines",
+                Json::Array(self.lines.iter().map(|line| Json::string(line)).collect()),
+            ),
+        ])
+    }
+}
+
+impl FromJson for Reply {
+    fn from_json(json: &Json) -> Option<Reply> {
+        let code = json.get("code")?.as_i64()?;
+        if !(100..1000).contains(&code) {
+            return None;
+        }
+        let enhanced = match json
+            .get("enhanced")
+            .map_or(Some(None), Json::as_optional_string)?
+        {
+            Some(enhanced) => Some(EnhancedCode::parse(&enhanced)?),
+            None => None,
+        };
+        let lines = json
+            .get("lines")?
+            .as_array()?
+            .iter()
+            .map(|line| line.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()?;
+        Some(Reply::multiline(code as u16, enhanced, lines))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_json() {
+        let reply = Reply::multiline(
+            550,
+            EnhancedCode::parse("5.1.1"),
+            vec![String::from("No such user"), String::from("Try again")],
+        );
+        let json = reply.to_json().to_string();
+        assert_eq!(
+            json,
+            "{\"code\":550,\"enhanced\":\"5.1.1\",\"lines\":[\"No such user\",\"Try again\"]}"
+        );
+        assert_eq!(Reply::from_json(&Json::parse(&json).unwrap()), Some(reply));
+        let json = Json::parse("{\"code\":42,\"lines\":[]}").unwrap();
+        assert_eq!(Reply::from_json(&json), None);
+    }
+
+    #[test]
+    fn test_reply() {
+        let reply = Reply::new(550, "5.1.1 No such user");
+        assert_eq!(reply.enhanced, Some(EnhancedCode(5, 1, 1)));
+        assert_eq!(reply.to_string(), "550 5.1.1 No such user\r\n");
+        assert_eq!(Reply::new(250, "Ok").to_string(), "250 Ok\r\n");
+        assert_eq!(
+            Reply::new(250, "1.2.3.4 relayed").to_string(),
+            "250 1.2.3.4 relayed\r\n"
+        );
+
+        let reply = Reply::multiline(
+            250,
+            None,
+            vec![String::from("mx.example.com"), String::from("PIPELINING")],
+        );
+        assert_eq!(
+            reply.to_string(),
+            "250-mx.example.com\r\n250 PIPELINING\r\n"
+        );
+    }
+
+    #[test]
+    fn test_read_from() {
+        let reply = Reply::new(550, "5.1.1 No such user");
+        let wire = reply.to_string();
+        assert_eq!(Reply::read_from(&mut wire.as_bytes()).unwrap(), reply);
+
+        let mut wire = "250-mx.example.com\r\n250 2.0.0 PIPELINING\r\n354 Go ahead\n".as_bytes();
+        let reply = Reply::read_from(&mut wire).unwrap();
+        assert_eq!(reply.enhanced, None);
+        assert_eq!(reply.lines, ["mx.example.com", "2.0.0 PIPELINING"]);
+        assert_eq!(Reply::read_from(&mut wire).unwrap().code, 354);
+        let e = Reply::read_from(&mut wire).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        assert!(Reply::read_from(&mut "250-a\r\n550 b\r\n".as_bytes()).is_err());
+        assert!(Reply::read_from(&mut "hello\r\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    io,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+/// The waits between delivery attempts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backoff {
+    /// Waits these in turn, the last one over and over, e.g. `5m, 15m, 1h`.
+    Intervals(Vec<Duration>),
+    /// Starts with `initial` and multiplies by `factor` up to `max`, e.g.
+    /// `exponential 1m 2 4h`.
+    Exponential {
+        initial: Duration,
+        factor: u32,
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// The wait after the `failures`th failed attempt, counting from 1.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let index = failures.saturating_sub(1);
+        match self {
+            Backoff::Intervals(intervals) => intervals
+                .get(index as usize)
+                .or_else(|| intervals.last())
+                .copied()
+                .unwrap_or_default(),
+            Backoff::Exponential {
+                initial,
+                factor,
+                max,
+            } => factor
+                .checked_pow(index)
+                .and_then(|multiple| initial.checked_mul(multiple))
+                .map_or(*max, |delay| delay.min(*max)),
+        }
+    }
+}
+
+impl FromStr for Backoff {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Backoff, String> {
+        let value = value.trim();
+        if let Some(parameters) = value.strip_prefix("exponential") {
+            let parameters: Vec<&str> = parameters.split_whitespace().collect();
+            if parameters.len() != 3 {
+                return Err(format!(
+                    "expected exponential <initial> <factor> <max>, got {}",
+                    value
+                ));
+            }
+            return Ok(Backoff::Exponential {
+                initial: parse_duration(parameters[0])?,
+                factor: parameters[1]
+                    .parse()
+                    .ok()
+                    .filter(|factor| *factor > 0)
+                    .ok_or_else(|| format!("invalid factor {}", parameters[1]))?,
+                max: parse_duration(parameters[2])?,
+            });
+        }
+        let intervals = value
+            .split(',')
+            .map(parse_duration)
+            .collect::<Result<Vec<Duration>, String>>()?;
+        Ok(Backoff::Intervals(intervals))
+    }
+}
+
+/// A duration such as `30s`, `15m`, `4h` or `5d`; a bare number is seconds.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {}", value))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(format!("invalid duration {}", value)),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+/// Why an attempt failed, as far as the schedule goes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// The other side was reached and answered with a temporary error, e.g. a 4xx reply.
+    Refused,
+    /// It could not be reached, or the connection broke.
+    Connection,
+}
+
+impl Failure {
+    /// Tells connection trouble from the rest of `error`.
+    pub fn of(error: &io::Error) -> Failure {
+        use io::ErrorKind::*;
+        match error.kind() {
+            ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected
+            | AddrNotAvailable | BrokenPipe | TimedOut | UnexpectedEof | WouldBlock | NotFound => {
+                Failure::Connection
+            }
+            _ => Failure::Refused,
+        }
+    }
+}
+
+/// How deliveries that failed temporarily are retried, and when they are given up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetrySchedule {
+    /// After a temporary refusal.
+    pub refused: Backoff,
+    /// After a connection failure, which tends to clear up sooner.
+    pub connection: Backoff,
+    /// How long a message may wait for delivery before it is returned to its sender. The
+    /// last attempt is made when it runs out, however long the backoff.
+    pub max_lifetime: Duration,
+    /// How many attempts are made at most, without limit when `None`.
+    pub max_attempts: Option<u32>,
+    /// How long a message may wait before its sender is told it is late, once. Never
+    /// when `None`, as in Postfix by default.
+    pub delay_warning: Option<Duration>,
+}
+
+impl Default for RetrySchedule {
+    /// Every 5 minutes at first, backing off to every 4 hours, for 5 days, as RFC 5321
+    /// suggests.
+    fn default() -> RetrySchedule {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_secs(300),
+            factor: 2,
+            max: Duration::from_secs(4 * 3600),
+        };
+        RetrySchedule {
+            refused: backoff.clone(),
+            connection: backoff,
+            max_lifetime: Duration::from_secs(5 * 86400),
+            max_attempts: None,
+            delay_warning: None,
+        }
+    }
+}
+
+impl RetrySchedule {
+    /// The default lifetime, with `backoff` after any failure.
+    pub fn from_backoff(backoff: Backoff) -> RetrySchedule {
+        RetrySchedule {
+            refused: backoff.clone(),
+            connection: backoff,
+            ..RetrySchedule::default()
+        }
+    }
+
+    /// When to try a message queued at `queued_at` again, after `failures` attempts and
+    /// the last one failing with `failure` at `now`, at the latest when it expires. `None`
+    /// when it is to be given up.
+    pub fn next_attempt(
+        &self,
+        failure: Failure,
+        failures: u32,
+        queued_at: SystemTime,
+        now: SystemTime,
+    ) -> Option<SystemTime> {
+        if self.max_attempts.is_some_and(|max| failures >= max) {
+            return None;
+        }
+        let backoff = match failure {
+            Failure::Refused => &self.refused,
+            Failure::Connection => &self.connection,
+        };
+        let next = now + backoff.delay(failures);
+        let expires = self.expires(queued_at);
+        Some(next.min(expires)).filter(|_| now < expires)
+    }
+
+    /// When a message queued at `queued_at` is given up if not delivered.
+    pub fn expires(&self, queued_at: SystemTime) -> SystemTime {
+        queued_at + self.max_lifetime
+    }
+
+    /// Whether the sender of a message queued at `queued_at` is to be told at `now` that
+    /// it is late, unless they were already.
+    pub fn is_late(&self, queued_at: SystemTime, now: SystemTime) -> bool {
+        self.delay_warning
+            .is_some_and(|warning| now.duration_since(queued_at).unwrap_or_default() >= warning)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_backoff() {
+        let intervals: Backoff = "1m, 5m,1h".parse().unwrap();
+        assert_eq!(intervals.delay(1), Duration::from_secs(60));
+        assert_eq!(intervals.delay(3), Duration::from_secs(3600));
+        assert_eq!(intervals.delay(9), Duration::from_secs(3600));
+
+        let exponential: Backoff = "exponential 1m 3 1h".parse().unwrap();
+        assert_eq!(exponential.delay(1), Duration::from_secs(60));
+        assert_eq!(exponential.delay(3), Duration::from_secs(540));
+        assert_eq!(exponential.delay(5), Duration::from_secs(3600));
+        assert_eq!(exponential.delay(60), Duration::from_secs(3600));
+
+        assert!("5x".parse::<Backoff>().is_err());
+        assert!("exponential 1m 0 1h".parse::<Backoff>().is_err());
+    }
+
+    #[test]
+    fn test_next_attempt() {
+        let schedule = RetrySchedule {
+            refused: "10m".parse().unwrap(),
+            connection: "1m".parse().unwrap(),
+            max_lifetime: Duration::from_secs(3600),
+            max_attempts: Some(5),
+            delay_warning: Some(Duration::from_secs(1800)),
+        };
+        let queued = UNIX_EPOCH;
+        let now = queued + Duration::from_secs(60);
+        assert_eq!(
+            schedule.next_attempt(Failure::Connection, 1, queued, now),
+            Some(now + Duration::from_secs(60))
+        );
+        assert_eq!(
+            schedule.next_attempt(Failure::Refused, 1, queued, now),
+            Some(now + Duration::from_secs(600))
+        );
+        assert_eq!(
+            schedule.next_attempt(Failure::Refused, 5, queued, now),
+            None
+        );
+        // The last attempt is when it expires, after which it is given up.
+        let late = queued + Duration::from_secs(3300);
+        assert_eq!(
+            schedule.next_attempt(Failure::Refused, 2, queued, late),
+            Some(queued + Duration::from_secs(3600))
+        );
+        assert_eq!(
+            schedule.next_attempt(Failure::Refused, 3, queued, schedule.expires(queued)),
+            None
+        );
+        assert!(!schedule.is_late(queued, now));
+        assert!(schedule.is_late(queued, late));
+
+        assert_eq!(
+            Failure::of(&io::Error::from(io::ErrorKind::ConnectionRefused)),
+            Failure::Connection
+        );
+        assert_eq!(
+            Failure::of(&io::Error::other("relay replied 451")),
+            Failure::Refused
+        );
+    }
+}
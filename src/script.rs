@@ -0,0 +1,723 @@
+use std::{fs, io, path::Path};
+
+use crate::{
+    dsn,
+    email::domain_of,
+    handler::Verdict,
+    headers::{HeaderRule, Message},
+    network::Cidr,
+    session::SessionContext,
+    sieve,
+};
+
+/// The step of a session a section of a script runs at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    /// Once the client connected, before the greeting.
+    Connect,
+    Mail,
+    Rcpt,
+    /// At the end of DATA, once the handler accepted the message.
+    Data,
+}
+
+/// What a script can see of the session and transaction.
+pub struct Scope<'a> {
+    pub context: &'a SessionContext,
+    /// What the client greeted with, empty before.
+    pub helo: &'a str,
+    /// The sender, as given in MAIL FROM.
+    pub from: &'a str,
+    /// The recipient given in the RCPT TO being checked.
+    pub rcpt: &'a str,
+    /// The recipients accepted so far.
+    pub recipients: &'a [String],
+    /// The message, at the end of DATA.
+    pub data: Option<&'a str>,
+}
+
+/// The variables a script may read, see `Scope::variable`.
+const VARIABLES: &[&str] = &[
+    "session_id",
+    "client.ip",
+    "client.name",
+    "helo",
+    "protocol",
+    "tls",
+    "authenticated",
+    "from",
+    "from.domain",
+    "rcpt",
+    "rcpt.domain",
+    "rcpt_count",
+    "size",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    String(String),
+    Number(i64),
+    Bool(bool),
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::String(value) => !value.is_empty(),
+            Value::Number(value) => *value != 0,
+            Value::Bool(value) => *value,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Value::String(value) => value.clone(),
+            Value::Number(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+        }
+    }
+
+    fn number(&self) -> Option<i64> {
+        match self {
+            Value::String(value) => value.trim().parse().ok(),
+            Value::Number(value) => Some(*value),
+            Value::Bool(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Matches,
+}
+
+impl Operator {
+    fn parse(token: &Token) -> Option<Operator> {
+        Some(match token {
+            Token::Symbol("==") => Operator::Equal,
+            Token::Symbol("!=") => Operator::NotEqual,
+            Token::Symbol("<") => Operator::Less,
+            Token::Symbol("<=") => Operator::LessOrEqual,
+            Token::Symbol(">") => Operator::Greater,
+            Token::Symbol(">=") => Operator::GreaterOrEqual,
+            Token::Word(word) => match word.as_str() {
+                "contains" => Operator::Contains,
+                "starts_with" => Operator::StartsWith,
+                "ends_with" => Operator::EndsWith,
+                "matches" => Operator::Matches,
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    /// Compares numbers as such when both sides are, and text ignoring case.
+    fn apply(&self, left: &Value, right: &Value) -> bool {
+        let numbers = left.number().zip(right.number());
+        let (left, right) = (left.text().to_lowercase(), right.text().to_lowercase());
+        match (self, numbers) {
+            (Operator::Equal, Some((left, right))) => left == right,
+            (Operator::Equal, None) => left == right,
+            (Operator::NotEqual, Some((left, right))) => left != right,
+            (Operator::NotEqual, None) => left != right,
+            (Operator::Less, Some((left, right))) => left < right,
+            (Operator::LessOrEqual, Some((left, right))) => left <= right,
+            (Operator::Greater, Some((left, right))) => left > right,
+            (Operator::GreaterOrEqual, Some((left, right))) => left >= right,
+            (Operator::Less, None)
+            | (Operator::LessOrEqual, None)
+            | (Operator::Greater, None)
+            | (Operator::GreaterOrEqual, None) => false,
+            (Operator::Contains, _) => left.contains(&right),
+            (Operator::StartsWith, _) => left.starts_with(&right),
+            (Operator::EndsWith, _) => left.ends_with(&right),
+            (Operator::Matches, _) => {
+                let left: Vec<char> = left.chars().collect();
+                let right: Vec<char> = right.chars().collect();
+                sieve::wildcard(&left, &right)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Value(Value),
+    Variable(String),
+    Header(Box<Expression>),
+    InNetwork(Box<Expression>, Cidr),
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Compare(Operator, Box<Expression>, Box<Expression>),
+    Concatenation(Vec<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Statement {
+    /// The blocks of `if` and each `elsif` with their conditions, then the `else` block.
+    If(Vec<(Expression, Vec<Statement>)>, Option<Vec<Statement>>),
+    Accept,
+    Reject(u16, Expression),
+    Defer(Expression),
+    Quarantine(Expression),
+    AddHeader(Expression, Expression),
+    SetHeader(Expression, Expression),
+    RemoveHeader(Expression),
+}
+
+/// A policy script: sections run at connect, MAIL FROM, RCPT TO and the end of DATA,
+/// deciding whether to go on, and at the end of DATA changing headers.
+///
+/// ```text
+/// on rcpt {
+///     if rcpt.domain == "example.org" and not authenticated {
+///         reject 550 "5.7.1 Relaying denied";
+///     }
+/// }
+/// on data {
+///     if header("Subject") contains "invoice" and from.domain != "example.com" {
+///         add_header "X-Suspicious" "invoice from " + from;
+///         quarantine "invoice from outside";
+///     }
+/// }
+/// ```
+///
+/// Each section runs its statements in order until `accept`, `reject CODE TEXT`, `defer
+/// TEXT` or `quarantine REASON` decides, or their end accepts. Conditions compare with
+/// `==`, `!=`, `<`, `<=`, `>`, `>=`, `contains`, `starts_with`, `ends_with` and `matches`
+/// (`*` and `?` wildcards), ignoring case, and combine with `and`, `or` and `not`; `+`
+/// joins text. They may read `session_id`, `client.ip`, `client.name`, `helo`,
+/// `protocol`, `tls`, `authenticated` (the identity), `from`, `from.domain`, `rcpt`,
+/// `rcpt.domain`, `rcpt_count` and `size`, `header(NAME)` at the end of DATA, and
+/// `in_network(ADDRESS, "CIDR")`. `add_header`, `set_header` and `remove_header` are only
+/// allowed at the end of DATA.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyScript {
+    sections: Vec<(Hook, Vec<Statement>)>,
+}
+
+impl PolicyScript {
+    pub fn parse(text: &str) -> Result<PolicyScript, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            hook: Hook::Connect,
+        };
+        let mut sections = Vec::new();
+        while parser.peek().is_some() {
+            if !parser.word("on") {
+                return Err(parser.error("expected on"));
+            }
+            parser.hook = match parser.next() {
+                Some(Token::Word(hook)) => match hook.as_str() {
+                    "connect" => Hook::Connect,
+                    "mail" => Hook::Mail,
+                    "rcpt" => Hook::Rcpt,
+                    "data" => Hook::Data,
+                    hook => return Err(parser.error(&format!("unknown hook {}", hook))),
+                },
+                _ => return Err(parser.error("expected a hook")),
+            };
+            sections.push((parser.hook, parser.block()?));
+        }
+        Ok(PolicyScript { sections })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<PolicyScript> {
+        let text = fs::read_to_string(path)?;
+        PolicyScript::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// What the sections for `hook` decide, and the header changes they make, in order.
+    pub fn run(&self, hook: Hook, scope: &Scope) -> (Verdict, Vec<HeaderRule>) {
+        let message = scope.data.map(Message::parse);
+        let mut rules = Vec::new();
+        for (_, statements) in self.sections.iter().filter(|(at, _)| *at == hook) {
+            if let Some(verdict) = execute(statements, scope, message.as_ref(), &mut rules) {
+                return (verdict, rules);
+            }
+        }
+        (Verdict::Accept, rules)
+    }
+}
+
+/// Runs `statements`, returning the verdict once one decides.
+fn execute(
+    statements: &[Statement],
+    scope: &Scope,
+    message: Option<&Message>,
+    rules: &mut Vec<HeaderRule>,
+) -> Option<Verdict> {
+    let text = |expression: &Expression| evaluate(expression, scope, message).text();
+    for statement in statements {
+        match statement {
+            Statement::If(branches, otherwise) => {
+                let block = branches
+                    .iter()
+                    .find(|(condition, _)| evaluate(condition, scope, message).is_true())
+                    .map(|(_, block)| block)
+                    .or(otherwise.as_ref());
+                if let Some(verdict) = block.and_then(|block| execute(block, scope, message, rules))
+                {
+                    return Some(verdict);
+                }
+            }
+            Statement::Accept => return Some(Verdict::Accept),
+            Statement::Reject(code, reason) => return Some(Verdict::reject(*code, &text(reason))),
+            Statement::Defer(reason) => return Some(Verdict::Defer(text(reason))),
+            Statement::Quarantine(reason) => return Some(Verdict::Quarantine(text(reason))),
+            Statement::AddHeader(name, value) => rules.push(HeaderRule::Add {
+                name: text(name),
+                value: text(value),
+            }),
+            Statement::SetHeader(name, value) => rules.push(HeaderRule::Replace {
+                name: text(name),
+                value: text(value),
+            }),
+            Statement::RemoveHeader(name) => rules.push(HeaderRule::Remove { name: text(name) }),
+        }
+    }
+    None
+}
+
+fn evaluate(expression: &Expression, scope: &Scope, message: Option<&Message>) -> Value {
+    let value = |expression: &Expression| evaluate(expression, scope, message);
+    match expression {
+        Expression::Value(value) => value.clone(),
+        Expression::Variable(name) => scope.variable(name),
+        Expression::Header(name) => {
+            let name = value(name).text();
+            let header = message.and_then(|message| message.get(&name)).unwrap_or("");
+            Value::String(header.replace(['\r', '\n'], ""))
+        }
+        Expression::InNetwork(address, network) => Value::Bool(
+            value(address)
+                .text()
+                .parse()
+                .is_ok_and(|address| network.contains(address)),
+        ),
+        Expression::Not(operand) => Value::Bool(!value(operand).is_true()),
+        Expression::And(left, right) => {
+            Value::Bool(value(left).is_true() && value(right).is_true())
+        }
+        Expression::Or(left, right) => Value::Bool(value(left).is_true() || value(right).is_true()),
+        Expression::Compare(operator, left, right) => {
+            Value::Bool(operator.apply(&value(left), &value(right)))
+        }
+        Expression::Concatenation(parts) => {
+            Value::String(parts.iter().map(|part| value(part).text()).collect())
+        }
+    }
+}
+
+impl<'a> Scope<'a> {
+    fn variable(&self, name: &str) -> Value {
+        let bare = |address: &str| {
+            let address = dsn::split_parameters(address).0;
+            String::from(address.trim().trim_start_matches('<').trim_end_matches('>'))
+        };
+        let domain = |address: &str| Value::String(domain_of(address).unwrap_or("").to_lowercase());
+        let optional = |value: &Option<String>| Value::String(value.clone().unwrap_or_default());
+        match name {
+            "session_id" => Value::String(self.context.session_id.clone()),
+            "client.ip" => Value::String(
+                self.context
+                    .peer
+                    .map(|peer| peer.ip().to_string())
+                    .unwrap_or_default(),
+            ),
+            "client.name" => optional(&self.context.client_name),
+            "helo" => Value::String(String::from(self.helo)),
+            "protocol" => optional(&self.context.protocol),
+            "tls" => Value::Bool(self.context.tls),
+            "authenticated" => optional(&self.context.authenticated),
+            "from" => Value::String(bare(self.from)),
+            "from.domain" => domain(self.from),
+            "rcpt" => Value::String(bare(self.rcpt)),
+            "rcpt.domain" => domain(self.rcpt),
+            "rcpt_count" => Value::Number(
+                self.recipients
+                    .iter()
+                    .filter(|rcpt| !rcpt.is_empty())
+                    .count() as i64,
+            ),
+            "size" => Value::Number(self.data.map_or(0, str::len) as i64),
+            _ => Value::String(String::new()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    String(String),
+    Number(i64),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "==", "!=", "<=", ">=", "<", ">", "(", ")", "{", "}", ",", ";", "+",
+];
+
+/// The tokens of `text`, each with its line number.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut tokens = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            let c = match rest.chars().next() {
+                None | Some('#') => break,
+                Some(c) => c,
+            };
+            let (token, length) = if c == '"' {
+                let mut value = String::new();
+                let mut chars = rest.char_indices().skip(1);
+                let end = loop {
+                    match chars.next() {
+                        Some((i, '"')) => break i + 1,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => value.push(c),
+                            None => return Err(format!("line {}: unterminated string", number)),
+                        },
+                        Some((_, c)) => value.push(c),
+                        None => return Err(format!("line {}: unterminated string", number)),
+                    }
+                };
+                (Token::String(value), end)
+            } else if c.is_ascii_digit() {
+                let digits = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                let (multiplier, suffix) = match rest[digits..].chars().next() {
+                    Some('K') | Some('k') => (1 << 10, 1),
+                    Some('M') | Some('m') => (1 << 20, 1),
+                    Some('G') | Some('g') => (1 << 30, 1),
+                    _ => (1, 0),
+                };
+                let value = rest[..digits]
+                    .parse::<i64>()
+                    .ok()
+                    .and_then(|value| value.checked_mul(multiplier))
+                    .ok_or_else(|| {
+                        format!("line {}: invalid number {}", number, &rest[..digits])
+                    })?;
+                (Token::Number(value), digits + suffix)
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let length = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+                    .unwrap_or(rest.len());
+                (Token::Word(rest[..length].to_lowercase()), length)
+            } else {
+                match SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+                    Some(symbol) => (Token::Symbol(symbol), symbol.len()),
+                    None => return Err(format!("line {}: unexpected {}", number, c)),
+                }
+            };
+            tokens.push((token, number));
+            rest = &rest[length..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// The section being parsed.
+    hook: Hook,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self
+            .tokens
+            .get(self.position.min(self.tokens.len().saturating_sub(1)))
+        {
+            Some((_, line)) => format!("line {}: {}", line, message),
+            None => String::from(message),
+        }
+    }
+
+    /// Whether the next token is `word`, consuming it if so.
+    fn word(&mut self, word: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(next)) if next == word);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    /// Whether the next token is `symbol`, consuming it if so.
+    fn symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(next)) if *next == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", symbol)))
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, String> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.symbol("}") {
+            if self.peek().is_none() {
+                return Err(self.error("expected }"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let name = match self.next() {
+            Some(Token::Word(name)) => name,
+            _ => return Err(self.error("expected a statement")),
+        };
+        if matches!(name.as_str(), "add_header" | "set_header" | "remove_header")
+            && self.hook != Hook::Data
+        {
+            return Err(self.error(&format!("{} is only allowed in on data", name)));
+        }
+        let statement = match name.as_str() {
+            "if" => {
+                let mut branches = vec![(self.expression()?, self.block()?)];
+                while self.word("elsif") {
+                    branches.push((self.expression()?, self.block()?));
+                }
+                let otherwise = if self.word("else") {
+                    Some(self.block()?)
+                } else {
+                    None
+                };
+                return Ok(Statement::If(branches, otherwise));
+            }
+            "accept" => Statement::Accept,
+            "reject" => {
+                let code = match self.next() {
+                    Some(Token::Number(code @ 400..=599)) => code as u16,
+                    _ => return Err(self.error("expected a 4xx or 5xx code")),
+                };
+                Statement::Reject(code, self.expression()?)
+            }
+            "defer" => Statement::Defer(self.expression()?),
+            "quarantine" => Statement::Quarantine(self.expression()?),
+            "add_header" => Statement::AddHeader(self.expression()?, self.expression()?),
+            "set_header" => Statement::SetHeader(self.expression()?, self.expression()?),
+            "remove_header" => Statement::RemoveHeader(self.expression()?),
+            name => return Err(self.error(&format!("unknown statement {}", name))),
+        };
+        self.expect(";")?;
+        Ok(statement)
+    }
+
+    fn expression(&mut self) -> Result<Expression, String> {
+        let mut left = self.conjunction()?;
+        while self.word("or") {
+            left = Expression::Or(Box::new(left), Box::new(self.conjunction()?));
+        }
+        Ok(left)
+    }
+
+    fn conjunction(&mut self) -> Result<Expression, String> {
+        let mut left = self.negation()?;
+        while self.word("and") {
+            left = Expression::And(Box::new(left), Box::new(self.negation()?));
+        }
+        Ok(left)
+    }
+
+    fn negation(&mut self) -> Result<Expression, String> {
+        if self.word("not") {
+            return Ok(Expression::Not(Box::new(self.negation()?)));
+        }
+        let left = self.concatenation()?;
+        match self.peek().and_then(Operator::parse) {
+            Some(operator) => {
+                self.position += 1;
+                let right = self.concatenation()?;
+                Ok(Expression::Compare(
+                    operator,
+                    Box::new(left),
+                    Box::new(right),
+                ))
+            }
+            None => Ok(left),
+        }
+    }
+
+    /// Operands joined with `+`, as text.
+    fn concatenation(&mut self) -> Result<Expression, String> {
+        let mut parts = vec![self.primary()?];
+        while self.symbol("+") {
+            parts.push(self.primary()?);
+        }
+        if parts.len() == 1 {
+            return Ok(parts.remove(0));
+        }
+        Ok(Expression::Concatenation(parts))
+    }
+
+    fn primary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::String(value)) => Ok(Expression::Value(Value::String(value))),
+            Some(Token::Number(value)) => Ok(Expression::Value(Value::Number(value))),
+            Some(Token::Symbol("(")) => {
+                let expression = self.expression()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Some(Token::Word(word)) => match word.as_str() {
+                "true" => Ok(Expression::Value(Value::Bool(true))),
+                "false" => Ok(Expression::Value(Value::Bool(false))),
+                "header" => {
+                    self.expect("(")?;
+                    let name = self.expression()?;
+                    self.expect(")")?;
+                    Ok(Expression::Header(Box::new(name)))
+                }
+                "in_network" => {
+                    self.expect("(")?;
+                    let address = self.expression()?;
+                    self.expect(",")?;
+                    let network = match self.next() {
+                        Some(Token::String(network)) => {
+                            network.parse().map_err(|e: String| self.error(&e))?
+                        }
+                        _ => return Err(self.error("expected a network")),
+                    };
+                    self.expect(")")?;
+                    Ok(Expression::InNetwork(Box::new(address), network))
+                }
+                name if VARIABLES.contains(&name) => Ok(Expression::Variable(word)),
+                name => Err(self.error(&format!("unknown variable {}", name))),
+            },
+            _ => Err(self.error("expected a value")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\
+        # Only clients of the office may send as example.com.\n\
+        on mail {\n\
+            if from.domain == \"Example.com\" and not in_network(client.ip, \"192.0.2.0/24\") {\n\
+                reject 550 \"5.7.1 Not from \" + client.ip;\n\
+            }\n\
+        }\n\
+        on rcpt {\n\
+            if rcpt_count >= 2 { defer \"4.5.3 Too many recipients\"; }\n\
+        }\n\
+        on data {\n\
+            remove_header \"X-Internal\";\n\
+            if header(\"Subject\") matches \"*invoice*\" or size > 1M {\n\
+                add_header \"X-Checked\" \"invoice\";\n\
+                quarantine \"invoice\";\n\
+            }\n\
+        }\n";
+
+    fn scope<'a>(context: &'a SessionContext, from: &'a str, data: Option<&'a str>) -> Scope<'a> {
+        Scope {
+            context,
+            helo: "client.example.com",
+            from,
+            rcpt: "",
+            recipients: &[],
+            data,
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let script = PolicyScript::parse(SCRIPT).unwrap();
+        let outside = SessionContext::new(Some("198.51.100.7:4000".parse().unwrap()), None);
+        let office = SessionContext::new(Some("192.0.2.7:4000".parse().unwrap()), None);
+        assert_eq!(
+            script
+                .run(Hook::Mail, &scope(&outside, "<a@example.com>", None))
+                .0,
+            Verdict::reject(550, "5.7.1 Not from 198.51.100.7")
+        );
+        assert_eq!(
+            script
+                .run(Hook::Mail, &scope(&office, "<a@example.com>", None))
+                .0,
+            Verdict::Accept
+        );
+        let recipients = vec![
+            String::from("<b@example.org>"),
+            String::from("<c@example.org>"),
+        ];
+        let rcpt = Scope {
+            recipients: &recipients,
+            ..scope(&office, "<a@example.com>", None)
+        };
+        assert_eq!(
+            script.run(Hook::Rcpt, &rcpt).0,
+            Verdict::Defer(String::from("4.5.3 Too many recipients"))
+        );
+
+        let data = "Subject: Your Invoice\r\nX-Internal: 1\r\n\r\nhi\r\n";
+        let (verdict, rules) = script.run(Hook::Data, &scope(&office, "", Some(data)));
+        assert_eq!(verdict, Verdict::Quarantine(String::from("invoice")));
+        let mut message = Message::parse(data);
+        for rule in rules.iter() {
+            rule.apply(&crate::email::Mail::new(), &mut message);
+        }
+        assert_eq!(
+            message.to_string(),
+            "X-Checked: invoice\r\nSubject: Your Invoice\r\n\r\nhi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            PolicyScript::parse("on rcpt { add_header \"X-A\" \"b\"; }"),
+            Err(String::from(
+                "line 1: add_header is only allowed in on data"
+            ))
+        );
+        assert_eq!(
+            PolicyScript::parse("on mail {\n if sender == \"a\" { accept; }\n}"),
+            Err(String::from("line 2: unknown variable sender"))
+        );
+        assert_eq!(
+            PolicyScript::parse("on mail { reject 250 \"Ok\"; }"),
+            Err(String::from("line 1: expected a 4xx or 5xx code"))
+        );
+        assert!(PolicyScript::parse("on mail { accept; ").is_err());
+    }
+}
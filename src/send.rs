@@ -0,0 +1,211 @@
+use std::{fs, io};
+
+use crate::{
+    clock, datetime,
+    email::Mail,
+    output::{
+        relay::{Credentials, Relay},
+        Output,
+    },
+    queue,
+};
+
+/// A test message to send, as `simple-smtp send` does, like swaks: to this server or any
+/// other, through the relay client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SendMail {
+    /// The server to send to, as `host:port`, port 25 when left out.
+    pub server: String,
+    pub from: String,
+    pub to: Vec<String>,
+    /// The name given in EHLO.
+    pub helo: String,
+    /// Whether to encrypt the session first, which is refused: this crate has no TLS.
+    pub starttls: bool,
+    /// The user and password to log in with, PLAIN or LOGIN as the server offers.
+    pub auth: Option<(String, String)>,
+    /// The file the body is read from, standard input for `-`. A short text when unset.
+    pub body: Option<String>,
+}
+
+impl SendMail {
+    pub fn new(server: &str) -> SendMail {
+        SendMail {
+            server: String::from(server),
+            from: String::from("sender@localhost"),
+            to: Vec::new(),
+            helo: String::from("localhost"),
+            starttls: false,
+            auth: None,
+            body: None,
+        }
+    }
+
+    /// Reads the options given after `send` on the command line, e.g. `--to a@example.com
+    /// --from b@example.com --server mx.example.com:25 --auth user:pass --body message.txt`.
+    /// `--to` may be given more than once and at least once.
+    pub fn from_args(args: &[String]) -> Result<SendMail, String> {
+        let mut send = SendMail::new("127.0.0.1:7878");
+        let mut args = args.iter();
+        while let Some(option) = args.next() {
+            if option == "--starttls" {
+                send.starttls = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", option))?;
+            match option.as_str() {
+                "--server" => send.server = value.clone(),
+                "--from" => send.from = value.clone(),
+                "--to" => send.to.push(value.clone()),
+                "--helo" => send.helo = value.clone(),
+                "--auth" => match value.split_once(':') {
+                    Some((user, password)) => {
+                        send.auth = Some((String::from(user), String::from(password)))
+                    }
+                    None => return Err(format!("invalid --auth {}, expected user:pass", value)),
+                },
+                "--body" => send.body = Some(value.clone()),
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        if send.to.is_empty() {
+            return Err(String::from("--to is required"));
+        }
+        Ok(send)
+    }
+
+    /// Sends the message, failing with the reply of the server that refused it.
+    pub fn run(&self) -> io::Result<()> {
+        if self.starttls {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "STARTTLS is not supported, simple-smtp has no TLS",
+            ));
+        }
+        let (host, port) = self.host_and_port()?;
+        let mut relay = Relay::new(host, port, &self.helo);
+        if let Some((user, secret)) = &self.auth {
+            relay.credentials = Some(Credentials {
+                user: user.clone(),
+                secret: secret.clone(),
+                mechanism: None,
+            });
+            // Asked for on the command line, knowing there is no TLS.
+            relay.insecure_auth = true;
+        }
+        relay.deliver(&self.mail()?)
+    }
+
+    fn host_and_port(&self) -> io::Result<(&str, u16)> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid server {}", self.server),
+            )
+        };
+        // `[2001:db8::1]:25`, `mx.example.com:25` or `mx.example.com`.
+        let (host, port) = match self.server.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            Some(_) => return Err(invalid()),
+            None => (self.server.as_str(), 25),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok((host, port))
+    }
+
+    /// The envelope and a message with the headers a test needs around the body.
+    fn mail(&self) -> io::Result<Mail> {
+        let now = clock::now();
+        let body = match self.body.as_deref() {
+            Some("-") => io::read_to_string(io::stdin())?,
+            Some(path) => fs::read_to_string(path)?,
+            None => String::from("This is a test message sent by simple-smtp.\n"),
+        };
+        let data = format!(
+            "Date: {}\nFrom: <{}>\nTo: {}\nSubject: test {}\nMessage-ID: <{}@{}>\n\
+             X-Mailer: simple-smtp send\n\n{}",
+            datetime::rfc5322(now),
+            self.from,
+            self.to
+                .iter()
+                .map(|to| format!("<{}>", to))
+                .collect::<Vec<String>>()
+                .join(", "),
+            datetime::rfc5322(now),
+            queue::new_queue_id(),
+            self.helo,
+            body
+        );
+        let mut mail = Mail::new();
+        mail.mail_from = Some(format!("<{}>", self.from));
+        mail.rcpt_to = self.to.iter().map(|to| format!("<{}>", to)).collect();
+        mail.data = Some(data);
+        Ok(mail)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, server::Server};
+    use std::{thread, time::Duration};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| String::from(*arg)).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let send = SendMail::from_args(&args(&[
+            "--to",
+            "a@example.com",
+            "--to",
+            "b@example.com",
+            "--auth",
+            "user:pa:ss",
+            "--starttls",
+            "--server",
+            "[::1]:2525",
+        ]))
+        .unwrap();
+        assert_eq!(send.to, ["a@example.com", "b@example.com"]);
+        assert_eq!(
+            send.auth,
+            Some((String::from("user"), String::from("pa:ss")))
+        );
+        assert!(send.starttls);
+        assert_eq!(send.host_and_port().unwrap(), ("::1", 2525));
+        assert!(send.run().is_err());
+
+        assert!(SendMail::from_args(&args(&["--from", "a@example.com"])).is_err());
+        assert!(SendMail::from_args(&args(&["--to", "a@b", "--auth", "user"])).is_err());
+        let mut send = SendMail::new("mx.example.com");
+        assert_eq!(send.host_and_port().unwrap(), ("mx.example.com", 25));
+        send.server = String::from("2001:db8::1");
+        assert!(send.host_and_port().is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let mut server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        let incoming = server.incoming();
+        thread::spawn(move || server.run());
+
+        let mut send = SendMail::new(&address.to_string());
+        send.to = vec![String::from("user@example.com")];
+        send.run().unwrap();
+        let received = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received.mail.rcpt_to[0], "<user@example.com>");
+        let data = received.mail.data.unwrap();
+        assert!(data.contains("\r\nX-Mailer: simple-smtp send\r\n"));
+        assert!(data.ends_with("\r\n\r\nThis is a test message sent by simple-smtp.\r\n"));
+    }
+}
@@ -0,0 +1,544 @@
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    audit::AuditEvent,
+    config::Config,
+    email::{self, Mail},
+    handle_stream,
+    journal::Journal,
+    plugin::Plugins,
+    reply::Reply,
+    thread_pool::ThreadPool,
+};
+#[cfg(unix)]
+use crate::{handle_unix_stream, policy::Policy};
+
+type Session = Box<dyn FnOnce() + Send + 'static>;
+
+/// A message accepted by the server, with where and when it came from.
+#[derive(Clone, Debug)]
+pub struct ReceivedMail {
+    pub mail: Mail,
+    pub peer: Option<SocketAddr>,
+    pub session_id: String,
+    pub received_at: SystemTime,
+}
+
+type Load = Box<dyn Fn() -> io::Result<Config> + Send + Sync>;
+
+/// Builds the configuration anew when asked, e.g. on SIGHUP, for the sessions accepted
+/// from then on. Sessions under way keep the configuration they started with, and the
+/// listening sockets stay open.
+pub struct Reloader {
+    load: Load,
+    current: RwLock<Option<Arc<Config>>>,
+}
+
+impl Reloader {
+    pub fn new<F>(load: F) -> Arc<Reloader>
+    where
+        F: Fn() -> io::Result<Config> + Send + Sync + 'static,
+    {
+        Arc::new(Reloader {
+            load: Box::new(load),
+            current: RwLock::new(None),
+        })
+    }
+
+    /// Builds the configuration and serves new sessions with it, keeping the one in use
+    /// if that fails. The receivers of `Server::incoming` keep getting messages.
+    pub fn reload(&self) -> io::Result<()> {
+        let mut config = (self.load)()?;
+        let mut current = self.current.write().unwrap();
+        if let Some(current) = current.as_ref() {
+            config.incoming.extend(current.incoming.iter().cloned());
+        }
+        *current = Some(Arc::new(config));
+        crate::info!("Configuration reloaded");
+        Ok(())
+    }
+
+    fn current(&self) -> Option<Arc<Config>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// Accepts SMTP connections and runs each session on a thread pool.
+pub struct Server {
+    listeners: Vec<TcpListener>,
+    #[cfg(unix)]
+    unix_listeners: Vec<(UnixListener, Policy)>,
+    config: Config,
+    workers: usize,
+    reloader: Option<Arc<Reloader>>,
+    /// Set to stop accepting connections, see `EphemeralServer::shutdown`.
+    stop: Arc<AtomicBool>,
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(address: A, config: Config) -> io::Result<Server> {
+        Ok(Server::new(TcpListener::bind(address)?, config))
+    }
+
+    pub fn new(listener: TcpListener, config: Config) -> Server {
+        Server {
+            listeners: vec![listener],
+            #[cfg(unix)]
+            unix_listeners: Vec::new(),
+            config,
+            workers: 4,
+            reloader: None,
+            stop: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Serves `config` on a port of the loopback interface the system picks, in the
+    /// background, for a test suite needing an SMTP server to send to:
+    ///
+    /// ```
+    /// use simple_smtp::{config::Config, server::Server};
+    ///
+    /// let server = Server::spawn_ephemeral(Config::default()).unwrap();
+    /// // Point the application under test at server.address(), then read
+    /// // server.incoming().
+    /// server.shutdown().unwrap();
+    /// ```
+    pub fn spawn_ephemeral(config: Config) -> io::Result<EphemeralServer> {
+        let mut server = Server::bind("127.0.0.1:0", config)?;
+        let address = server.local_addr()?;
+        let incoming = server.incoming();
+        let stop = Arc::clone(&server.stop);
+        let thread = thread::spawn(move || server.run());
+        Ok(EphemeralServer {
+            address,
+            incoming,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Serves each session with the configuration `reloader` last built, `config` until
+    /// it is first reloaded.
+    pub fn reloader(mut self, reloader: Arc<Reloader>) -> Server {
+        self.reloader = Some(reloader);
+        self
+    }
+
+    /// Extends the configuration with `plugins`, see `Plugins::install`. Configurations a
+    /// reloader builds need to install them too.
+    pub fn plugins(mut self, plugins: &Plugins) -> Server {
+        plugins.install(&mut self.config);
+        self
+    }
+
+    /// Number of sessions served at the same time, 4 by default.
+    pub fn workers(mut self, workers: usize) -> Server {
+        self.workers = workers;
+        self
+    }
+
+    /// Also accepts connections on `listener`, e.g. the submission port next to port 25.
+    /// See `Config::profiles` to give its sessions a policy of their own.
+    pub fn listen(mut self, listener: TcpListener) -> Server {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Also accepts connections on the Unix socket `listener`, serving them with `policy`,
+    /// e.g. `Policy::default().lmtp(true)` for local delivery from Postfix or Exim.
+    #[cfg(unix)]
+    pub fn listen_unix(mut self, listener: UnixListener, policy: Policy) -> Server {
+        self.unix_listeners.push((listener, policy));
+        self
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// Every message accepted from now on is also sent to the returned receiver, e.g. for
+    /// a test suite asserting on what the application under test sent.
+    pub fn incoming(&mut self) -> Receiver<ReceivedMail> {
+        let (sender, receiver) = mpsc::channel();
+        self.config.incoming.push(sender);
+        receiver
+    }
+
+    /// Serves connections until it is shut down. A connection that cannot be accepted,
+    /// e.g. one reset at once or one too many for the open file limit, is logged and
+    /// skipped; the error returned is that of recovering the journal.
+    pub fn run(self) -> io::Result<()> {
+        let pool = ThreadPool::new(self.workers);
+        let config = Arc::new(self.config);
+        if let Some(journal) = &config.journal {
+            recover(&config, journal)?;
+        }
+        let reloader = self.reloader;
+        if let Some(reloader) = &reloader {
+            let mut current = reloader.current.write().unwrap();
+            current.get_or_insert_with(|| Arc::clone(&config));
+        }
+        // The configuration of the session accepted next.
+        let current = Arc::new(move || {
+            reloader
+                .as_ref()
+                .and_then(|reloader| reloader.current())
+                .unwrap_or_else(|| Arc::clone(&config))
+        });
+        let (sender, receiver) = mpsc::channel::<Session>();
+        for listener in self.listeners {
+            let sender = sender.clone();
+            let current = Arc::clone(&current);
+            let stop = Arc::clone(&self.stop);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            accept_failed(&e);
+                            continue;
+                        }
+                    };
+                    let config = current();
+                    if is_refused(&config, &stream) {
+                        continue;
+                    }
+                    if sender
+                        .send(Box::new(move || handle_stream(stream, config)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+        #[cfg(unix)]
+        for (listener, policy) in self.unix_listeners {
+            let sender = sender.clone();
+            let current = Arc::clone(&current);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            accept_failed(&e);
+                            continue;
+                        }
+                    };
+                    let config = current();
+                    let policy = policy.clone();
+                    if sender
+                        .send(Box::new(move || handle_unix_stream(stream, config, policy)))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        for session in receiver {
+            pool.execute(session);
+        }
+        Ok(())
+    }
+}
+
+/// A server running in the background on a port of its own, see `Server::spawn_ephemeral`.
+/// Dropping it shuts it down.
+pub struct EphemeralServer {
+    address: SocketAddr,
+    incoming: Receiver<ReceivedMail>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl EphemeralServer {
+    /// Where to connect to, e.g. `127.0.0.1:40123`.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The messages the server accepted, in order.
+    pub fn incoming(&self) -> &Receiver<ReceivedMail> {
+        &self.incoming
+    }
+
+    /// Stops accepting connections and waits for the sessions under way to end, returning
+    /// the error the server stopped on, if any.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the listener, which sees it is to stop.
+        let _ = TcpStream::connect(self.address);
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("server panicked")))
+    }
+}
+
+impl Drop for EphemeralServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Whether the client of `stream` is turned away, because it is banned, the server is
+/// paused or the client connects too often, in which case it is told so, without waiting
+/// for it to read.
+fn is_refused(config: &Config, mut stream: &TcpStream) -> bool {
+    let peer = stream.peer_addr().ok().map(|peer| peer.ip());
+    let banned = match (&config.bans, peer) {
+        (Some(bans), Some(peer)) => bans.banned_until(peer).is_some(),
+        _ => false,
+    };
+    let (reply, event) = if banned {
+        let reply = Reply::new(421, "4.7.0 Too many errors, try again later");
+        (reply, Some(AuditEvent::Blocklisted))
+    } else {
+        match config.control.refusal(peer) {
+            // Not the client's doing.
+            Some(reply) if config.control.is_paused() => (reply, None),
+            Some(reply) => (reply, Some(AuditEvent::RateLimited)),
+            None => return false,
+        }
+    };
+    if let (Some(audit), Some(event)) = (&config.audit, event) {
+        let peer = peer.map_or_else(|| String::from("unknown"), |peer| peer.to_string());
+        audit.record(event, &peer, "-", &[], &reply.to_string());
+    }
+    crate::debug!(
+        "Refused {}: {}",
+        peer.map(|peer| peer.to_string()).unwrap_or_default(),
+        reply.text()
+    );
+    let _ = stream.set_nonblocking(true);
+    let _ = stream.write_all(reply.to_string().as_bytes());
+    true
+}
+
+/// Logs a connection that could not be accepted. Pauses a little, as what ran out, such as
+/// file descriptors, is seldom back at once.
+fn accept_failed(e: &io::Error) {
+    crate::warn!("Connection failed: {}", e);
+    thread::sleep(Duration::from_millis(100));
+}
+
+/// Hands the messages a crash left in `journal` to the outputs again. Those the outputs
+/// still refuse stay in the journal for the next start.
+fn recover(config: &Config, journal: &Journal) -> io::Result<()> {
+    for mail in journal.recover()? {
+        let queue_id = mail.queue_id.clone().unwrap_or_default();
+        match email::deliver(config, &mail, &queue_id) {
+            Ok(()) => {
+                crate::info!("Recovered {}", queue_id);
+                journal.complete(&queue_id)?;
+            }
+            Err(e) => crate::error!("Unable to recover {}: {}", queue_id, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        audit::AuditLog,
+        ban::{BanList, Offence},
+        log::Rotation,
+        testing::TestClient,
+    };
+    use std::{
+        io::{BufRead, BufReader, Read},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_incoming() {
+        let mut server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        let incoming = server.incoming();
+        thread::spawn(move || server.run());
+
+        let mut client = TestClient::connect(address).unwrap();
+        client.command("HELO client").unwrap();
+        let reply = client
+            .send_mail(
+                "<app@example.com>",
+                &["<user@example.com>"],
+                "Subject: Welcome\n",
+            )
+            .unwrap();
+        assert_eq!(reply.code, 250);
+
+        let received = incoming.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            received.mail.mail_from.as_deref(),
+            Some("<app@example.com>")
+        );
+        let data = received.mail.data.unwrap();
+        assert!(data.starts_with("Received: from "));
+        assert!(data.ends_with("\r\nSubject: Welcome\r\n"));
+        assert_eq!(
+            received.peer.map(|peer| peer.ip()),
+            Some([127, 0, 0, 1].into())
+        );
+    }
+
+    #[test]
+    fn test_spawn_ephemeral() {
+        let server = Server::spawn_ephemeral(Config::default()).unwrap();
+        let mut client = TestClient::connect(server.address()).unwrap();
+        client.command("HELO client").unwrap();
+        client
+            .send_mail(
+                "<app@example.com>",
+                &["<user@example.com>"],
+                "Subject: hi\n",
+            )
+            .unwrap();
+        client.quit().unwrap();
+        let received = server
+            .incoming()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(received.mail.rcpt_to[0], "<user@example.com>");
+
+        let address = server.address();
+        server.shutdown().unwrap();
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let reloader = Reloader::new(|| Ok(Config::new(String::from("reloaded.server"))));
+        let server = Server::bind("127.0.0.1:0", Config::default())
+            .unwrap()
+            .workers(2)
+            .reloader(Arc::clone(&reloader));
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let greeting = |stream: &TcpStream| {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+        let established = TcpStream::connect(address).unwrap();
+        assert!(greeting(&established).contains("my.server"));
+        reloader.reload().unwrap();
+        let stream = TcpStream::connect(address).unwrap();
+        assert!(greeting(&stream).contains("reloaded.server"));
+
+        // The session started before keeps its configuration and connection.
+        let mut writer = &established;
+        writer.write_all(b"HELO client\n").unwrap();
+        assert!(greeting(&established).contains("my.server"));
+    }
+
+    #[test]
+    fn test_banned() {
+        let path = std::env::temp_dir().join(format!("banned-{}.log", std::process::id()));
+        let audit = AuditLog::to_file(path.to_str().unwrap(), Rotation::Never, 0).unwrap();
+        let bans = Arc::new(BanList::new());
+        bans.record("127.0.0.1".parse().unwrap(), Offence::Blocklisted);
+        let config = Config {
+            bans: Some(bans),
+            audit: Some(Arc::new(audit)),
+            ..Config::default()
+        };
+        let server = Server::bind("127.0.0.1:0", config).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut reply = String::new();
+        BufReader::new(&stream).read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "421 4.7.0 Too many errors, try again later\r\n");
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert!(audit.contains(" audit event=blocklisted peer=127.0.0.1 session=- "));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let path = std::env::temp_dir().join(format!("rate-limited-{}.log", std::process::id()));
+        let audit = AuditLog::to_file(path.to_str().unwrap(), Rotation::Never, 0).unwrap();
+        let config = Config {
+            audit: Some(Arc::new(audit)),
+            ..Config::default()
+        };
+        config.control.set_connection_rate(1);
+        let server = Server::bind("127.0.0.1:0", config).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let _first = TestClient::connect(address).unwrap();
+        let stream = TcpStream::connect(address).unwrap();
+        let mut reply = String::new();
+        BufReader::new(&stream).read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "421 4.7.0 Too many connections, try again later\r\n");
+        let audit = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            audit.lines().count(),
+            1,
+            "only the second connection is refused"
+        );
+        assert!(audit.contains(
+            " audit event=rate_limited peer=127.0.0.1 session=- \
+             reason=\"421 4.7.0 Too many connections, try again later\""
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_listen_unix() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("simple-smtp-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server =
+            Server::new(tcp, Config::default()).listen_unix(listener, Policy::default().lmtp(true));
+        thread::spawn(move || server.run());
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.contains("LMTP"));
+        writer.write_all(b"LHLO client\n").unwrap();
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("250"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
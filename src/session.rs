@@ -0,0 +1,55 @@
+use core::net::SocketAddr;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use crate::clock;
+
+static SESSION_COUNTER: AtomicU64 = AtomicU64::new(1);
+
+/// What is known about the client and connection of a session, shared with the state
+/// machine and every hook.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionContext {
+    pub session_id: String,
+    pub peer: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    /// The host name of the client, when a trusted frontend gave it through XCLIENT.
+    pub client_name: Option<String>,
+    /// `SMTP` or `ESMTP` once the client greeted, or as given by a frontend through XCLIENT.
+    pub protocol: Option<String>,
+    /// Whether the connection is encrypted.
+    pub tls: bool,
+    /// The identity the client authenticated as, if it did.
+    pub authenticated: Option<String>,
+    pub connected_at: SystemTime,
+    /// When the current transaction started with MAIL FROM.
+    pub transaction_started_at: Option<SystemTime>,
+}
+
+impl SessionContext {
+    /// A context for a new connection, with a fresh session ID.
+    pub fn new(peer: Option<SocketAddr>, local: Option<SocketAddr>) -> SessionContext {
+        SessionContext {
+            session_id: format!("{:08x}", SESSION_COUNTER.fetch_add(1, Ordering::Relaxed)),
+            peer,
+            local,
+            client_name: None,
+            protocol: None,
+            tls: false,
+            authenticated: None,
+            connected_at: clock::now(),
+            transaction_started_at: None,
+        }
+    }
+
+    pub fn peer_name(&self) -> String {
+        self.peer
+            .map_or_else(|| String::from("unknown"), |addr| addr.to_string())
+    }
+
+    pub fn local_port(&self) -> Option<u16> {
+        self.local.map(|addr| addr.port())
+    }
+}
@@ -0,0 +1,915 @@
+#[cfg(feature = "server")]
+use std::path::{Path, PathBuf};
+use std::{fmt, fs, io, sync::Arc, time::Duration};
+
+use crate::{
+    alias::Aliases,
+    auth::Passwords,
+    config::Config,
+    domain::{Domain, Domains},
+    log::{self, Level},
+    network::Cidr,
+    quota::{MailboxLimits, Quota},
+    retry,
+    script::PolicyScript,
+    sieve::Sieve,
+};
+#[cfg(feature = "server")]
+use crate::{output::pipe::Pipe, sandbox::Sandbox};
+
+/// The configuration file `main` reads, and reads again on SIGHUP.
+pub const CONFIG_ENV: &str = "SIMPLE_SMTP_CONFIG";
+
+/// What the variables overriding the configuration file start with, see
+/// `Settings::overlay_env`.
+pub const ENV_PREFIX: &str = "SIMPLE_SMTP_";
+
+/// Every key a file may set.
+const KEYS: &[&str] = &[
+    "listen",
+    "server_name",
+    "max_hops",
+    "max_line_length",
+    "command_timeout",
+    "max_recipients",
+    "connection_rate",
+    "max_queue_lifetime",
+    "delay_warning",
+    "sender_quota",
+    "mailbox_quota",
+    "require_helo",
+    "require_auth",
+    "smtp_passwords",
+    "insecure_auth",
+    "trust",
+    "domain",
+    "aliases",
+    "script",
+    "policy_service",
+    "milter",
+    "sieve",
+    "clamd",
+    "clamd_quarantine",
+    "spam_scanner",
+    "spam_reject_score",
+    "spam_quarantine_score",
+    "journal",
+    "quarantine",
+    "bans",
+    "user",
+    "group",
+    "sandbox",
+    "sandbox_read",
+    "sandbox_write",
+    "admin_listen",
+    "admin_token",
+    "pop3_listen",
+    "pop3_passwords",
+    "imap_listen",
+    "imap_passwords",
+    "log_level",
+];
+
+/// The keys that add to a list rather than replace.
+const LISTS: &[&str] = &[
+    "listen",
+    "trust",
+    "domain",
+    "sender_quota",
+    "mailbox_quota",
+    "milter",
+    "sandbox_read",
+    "sandbox_write",
+];
+
+/// What a configuration file sets: `key = value` lines, with blank lines and `#` comments
+/// skipped. `trust`, `domain`, `listen`, the quotas, `milter` and the `sandbox_*` paths may
+/// be given more than once, a later value of any other key replaces the earlier one. For example:
+///
+/// ```text
+/// server_name = mx.example.com
+/// listen = 0.0.0.0:25
+/// domain = example.com
+/// trust = 10.0.0.0/8
+/// max_recipients = 50
+/// log_level = debug
+/// ```
+///
+/// The environment overrides the file, see `overlay_env`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// The addresses to accept connections on. Read at startup only, reloading keeps the
+    /// listening sockets.
+    pub listen: Vec<String>,
+    pub server_name: Option<String>,
+    pub max_hops: Option<usize>,
+    pub max_line_length: Option<usize>,
+    /// How long a client may stay silent, see `Config::command_timeout`, e.g. `5m`, `0`
+    /// for ever.
+    pub command_timeout: Option<Duration>,
+    pub max_recipients: Option<usize>,
+    /// Connections accepted from one address a minute, see `Control::set_connection_rate`.
+    /// Reloading sets it again, replacing what was set through the admin API.
+    pub connection_rate: Option<u32>,
+    /// How long a queued message may wait for delivery, see `RetrySchedule::max_lifetime`,
+    /// e.g. `5d`.
+    pub max_queue_lifetime: Option<Duration>,
+    /// How long a queued message may wait before its sender is told, `0` for never, see
+    /// `RetrySchedule::delay_warning`.
+    pub delay_warning: Option<Duration>,
+    /// What each authenticated client may send, e.g. `100 messages/1h`, see
+    /// `quota::SenderQuotas`. Reloading replaces them, keeping the counts.
+    pub sender_quotas: Vec<Quota>,
+    /// How much each recipient's messages may take of the store kept for POP3, IMAP and
+    /// the HTTP API: `50M` for every mailbox, `alice@example.com 1G` for one, see
+    /// `quota::MailboxQuotas`.
+    pub mailbox_quotas: MailboxLimits,
+    pub require_helo: Option<bool>,
+    pub require_auth: Option<bool>,
+    /// A file of the users who may authenticate with AUTH, see `auth::Passwords::load` and
+    /// `Config::authenticator`.
+    pub smtp_passwords: Option<String>,
+    /// Whether AUTH is offered without TLS, see `Policy::insecure_auth`.
+    pub insecure_auth: Option<bool>,
+    /// Networks whose clients may relay.
+    pub trust: Vec<Cidr>,
+    /// The recipient domains mail is accepted for.
+    pub domains: Vec<String>,
+    /// A file of aliases, see `Aliases::load`.
+    pub aliases: Option<String>,
+    /// A policy script run at each step of a session, see `script::PolicyScript`.
+    pub script: Option<String>,
+    /// The address of a Postfix policy service, `host:port` or `unix:` and a socket path,
+    /// see `policy_service::PolicyService`.
+    pub policy_service: Option<String>,
+    /// The addresses of the milters messages are shown to, in order, `host:port` or
+    /// `unix:` and a socket path, see `milter::Milter`.
+    pub milters: Vec<String>,
+    /// The directory of the Sieve scripts run at local delivery, see `sieve::Sieve`.
+    pub sieve: Option<String>,
+    /// The address of clamd, `host:port` or `unix:` and a socket path, see
+    /// `clamav::Clamd`.
+    pub clamd: Option<String>,
+    /// Whether infected messages are quarantined rather than refused.
+    pub clamd_quarantine: Option<bool>,
+    /// The spam scanner each message is submitted to, rspamd at an `http://` URL or spamd
+    /// at `host:port`, see `spam::SpamFilter`.
+    pub spam_scanner: Option<String>,
+    /// The score from which scanned messages are refused.
+    pub spam_reject_score: Option<f64>,
+    /// The score from which scanned messages are quarantined.
+    pub spam_quarantine_score: Option<f64>,
+    /// The journal directory. Read at startup only, like `listen`.
+    pub journal: Option<String>,
+    /// The directory messages a handler quarantines are kept in, see
+    /// `quarantine::Quarantine`. Read at startup only.
+    pub quarantine: Option<String>,
+    /// The file bans of abusive clients are kept in, which enables them, see
+    /// `ban::BanList`. Read at startup only.
+    pub bans: Option<String>,
+    /// The unprivileged user to run as once `listen` is bound, see
+    /// `daemon::drop_privileges`. Read at startup only.
+    pub user: Option<String>,
+    /// The group to run as instead of the primary group of `user`.
+    pub group: Option<String>,
+    /// Whether the server confines itself to the files it needs, see `sandbox::Sandbox`.
+    /// Read at startup only.
+    pub sandbox: bool,
+    /// Paths the sandbox may read besides the ones this file names.
+    pub sandbox_read: Vec<String>,
+    /// Paths the sandbox may write besides the journal and log files.
+    pub sandbox_write: Vec<String>,
+    /// The address of the admin API, see `admin::Admin`. Read at startup only.
+    pub admin_listen: Option<String>,
+    /// The token clients of the admin API authenticate with, required with `admin_listen`.
+    pub admin_token: Option<String>,
+    /// The address of the POP3 service, see `pop3::Pop3`. Read at startup only.
+    pub pop3_listen: Option<String>,
+    /// A file of the users of the POP3 service, see `auth::Passwords::load`, required with
+    /// `pop3_listen`. Read at startup only.
+    pub pop3_passwords: Option<String>,
+    /// The address of the IMAP service, see `imap::Imap`, which needs the `imap` feature.
+    /// Read at startup only.
+    pub imap_listen: Option<String>,
+    /// The users of the IMAP service, like `pop3_passwords`.
+    pub imap_passwords: Option<String>,
+    pub log_level: Option<Level>,
+}
+
+/// A line of a configuration file that could not be read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SettingsError {
+    /// Counted from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Settings {
+    /// Reads every line of `text`, returning the errors of all invalid lines if any.
+    pub fn parse(text: &str) -> Result<Settings, Vec<SettingsError>> {
+        let mut settings = Settings::default();
+        let mut errors = Vec::new();
+        for (line, entry) in entries(text) {
+            let result = match entry {
+                Some((key, value)) => settings.set(key, value),
+                None => Err(String::from("expected key = value")),
+            };
+            if let Err(message) = result {
+                errors.push(SettingsError { line, message });
+            }
+        }
+        if errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Everything wrong with `text`, in line order: the lines `parse` refuses, referenced
+    /// files and directories that are missing or unreadable, policy and Sieve scripts that
+    /// do not parse, and `listen` addresses that do not resolve. Empty when the file is
+    /// good to deploy.
+    pub fn check(text: &str) -> Vec<SettingsError> {
+        let mut errors = Settings::parse(text).err().unwrap_or_default();
+        for (line, entry) in entries(text) {
+            if let Some((key, value)) = entry {
+                if let Err(message) = check_entry(key, value) {
+                    errors.push(SettingsError { line, message });
+                }
+            }
+        }
+        errors.sort_by_key(|e| e.line);
+        errors
+    }
+
+    /// Reads the file at `path`, its errors reported as `path:line: message`.
+    pub fn load(path: &str) -> io::Result<Settings> {
+        Settings::parse(&fs::read_to_string(path)?).map_err(|errors| {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|e| format!("{}:{}: {}", path, e.line, e.message))
+                .collect();
+            io::Error::new(io::ErrorKind::InvalidData, errors.join("; "))
+        })
+    }
+
+    /// The settings the server runs with: those of the file `SIMPLE_SMTP_CONFIG` names, if
+    /// set, overridden by the environment.
+    pub fn from_env() -> io::Result<Settings> {
+        let mut settings = match std::env::var(CONFIG_ENV) {
+            Ok(path) => Settings::load(&path)?,
+            Err(_) => Settings::default(),
+        };
+        let vars = std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        settings.overlay_env(vars)?;
+        Ok(settings)
+    }
+
+    /// Overrides what the file sets with the `SIMPLE_SMTP_<KEY>` variables among `vars`, as
+    /// containers are configured: `SIMPLE_SMTP_SERVER_NAME=mx.example.com` replaces
+    /// `server_name`. The keys that may be given more than once take a comma-separated
+    /// list, which replaces the one of the file. Variables that name no key, like
+    /// `SIMPLE_SMTP_CONFIG` or `SIMPLE_SMTP_LOG`, are left to what reads them. The errors
+    /// name their variable.
+    pub fn overlay_env<I>(&mut self, vars: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut errors = Vec::new();
+        for (name, value) in vars {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) => key.to_lowercase(),
+                None => continue,
+            };
+            if !KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let result = if LISTS.contains(&key.as_str()) {
+                self.clear(&key);
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .try_for_each(|value| self.set(&key, value))
+            } else {
+                self.set(&key, value.trim())
+            };
+            if let Err(message) = result {
+                errors.push(format!("{}: {}", name, message));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                errors.join("; "),
+            ))
+        }
+    }
+
+    fn clear(&mut self, key: &str) {
+        match key {
+            "listen" => self.listen.clear(),
+            "trust" => self.trust.clear(),
+            "domain" => self.domains.clear(),
+            "sender_quota" => self.sender_quotas.clear(),
+            "mailbox_quota" => self.mailbox_quotas = MailboxLimits::default(),
+            "milter" => self.milters.clear(),
+            "sandbox_read" => self.sandbox_read.clear(),
+            "sandbox_write" => self.sandbox_write.clear(),
+            _ => {}
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = || -> Result<usize, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid {} {}", key, value))
+        };
+        let score = || -> Result<f64, String> {
+            value
+                .parse()
+                .map_err(|_| format!("invalid {} {}", key, value))
+        };
+        let flag = || -> Result<bool, String> {
+            match value {
+                "yes" | "true" | "on" => Ok(true),
+                "no" | "false" | "off" => Ok(false),
+                _ => Err(format!("invalid {} {}, expected yes or no", key, value)),
+            }
+        };
+        match key {
+            "listen" => self.listen.push(String::from(value)),
+            "server_name" => self.server_name = Some(String::from(value)),
+            "max_hops" => self.max_hops = Some(number()?),
+            "max_line_length" => self.max_line_length = Some(number()?),
+            "command_timeout" => self.command_timeout = Some(retry::parse_duration(value)?),
+            "max_recipients" => self.max_recipients = Some(number()?),
+            "connection_rate" => {
+                let rate = value
+                    .parse()
+                    .map_err(|_| format!("invalid {} {}", key, value))?;
+                self.connection_rate = Some(rate);
+            }
+            "max_queue_lifetime" => self.max_queue_lifetime = Some(retry::parse_duration(value)?),
+            "delay_warning" => self.delay_warning = Some(retry::parse_duration(value)?),
+            "sender_quota" => self.sender_quotas.push(value.parse()?),
+            "mailbox_quota" => self.mailbox_quotas.add(value)?,
+            "require_helo" => self.require_helo = Some(flag()?),
+            "require_auth" => self.require_auth = Some(flag()?),
+            "insecure_auth" => self.insecure_auth = Some(flag()?),
+            "smtp_passwords" => self.smtp_passwords = Some(String::from(value)),
+            "trust" => self.trust.push(value.parse()?),
+            "domain" => self.domains.push(String::from(value)),
+            "aliases" => self.aliases = Some(String::from(value)),
+            "script" => self.script = Some(String::from(value)),
+            "policy_service" => self.policy_service = Some(String::from(value)),
+            "milter" => self.milters.push(String::from(value)),
+            "sieve" => self.sieve = Some(String::from(value)),
+            "clamd" => self.clamd = Some(String::from(value)),
+            "clamd_quarantine" => self.clamd_quarantine = Some(flag()?),
+            "spam_scanner" => self.spam_scanner = Some(String::from(value)),
+            "spam_reject_score" => self.spam_reject_score = Some(score()?),
+            "spam_quarantine_score" => self.spam_quarantine_score = Some(score()?),
+            "journal" => self.journal = Some(String::from(value)),
+            "quarantine" => self.quarantine = Some(String::from(value)),
+            "bans" => self.bans = Some(String::from(value)),
+            "user" => self.user = Some(String::from(value)),
+            "group" => self.group = Some(String::from(value)),
+            "sandbox" => self.sandbox = flag()?,
+            "sandbox_read" => self.sandbox_read.push(String::from(value)),
+            "sandbox_write" => self.sandbox_write.push(String::from(value)),
+            "admin_listen" => self.admin_listen = Some(String::from(value)),
+            "admin_token" => self.admin_token = Some(String::from(value)),
+            "pop3_listen" => self.pop3_listen = Some(String::from(value)),
+            "pop3_passwords" => self.pop3_passwords = Some(String::from(value)),
+            "imap_listen" => self.imap_listen = Some(String::from(value)),
+            "imap_passwords" => self.imap_passwords = Some(String::from(value)),
+            "log_level" => self.log_level = Some(value.parse()?),
+            _ => return Err(format!("unknown key {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Sets what the file gives in `config`, reading the alias file, and sets the log
+    /// level. `listen`, `journal`, `quarantine`, `bans`, `user`, `group`, the sandbox, the
+    /// admin API, POP3 and IMAP are left to the caller.
+    pub fn apply(&self, config: &mut Config) -> io::Result<()> {
+        if let Some(server_name) = &self.server_name {
+            config.server_name = server_name.clone();
+        }
+        if let Some(max_hops) = self.max_hops {
+            config.max_hops = max_hops;
+        }
+        if let Some(max_line_length) = self.max_line_length {
+            config.max_line_length = max_line_length;
+        }
+        if let Some(timeout) = self.command_timeout {
+            config.command_timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        }
+        if let Some(lifetime) = self.max_queue_lifetime {
+            config.retry.max_lifetime = lifetime;
+        }
+        if let Some(warning) = self.delay_warning {
+            config.retry.delay_warning = Some(warning).filter(|warning| !warning.is_zero());
+        }
+        config.sender_quotas.set_quotas(self.sender_quotas.clone());
+        if let Some(quotas) = &config.mailbox_quotas {
+            quotas.set_limits(self.mailbox_quotas.clone());
+        }
+        let mut policy = config.policy.clone();
+        if let Some(max_recipients) = self.max_recipients {
+            policy = policy.max_recipients(max_recipients);
+        }
+        if let Some(require_helo) = self.require_helo {
+            policy = policy.require_helo(require_helo);
+        }
+        if let Some(require_auth) = self.require_auth {
+            policy = policy.require_auth(require_auth);
+        }
+        if let Some(insecure_auth) = self.insecure_auth {
+            policy = policy.insecure_auth(insecure_auth);
+        }
+        for network in &self.trust {
+            policy = policy.trust(*network);
+        }
+        config.policy = policy;
+        #[cfg(feature = "server")]
+        if let Some(rate) = self.connection_rate {
+            config.control.set_connection_rate(rate);
+        }
+        if !self.domains.is_empty() {
+            let mut domains = Domains::default();
+            for domain in &self.domains {
+                domains.add(Domain::new(domain));
+            }
+            config.domains = domains;
+        }
+        if let Some(path) = &self.aliases {
+            config.aliases = Aliases::load(path)?;
+        }
+        if let Some(path) = &self.script {
+            config.script = Some(Arc::new(PolicyScript::load(path)?));
+        }
+        if let Some(path) = &self.smtp_passwords {
+            config.authenticator = Some(Arc::new(Passwords::load(path)?));
+        }
+        #[cfg(feature = "server")]
+        if let Some(address) = &self.policy_service {
+            let service = crate::policy_service::PolicyService::new(address);
+            config.policy_service = Some(std::sync::Arc::new(service));
+        }
+        #[cfg(feature = "server")]
+        if !self.milters.is_empty() {
+            config.milters = self
+                .milters
+                .iter()
+                .map(|address| std::sync::Arc::new(crate::milter::Milter::new(address)))
+                .collect();
+        }
+        if let Some(directory) = &self.sieve {
+            config.sieve = Some(Arc::new(Sieve::new(directory)));
+        }
+        #[cfg(feature = "server")]
+        if let Some(address) = &self.clamd {
+            let mut clamd = crate::clamav::Clamd::new(address);
+            clamd.quarantine = self.clamd_quarantine.unwrap_or(false);
+            config.clamd = Some(std::sync::Arc::new(clamd));
+        }
+        #[cfg(feature = "server")]
+        if let Some(scanner) = &self.spam_scanner {
+            let scanner = scanner
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mut filter = crate::spam::SpamFilter::new(scanner);
+            filter.reject_score = self.spam_reject_score;
+            filter.quarantine_score = self.spam_quarantine_score;
+            config.spam_filter = Some(std::sync::Arc::new(filter));
+        }
+        if let Some(level) = self.log_level {
+            log::set_level(level);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+impl Settings {
+    /// What the server needs once confined, see `Sandbox`: the configuration file
+    /// `config_file` and every file it names, read again on reload, the directories of the
+    /// files replaced rather than written in place, the log files, the bans and the PID
+    /// file, the commands of the pipe transports of `config` with the libraries they load,
+    /// and what the resolver reads.
+    pub fn sandbox(
+        &self,
+        config_file: Option<&str>,
+        pid_file: Option<&str>,
+        config: &Config,
+    ) -> Sandbox {
+        let mut sandbox = Sandbox::new();
+        sandbox.allow_read("/etc/resolv.conf");
+        sandbox.allow_read("/etc/hosts");
+        let read = config_file
+            .into_iter()
+            .chain(self.aliases.as_deref())
+            .chain(self.script.as_deref())
+            .chain(self.smtp_passwords.as_deref())
+            .chain(self.sieve.as_deref());
+        for path in read.chain(self.sandbox_read.iter().map(String::as_str)) {
+            sandbox.allow_read(path);
+        }
+        let files = [log::FILE_ENV, crate::audit::AUDIT_ENV]
+            .iter()
+            .filter_map(|name| std::env::var(name).ok())
+            .chain(self.bans.clone())
+            .chain(pid_file.map(String::from))
+            .filter_map(|file| Path::new(&file).parent().map(Path::to_path_buf));
+        for path in files {
+            sandbox.allow_write(path);
+        }
+        let directories = self.journal.iter().chain(&self.quarantine);
+        for path in directories.chain(&self.sandbox_write) {
+            sandbox.allow_write(path);
+        }
+        let commands: Vec<PathBuf> = config.transports.pipes().filter_map(Pipe::path).collect();
+        if !commands.is_empty() {
+            // The dynamic linker and the libraries it maps.
+            for path in ["/lib", "/lib64", "/usr/lib", "/usr/lib64"] {
+                sandbox.allow_execute(path);
+            }
+            sandbox.allow_read("/etc/ld.so.cache");
+        }
+        for command in commands {
+            sandbox.allow_execute(command);
+        }
+        sandbox
+    }
+}
+
+/// The `key = value` entries of `text` with their line numbers, `None` for lines that are
+/// not, skipping blank lines and comments.
+fn entries(text: &str) -> impl Iterator<Item = (usize, Option<(&str, &str)>)> {
+    text.lines().enumerate().filter_map(|(number, line)| {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            return None;
+        }
+        let entry = line
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()));
+        Some((number + 1, entry))
+    })
+}
+
+/// `line` without its comment, which starts with `#` at the beginning of the line or after
+/// whitespace, so that values such as `admin_token = a#b` keep theirs.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = None;
+    for (index, c) in line.char_indices() {
+        if c == '#' && previous.is_none_or(char::is_whitespace) {
+            return &line[..index];
+        }
+        previous = Some(c);
+    }
+    line
+}
+
+/// Whether what the entry refers to is there to be used.
+fn check_entry(key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "aliases" => Aliases::load(value)
+            .map(drop)
+            .map_err(|e| format!("unable to read aliases {}: {}", value, e)),
+        "script" => PolicyScript::load(value)
+            .map(drop)
+            .map_err(|e| format!("unable to load script {}: {}", value, e)),
+        "sieve" => check_sieve(value),
+        "bans" => {
+            // Saved next to the file, then renamed over it.
+            let directory = std::path::Path::new(value)
+                .parent()
+                .filter(|directory| !directory.as_os_str().is_empty())
+                .unwrap_or_else(|| std::path::Path::new("."));
+            if !directory.is_dir() {
+                return Err(format!(
+                    "bans directory {} does not exist",
+                    directory.display()
+                ));
+            }
+            crate::ban::BanList::open(value)
+                .map(drop)
+                .map_err(|e| format!("unable to read bans {}: {}", value, e))
+        }
+        "smtp_passwords" | "pop3_passwords" | "imap_passwords" => Passwords::load(value)
+            .map(drop)
+            .map_err(|e| format!("unable to read passwords {}: {}", value, e)),
+        "journal" | "quarantine" => match fs::metadata(value) {
+            Ok(metadata) if !metadata.is_dir() => Err(format!("{} is not a directory", value)),
+            Ok(metadata) if metadata.permissions().readonly() => {
+                Err(format!("{} directory {} is read-only", key, value))
+            }
+            Ok(_) => fs::read_dir(value)
+                .map(drop)
+                .map_err(|e| format!("unable to read {} directory {}: {}", key, value, e)),
+            // Created when it is opened.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!(
+                "unable to access {} directory {}: {}",
+                key, value, e
+            )),
+        },
+        #[cfg(feature = "server")]
+        "listen" | "admin_listen" | "pop3_listen" | "imap_listen" => {
+            match std::net::ToSocketAddrs::to_socket_addrs(value) {
+                Ok(addresses) if addresses.len() > 0 => Ok(()),
+                Ok(_) => Err(format!("{} resolves to no address", value)),
+                Err(e) => Err(format!("unable to resolve {}: {}", value, e)),
+            }
+        }
+        #[cfg(feature = "server")]
+        "spam_scanner" => value.parse::<crate::spam::Scanner>().map(drop),
+        #[cfg(feature = "server")]
+        "user" => crate::daemon::lookup_user(value)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        #[cfg(feature = "server")]
+        "group" => crate::daemon::lookup_group(value)
+            .map(drop)
+            .map_err(|e| e.to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the Sieve scripts in `directory` can all be read and parsed.
+fn check_sieve(directory: &str) -> Result<(), String> {
+    let entries = fs::read_dir(directory)
+        .map_err(|e| format!("unable to read sieve directory {}: {}", directory, e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path
+            .extension()
+            .is_none_or(|extension| extension != "sieve")
+        {
+            continue;
+        }
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        crate::sieve::Script::parse(&text)
+            .map_err(|e| format!("invalid Sieve script {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let settings = Settings::parse(
+            "# an MX\n\
+             server_name = mx.example.com\n\
+             \n\
+             domain = example.com\n\
+             domain = example.net  # and this one\n\
+             trust = 10.0.0.0/8\n\
+             max_recipients = 50\n\
+             require_helo = no\n\
+             insecure_auth = yes\n\
+             connection_rate = 20\n\
+             command_timeout = 10m\n\
+             max_queue_lifetime = 2d\n\
+             delay_warning = 4h\n\
+             sender_quota = 100 messages/1h\n\
+             mailbox_quota = 50M\n\
+             sieve = /etc/simple-smtp/sieve\n\
+             clamd = unix:/run/clamav/clamd.ctl\n\
+             spam_scanner = 127.0.0.1:783\n\
+             spam_reject_score = 15.5\n\
+             admin_token = s3cr#t # not this\n\
+             \t# indented\n",
+        )
+        .unwrap();
+        assert_eq!(settings.server_name.as_deref(), Some("mx.example.com"));
+        assert_eq!(settings.domains, vec!["example.com", "example.net"]);
+        assert_eq!(settings.max_recipients, Some(50));
+        assert_eq!(settings.require_helo, Some(false));
+        assert_eq!(settings.admin_token.as_deref(), Some("s3cr#t"));
+
+        let mut config = Config::default();
+        settings.apply(&mut config).unwrap();
+        assert_eq!(config.server_name, "mx.example.com");
+        #[cfg(feature = "server")]
+        assert_eq!(config.control.connection_rate(), 20);
+        assert!(config.domains.accepts("<a@example.net>"));
+        assert!(!config.domains.accepts("<a@example.org>"));
+        assert_eq!(config.command_timeout, Some(Duration::from_secs(600)));
+        assert_eq!(config.retry.max_lifetime, Duration::from_secs(2 * 86400));
+        assert_eq!(
+            config.retry.delay_warning,
+            Some(Duration::from_secs(4 * 3600))
+        );
+        assert_eq!(settings.mailbox_quotas.default, Some(50 << 20));
+        #[cfg(feature = "server")]
+        assert_eq!(
+            config
+                .spam_filter
+                .as_ref()
+                .and_then(|filter| filter.reject_score),
+            Some(15.5)
+        );
+        assert_eq!(
+            config.sender_quotas.quotas(),
+            vec!["100 messages/1h".parse().unwrap()]
+        );
+        assert_eq!(
+            config.policy,
+            Config::default()
+                .policy
+                .max_recipients(50)
+                .require_helo(false)
+                .insecure_auth(true)
+                .trust("10.0.0.0/8".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors =
+            Settings::parse("max_hops = many\nserver_name\n\nlog_level = info\ncolour = blue\n")
+                .unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![1, 2, 5]);
+        assert_eq!(errors[2].to_string(), "line 5: unknown key colour");
+    }
+
+    #[test]
+    fn test_check() {
+        let directory = std::env::temp_dir().join(format!("settings-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let aliases = directory.join("aliases");
+        fs::write(&aliases, "postmaster@example.com <admin@example.com>\n").unwrap();
+        let sieve = directory.join("sieve");
+        fs::create_dir_all(&sieve).unwrap();
+        fs::write(sieve.join("example.com.sieve"), "keep;").unwrap();
+        fs::write(
+            sieve.join("alice@example.com.sieve"),
+            "if true {\n  keep;\n",
+        )
+        .unwrap();
+        let script = directory.join("policy.script");
+        fs::write(&script, "on mail { reject 250 \"Ok\"; }").unwrap();
+        let text = format!(
+            "aliases = {}\n\
+             aliases = {}/missing\n\
+             journal = {}\n\
+             journal = {}\n\
+             max_hops = -1\n\
+             quarantine = {}\n\
+             sieve = {}\n\
+             sieve = {}/missing\n\
+             bans = {}/bans\n\
+             bans = {}/missing/bans\n\
+             script = {}\n",
+            aliases.display(),
+            directory.display(),
+            directory.display(),
+            aliases.display(),
+            aliases.display(),
+            sieve.display(),
+            directory.display(),
+            directory.display(),
+            directory.display(),
+            script.display(),
+        );
+        let errors = Settings::check(&text);
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![2, 4, 5, 6, 7, 8, 10, 11]);
+        assert_eq!(
+            errors[4].message,
+            format!(
+                "invalid Sieve script {}: line 2: expected }}",
+                sieve.join("alice@example.com.sieve").display()
+            )
+        );
+        fs::remove_file(sieve.join("alice@example.com.sieve")).unwrap();
+        assert!(Settings::check(&format!("sieve = {}\n", sieve.display())).is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_env() {
+        let mut settings = Settings::parse(
+            "server_name = mx.example.com\n\
+             domain = example.com\n\
+             max_recipients = 50\n",
+        )
+        .unwrap();
+        let vars = [
+            ("SIMPLE_SMTP_SERVER_NAME", "mx2.example.com"),
+            ("SIMPLE_SMTP_DOMAIN", "example.net, example.org"),
+            ("SIMPLE_SMTP_LISTEN", "0.0.0.0:25,[::]:25"),
+            ("SIMPLE_SMTP_CONFIG", "/etc/simple-smtp.conf"),
+            ("SIMPLE_SMTP_LOG", "debug"),
+            ("PATH", "/usr/bin"),
+        ];
+        settings
+            .overlay_env(
+                vars.iter()
+                    .map(|(name, value)| (String::from(*name), String::from(*value))),
+            )
+            .unwrap();
+        assert_eq!(settings.server_name.as_deref(), Some("mx2.example.com"));
+        assert_eq!(settings.domains, vec!["example.net", "example.org"]);
+        assert_eq!(settings.listen, vec!["0.0.0.0:25", "[::]:25"]);
+        assert_eq!(settings.max_recipients, Some(50));
+
+        let e = settings
+            .overlay_env(vec![
+                (String::from("SIMPLE_SMTP_MAX_HOPS"), String::from("many")),
+                (
+                    String::from("SIMPLE_SMTP_REQUIRE_HELO"),
+                    String::from("yes"),
+                ),
+            ])
+            .unwrap_err();
+        assert_eq!(e.to_string(), "SIMPLE_SMTP_MAX_HOPS: invalid max_hops many");
+        assert_eq!(settings.require_helo, Some(true));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_sandbox() {
+        use crate::transport::Transport;
+
+        let settings = Settings::parse(
+            "aliases = /etc/simple-smtp/aliases\n\
+             script = /etc/simple-smtp/policy.script\n\
+             sieve = /var/lib/simple-smtp/sieve\n\
+             journal = /var/spool/simple-smtp/journal\n\
+             quarantine = /var/spool/simple-smtp/quarantine\n\
+             bans = /var/lib/simple-smtp/bans\n\
+             sandbox_read = /etc/ssl/certs\n\
+             sandbox_write = /var/tmp/simple-smtp\n",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.transports.add(
+            "lists.example.com",
+            Transport::Pipe(Pipe::new("/usr/bin/procmail", &["-d", "{recipient}"])),
+        );
+        let sandbox = settings.sandbox(
+            Some("/etc/simple-smtp.conf"),
+            Some("/run/simple-smtp/simple-smtp.pid"),
+            &config,
+        );
+        for path in [
+            "/etc/simple-smtp.conf",
+            "/etc/simple-smtp/aliases",
+            "/etc/simple-smtp/policy.script",
+            "/var/lib/simple-smtp/sieve",
+            "/etc/ssl/certs",
+            "/etc/resolv.conf",
+        ] {
+            assert!(sandbox.read.contains(&PathBuf::from(path)), "{}", path);
+        }
+        for path in [
+            "/var/spool/simple-smtp/journal",
+            "/var/spool/simple-smtp/quarantine",
+            "/var/lib/simple-smtp",
+            "/run/simple-smtp",
+            "/var/tmp/simple-smtp",
+        ] {
+            assert!(sandbox.write.contains(&PathBuf::from(path)), "{}", path);
+        }
+        assert!(sandbox
+            .execute
+            .contains(&PathBuf::from("/usr/bin/procmail")));
+        assert!(sandbox.execute.contains(&PathBuf::from("/usr/lib")));
+        assert!(Settings::default()
+            .sandbox(None, None, &Config::default())
+            .execute
+            .is_empty());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_check_listen() {
+        let errors = Settings::check(
+            "listen = 127.0.0.1:25\nlisten = 127.0.0.1\nuser = no-such-user-here\n\
+             admin_listen = 127.0.0.1\n",
+        );
+        let lines: Vec<usize> = errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![2, 3, 4]);
+    }
+}
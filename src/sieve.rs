@@ -0,0 +1,744 @@
+use std::{fs, io, iter::Peekable, path::PathBuf, str::Chars};
+
+use crate::{dsn, email::Mail, headers::Message};
+
+/// What a script does with a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Files it into the inbox.
+    Keep,
+    FileInto(String),
+    /// Forwards it to the address, the envelope sender unchanged.
+    Redirect(String),
+    Discard,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+/// How a `header` or `address` test compares: case-insensitively unless the comparator
+/// is `i;octet`.
+#[derive(Clone, Debug, PartialEq)]
+struct Comparison {
+    match_type: MatchType,
+    octet: bool,
+    names: Vec<String>,
+    keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AnyOf(Vec<Test>),
+    AllOf(Vec<Test>),
+    Exists(Vec<String>),
+    Header(Comparison),
+    Address(AddressPart, Comparison),
+    Size { over: bool, limit: usize },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Command {
+    /// The blocks of `if` and each `elsif` with their tests, then the `else` block.
+    If(Vec<(Test, Vec<Command>)>, Option<Vec<Command>>),
+    Action(Action),
+    Stop,
+}
+
+/// A Sieve script (RFC 5228), limited to the `if`, `keep`, `discard`, `redirect`, `stop`
+/// and `fileinto` commands and the `header`, `address`, `exists`, `size`, `not`, `anyof`,
+/// `allof`, `true` and `false` tests, with the `:is`, `:contains` and `:matches` match
+/// types and the `i;ascii-casemap` and `i;octet` comparators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Script {
+    commands: Vec<Command>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            capabilities: Vec::new(),
+        };
+        let commands = parser.commands(false)?;
+        Ok(Script { commands })
+    }
+
+    /// The actions the script takes on `mail`, `Keep` when it takes none.
+    pub fn run(&self, mail: &Mail) -> Vec<Action> {
+        let data = mail.data.as_deref().unwrap_or("");
+        let message = Message::parse(data);
+        let mut actions = Vec::new();
+        execute(&self.commands, &message, data.len(), &mut actions);
+        if actions.is_empty() {
+            actions.push(Action::Keep);
+        }
+        actions
+    }
+}
+
+/// Runs the Sieve scripts of local recipients as messages are delivered to them, see
+/// `email::deliver`: `<directory>/<user@domain>.sieve` for one recipient, or else
+/// `<directory>/<domain>.sieve` for every recipient of the domain. Scripts are read at
+/// each delivery, so changes apply at once. Messages are kept in the inbox of recipients
+/// without one, or whose script cannot be loaded.
+pub struct Sieve {
+    pub directory: PathBuf,
+}
+
+impl Sieve {
+    pub fn new(directory: impl Into<PathBuf>) -> Sieve {
+        Sieve {
+            directory: directory.into(),
+        }
+    }
+
+    /// The script of `recipient`, given as in RCPT TO, if there is one.
+    pub fn script(&self, recipient: &str) -> io::Result<Option<Script>> {
+        let address = dsn::split_parameters(recipient)
+            .0
+            .trim()
+            .trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_lowercase();
+        let domain = address.rsplit_once('@').map_or("", |(_, domain)| domain);
+        for name in [address.as_str(), domain].iter() {
+            // Names are taken from the client, so none may leave the directory.
+            if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                continue;
+            }
+            let text = match fs::read_to_string(self.directory.join(format!("{}.sieve", name))) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            return Script::parse(&text)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        Ok(None)
+    }
+
+    /// Splits `mail` by what the scripts of its recipients do with it: the copies to
+    /// deliver locally, by folder, then the ones to redirect. Redirected copies are not
+    /// filtered again, so that scripts cannot redirect a message around in a loop.
+    pub fn sort(&self, mail: &Mail) -> (Vec<Mail>, Vec<Mail>) {
+        let mut local: Vec<Mail> = Vec::new();
+        let mut redirected: Vec<Mail> = Vec::new();
+        for rcpt in mail.rcpt_to.iter() {
+            let actions = match self.script(rcpt) {
+                Ok(Some(script)) => script.run(mail),
+                Ok(None) => vec![Action::Keep],
+                Err(e) => {
+                    crate::warn!("Unable to load the Sieve script of {}: {}", rcpt, e);
+                    vec![Action::Keep]
+                }
+            };
+            for action in actions {
+                let (copies, folder, rcpt) = match action {
+                    Action::Keep => (&mut local, None, rcpt.clone()),
+                    Action::FileInto(folder) if folder.eq_ignore_ascii_case("INBOX") => {
+                        (&mut local, None, rcpt.clone())
+                    }
+                    Action::FileInto(folder) => (&mut local, Some(folder), rcpt.clone()),
+                    Action::Redirect(address) => (&mut redirected, None, format!("<{}>", address)),
+                    Action::Discard => continue,
+                };
+                match copies.iter_mut().find(|copy| copy.folder == folder) {
+                    Some(copy) if copy.rcpt_to.contains(&rcpt) => {}
+                    Some(copy) => copy.rcpt_to.push(rcpt),
+                    None => copies.push(Mail {
+                        rcpt_to: vec![rcpt],
+                        folder,
+                        ..mail.clone()
+                    }),
+                }
+            }
+        }
+        (local, redirected)
+    }
+}
+
+/// Runs `commands`, gathering the actions they take, and returns whether one stopped.
+fn execute(
+    commands: &[Command],
+    message: &Message,
+    size: usize,
+    actions: &mut Vec<Action>,
+) -> bool {
+    for command in commands {
+        match command {
+            Command::Action(action) => {
+                if !actions.contains(action) {
+                    actions.push(action.clone());
+                }
+            }
+            Command::Stop => return true,
+            Command::If(branches, otherwise) => {
+                let block = branches
+                    .iter()
+                    .find(|(test, _)| evaluate(test, message, size))
+                    .map(|(_, block)| block)
+                    .or(otherwise.as_ref());
+                if let Some(block) = block {
+                    if execute(block, message, size, actions) {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+fn evaluate(test: &Test, message: &Message, size: usize) -> bool {
+    match test {
+        Test::True => true,
+        Test::False => false,
+        Test::Not(test) => !evaluate(test, message, size),
+        Test::AnyOf(tests) => tests.iter().any(|test| evaluate(test, message, size)),
+        Test::AllOf(tests) => tests.iter().all(|test| evaluate(test, message, size)),
+        Test::Exists(names) => names.iter().all(|name| message.get(name).is_some()),
+        Test::Header(comparison) => {
+            values(message, &comparison.names).any(|value| comparison.matches(&value))
+        }
+        Test::Address(part, comparison) => values(message, &comparison.names)
+            .flat_map(|value| addresses(&value))
+            .any(|address| comparison.matches(part.of(&address))),
+        Test::Size { over, limit } => {
+            if *over {
+                size > *limit
+            } else {
+                size < *limit
+            }
+        }
+    }
+}
+
+/// The unfolded values of the headers of `message` named `names`.
+fn values<'a>(message: &'a Message, names: &'a [String]) -> impl Iterator<Item = String> + 'a {
+    message
+        .headers
+        .iter()
+        .filter(move |header| {
+            names
+                .iter()
+                .any(|name| header.name.eq_ignore_ascii_case(name))
+        })
+        .map(|header| header.value.replace(['\r', '\n'], ""))
+}
+
+/// The addresses in an address list, e.g. `Alice <alice@example.com>, bob@example.com`.
+fn addresses(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|mailbox| match (mailbox.rfind('<'), mailbox.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+            _ => mailbox,
+        })
+        .map(|address| String::from(address.trim()))
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+impl AddressPart {
+    fn of<'a>(&self, address: &'a str) -> &'a str {
+        match (self, address.rsplit_once('@')) {
+            (AddressPart::LocalPart, Some((local, _))) => local,
+            (AddressPart::Domain, Some((_, domain))) => domain,
+            (AddressPart::Domain, None) => "",
+            _ => address,
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, value: &str) -> bool {
+        let fold = |text: &str| {
+            if self.octet {
+                String::from(text)
+            } else {
+                text.to_lowercase()
+            }
+        };
+        let value = fold(value);
+        self.keys.iter().any(|key| {
+            let key = fold(key);
+            match self.match_type {
+                MatchType::Is => value == key,
+                MatchType::Contains => value.contains(&key),
+                MatchType::Matches => {
+                    let value: Vec<char> = value.chars().collect();
+                    let pattern: Vec<char> = key.chars().collect();
+                    wildcard(&value, &pattern)
+                }
+            }
+        })
+    }
+}
+
+/// Whether `value` matches `pattern`, where `*` stands for any run of characters, `?`
+/// for any one, and `\` escapes the next.
+pub(crate) fn wildcard(value: &[char], pattern: &[char]) -> bool {
+    match pattern {
+        [] => value.is_empty(),
+        ['*', rest @ ..] => (0..=value.len()).any(|skip| wildcard(&value[skip..], rest)),
+        ['?', rest @ ..] => !value.is_empty() && wildcard(&value[1..], rest),
+        ['\\', c, rest @ ..] | [c, rest @ ..] => {
+            value.first() == Some(c) && wildcard(&value[1..], rest)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    String(String),
+    Number(usize),
+    Special(char),
+}
+
+/// The tokens of `text`, each with its line number.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    fn take_while(chars: &mut Peekable<Chars>, first: Option<char>, f: fn(char) -> bool) -> String {
+        let mut taken: String = first.into_iter().collect();
+        while let Some(&c) = chars.peek() {
+            if !f(c) {
+                break;
+            }
+            taken.push(c);
+            chars.next();
+        }
+        taken
+    }
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    while let Some(c) = chars.next() {
+        let start = line;
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '#' => {
+                take_while(&mut chars, None, |c| c != '\n');
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => return Err(format!("line {}: unterminated comment", start)),
+                    }
+                }
+                continue;
+            }
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => break,
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            value.push(c);
+                        }
+                        None => return Err(format!("line {}: unterminated string", start)),
+                    }
+                }
+                Token::String(value)
+            }
+            ':' => {
+                let name = take_while(&mut chars, None, word);
+                if name.is_empty() {
+                    return Err(format!("line {}: expected a tag after :", start));
+                }
+                Token::Tag(name.to_lowercase())
+            }
+            c if c.is_ascii_digit() => {
+                let digits = take_while(&mut chars, Some(c), |c| c.is_ascii_digit());
+                let multiplier = match chars.peek().map(char::to_ascii_uppercase) {
+                    Some('K') => 1 << 10,
+                    Some('M') => 1 << 20,
+                    Some('G') => 1 << 30,
+                    _ => 1,
+                };
+                if multiplier > 1 {
+                    chars.next();
+                }
+                let number = digits
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| number.checked_mul(multiplier))
+                    .ok_or_else(|| format!("line {}: invalid number {}", start, digits))?;
+                Token::Number(number)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                Token::Identifier(take_while(&mut chars, Some(c), word).to_lowercase())
+            }
+            '(' | ')' | '[' | ']' | '{' | '}' | ',' | ';' => Token::Special(c),
+            c => return Err(format!("line {}: unexpected {}", start, c)),
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// The extensions `require` asked for.
+    capabilities: Vec<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> String {
+        match self
+            .tokens
+            .get(self.position.min(self.tokens.len().saturating_sub(1)))
+        {
+            Some((_, line)) => format!("line {}: {}", line, message),
+            None => String::from(message),
+        }
+    }
+
+    fn expect(&mut self, special: char) -> Result<(), String> {
+        if self.peek() != Some(&Token::Special(special)) {
+            return Err(self.error(&format!("expected {}", special)));
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    /// The commands up to the end of the script, or of the block when `block`.
+    fn commands(&mut self, block: bool) -> Result<Vec<Command>, String> {
+        let mut commands = Vec::new();
+        loop {
+            match self.peek() {
+                None if block => return Err(self.error("expected }")),
+                None => return Ok(commands),
+                Some(Token::Special('}')) if block => {
+                    self.position += 1;
+                    return Ok(commands);
+                }
+                Some(Token::Identifier(_)) => {
+                    if let Some(command) = self.command()? {
+                        commands.push(command);
+                    }
+                }
+                Some(_) => return Err(self.error("expected a command")),
+            }
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Command>, String> {
+        self.expect('{')?;
+        self.commands(true)
+    }
+
+    fn command(&mut self) -> Result<Option<Command>, String> {
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.error("expected a command")),
+        };
+        let command = match name.as_str() {
+            "require" => {
+                for capability in self.strings()? {
+                    if capability != "fileinto" {
+                        return Err(self.error(&format!("unsupported extension {}", capability)));
+                    }
+                    self.capabilities.push(capability);
+                }
+                self.expect(';')?;
+                return Ok(None);
+            }
+            "if" => {
+                let mut branches = vec![(self.test()?, self.block()?)];
+                let mut otherwise = None;
+                loop {
+                    match self.peek() {
+                        Some(Token::Identifier(name)) if name == "elsif" => {
+                            self.position += 1;
+                            branches.push((self.test()?, self.block()?));
+                        }
+                        Some(Token::Identifier(name)) if name == "else" => {
+                            self.position += 1;
+                            otherwise = Some(self.block()?);
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                return Ok(Some(Command::If(branches, otherwise)));
+            }
+            "keep" => Command::Action(Action::Keep),
+            "discard" => Command::Action(Action::Discard),
+            "stop" => Command::Stop,
+            "fileinto" => {
+                if !self.capabilities.iter().any(|c| c == "fileinto") {
+                    return Err(self.error("fileinto used without require \"fileinto\""));
+                }
+                Command::Action(Action::FileInto(self.string()?))
+            }
+            "redirect" => Command::Action(Action::Redirect(self.string()?)),
+            name => return Err(self.error(&format!("unknown command {}", name))),
+        };
+        self.expect(';')?;
+        Ok(Some(command))
+    }
+
+    fn test(&mut self) -> Result<Test, String> {
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return Err(self.error("expected a test")),
+        };
+        Ok(match name.as_str() {
+            "true" => Test::True,
+            "false" => Test::False,
+            "not" => Test::Not(Box::new(self.test()?)),
+            "anyof" | "allof" => {
+                self.expect('(')?;
+                let mut tests = vec![self.test()?];
+                while self.peek() == Some(&Token::Special(',')) {
+                    self.position += 1;
+                    tests.push(self.test()?);
+                }
+                self.expect(')')?;
+                if name == "anyof" {
+                    Test::AnyOf(tests)
+                } else {
+                    Test::AllOf(tests)
+                }
+            }
+            "exists" => Test::Exists(self.strings()?),
+            "header" => Test::Header(self.comparison(false)?.1),
+            "address" => {
+                let (part, comparison) = self.comparison(true)?;
+                Test::Address(part, comparison)
+            }
+            "size" => {
+                let over = match self.next() {
+                    Some(Token::Tag(tag)) if tag == "over" => true,
+                    Some(Token::Tag(tag)) if tag == "under" => false,
+                    _ => return Err(self.error("expected :over or :under")),
+                };
+                match self.next() {
+                    Some(Token::Number(limit)) => Test::Size { over, limit },
+                    _ => return Err(self.error("expected a number")),
+                }
+            }
+            name => return Err(self.error(&format!("unknown test {}", name))),
+        })
+    }
+
+    /// The tags, header names and keys of a `header` test, or with an address part of an
+    /// `address` one.
+    fn comparison(&mut self, address: bool) -> Result<(AddressPart, Comparison), String> {
+        let mut part = AddressPart::All;
+        let mut match_type = MatchType::Is;
+        let mut octet = false;
+        while let Some(Token::Tag(tag)) = self.peek().cloned() {
+            self.position += 1;
+            match tag.as_str() {
+                "is" => match_type = MatchType::Is,
+                "contains" => match_type = MatchType::Contains,
+                "matches" => match_type = MatchType::Matches,
+                "comparator" => {
+                    octet = match self.string()?.as_str() {
+                        "i;octet" => true,
+                        "i;ascii-casemap" => false,
+                        comparator => {
+                            return Err(
+                                self.error(&format!("unsupported comparator {}", comparator))
+                            )
+                        }
+                    }
+                }
+                "all" if address => part = AddressPart::All,
+                "localpart" if address => part = AddressPart::LocalPart,
+                "domain" if address => part = AddressPart::Domain,
+                tag => return Err(self.error(&format!("unknown tag :{}", tag))),
+            }
+        }
+        let names = self.strings()?;
+        let keys = self.strings()?;
+        Ok((
+            part,
+            Comparison {
+                match_type,
+                octet,
+                names,
+                keys,
+            },
+        ))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::String(value)) => Ok(value),
+            _ => Err(self.error("expected a string")),
+        }
+    }
+
+    /// A string, or a list of them in brackets.
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        if self.peek() != Some(&Token::Special('[')) {
+            return Ok(vec![self.string()?]);
+        }
+        self.position += 1;
+        let mut strings = vec![self.string()?];
+        while self.peek() == Some(&Token::Special(',')) {
+            self.position += 1;
+            strings.push(self.string()?);
+        }
+        self.expect(']')?;
+        Ok(strings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(data: &str) -> Mail {
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.org>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>")];
+        mail.data = Some(String::from(data));
+        mail
+    }
+
+    #[test]
+    fn test_run() {
+        let script = Script::parse(
+            "require [\"fileinto\"];\n\
+             # Lists go to their own folder.\n\
+             if header :contains \"List-Id\" \"announce\" {\n\
+                 fileinto \"Lists\";\n\
+             } elsif address :domain :is [\"From\", \"Sender\"] \"Example.NET\" {\n\
+                 redirect \"c@example.org\";\n\
+                 keep;\n\
+             } elsif anyof (header :matches \"Subject\" \"*[SPAM]*\", size :over 1M) {\n\
+                 discard;\n\
+                 stop;\n\
+             }\n",
+        )
+        .unwrap();
+        assert_eq!(
+            script.run(&mail(
+                "List-Id: Announcements <announce.example.org>\r\n\r\nhi\r\n"
+            )),
+            vec![Action::FileInto(String::from("Lists"))]
+        );
+        assert_eq!(
+            script.run(&mail("From: Carol <carol@example.net>\r\n\r\nhi\r\n")),
+            vec![
+                Action::Redirect(String::from("c@example.org")),
+                Action::Keep
+            ]
+        );
+        assert_eq!(
+            script.run(&mail("Subject: Re: [SPAM]\r\n\tbuy now\r\n\r\nhi\r\n")),
+            vec![Action::Discard]
+        );
+        assert_eq!(
+            script.run(&mail("Subject: hello\r\n\r\nhi\r\n")),
+            vec![Action::Keep]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Script::parse("fileinto \"Lists\";"),
+            Err(String::from(
+                "line 1: fileinto used without require \"fileinto\""
+            ))
+        );
+        assert_eq!(
+            Script::parse("require \"vacation\";"),
+            Err(String::from("line 1: unsupported extension vacation"))
+        );
+        assert_eq!(
+            Script::parse("if true {\n  keep;\n"),
+            Err(String::from("line 2: expected }"))
+        );
+        assert!(Script::parse("if header :over \"a\" \"b\" { keep; }").is_err());
+    }
+
+    #[test]
+    fn test_sort() {
+        let directory = std::env::temp_dir().join(format!("sieve-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(
+            directory.join("example.com.sieve"),
+            "require \"fileinto\"; fileinto \"Archive\";",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("b@example.com.sieve"),
+            "redirect \"b@example.org\";",
+        )
+        .unwrap();
+        let sieve = Sieve::new(&directory);
+        let mut mail = mail("Subject: hi\r\n\r\nhi\r\n");
+        mail.rcpt_to = vec![
+            String::from("<B@example.com>"),
+            String::from("<c@example.com>"),
+            String::from("<d@example.com>"),
+            String::from("<e@example.net>"),
+        ];
+        let (local, redirected) = sieve.sort(&mail);
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(local.len(), 2);
+        assert_eq!(local[0].folder.as_deref(), Some("Archive"));
+        assert_eq!(local[0].rcpt_to, vec!["<c@example.com>", "<d@example.com>"]);
+        assert_eq!(local[1].folder, None);
+        assert_eq!(local[1].rcpt_to, vec!["<e@example.net>"]);
+        assert_eq!(redirected.len(), 1);
+        assert_eq!(redirected[0].rcpt_to, vec!["<b@example.org>"]);
+        assert_eq!(redirected[0].mail_from.as_deref(), Some("<a@example.org>"));
+    }
+}
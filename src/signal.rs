@@ -0,0 +1,119 @@
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// A signal the process may be asked to act on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    /// SIGHUP, conventionally asking a daemon to read its configuration again.
+    Hangup,
+    /// SIGTERM, asking the process to end, e.g. from an init script.
+    Terminate,
+}
+
+impl Signal {
+    fn received(&self) -> &'static AtomicBool {
+        static HANGUP: AtomicBool = AtomicBool::new(false);
+        static TERMINATE: AtomicBool = AtomicBool::new(false);
+        match self {
+            Signal::Hangup => &HANGUP,
+            Signal::Terminate => &TERMINATE,
+        }
+    }
+}
+
+/// How often the thread started by `watch` looks for signals received.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Runs `action` on a thread of its own each time the process receives `signal`, instead
+/// of the default of terminating. Signals received while `action` runs are coalesced.
+pub fn watch<F>(signal: Signal, action: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    sys::install(signal)?;
+    thread::spawn(move || loop {
+        thread::sleep(POLL_INTERVAL);
+        if signal.received().swap(false, Ordering::SeqCst) {
+            action();
+        }
+    });
+    Ok(())
+}
+
+/// Installing a handler that only sets the flag of the signal: anything more is not
+/// async-signal-safe.
+#[cfg(unix)]
+mod sys {
+    use std::{io, os::raw::c_int, sync::atomic::Ordering};
+
+    use super::Signal;
+
+    const SIGHUP: c_int = 1;
+    const SIGTERM: c_int = 15;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_hangup(_: c_int) {
+        Signal::Hangup.received().store(true, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_terminate(_: c_int) {
+        Signal::Terminate.received().store(true, Ordering::SeqCst);
+    }
+
+    pub fn install(which: Signal) -> io::Result<()> {
+        let (signum, handler) = match which {
+            Signal::Hangup => (SIGHUP, on_hangup as extern "C" fn(c_int) as usize),
+            Signal::Terminate => (SIGTERM, on_terminate as extern "C" fn(c_int) as usize),
+        };
+        // SAFETY: `handler` is an `extern "C" fn(c_int)` that only stores to an atomic.
+        if unsafe { signal(signum, handler) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+
+    use super::Signal;
+
+    pub fn install(_: Signal) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "signals are only handled on Unix",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_watch() {
+        extern "C" {
+            fn raise(signum: std::os::raw::c_int) -> std::os::raw::c_int;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        watch(Signal::Hangup, move || {
+            let _ = sender.send(());
+        })
+        .unwrap();
+        // SAFETY: SIGHUP now only sets a flag.
+        assert_eq!(unsafe { raise(1) }, 0);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
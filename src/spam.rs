@@ -0,0 +1,344 @@
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    connect,
+    email::Mail,
+    handler::Verdict,
+    headers::Message,
+    http::{self, Url},
+    json::Json,
+    session::SessionContext,
+};
+
+/// Where messages are scanned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Scanner {
+    /// rspamd, through its HTTP protocol, e.g. `http://127.0.0.1:11333/checkv2`.
+    Rspamd(Url),
+    /// SpamAssassin's spamd, through the SPAMC protocol.
+    Spamd { host: String, port: u16 },
+}
+
+impl FromStr for Scanner {
+    type Err = String;
+
+    /// rspamd at an `http://` URL, or spamd at `host:port`.
+    fn from_str(value: &str) -> Result<Scanner, String> {
+        if value.starts_with("http://") {
+            return Url::parse(value)
+                .map(Scanner::Rspamd)
+                .map_err(|e| e.to_string());
+        }
+        let (host, port) = value
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .ok_or_else(|| format!("invalid spam scanner {}", value))?;
+        Ok(Scanner::Spamd {
+            host: String::from(host.trim_matches(&['[', ']'][..])),
+            port,
+        })
+    }
+}
+
+/// What a scanner advises doing with a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Accept,
+    /// Deliver it marked as spam.
+    Tag,
+    /// Ask the client to try again later, e.g. rspamd's `greylist`.
+    Defer,
+    Reject,
+}
+
+/// What a scanner made of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scan {
+    pub score: f64,
+    /// The score from which the scanner takes a message for spam.
+    pub required: f64,
+    pub action: Action,
+    /// The subject rspamd rewrote, for `rewrite subject`.
+    pub subject: Option<String>,
+}
+
+/// Submits each accepted message to a spam scanner, then refuses, quarantines or tags it,
+/// see `decide` and `tag`.
+pub struct SpamFilter {
+    pub scanner: Scanner,
+    pub timeout: Duration,
+    /// Whether messages are accepted unscanned when the scanner cannot be reached, rather
+    /// than deferred.
+    pub fail_open: bool,
+    /// Messages scoring at least this are refused, whatever the scanner advises.
+    pub reject_score: Option<f64>,
+    /// Messages scoring at least this, and not refused, are quarantined.
+    pub quarantine_score: Option<f64>,
+}
+
+impl SpamFilter {
+    pub fn new(scanner: Scanner) -> SpamFilter {
+        SpamFilter {
+            scanner,
+            timeout: Duration::from_secs(30),
+            fail_open: false,
+            reject_score: None,
+            quarantine_score: None,
+        }
+    }
+
+    /// Scans `mail`, received in the session of `context` and queued as `queue_id`.
+    pub fn scan(&self, mail: &Mail, context: &SessionContext, queue_id: &str) -> io::Result<Scan> {
+        let data = mail.data.as_deref().unwrap_or("");
+        match &self.scanner {
+            Scanner::Rspamd(url) => self.rspamd(url, mail, context, queue_id, data),
+            Scanner::Spamd { host, port } => self.spamd(host, *port, context, data),
+        }
+    }
+
+    /// What happens to a message scanned as `scan`: `Accept` for one to deliver, tagged
+    /// or not.
+    pub fn decide(&self, scan: &Scan) -> Verdict {
+        if self.reject_score.is_some_and(|score| scan.score >= score) {
+            return Verdict::reject(554, "5.7.1 Message rejected as spam");
+        }
+        if self
+            .quarantine_score
+            .is_some_and(|score| scan.score >= score)
+        {
+            return Verdict::Quarantine(format!("spam score {:.1}", scan.score));
+        }
+        match scan.action {
+            Action::Reject => Verdict::reject(554, "5.7.1 Message rejected as spam"),
+            Action::Defer => Verdict::Defer(String::from("4.7.1 Try again later")),
+            Action::Accept | Action::Tag => Verdict::Accept,
+        }
+    }
+
+    fn rspamd(
+        &self,
+        url: &Url,
+        mail: &Mail,
+        context: &SessionContext,
+        queue_id: &str,
+        data: &str,
+    ) -> io::Result<Scan> {
+        let mut headers: Vec<(&str, String)> = Vec::new();
+        if let Some(peer) = context.peer {
+            headers.push(("IP", peer.ip().to_string()));
+        }
+        if let Some(helo) = &mail.helo {
+            headers.push(("Helo", helo.clone()));
+        }
+        if let Some(name) = &context.client_name {
+            headers.push(("Hostname", name.clone()));
+        }
+        if let Some(from) = &mail.mail_from {
+            headers.push(("From", from.clone()));
+        }
+        for rcpt in mail.rcpt_to.iter().filter(|rcpt| !rcpt.is_empty()) {
+            headers.push(("Rcpt", rcpt.clone()));
+        }
+        headers.push(("Queue-Id", String::from(queue_id)));
+        if let Some(identity) = &context.authenticated {
+            headers.push(("User", identity.clone()));
+        }
+        let response = http::post(url, &headers, data.as_bytes(), self.timeout)?;
+        if response.status != 200 {
+            return Err(io::Error::other(format!(
+                "rspamd replied {}",
+                response.status
+            )));
+        }
+        let json = Json::parse(&String::from_utf8_lossy(&response.body))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+        let number = |name: &str| json.get(name).and_then(Json::as_f64);
+        let score = number("score")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "rspamd sent no score"))?;
+        let action = match json.get("action").and_then(Json::as_str) {
+            Some("reject") => Action::Reject,
+            Some("soft reject") | Some("greylist") => Action::Defer,
+            Some("add header") | Some("rewrite subject") => Action::Tag,
+            _ => Action::Accept,
+        };
+        Ok(Scan {
+            score,
+            required: number("required_score").unwrap_or(0.0),
+            action,
+            subject: json.get("subject").and_then(Json::as_str).map(String::from),
+        })
+    }
+
+    fn spamd(
+        &self,
+        host: &str,
+        port: u16,
+        context: &SessionContext,
+        data: &str,
+    ) -> io::Result<Scan> {
+        let mut stream = connect::connect(host, port, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut request = format!("CHECK SPAMC/1.5\r\nContent-length: {}\r\n", data.len());
+        if let Some(identity) = &context.authenticated {
+            request.push_str(&format!("User: {}\r\n", identity));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(data.as_bytes())?;
+        stream.flush()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        parse_spamd(&response)
+    }
+}
+
+/// The scan in a reply of spamd to CHECK, e.g. `Spam: True ; 15.0 / 5.0`.
+fn parse_spamd(response: &str) -> io::Result<Scan> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut lines = response.lines();
+    let status = lines.next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("0") {
+        return Err(io::Error::other(format!("spamd replied {}", status)));
+    }
+    let spam = lines
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some(value.trim()).filter(|_| name.eq_ignore_ascii_case("Spam"))
+        })
+        .ok_or_else(|| invalid(String::from("spamd sent no result")))?;
+    let (flag, scores) = spam
+        .split_once(';')
+        .ok_or_else(|| invalid(format!("invalid spamd result {}", spam)))?;
+    let (score, required) = scores
+        .split_once('/')
+        .and_then(|(score, required)| {
+            Some((score.trim().parse().ok()?, required.trim().parse().ok()?))
+        })
+        .ok_or_else(|| invalid(format!("invalid spamd result {}", spam)))?;
+    let action = match flag.trim().to_lowercase().as_str() {
+        "true" | "yes" => Action::Tag,
+        _ => Action::Accept,
+    };
+    Ok(Scan {
+        score,
+        required,
+        action,
+        subject: None,
+    })
+}
+
+/// Adds the `X-Spam-*` headers of `scan` to `mail`, replacing any the sender put there,
+/// and the subject rspamd rewrote.
+pub fn tag(scan: &Scan, mail: &mut Mail) {
+    let data = match &mail.data {
+        Some(data) => data,
+        None => return,
+    };
+    let mut message = Message::parse(data);
+    let spam = scan.action == Action::Tag;
+    message.remove("X-Spam-Flag");
+    message.replace("X-Spam-Score", &format!("{:.1}", scan.score));
+    message.replace(
+        "X-Spam-Status",
+        &format!(
+            "{}, score={:.1} required={:.1}",
+            if spam { "Yes" } else { "No" },
+            scan.score,
+            scan.required
+        ),
+    );
+    if spam {
+        message.add("X-Spam-Flag", "YES");
+        if let Some(subject) = &scan.subject {
+            message.replace("Subject", subject);
+        }
+    }
+    mail.data = Some(message.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_parse_spamd() {
+        let scan = parse_spamd(
+            "SPAMD/1.1 0 EX_OK\r\nContent-length: 0\r\nSpam: True ; 15.5 / 5.0\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(scan.score, 15.5);
+        assert_eq!(scan.required, 5.0);
+        assert_eq!(scan.action, Action::Tag);
+        assert!(parse_spamd("SPAMD/1.0 76 Bad header line\r\n\r\n").is_err());
+        assert_eq!(
+            "127.0.0.1:783".parse(),
+            Ok(Scanner::Spamd {
+                host: String::from("127.0.0.1"),
+                port: 783
+            })
+        );
+    }
+
+    #[test]
+    fn test_rspamd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+            let request = http::read_request(&mut reader).unwrap();
+            let body = r#"{"score": 7.25, "required_score": 15, "action": "rewrite subject",
+                "subject": "[SPAM] hi"}"#;
+            http::write_response(&mut stream, 200, "application/json", body.as_bytes()).unwrap();
+            request
+        });
+        let mut filter = SpamFilter::new(format!("http://{}/checkv2", address).parse().unwrap());
+        filter.quarantine_score = Some(10.0);
+        let mut mail = Mail::new();
+        mail.mail_from = Some(String::from("<a@example.org>"));
+        mail.rcpt_to = vec![String::from("<b@example.com>"), String::new()];
+        mail.data = Some(String::from(
+            "Subject: hi\r\nX-Spam-Flag: NO\r\n\r\nhello\r\n",
+        ));
+        let scan = filter
+            .scan(&mail, &SessionContext::new(None, None), "4Xb2kQ9")
+            .unwrap();
+        let request = server.join().unwrap();
+        assert_eq!(request.path, "/checkv2");
+        assert_eq!(request.header("Rcpt"), Some("<b@example.com>"));
+        assert_eq!(request.header("Queue-Id"), Some("4Xb2kQ9"));
+        assert_eq!(
+            request.body,
+            b"Subject: hi\r\nX-Spam-Flag: NO\r\n\r\nhello\r\n"
+        );
+        assert_eq!(filter.decide(&scan), Verdict::Accept);
+        tag(&scan, &mut mail);
+        assert_eq!(
+            mail.data.as_deref(),
+            Some(
+                "X-Spam-Flag: YES\r\nX-Spam-Status: Yes, score=7.2 required=15.0\r\n\
+                 X-Spam-Score: 7.2\r\nSubject: [SPAM] hi\r\n\r\nhello\r\n"
+            )
+        );
+
+        let scan = Scan {
+            score: 12.0,
+            ..scan
+        };
+        assert_eq!(
+            filter.decide(&scan),
+            Verdict::Quarantine(String::from("spam score 12.0"))
+        );
+        filter.reject_score = Some(12.0);
+        assert_eq!(
+            filter.decide(&scan),
+            Verdict::reject(554, "5.7.1 Message rejected as spam")
+        );
+    }
+}
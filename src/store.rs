@@ -0,0 +1,130 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use crate::{email::Mail, output::Output, trace};
+
+#[derive(Clone)]
+pub struct StoredMail {
+    pub id: String,
+    pub mail: Mail,
+}
+
+/// Keeps accepted messages around so they can be listed, fetched and deleted later.
+pub trait MessageStore: Send + Sync {
+    fn save(&self, mail: &Mail) -> io::Result<String>;
+    fn list(&self) -> Vec<StoredMail>;
+    fn get(&self, id: &str) -> Option<StoredMail>;
+    fn delete(&self, id: &str) -> bool;
+}
+
+impl<T: MessageStore + ?Sized> MessageStore for Arc<T> {
+    fn save(&self, mail: &Mail) -> io::Result<String> {
+        (**self).save(mail)
+    }
+
+    fn list(&self) -> Vec<StoredMail> {
+        (**self).list()
+    }
+
+    fn get(&self, id: &str) -> Option<StoredMail> {
+        (**self).get(id)
+    }
+
+    fn delete(&self, id: &str) -> bool {
+        (**self).delete(id)
+    }
+}
+
+/// Wraps a store so it can sit in `Config::outputs` while also being read elsewhere.
+pub struct StoreOutput(pub Arc<dyn MessageStore>);
+
+impl Output for StoreOutput {
+    fn deliver(&self, mail: &Mail) -> io::Result<()> {
+        let queue_id = mail.queue_id.as_deref().unwrap_or("");
+        let mut span = trace::start(
+            "store.save",
+            trace::Kind::Internal,
+            &[("queue_id", &queue_id)],
+        );
+        let result = self.0.save(mail);
+        match &result {
+            Ok(id) => span.set_attribute("store.id", id),
+            Err(e) => span.set_error(e),
+        }
+        result.map(|_| ())
+    }
+}
+
+/// In-memory store holding at most `capacity` messages, dropping the oldest first.
+pub struct MemoryStore {
+    capacity: usize,
+    state: Mutex<(u64, Vec<StoredMail>)>,
+}
+
+impl MemoryStore {
+    pub fn new(capacity: usize) -> MemoryStore {
+        MemoryStore {
+            capacity,
+            state: Mutex::new((0, Vec::new())),
+        }
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn save(&self, mail: &Mail) -> io::Result<String> {
+        let mut state = self.state.lock().unwrap();
+        state.0 += 1;
+        let id = state.0.to_string();
+        state.1.push(StoredMail {
+            id: id.clone(),
+            mail: mail.clone(),
+        });
+        if state.1.len() > self.capacity {
+            state.1.remove(0);
+        }
+        Ok(id)
+    }
+
+    fn list(&self) -> Vec<StoredMail> {
+        let state = self.state.lock().unwrap();
+        state.1.clone()
+    }
+
+    fn get(&self, id: &str) -> Option<StoredMail> {
+        let state = self.state.lock().unwrap();
+        state.1.iter().find(|stored| stored.id == id).cloned()
+    }
+
+    fn delete(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.1.len();
+        state.1.retain(|stored| stored.id != id);
+        state.1.len() != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new(2);
+        let mut mail = Mail::new();
+        for data in ["one", "two", "three"].iter() {
+            mail.data = Some(String::from(*data));
+            store.save(&mail).unwrap();
+        }
+        let ids: Vec<String> = store.list().into_iter().map(|stored| stored.id).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert_eq!(
+            store.get("3").and_then(|stored| stored.mail.data),
+            Some(String::from("three"))
+        );
+        assert!(store.delete("2"));
+        assert!(!store.delete("2"));
+        assert!(store.get("2").is_none());
+    }
+}
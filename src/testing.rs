@@ -0,0 +1,291 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    sync::{mpsc, Arc},
+    thread,
+    time::Duration,
+};
+
+use crate::{config::Config, handle_connection, reply::Reply, session::SessionContext};
+
+/// How long a reply is waited for before the test fails rather than hangs.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The client side of a session for tests, of this crate and of those embedding it: sends
+/// commands and returns the replies parsed, so that tests state what they send and expect
+/// rather than handling sockets. For example:
+///
+/// ```no_run
+/// use simple_smtp::testing::TestClient;
+///
+/// let mut client = TestClient::connect("127.0.0.1:2525").unwrap();
+/// client.command("EHLO client.example.com").unwrap();
+/// let reply = client
+///     .send_mail("<a@example.com>", &["<b@example.com>"], "Subject: hi\n\nhi\n")
+///     .unwrap();
+/// assert_eq!(reply.code, 250);
+/// ```
+///
+/// Failing to read a reply within 10 seconds is an error.
+pub struct TestClient {
+    reader: Box<dyn BufRead + Send>,
+    writer: Box<dyn Write + Send>,
+    greeting: Reply,
+}
+
+impl TestClient {
+    /// Connects to a running server, e.g. a `Server` bound to port 0, and reads its
+    /// greeting.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<TestClient> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        TestClient::start(Box::new(reader), Box::new(stream))
+    }
+
+    /// Runs a session with `config` on a thread of its own, as if `peer` had connected,
+    /// without a socket. It ends when the client is dropped or quits.
+    pub fn in_memory(config: Arc<Config>, peer: Option<SocketAddr>) -> io::Result<TestClient> {
+        let (to_server, from_client) = pipe();
+        let (to_client, from_server) = pipe();
+        thread::spawn(move || {
+            let context = SessionContext::new(peer, None);
+            let reader = BufReader::new(from_client);
+            if let Err(e) = handle_connection(reader, to_client, context, config) {
+                crate::debug!("Test session failed: {}", e);
+            }
+        });
+        TestClient::start(Box::new(BufReader::new(from_server)), Box::new(to_server))
+    }
+
+    fn start(
+        mut reader: Box<dyn BufRead + Send>,
+        writer: Box<dyn Write + Send>,
+    ) -> io::Result<TestClient> {
+        let greeting = Reply::read_from(&mut reader)?;
+        Ok(TestClient {
+            reader,
+            writer,
+            greeting,
+        })
+    }
+
+    /// The reply the server greeted with, 220 when it accepts the session.
+    pub fn greeting(&self) -> &Reply {
+        &self.greeting
+    }
+
+    /// Sends `command`, without its line ending, and reads the reply.
+    pub fn command(&mut self, command: &str) -> io::Result<Reply> {
+        self.write_line(command)?;
+        self.read_reply()
+    }
+
+    /// Sends each command once the reply to the one before is read, returning the replies.
+    pub fn script(&mut self, commands: &[&str]) -> io::Result<Vec<Reply>> {
+        commands
+            .iter()
+            .map(|command| self.command(command))
+            .collect()
+    }
+
+    /// Sends all the commands at once, as clients do when the server offers PIPELINING,
+    /// then reads as many replies.
+    pub fn pipeline(&mut self, commands: &[&str]) -> io::Result<Vec<Reply>> {
+        let mut batch = String::new();
+        for command in commands {
+            batch.push_str(command);
+            batch.push_str("\r\n");
+        }
+        self.write_raw(batch.as_bytes())?;
+        commands.iter().map(|_| self.read_reply()).collect()
+    }
+
+    /// Sends a message in one transaction, returning the first reply refusing it, or the
+    /// reply to its end. `message` may have bare LF line endings; it is dot-stuffed and
+    /// terminated here.
+    pub fn send_mail(&mut self, from: &str, to: &[&str], message: &str) -> io::Result<Reply> {
+        let mail = self.command(&format!("MAIL FROM:{}", from))?;
+        if !mail.is_positive() {
+            return Ok(mail);
+        }
+        let mut accepted = 0;
+        let mut refused = None;
+        for rcpt in to {
+            let reply = self.command(&format!("RCPT TO:{}", rcpt))?;
+            if reply.is_positive() {
+                accepted += 1;
+            } else {
+                refused.get_or_insert(reply);
+            }
+        }
+        if accepted == 0 {
+            let reply = refused.unwrap_or_else(|| Reply::new(554, "No recipients given"));
+            self.command("RSET")?;
+            return Ok(reply);
+        }
+        let data = self.command("DATA")?;
+        if data.code != 354 {
+            return Ok(data);
+        }
+        self.send_data(message)
+    }
+
+    /// Sends the content of a message after DATA was accepted, and reads the reply.
+    pub fn send_data(&mut self, message: &str) -> io::Result<Reply> {
+        let mut data = String::new();
+        for line in message.lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        self.write_raw(data.as_bytes())?;
+        self.read_reply()
+    }
+
+    /// Sends QUIT and reads the reply, ending the session.
+    pub fn quit(mut self) -> io::Result<Reply> {
+        self.command("QUIT")
+    }
+
+    /// Sends `bytes` as they are, e.g. a malformed command, reading no reply.
+    pub fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.writer.flush()
+    }
+
+    /// Reads the next reply, e.g. after `write_raw`.
+    pub fn read_reply(&mut self) -> io::Result<Reply> {
+        Reply::read_from(&mut self.reader)
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_raw(format!("{}\r\n", line).as_bytes())
+    }
+}
+
+/// An in-memory byte stream from `PipeWriter` to `PipeReader`, which reads the end once the
+/// writer is dropped.
+fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = mpsc::channel();
+    let reader = PipeReader {
+        receiver,
+        chunk: Vec::new(),
+        position: 0,
+    };
+    (PipeWriter(sender), reader)
+}
+
+struct PipeWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "session ended"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct PipeReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.receiver.recv_timeout(TIMEOUT) {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply"))
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.chunk.len() - self.position);
+        buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Server;
+
+    #[test]
+    fn test_in_memory() {
+        let (sender, receiver) = mpsc::channel();
+        let config = Config {
+            incoming: vec![sender],
+            ..Config::default()
+        };
+        let peer = "192.0.2.1:2525".parse().unwrap();
+        let mut client = TestClient::in_memory(Arc::new(config), Some(peer)).unwrap();
+        assert_eq!(client.greeting().code, 220);
+
+        let replies = client.script(&["EHLO client", "RSET"]).unwrap();
+        assert!(replies[0].lines.contains(&String::from("DSN")));
+        assert_eq!(replies[1].code, 250);
+        let reply = client
+            .send_mail(
+                "<a@example.com>",
+                &["<b@example.com>"],
+                "Subject: hi\n\nhi\n",
+            )
+            .unwrap();
+        assert_eq!(reply.code, 250);
+        let received = receiver.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(received.peer, Some(peer));
+        assert!(received
+            .mail
+            .data
+            .unwrap()
+            .ends_with("\r\nSubject: hi\r\n\r\nhi\r\n"));
+
+        client.write_raw(b"BOGUS\r\n").unwrap();
+        assert_eq!(client.read_reply().unwrap().code, 500);
+        assert_eq!(client.quit().unwrap().code, 221);
+    }
+
+    #[test]
+    fn test_connect() {
+        let server = Server::bind("127.0.0.1:0", Config::default()).unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut client = TestClient::connect(address).unwrap();
+        client.command("HELO client").unwrap();
+        let replies = client
+            .pipeline(&[
+                "MAIL FROM:<a@example.com>",
+                "RCPT TO:<b@example.com>",
+                "DATA",
+            ])
+            .unwrap();
+        let codes: Vec<u16> = replies.iter().map(|reply| reply.code).collect();
+        assert_eq!(codes, [250, 250, 354]);
+        assert_eq!(client.send_data("Subject: hi\n").unwrap().code, 250);
+
+        // A transaction is under way already.
+        client.command("MAIL FROM:<a@example.com>").unwrap();
+        let reply = client
+            .send_mail("<a@example.com>", &["<b@example.com>"], "")
+            .unwrap();
+        assert_eq!(reply.code, 500);
+        assert_eq!(client.quit().unwrap().code, 221);
+    }
+}
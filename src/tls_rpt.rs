@@ -0,0 +1,457 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{clock, datetime, email::Mail, json::Json, output::Output};
+
+/// The policy a session was checked against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PolicyType {
+    Sts,
+    Tlsa,
+    NoPolicyFound,
+}
+
+impl PolicyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PolicyType::Sts => "sts",
+            PolicyType::Tlsa => "tlsa",
+            PolicyType::NoPolicyFound => "no-policy-found",
+        }
+    }
+}
+
+/// Why a session failed, as RFC 8460 names the result types.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResultType {
+    StarttlsNotSupported,
+    CertificateHostMismatch,
+    CertificateExpired,
+    CertificateNotTrusted,
+    ValidationFailure,
+    TlsaInvalid,
+    DnssecInvalid,
+    DaneRequired,
+    StsPolicyFetchError,
+    StsPolicyInvalid,
+    StsWebpkiInvalid,
+}
+
+impl ResultType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResultType::StarttlsNotSupported => "starttls-not-supported",
+            ResultType::CertificateHostMismatch => "certificate-host-mismatch",
+            ResultType::CertificateExpired => "certificate-expired",
+            ResultType::CertificateNotTrusted => "certificate-not-trusted",
+            ResultType::ValidationFailure => "validation-failure",
+            ResultType::TlsaInvalid => "tlsa-invalid",
+            ResultType::DnssecInvalid => "dnssec-invalid",
+            ResultType::DaneRequired => "dane-required",
+            ResultType::StsPolicyFetchError => "sts-policy-fetch-error",
+            ResultType::StsPolicyInvalid => "sts-policy-invalid",
+            ResultType::StsWebpkiInvalid => "sts-webpki-invalid",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    successes: i64,
+    /// Failed sessions by result type and receiving MX.
+    failures: HashMap<(ResultType, String), i64>,
+}
+
+struct Unsent {
+    domain: String,
+    report: Json,
+    /// The addresses it is still to be sent to, `None` before they were looked up.
+    addresses: Option<Vec<String>>,
+}
+
+/// The reporting addresses in the `_smtp._tls.<domain>` TXT record, e.g.
+/// `v=TLSRPTv1; rua=mailto:tlsrpt@example.com`.
+pub fn record_addresses(txt: &str) -> Vec<String> {
+    let mut fields = txt.split(';').map(str::trim);
+    if fields.next() != Some("v=TLSRPTv1") {
+        return Vec::new();
+    }
+    fields
+        .filter_map(|field| field.strip_prefix("rua="))
+        .flat_map(|rua| rua.split(','))
+        .map(|address| String::from(address.trim()))
+        .filter(|address| !address.is_empty())
+        .collect()
+}
+
+/// Looks up the TXT record of `_smtp._tls.<domain>`, `None` if there is none.
+pub trait ReportAddressLookup: Send + Sync {
+    fn txt_record(&self, domain: &str) -> io::Result<Option<String>>;
+}
+
+impl<F> ReportAddressLookup for F
+where
+    F: Fn(&str) -> io::Result<Option<String>> + Send + Sync,
+{
+    fn txt_record(&self, domain: &str) -> io::Result<Option<String>> {
+        self(domain)
+    }
+}
+
+/// Counts outbound TLS sessions per recipient domain and policy and turns them into the
+/// aggregate reports of RFC 8460.
+pub struct TlsReports {
+    pub organization: String,
+    pub contact: String,
+    start: Mutex<SystemTime>,
+    summaries: Mutex<HashMap<(String, PolicyType), Summary>>,
+    /// Reports `send` could not deliver, to try again.
+    unsent: Mutex<Vec<Unsent>>,
+}
+
+impl TlsReports {
+    pub fn new(organization: &str, contact: &str) -> TlsReports {
+        TlsReports {
+            organization: String::from(organization),
+            contact: String::from(contact),
+            start: Mutex::new(clock::now()),
+            summaries: Mutex::new(HashMap::new()),
+            unsent: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn success(&self, domain: &str, policy: PolicyType) {
+        let mut summaries = self.summaries.lock().unwrap();
+        summaries
+            .entry((domain.to_lowercase(), policy))
+            .or_default()
+            .successes += 1;
+    }
+
+    pub fn failure(&self, domain: &str, policy: PolicyType, result: ResultType, mx: &str) {
+        let mut summaries = self.summaries.lock().unwrap();
+        *summaries
+            .entry((domain.to_lowercase(), policy))
+            .or_default()
+            .failures
+            .entry((result, String::from(mx)))
+            .or_insert(0) += 1;
+    }
+
+    /// The reports for the sessions since the last call, by domain, starting a new period.
+    pub fn take(&self) -> Vec<(String, Json)> {
+        let end = clock::now();
+        let start = std::mem::replace(&mut *self.start.lock().unwrap(), end);
+        let summaries = std::mem::take(&mut *self.summaries.lock().unwrap());
+        let mut policies: HashMap<String, Vec<Json>> = HashMap::new();
+        for ((domain, policy), summary) in summaries {
+            let mut failures: Vec<_> = summary.failures.into_iter().collect();
+            failures.sort_by(|a, b| a.0 .1.cmp(&b.0 .1));
+            let failed: i64 = failures.iter().map(|(_, count)| count).sum();
+            let details = failures
+                .into_iter()
+                .map(|((result, mx), count)| {
+                    Json::object(vec![
+                        ("result-type", Json::string(result.as_str())),
+                        ("receiving-mx-hostname", Json::string(&mx)),
+                        ("failed-session-count", Json::Number(count)),
+                    ])
+                })
+                .collect();
+            let entry = Json::object(vec![
+                (
+                    "policy",
+                    Json::object(vec![
+                        ("policy-type", Json::string(policy.as_str())),
+                        ("policy-domain", Json::string(&domain)),
+                    ]),
+                ),
+                (
+                    "summary",
+                    Json::object(vec![
+                        (
+                            "total-successful-session-count",
+                            Json::Number(summary.successes),
+                        ),
+                        ("total-failure-session-count", Json::Number(failed)),
+                    ]),
+                ),
+                ("failure-details", Json::Array(details)),
+            ]);
+            policies.entry(domain).or_default().push(entry);
+        }
+        let mut reports: Vec<(String, Json)> = policies
+            .into_iter()
+            .map(|(domain, policies)| {
+                let report = Json::object(vec![
+                    ("organization-name", Json::string(&self.organization)),
+                    (
+                        "date-range",
+                        Json::object(vec![
+                            ("start-datetime", Json::string(&datetime::rfc3339(start))),
+                            ("end-datetime", Json::string(&datetime::rfc3339(end))),
+                        ]),
+                    ),
+                    ("contact-info", Json::string(&self.contact)),
+                    ("report-id", Json::string(&report_id(end, &domain))),
+                    ("policies", Json::Array(policies)),
+                ]);
+                (domain, report)
+            })
+            .collect();
+        reports.sort_by(|a, b| a.0.cmp(&b.0));
+        reports
+    }
+
+    /// Takes the reports and mails each to the `mailto:` addresses its domain publishes
+    /// through `output`, returning how many were sent. `https:` addresses are skipped as
+    /// the crate has no TLS. A domain that publishes no address gets no report. Reports
+    /// that fail to go out are kept for the next call, with the first error returned once
+    /// the others were tried.
+    pub fn send(
+        &self,
+        server_name: &str,
+        lookup: &dyn ReportAddressLookup,
+        output: &dyn Output,
+    ) -> io::Result<usize> {
+        let mut pending = std::mem::take(&mut *self.unsent.lock().unwrap());
+        pending.extend(self.take().into_iter().map(|(domain, report)| Unsent {
+            domain,
+            report,
+            addresses: None,
+        }));
+        let mut sent = 0;
+        let mut failure = None;
+        let mut unsent = Vec::new();
+        for Unsent {
+            domain,
+            report,
+            addresses,
+        } in pending
+        {
+            let addresses = match addresses {
+                Some(addresses) => addresses,
+                None => match lookup.txt_record(&domain) {
+                    Ok(txt) => record_addresses(txt.as_deref().unwrap_or_default()),
+                    Err(e) => {
+                        failure.get_or_insert(e);
+                        unsent.push(Unsent {
+                            domain,
+                            report,
+                            addresses: None,
+                        });
+                        continue;
+                    }
+                },
+            };
+            let mut remaining = Vec::new();
+            for address in addresses {
+                if let Some(mailto) = address.strip_prefix("mailto:") {
+                    let mail = report_mail(server_name, &self.contact, &domain, &report, mailto);
+                    match output.deliver(&mail) {
+                        Ok(()) => sent += 1,
+                        Err(e) => {
+                            failure.get_or_insert(e);
+                            remaining.push(address);
+                        }
+                    }
+                }
+            }
+            if !remaining.is_empty() {
+                unsent.push(Unsent {
+                    domain,
+                    report,
+                    addresses: Some(remaining),
+                });
+            }
+        }
+        self.unsent.lock().unwrap().extend(unsent);
+        match failure {
+            Some(error) => Err(error),
+            None => Ok(sent),
+        }
+    }
+
+    /// Sends the reports every `interval`, a day as RFC 8460 suggests, from a background
+    /// thread, logging what fails. See `send`.
+    pub fn send_every(
+        self: Arc<Self>,
+        interval: Duration,
+        server_name: &str,
+        lookup: Arc<dyn ReportAddressLookup>,
+        output: Arc<dyn Output>,
+    ) {
+        let server_name = String::from(server_name);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match self.send(&server_name, &*lookup, &*output) {
+                Ok(sent) => crate::debug!("Sent {} TLS reports", sent),
+                Err(e) => crate::warn!("TLS reports not sent: {}", e),
+            }
+        });
+    }
+}
+
+fn report_id(end: SystemTime, domain: &str) -> String {
+    let seconds = end.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{}-{}", seconds, domain)
+}
+
+/// The message carrying `report` about `domain` to `address`, with the JSON attached
+/// uncompressed as `application/tlsrpt+json`.
+pub fn report_mail(
+    server_name: &str,
+    contact: &str,
+    domain: &str,
+    report: &Json,
+    address: &str,
+) -> Mail {
+    let now = clock::now();
+    let id = report
+        .get("report-id")
+        .and_then(Json::as_str)
+        .unwrap_or("")
+        .to_string();
+    let boundary = format!("{}/{}", id, server_name);
+    let mut data = String::new();
+    data.push_str(&format!("From: <{}>\r\n", contact));
+    data.push_str(&format!("To: <{}>\r\n", address));
+    data.push_str(&format!(
+        "Subject: Report Domain: {} Submitter: {} Report-ID: <{}>\r\n",
+        domain, server_name, id
+    ));
+    data.push_str(&format!("Date: {}\r\n", datetime::rfc5322(now)));
+    data.push_str("TLS-Report-Domain: ");
+    data.push_str(domain);
+    data.push_str(&format!("\r\nTLS-Report-Submitter: {}\r\n", server_name));
+    data.push_str("MIME-Version: 1.0\r\n");
+    data.push_str(&format!(
+        "Content-Type: multipart/report; report-type=tlsrpt; boundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+    data.push_str(&format!(
+        "--{}\r\nContent-Type: text/plain; charset=us-ascii\r\n\r\n\
+         This is an aggregate TLS report from {}.\r\n",
+        boundary, server_name
+    ));
+    data.push_str(&format!(
+        "\r\n--{}\r\nContent-Type: application/tlsrpt+json\r\n\
+         Content-Disposition: attachment; filename=\"{}!{}!{}.json\"\r\n\r\n{}\r\n",
+        boundary,
+        server_name,
+        domain,
+        datetime::unix_seconds(now),
+        report
+    ));
+    data.push_str(&format!("\r\n--{}--\r\n", boundary));
+
+    let mut mail = Mail::new();
+    mail.helo = Some(String::from(server_name));
+    mail.mail_from = Some(format!("<{}>", contact));
+    mail.rcpt_to = vec![format!("<{}>", address)];
+    mail.data = Some(data);
+    mail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_addresses() {
+        assert_eq!(
+            record_addresses("v=TLSRPTv1; rua=mailto:a@example.com, https://r.example.com/"),
+            vec!["mailto:a@example.com", "https://r.example.com/"]
+        );
+        assert!(record_addresses("v=spf1 -all").is_empty());
+    }
+
+    struct Sent(Mutex<Vec<Mail>>);
+
+    impl Output for Sent {
+        fn deliver(&self, mail: &Mail) -> io::Result<()> {
+            self.0.lock().unwrap().push(mail.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send() {
+        let reports = TlsReports::new("Example", "postmaster@mx.example.net");
+        reports.success("Example.com", PolicyType::Sts);
+        reports.failure(
+            "example.com",
+            PolicyType::Sts,
+            ResultType::ValidationFailure,
+            "mx.example.com",
+        );
+        reports.failure(
+            "example.com",
+            PolicyType::Sts,
+            ResultType::ValidationFailure,
+            "mx.example.com",
+        );
+        reports.success("example.org", PolicyType::NoPolicyFound);
+
+        let lookup = |domain: &str| -> io::Result<Option<String>> {
+            Ok(
+                Some(String::from("v=TLSRPTv1; rua=mailto:tlsrpt@example.com"))
+                    .filter(|_| domain == "example.com"),
+            )
+        };
+        let output = Sent(Mutex::new(Vec::new()));
+        assert_eq!(reports.send("mx.example.net", &lookup, &output).unwrap(), 1);
+        let sent = output.0.into_inner().unwrap();
+        assert_eq!(sent[0].rcpt_to, vec!["<tlsrpt@example.com>"]);
+        let data = sent[0].data.as_deref().unwrap();
+        assert!(data.contains("TLS-Report-Domain: example.com\r\n"));
+        assert!(data.contains("\"total-successful-session-count\":1"));
+        assert!(data.contains("\"total-failure-session-count\":2"));
+        assert!(data.contains("\"result-type\":\"validation-failure\""));
+        assert!(reports.take().is_empty());
+    }
+
+    struct Down;
+
+    impl Output for Down {
+        fn deliver(&self, _: &Mail) -> io::Result<()> {
+            Err(io::Error::other("relay replied 451"))
+        }
+    }
+
+    #[test]
+    fn test_send_again() {
+        let reports = TlsReports::new("Example", "postmaster@mx.example.net");
+        reports.success("example.com", PolicyType::NoPolicyFound);
+        reports.success("example.org", PolicyType::NoPolicyFound);
+        let lookup = |domain: &str| -> io::Result<Option<String>> {
+            if domain == "example.org" {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            Ok(Some(String::from(
+                "v=TLSRPTv1; rua=mailto:tlsrpt@example.com",
+            )))
+        };
+        assert!(reports.send("mx.example.net", &lookup, &Down).is_err());
+
+        let lookup = |_: &str| -> io::Result<Option<String>> {
+            Ok(Some(String::from(
+                "v=TLSRPTv1; rua=mailto:tlsrpt@example.com",
+            )))
+        };
+        let output = Sent(Mutex::new(Vec::new()));
+        assert_eq!(reports.send("mx.example.net", &lookup, &output).unwrap(), 2);
+        let sent = output.0.into_inner().unwrap();
+        assert!(sent.iter().any(|mail| mail
+            .data
+            .as_deref()
+            .unwrap()
+            .contains("Domain: example.org")));
+        assert_eq!(reports.send("mx.example.net", &lookup, &Down).unwrap(), 0);
+    }
+}
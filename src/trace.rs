@@ -0,0 +1,305 @@
+// Without the `server` feature spans are never exported, leaving the encoding unused.
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+
+use std::{
+    cell::RefCell,
+    collections::hash_map::RandomState,
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "server")]
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Instant,
+};
+
+#[cfg(feature = "server")]
+use crate::http::{self, Url};
+use crate::{digest::to_hex, json::Json};
+
+/// Environment variable with the OTLP/HTTP collector base URL, e.g. `http://localhost:4318`.
+pub const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+
+const BATCH_SIZE: usize = 256;
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+struct Finished {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    kind: Kind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+static EXPORTER: OnceLock<Mutex<Sender<Finished>>> = OnceLock::new();
+
+thread_local! {
+    static CURRENT: RefCell<Vec<([u8; 16], [u8; 8])>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A number no one else can guess, hashed with the keys `RandomState` draws from the
+/// operating system, e.g. for trace and DNS query IDs.
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// A span that is exported when dropped. Spans started while it is alive on the same
+/// thread become its children. Without an exporter every operation is a no-op.
+pub struct Span {
+    inner: Option<Finished>,
+}
+
+impl Span {
+    pub fn set_attribute(&mut self, key: &'static str, value: &dyn Display) {
+        if let Some(span) = &mut self.inner {
+            span.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn set_error(&mut self, message: &dyn Display) {
+        if let Some(span) = &mut self.inner {
+            span.error = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.inner.take() {
+            CURRENT.with(|current| current.borrow_mut().retain(|(_, id)| *id != span.span_id));
+            span.end = crate::clock::now();
+            if let Some(exporter) = EXPORTER.get() {
+                let _ = exporter.lock().unwrap().send(span);
+            }
+        }
+    }
+}
+
+pub fn start(name: &'static str, kind: Kind, attributes: &[(&'static str, &dyn Display)]) -> Span {
+    if EXPORTER.get().is_none() {
+        return Span { inner: None };
+    }
+    let parent = CURRENT.with(|current| current.borrow().last().copied());
+    let trace_id = match parent {
+        Some((trace_id, _)) => trace_id,
+        None => {
+            let mut trace_id = [0u8; 16];
+            trace_id[..8].copy_from_slice(&random_u64().to_be_bytes());
+            trace_id[8..].copy_from_slice(&random_u64().to_be_bytes());
+            trace_id
+        }
+    };
+    let span_id = random_u64().to_be_bytes();
+    CURRENT.with(|current| current.borrow_mut().push((trace_id, span_id)));
+    let now = crate::clock::now();
+    Span {
+        inner: Some(Finished {
+            trace_id,
+            span_id,
+            parent_id: parent.map(|(_, id)| id),
+            name,
+            kind,
+            start: now,
+            end: now,
+            attributes: attributes
+                .iter()
+                .map(|(key, value)| (*key, value.to_string()))
+                .collect(),
+            error: None,
+        }),
+    }
+}
+
+/// Starts exporting spans to `endpoint` (the collector base URL) from a background thread.
+/// Only the first call has an effect.
+#[cfg(feature = "server")]
+pub fn init(endpoint: &str, service_name: &str) -> std::io::Result<()> {
+    let url = Url::parse(&format!("{}/v1/traces", endpoint.trim_end_matches('/')))?;
+    let service_name = String::from(service_name);
+    let (sender, receiver) = mpsc::channel::<Finished>();
+    if EXPORTER.set(Mutex::new(sender)).is_err() {
+        return Ok(());
+    }
+    thread::spawn(move || {
+        let mut batch = Vec::new();
+        let mut deadline = Instant::now() + BATCH_INTERVAL;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let disconnected = match receiver.recv_timeout(timeout) {
+                Ok(span) => {
+                    batch.push(span);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+            if !batch.is_empty()
+                && (batch.len() >= BATCH_SIZE || Instant::now() >= deadline || disconnected)
+            {
+                let body = export_json(&service_name, &batch).to_string();
+                let headers = [("Content-Type", String::from("application/json"))];
+                match http::post(&url, &headers, body.as_bytes(), EXPORT_TIMEOUT) {
+                    Ok(response) if response.status < 300 => {}
+                    Ok(response) => {
+                        crate::warn!("Trace export rejected with status {}", response.status)
+                    }
+                    Err(e) => crate::warn!("Trace export failed: {}", e),
+                }
+                batch.clear();
+            }
+            if Instant::now() >= deadline {
+                deadline = Instant::now() + BATCH_INTERVAL;
+            }
+            if disconnected {
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Enables export when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+#[cfg(feature = "server")]
+pub fn init_from_env() {
+    if let Ok(endpoint) = std::env::var(ENDPOINT_ENV) {
+        let service_name =
+            std::env::var(SERVICE_NAME_ENV).unwrap_or_else(|_| String::from("simple-smtp"));
+        if let Err(e) = init(&endpoint, &service_name) {
+            eprintln!("Ignoring {}: {}", ENDPOINT_ENV, e);
+        }
+    }
+}
+
+fn nanos(time: SystemTime) -> Json {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // 64-bit integers are strings in the OTLP JSON encoding.
+    Json::String(nanos.to_string())
+}
+
+fn span_json(span: &Finished) -> Json {
+    let mut fields = vec![
+        ("traceId", Json::String(to_hex(&span.trace_id))),
+        ("spanId", Json::String(to_hex(&span.span_id))),
+    ];
+    if let Some(parent_id) = &span.parent_id {
+        fields.push(("parentSpanId", Json::String(to_hex(parent_id))));
+    }
+    fields.extend(vec![
+        ("name", Json::string(span.name)),
+        ("kind", Json::Number(span.kind as i64)),
+        ("startTimeUnixNano", nanos(span.start)),
+        ("endTimeUnixNano", nanos(span.end)),
+        (
+            "attributes",
+            Json::Array(
+                span.attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect(),
+            ),
+        ),
+    ]);
+    if let Some(message) = &span.error {
+        fields.push((
+            "status",
+            Json::object(vec![
+                ("code", Json::Number(2)),
+                ("message", Json::string(message)),
+            ]),
+        ));
+    }
+    Json::object(fields)
+}
+
+fn attribute(key: &str, value: &str) -> Json {
+    Json::object(vec![
+        ("key", Json::string(key)),
+        (
+            "value",
+            Json::object(vec![("stringValue", Json::string(value))]),
+        ),
+    ])
+}
+
+fn export_json(service_name: &str, spans: &[Finished]) -> Json {
+    Json::object(vec![(
+        "resourceSpans",
+        Json::Array(vec![Json::object(vec![
+            (
+                "resource",
+                Json::object(vec![(
+                    "attributes",
+                    Json::Array(vec![attribute("service.name", service_name)]),
+                )]),
+            ),
+            (
+                "scopeSpans",
+                Json::Array(vec![Json::object(vec![
+                    (
+                        "scope",
+                        Json::object(vec![("name", Json::string("simple-smtp"))]),
+                    ),
+                    ("spans", Json::Array(spans.iter().map(span_json).collect())),
+                ])]),
+            ),
+        ])]),
+    )])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_json() {
+        let span = Finished {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            parent_id: Some([3; 8]),
+            name: "transaction",
+            kind: Kind::Server,
+            start: UNIX_EPOCH + Duration::from_millis(1500),
+            end: UNIX_EPOCH + Duration::from_secs(2),
+            attributes: vec![("queue_id", String::from("ABC"))],
+            error: Some(String::from("Output failed")),
+        };
+        let json = export_json("mx", &[span]);
+        let resource = &json.get("resourceSpans").unwrap().as_array().unwrap()[0];
+        let scope = &resource.get("scopeSpans").unwrap().as_array().unwrap()[0];
+        let span = &scope.get("spans").unwrap().as_array().unwrap()[0];
+        assert_eq!(
+            span.to_string(),
+            "{\"traceId\":\"01010101010101010101010101010101\",\"spanId\":\"0202020202020202\",\
+             \"parentSpanId\":\"0303030303030303\",\"name\":\"transaction\",\"kind\":2,\
+             \"startTimeUnixNano\":\"1500000000\",\"endTimeUnixNano\":\"2000000000\",\
+             \"attributes\":[{\"key\":\"queue_id\",\"value\":{\"stringValue\":\"ABC\"}}],\
+             \"status\":{\"code\":2,\"message\":\"Output failed\"}}"
+        );
+    }
+}
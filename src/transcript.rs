@@ -0,0 +1,230 @@
+use core::net::IpAddr;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+pub enum TranscriptSink {
+    /// Each line is logged at debug level within the session span.
+    Log,
+    /// Each session is written to `<directory>/<session id>.log`.
+    Directory(PathBuf),
+}
+
+/// Decides which sessions get a protocol transcript. Peers can be added and removed while
+/// the server runs, e.g. through the HTTP API.
+pub struct Transcripts {
+    all: AtomicBool,
+    peers: Mutex<Vec<IpAddr>>,
+    pub sink: TranscriptSink,
+    /// Bytes of each DATA body kept in the transcript; the rest is elided.
+    pub max_data: usize,
+}
+
+impl Transcripts {
+    pub fn new(sink: TranscriptSink, max_data: usize) -> Transcripts {
+        Transcripts {
+            all: AtomicBool::new(false),
+            peers: Mutex::new(Vec::new()),
+            sink,
+            max_data,
+        }
+    }
+
+    pub fn set_all(&self, enabled: bool) {
+        self.all.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enable(&self, peer: IpAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+
+    /// Returns whether the peer had been enabled.
+    pub fn disable(&self, peer: IpAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let before = peers.len();
+        peers.retain(|p| *p != peer);
+        peers.len() != before
+    }
+
+    pub fn peers(&self) -> Vec<IpAddr> {
+        self.peers.lock().unwrap().clone()
+    }
+
+    pub fn is_enabled(&self, peer: IpAddr) -> bool {
+        self.all.load(Ordering::Relaxed) || self.peers.lock().unwrap().contains(&peer)
+    }
+
+    /// Starts a transcript for a new session if its peer is selected.
+    pub fn start(&self, peer: Option<IpAddr>, session_id: &str) -> Option<Transcript> {
+        if !peer.is_some_and(|peer| self.is_enabled(peer)) {
+            return None;
+        }
+        let file = match &self.sink {
+            TranscriptSink::Log => None,
+            TranscriptSink::Directory(directory) => {
+                match File::create(directory.join(format!("{}.log", session_id))) {
+                    Ok(file) => Some(file),
+                    Err(e) => {
+                        crate::warn!("Unable to create transcript: {}", e);
+                        return None;
+                    }
+                }
+            }
+        };
+        Some(Transcript {
+            file,
+            max_data: self.max_data,
+            in_data: false,
+            in_auth: false,
+            data_size: 0,
+        })
+    }
+}
+
+impl Default for Transcripts {
+    fn default() -> Transcripts {
+        Transcripts::new(TranscriptSink::Log, 0)
+    }
+}
+
+/// Records the lines of one session, `C:` for the client and `S:` for the server.
+pub struct Transcript {
+    file: Option<File>,
+    max_data: usize,
+    in_data: bool,
+    data_size: usize,
+    /// Set by a 334 reply to AUTH, whose answer holds credentials.
+    in_auth: bool,
+}
+
+impl Transcript {
+    fn write(&mut self, prefix: &str, line: &str) {
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        match &mut self.file {
+            Some(file) => {
+                if let Err(e) = writeln!(file, "{} {}", prefix, line) {
+                    crate::warn!("Unable to write transcript: {}", e);
+                    self.file = None;
+                }
+            }
+            None => crate::debug!("{} {}", prefix, line),
+        }
+    }
+
+    /// Records a line of the client, with the credentials of AUTH hidden.
+    pub fn client(&mut self, line: &str) {
+        if self.in_auth {
+            self.in_auth = false;
+            self.write("C:", "***");
+            return;
+        }
+        if !self.in_data {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                [verb, mechanism, _] if verb.eq_ignore_ascii_case("AUTH") => {
+                    self.write("C:", &format!("{} {} ***", verb, mechanism))
+                }
+                _ => self.write("C:", line),
+            }
+            return;
+        }
+        if line.trim_end() == "." {
+            if self.data_size > self.max_data {
+                let elided = format!("[{} bytes elided]", self.data_size - self.max_data);
+                self.write("C:", &elided);
+            }
+            self.write("C:", line);
+            self.in_data = false;
+            return;
+        }
+        let kept = self.max_data.saturating_sub(self.data_size);
+        self.data_size += line.len();
+        if kept >= line.len() {
+            self.write("C:", line);
+        } else if kept > 0 {
+            let mut end = kept;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.write("C:", &line[..end]);
+        }
+    }
+
+    pub fn server(&mut self, reply: &str) {
+        for line in reply.lines() {
+            self.write("S:", line);
+        }
+        if reply.starts_with("354") {
+            self.in_data = true;
+            self.data_size = 0;
+        }
+        self.in_auth = reply.starts_with("334");
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript() {
+        let directory = std::env::temp_dir().join(format!("simple-smtp-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let transcripts = Transcripts::new(TranscriptSink::Directory(directory.clone()), 8);
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(transcripts.start(Some(peer), "00000001").is_none());
+        transcripts.enable(peer);
+
+        let mut transcript = transcripts.start(Some(peer), "00000001").unwrap();
+        transcript.client("DATA\r\n");
+        transcript.server("354 End data with <CR><LF>.<CR><LF>\n");
+        transcript.client("Subject: hi\r\n");
+        transcript.client("\r\n");
+        transcript.client(".\r\n");
+        transcript.server("250 Ok\n");
+        transcript.flush().unwrap();
+
+        let path = directory.join("00000001.log");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "C: DATA\nS: 354 End data with <CR><LF>.<CR><LF>\nC: Subject:\n\
+             C: [7 bytes elided]\nC: .\nS: 250 Ok\n"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut transcript = transcripts.start(Some(peer), "00000002").unwrap();
+        transcript.client("AUTH PLAIN AGFsaWNlAHMzY3JldA==\r\n");
+        transcript.server("535 5.7.8 Authentication credentials invalid\r\n");
+        transcript.client("AUTH PLAIN\r\n");
+        transcript.server("334 \r\n");
+        transcript.client("AGFsaWNlAHMzY3JldA==\r\n");
+        transcript.server("235 2.7.0 Authentication successful\r\n");
+        transcript.flush().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(directory.join("00000002.log")).unwrap(),
+            "C: AUTH PLAIN ***\nS: 535 5.7.8 Authentication credentials invalid\n\
+             C: AUTH PLAIN\nS: 334 \nC: ***\nS: 235 2.7.0 Authentication successful\n"
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert!(transcripts.disable(peer));
+        assert!(!transcripts.is_enabled(peer));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+#[cfg(feature = "server")]
+use crate::output::{pipe::Pipe, relay::Relay};
+use crate::{
+    email::{domain_of, Mail},
+    output::Output,
+};
+
+/// What happens to the recipients of a domain once a message is accepted.
+pub enum Transport {
+    /// Handed to the configured outputs, e.g. the message store.
+    Local,
+    #[cfg(feature = "server")]
+    Relay(Box<Relay>),
+    #[cfg(feature = "server")]
+    Pipe(Pipe),
+    /// Accepted and dropped.
+    Discard,
+}
+
+impl Transport {
+    /// The outputs the message goes to, `local` being the configured ones.
+    pub fn outputs<'a>(&'a self, local: &'a [Box<dyn Output>]) -> Vec<&'a dyn Output> {
+        match self {
+            Transport::Local => local.iter().map(|output| output.as_ref()).collect(),
+            #[cfg(feature = "server")]
+            Transport::Relay(relay) => vec![relay.as_ref()],
+            #[cfg(feature = "server")]
+            Transport::Pipe(pipe) => vec![pipe],
+            Transport::Discard => Vec::new(),
+        }
+    }
+}
+
+static LOCAL: Transport = Transport::Local;
+
+/// The transport of every recipient domain. Domains not listed use the `*` entry, or are
+/// local when there is none.
+#[derive(Default)]
+pub struct Transports {
+    transports: HashMap<String, Transport>,
+}
+
+impl Transports {
+    pub fn add(&mut self, domain: &str, transport: Transport) {
+        self.transports.insert(domain.to_lowercase(), transport);
+    }
+
+    /// The commands of the pipe transports.
+    #[cfg(feature = "server")]
+    pub fn pipes(&self) -> impl Iterator<Item = &Pipe> {
+        self.transports
+            .values()
+            .filter_map(|transport| match transport {
+                Transport::Pipe(pipe) => Some(pipe),
+                _ => None,
+            })
+    }
+
+    pub fn get(&self, domain: Option<&str>) -> &Transport {
+        domain
+            .and_then(|domain| self.transports.get(&domain.to_lowercase()))
+            .or_else(|| self.transports.get("*"))
+            .unwrap_or(&LOCAL)
+    }
+
+    /// Splits the recipients of `mail` by transport, each with a copy of the message
+    /// addressed to its recipients only.
+    pub fn route(&self, mail: &Mail) -> Vec<(&Transport, Mail)> {
+        let mut routes: Vec<(&Transport, Mail)> = Vec::new();
+        for rcpt in mail.rcpt_to.iter() {
+            let transport = self.get(domain_of(rcpt));
+            match routes
+                .iter_mut()
+                .find(|(known, _)| std::ptr::eq(*known, transport))
+            {
+                Some((_, routed)) => routed.rcpt_to.push(rcpt.clone()),
+                None => routes.push((
+                    transport,
+                    Mail {
+                        rcpt_to: vec![rcpt.clone()],
+                        ..mail.clone()
+                    },
+                )),
+            }
+        }
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        let mut transports = Transports::default();
+        transports.add("example.org", Transport::Discard);
+        let mut mail = Mail::new();
+        mail.rcpt_to = vec![
+            String::from("<a@example.com>"),
+            String::from("<b@Example.org>"),
+            String::from("<c@example.com>"),
+        ];
+        let routes = transports.route(&mail);
+        assert_eq!(routes.len(), 2);
+        assert!(matches!(routes[0].0, Transport::Local));
+        assert_eq!(
+            routes[0].1.rcpt_to,
+            vec!["<a@example.com>", "<c@example.com>"]
+        );
+        assert!(matches!(routes[1].0, Transport::Discard));
+        assert_eq!(routes[1].1.rcpt_to, vec!["<b@Example.org>"]);
+
+        transports.add("*", Transport::Discard);
+        assert!(matches!(
+            transports.get(Some("example.net")),
+            Transport::Discard
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+/// The variable envelope return path for `recipient` based on `sender`, both without
+/// angle brackets, e.g. `owner+alice=example.com@lists.example.com` for `alice@example.com`,
+/// so that a bounce tells which recipient failed without parsing the bounce itself.
+pub fn encode(sender: &str, recipient: &str) -> String {
+    let (local_part, domain) = sender.rsplit_once('@').unwrap_or((sender, ""));
+    let recipient = recipient.replacen('@', "=", 1);
+    match domain {
+        "" => format!("{}+{}", local_part, recipient),
+        domain => format!("{}+{}@{}", local_part, recipient, domain),
+    }
+}
+
+/// The sender and recipient a return path made by `encode` stands for, `None` for any
+/// other address.
+pub fn decode(address: &str) -> Option<(String, String)> {
+    let address = address.trim().trim_start_matches('<').trim_end_matches('>');
+    let (local_part, domain) = address.rsplit_once('@')?;
+    let (prefix, recipient) = local_part.split_once('+')?;
+    let (user, recipient_domain) = recipient.rsplit_once('=')?;
+    if prefix.is_empty() || user.is_empty() || recipient_domain.is_empty() {
+        return None;
+    }
+    Some((
+        format!("{}@{}", prefix, domain),
+        format!("{}@{}", user, recipient_domain),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let address = encode("owner@lists.example.com", "alice@example.com");
+        assert_eq!(address, "owner+alice=example.com@lists.example.com");
+        assert_eq!(
+            decode(&format!("<{}>", address)),
+            Some((
+                String::from("owner@lists.example.com"),
+                String::from("alice@example.com")
+            ))
+        );
+        assert_eq!(decode("owner@lists.example.com"), None);
+        assert_eq!(decode("owner+tag@lists.example.com"), None);
+    }
+}